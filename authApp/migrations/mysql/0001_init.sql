@@ -0,0 +1,72 @@
+-- MySQL schema of docker/sql/init-scheme.sql, which an existing database may already have
+
+-- 1) realm table (holds settings and configs)
+CREATE TABLE IF NOT EXISTS realm (
+    realm_name                              VARCHAR(255)  NOT NULL,
+    is_confirmation_required                TINYINT(1)    NOT NULL DEFAULT 0,
+    is_guest_allowed                        TINYINT(1)    NOT NULL DEFAULT 0,
+    realm_salt_itr                          INT           NOT NULL DEFAULT 10000,
+    authentication_token_duration_seconds   INT           NOT NULL DEFAULT 900,    -- Example: 15min
+    refresh_token_duration_seconds          INT           NOT NULL DEFAULT 604800, -- Example: 7d
+    password_reset_token_duration_seconds   INT           NOT NULL DEFAULT 1800,   -- Example: 30min
+
+    CONSTRAINT PK_realm PRIMARY KEY (realm_name)
+);
+
+-- 2) realm_user table (stores user info for each realm)
+CREATE TABLE IF NOT EXISTS realm_user (
+    id          BIGINT        NOT NULL AUTO_INCREMENT,
+    user_id      VARCHAR(36)   NOT NULL UNIQUE,
+    realm_name   VARCHAR(255) NOT NULL,
+    username     VARCHAR(255) NOT NULL,
+    auth_token   TEXT,
+    reset_token  TEXT,
+    expires_at   DATETIME     NOT NULL,
+    is_god       BOOLEAN      NOT NULL,
+    role         VARCHAR(20)  NOT NULL DEFAULT 'CUSTOMER',
+    password     TEXT         NOT NULL,
+    email        VARCHAR(100),
+    name         VARCHAR(100),
+
+    -- New column for forced password reset
+    pasword_reset_required BOOLEAN NOT NULL DEFAULT 0,
+
+    CONSTRAINT PK_realm_user PRIMARY KEY (id),
+    CONSTRAINT UQ_realm_username UNIQUE (realm_name, username),
+    CONSTRAINT FK_realm_user_realm
+        FOREIGN KEY (realm_name)
+        REFERENCES realm (realm_name)
+        ON UPDATE CASCADE
+        ON DELETE RESTRICT
+);
+
+-- 3) address table (optional, referencing realm_user)
+CREATE TABLE IF NOT EXISTS address (
+    address_id   VARCHAR(36) NOT NULL,
+    user_id      VARCHAR(36) NOT NULL,
+    street       TEXT        NOT NULL,
+    city         TEXT,
+    post_code    VARCHAR(50),
+    country      VARCHAR(100),
+    country_code VARCHAR(10),
+
+    CONSTRAINT PK_address PRIMARY KEY (address_id),
+    CONSTRAINT FK_address_user
+        FOREIGN KEY (user_id)
+        REFERENCES realm_user (user_id)
+        ON UPDATE CASCADE
+        ON DELETE CASCADE
+);
+
+-- Example realm entries (seed data):
+INSERT IGNORE INTO realm (
+    realm_name,
+    is_confirmation_required,
+    is_guest_allowed,
+    realm_salt_itr,
+    authentication_token_duration_seconds,
+    refresh_token_duration_seconds,
+    password_reset_token_duration_seconds
+) VALUES
+('rj.wire', 1, 0, 15000, 900, 604800, 1800),
+('rj.haven', 0, 1, 12000, 900, 604800, 1800);
@@ -0,0 +1,7 @@
+-- Email verification state, reset whenever the email changes
+ALTER TABLE realm_user
+    ADD COLUMN email_verified BOOLEAN NOT NULL DEFAULT 0,
+    ADD COLUMN verification_token TEXT;
+
+-- An email can only belong to one user per realm
+ALTER TABLE realm_user ADD CONSTRAINT UQ_realm_email UNIQUE (realm_name, email);
@@ -0,0 +1,2 @@
+-- Unix time a user was disabled, NULL for active users
+ALTER TABLE realm_user ADD COLUMN deleted_at BIGINT;
@@ -0,0 +1,12 @@
+-- Users may have several addresses, each billing or shipping with one default per type
+ALTER TABLE address
+    ADD COLUMN address_type VARCHAR(16) NOT NULL DEFAULT 'shipping',
+    ADD COLUMN is_default BOOLEAN NOT NULL DEFAULT 0,
+    ADD INDEX IX_address_user (user_id, address_type);
+
+-- Until now a user had a single address, which becomes its default. Should a user have more,
+-- only the lowest id is marked, as every user may have one default per type
+UPDATE address a
+JOIN (SELECT MIN(address_id) AS address_id FROM address GROUP BY user_id) first_address
+    ON a.address_id = first_address.address_id
+SET a.is_default = 1;
@@ -0,0 +1,9 @@
+-- Per-realm password policy. Character classes are comma separated (lowercase, uppercase, digit,
+-- symbol), the deny list holds one password per line and a NULL max repeat means unlimited
+ALTER TABLE realm
+    ADD COLUMN password_min_length INT NOT NULL DEFAULT 8,
+    ADD COLUMN password_max_length INT NOT NULL DEFAULT 128,
+    ADD COLUMN password_required_classes VARCHAR(64) NOT NULL DEFAULT '',
+    ADD COLUMN password_max_repeated INT,
+    ADD COLUMN password_reject_user_details TINYINT(1) NOT NULL DEFAULT 1,
+    ADD COLUMN password_deny_list TEXT;
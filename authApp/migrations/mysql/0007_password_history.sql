@@ -0,0 +1,21 @@
+-- Recent password hashes of each user, pruned to the realm's history size
+ALTER TABLE realm ADD COLUMN password_history_size INT NOT NULL DEFAULT 5;
+
+CREATE TABLE IF NOT EXISTS password_history (
+    id             BIGINT       NOT NULL AUTO_INCREMENT,
+    user_id        VARCHAR(36)  NOT NULL,
+    password_hash  TEXT         NOT NULL,
+    created_at     BIGINT       NOT NULL,
+
+    CONSTRAINT PK_password_history PRIMARY KEY (id),
+    INDEX IX_password_history_user (user_id, id),
+    CONSTRAINT FK_password_history_user
+        FOREIGN KEY (user_id)
+        REFERENCES realm_user (user_id)
+        ON UPDATE CASCADE
+        ON DELETE CASCADE
+);
+
+-- The current password is the first entry of every existing user
+INSERT INTO password_history (user_id, password_hash, created_at)
+SELECT user_id, password, created_at FROM realm_user;
@@ -0,0 +1,17 @@
+-- Single-use recovery codes of users with a second factor, SHA-256 hashed
+CREATE TABLE IF NOT EXISTS mfa_recovery_code (
+    id          BIGINT       NOT NULL AUTO_INCREMENT,
+    user_id     VARCHAR(36)  NOT NULL,
+    code_hash   VARCHAR(64)  NOT NULL,
+    created_at  BIGINT       NOT NULL,
+    used_at     BIGINT,
+    used_ip     VARCHAR(64),
+
+    CONSTRAINT PK_mfa_recovery_code PRIMARY KEY (id),
+    INDEX IX_mfa_recovery_code_user (user_id, code_hash),
+    CONSTRAINT FK_mfa_recovery_code_user
+        FOREIGN KEY (user_id)
+        REFERENCES realm_user (user_id)
+        ON UPDATE CASCADE
+        ON DELETE CASCADE
+);
@@ -0,0 +1,40 @@
+-- Relying party of the realm's passkeys, passkeys are disabled while the id is NULL. Origins are
+-- comma separated (e.g. https://login.example.com)
+ALTER TABLE realm
+    ADD COLUMN webauthn_rp_id VARCHAR(255),
+    ADD COLUMN webauthn_origins VARCHAR(1024) NOT NULL DEFAULT '';
+
+-- Registered passkeys, the public key a base64url COSE key
+CREATE TABLE IF NOT EXISTS webauthn_credential (
+    credential_id  VARCHAR(255)  NOT NULL,
+    user_id        VARCHAR(36)   NOT NULL,
+    public_key     TEXT          NOT NULL,
+    sign_count     BIGINT        NOT NULL DEFAULT 0,
+    name           VARCHAR(100),
+    created_at     BIGINT        NOT NULL,
+    last_used_at   BIGINT,
+
+    CONSTRAINT PK_webauthn_credential PRIMARY KEY (credential_id),
+    INDEX IX_webauthn_credential_user (user_id),
+    CONSTRAINT FK_webauthn_credential_user
+        FOREIGN KEY (user_id)
+        REFERENCES realm_user (user_id)
+        ON UPDATE CASCADE
+        ON DELETE CASCADE
+);
+
+-- Challenges of ceremonies in progress, removed when answered or expired
+CREATE TABLE IF NOT EXISTS webauthn_challenge (
+    realm_name  VARCHAR(255)  NOT NULL,
+    challenge   VARCHAR(64)   NOT NULL,
+    ceremony    VARCHAR(20)   NOT NULL,
+    user_id     VARCHAR(36),
+    expires_at  BIGINT        NOT NULL,
+
+    CONSTRAINT PK_webauthn_challenge PRIMARY KEY (realm_name, challenge),
+    CONSTRAINT FK_webauthn_challenge_realm
+        FOREIGN KEY (realm_name)
+        REFERENCES realm (realm_name)
+        ON UPDATE CASCADE
+        ON DELETE CASCADE
+);
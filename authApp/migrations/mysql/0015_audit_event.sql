@@ -0,0 +1,19 @@
+-- Append-only audit trail. Events outlive the users they are about, so there are no foreign keys,
+-- and only the retention period deletes them
+CREATE TABLE IF NOT EXISTS audit_event (
+    id           BIGINT        NOT NULL AUTO_INCREMENT,
+    realm_name   VARCHAR(255)  NOT NULL,
+    event_type   VARCHAR(40)   NOT NULL,
+    user_id      VARCHAR(36),
+    ip           VARCHAR(45),
+    detail       TEXT,
+    occurred_at  BIGINT        NOT NULL,
+
+    CONSTRAINT PK_audit_event PRIMARY KEY (id),
+    INDEX IX_audit_event_realm (realm_name, id),
+    INDEX IX_audit_event_occurred_at (occurred_at)
+);
+
+CREATE TRIGGER TR_audit_event_append_only BEFORE UPDATE ON audit_event
+FOR EACH ROW
+SIGNAL SQLSTATE '45000' SET MESSAGE_TEXT = 'audit events are append-only';
@@ -0,0 +1,19 @@
+-- Hash chain over the audit events of each realm. Events appended before have no link and stay
+-- outside the chain
+ALTER TABLE audit_event
+    ADD COLUMN sequence BIGINT,
+    ADD COLUMN prev_hash VARCHAR(64),
+    ADD COLUMN entry_hash VARCHAR(64),
+    ADD CONSTRAINT UX_audit_event_sequence UNIQUE (realm_name, sequence);
+
+-- The newest entry of each chain, and the newest one removed by retention that the oldest kept
+-- entry links to
+CREATE TABLE IF NOT EXISTS audit_chain (
+    realm_name       VARCHAR(255)  NOT NULL,
+    head_sequence    BIGINT        NOT NULL,
+    head_hash        VARCHAR(64)   NOT NULL,
+    anchor_sequence  BIGINT        NOT NULL,
+    anchor_hash      VARCHAR(64)   NOT NULL,
+
+    CONSTRAINT PK_audit_chain PRIMARY KEY (realm_name)
+);
@@ -0,0 +1,21 @@
+-- Erasing a user pseudonymises its audit events: the user id is replaced and the ip and detail
+-- cleared, while the chain link stays so the rest of the chain still verifies
+ALTER TABLE audit_event ADD COLUMN redacted_at BIGINT;
+
+DROP TRIGGER IF EXISTS TR_audit_event_append_only;
+CREATE TRIGGER TR_audit_event_append_only BEFORE UPDATE ON audit_event
+FOR EACH ROW
+BEGIN
+    IF NOT (OLD.redacted_at IS NULL AND NEW.redacted_at IS NOT NULL
+        AND NEW.id = OLD.id
+        AND NEW.realm_name = OLD.realm_name
+        AND NEW.event_type = OLD.event_type
+        AND NEW.occurred_at = OLD.occurred_at
+        AND NEW.sequence <=> OLD.sequence
+        AND NEW.prev_hash <=> OLD.prev_hash
+        AND NEW.entry_hash <=> OLD.entry_hash
+        AND NEW.ip IS NULL
+        AND NEW.detail IS NULL) THEN
+        SIGNAL SQLSTATE '45000' SET MESSAGE_TEXT = 'audit events are append-only';
+    END IF;
+END;
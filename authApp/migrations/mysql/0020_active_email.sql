@@ -0,0 +1,7 @@
+-- Disabled users keep their email, which must not stop someone else from registering it. MySQL has
+-- no partial indexes, so the unique key covers a column that is NULL for disabled users
+ALTER TABLE realm_user
+    DROP INDEX UQ_realm_email,
+    ADD COLUMN active_email VARCHAR(100)
+        GENERATED ALWAYS AS (CASE WHEN deleted_at IS NULL THEN email END) VIRTUAL,
+    ADD CONSTRAINT UQ_realm_email UNIQUE (realm_name, active_email);
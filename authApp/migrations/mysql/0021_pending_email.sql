@@ -0,0 +1,2 @@
+-- A changed email waits here until its owner confirms it, the current one stays in use meanwhile
+ALTER TABLE realm_user ADD COLUMN pending_email VARCHAR(100);
@@ -0,0 +1,6 @@
+-- Email verification state, reset whenever the email changes
+ALTER TABLE realm_user ADD COLUMN IF NOT EXISTS email_verified BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE realm_user ADD COLUMN IF NOT EXISTS verification_token TEXT;
+
+-- An email can only belong to one user per realm
+CREATE UNIQUE INDEX IF NOT EXISTS UQ_realm_email ON realm_user (realm_name, email);
@@ -0,0 +1,4 @@
+-- Unix time a user was created, 0 for users created before this column existed
+ALTER TABLE realm_user ADD COLUMN IF NOT EXISTS created_at BIGINT NOT NULL DEFAULT 0;
+
+CREATE INDEX IF NOT EXISTS IX_realm_user_created_at ON realm_user (realm_name, created_at, user_id);
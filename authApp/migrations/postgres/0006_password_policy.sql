@@ -0,0 +1,8 @@
+-- Per-realm password policy. Character classes are comma separated (lowercase, uppercase, digit,
+-- symbol), the deny list holds one password per line and a NULL max repeat means unlimited
+ALTER TABLE realm ADD COLUMN IF NOT EXISTS password_min_length INT NOT NULL DEFAULT 8;
+ALTER TABLE realm ADD COLUMN IF NOT EXISTS password_max_length INT NOT NULL DEFAULT 128;
+ALTER TABLE realm ADD COLUMN IF NOT EXISTS password_required_classes TEXT NOT NULL DEFAULT '';
+ALTER TABLE realm ADD COLUMN IF NOT EXISTS password_max_repeated INT;
+ALTER TABLE realm ADD COLUMN IF NOT EXISTS password_reject_user_details BOOLEAN NOT NULL DEFAULT TRUE;
+ALTER TABLE realm ADD COLUMN IF NOT EXISTS password_deny_list TEXT NOT NULL DEFAULT '';
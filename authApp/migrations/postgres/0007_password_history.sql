@@ -0,0 +1,21 @@
+-- Recent password hashes of each user, pruned to the realm's history size
+ALTER TABLE realm ADD COLUMN IF NOT EXISTS password_history_size INT NOT NULL DEFAULT 5;
+
+CREATE TABLE IF NOT EXISTS password_history (
+    id             BIGSERIAL     PRIMARY KEY,
+    user_id        VARCHAR(36)   NOT NULL,
+    password_hash  TEXT          NOT NULL,
+    created_at     BIGINT        NOT NULL,
+
+    CONSTRAINT FK_password_history_user
+        FOREIGN KEY (user_id)
+        REFERENCES realm_user (user_id)
+        ON UPDATE CASCADE
+        ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS IX_password_history_user ON password_history (user_id, id);
+
+-- The current password is the first entry of every existing user
+INSERT INTO password_history (user_id, password_hash, created_at)
+SELECT user_id, password, created_at FROM realm_user;
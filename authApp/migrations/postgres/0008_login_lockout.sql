@@ -0,0 +1,23 @@
+-- Per-realm brute-force lockout. A threshold of 0 disables lockout for that key type
+ALTER TABLE realm ADD COLUMN IF NOT EXISTS lockout_max_user_failures INT NOT NULL DEFAULT 5;
+ALTER TABLE realm ADD COLUMN IF NOT EXISTS lockout_max_ip_failures INT NOT NULL DEFAULT 20;
+ALTER TABLE realm ADD COLUMN IF NOT EXISTS lockout_failure_window_seconds INT NOT NULL DEFAULT 900;
+ALTER TABLE realm ADD COLUMN IF NOT EXISTS lockout_duration_seconds INT NOT NULL DEFAULT 300;
+ALTER TABLE realm ADD COLUMN IF NOT EXISTS lockout_max_duration_seconds INT NOT NULL DEFAULT 86400;
+
+-- Failed login attempts, keyed by "user:<user_id>" or "ip:<address>"
+CREATE TABLE IF NOT EXISTS login_attempt (
+    realm_name       VARCHAR(255)  NOT NULL,
+    attempt_key      VARCHAR(128)  NOT NULL,
+    failures         INT           NOT NULL DEFAULT 0,
+    lockouts         INT           NOT NULL DEFAULT 0,
+    last_failure_at  BIGINT        NOT NULL,
+    locked_until     BIGINT,
+
+    CONSTRAINT PK_login_attempt PRIMARY KEY (realm_name, attempt_key),
+    CONSTRAINT FK_login_attempt_realm
+        FOREIGN KEY (realm_name)
+        REFERENCES realm (realm_name)
+        ON UPDATE CASCADE
+        ON DELETE CASCADE
+);
@@ -0,0 +1,20 @@
+-- Per-realm rate limits in requests per minute for each route class, 0 leaves a class unlimited
+ALTER TABLE realm ADD COLUMN IF NOT EXISTS rate_limit_login_per_minute INT NOT NULL DEFAULT 30;
+ALTER TABLE realm ADD COLUMN IF NOT EXISTS rate_limit_registration_per_minute INT NOT NULL DEFAULT 10;
+ALTER TABLE realm ADD COLUMN IF NOT EXISTS rate_limit_password_reset_per_minute INT NOT NULL DEFAULT 10;
+ALTER TABLE realm ADD COLUMN IF NOT EXISTS rate_limit_admin_per_minute INT NOT NULL DEFAULT 120;
+
+-- Token buckets of the storage backed rate limiter, updated_at in unix milliseconds
+CREATE TABLE IF NOT EXISTS rate_limit_bucket (
+    realm_name  VARCHAR(255)      NOT NULL,
+    bucket_key  VARCHAR(255)      NOT NULL,
+    tokens      DOUBLE PRECISION  NOT NULL,
+    updated_at  BIGINT            NOT NULL,
+
+    CONSTRAINT PK_rate_limit_bucket PRIMARY KEY (realm_name, bucket_key),
+    CONSTRAINT FK_rate_limit_bucket_realm
+        FOREIGN KEY (realm_name)
+        REFERENCES realm (realm_name)
+        ON UPDATE CASCADE
+        ON DELETE CASCADE
+);
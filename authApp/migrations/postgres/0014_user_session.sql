@@ -0,0 +1,26 @@
+-- Login sessions, each with the SHA-256 hash of its current refresh token
+CREATE TABLE IF NOT EXISTS user_session (
+    session_id          VARCHAR(36)   NOT NULL,
+    realm_name          VARCHAR(255)  NOT NULL,
+    user_id             VARCHAR(36)   NOT NULL,
+    refresh_token_hash  VARCHAR(64)   NOT NULL,
+    user_agent          VARCHAR(255),
+    ip                  VARCHAR(45),
+    created_at          BIGINT        NOT NULL,
+    last_used_at        BIGINT        NOT NULL,
+    expires_at          BIGINT        NOT NULL,
+
+    CONSTRAINT PK_user_session PRIMARY KEY (session_id),
+    CONSTRAINT FK_user_session_realm
+        FOREIGN KEY (realm_name)
+        REFERENCES realm (realm_name)
+        ON UPDATE CASCADE
+        ON DELETE CASCADE,
+    CONSTRAINT FK_user_session_user
+        FOREIGN KEY (user_id)
+        REFERENCES realm_user (user_id)
+        ON UPDATE CASCADE
+        ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS IX_user_session_user ON user_session (user_id);
@@ -0,0 +1,26 @@
+-- Append-only audit trail. Events outlive the users they are about, so there are no foreign keys,
+-- and only the retention period deletes them
+CREATE TABLE IF NOT EXISTS audit_event (
+    id           BIGSERIAL     NOT NULL,
+    realm_name   VARCHAR(255)  NOT NULL,
+    event_type   VARCHAR(40)   NOT NULL,
+    user_id      VARCHAR(36),
+    ip           VARCHAR(45),
+    detail       TEXT,
+    occurred_at  BIGINT        NOT NULL,
+
+    CONSTRAINT PK_audit_event PRIMARY KEY (id)
+);
+
+CREATE INDEX IF NOT EXISTS IX_audit_event_realm ON audit_event (realm_name, id);
+CREATE INDEX IF NOT EXISTS IX_audit_event_occurred_at ON audit_event (occurred_at);
+
+CREATE OR REPLACE FUNCTION audit_event_append_only() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'audit events are append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS TR_audit_event_append_only ON audit_event;
+CREATE TRIGGER TR_audit_event_append_only BEFORE UPDATE ON audit_event
+    FOR EACH ROW EXECUTE FUNCTION audit_event_append_only();
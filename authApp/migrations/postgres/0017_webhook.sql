@@ -0,0 +1,44 @@
+-- Webhook subscriptions of each realm, with their signing secret sealed by the secret cipher
+CREATE TABLE IF NOT EXISTS webhook_subscription (
+    subscription_id  VARCHAR(36)    NOT NULL,
+    realm_name       VARCHAR(255)   NOT NULL,
+    url              VARCHAR(2048)  NOT NULL,
+    sealed_secret    TEXT           NOT NULL,
+    event_types      VARCHAR(255)   NOT NULL,
+    created_at       BIGINT         NOT NULL,
+
+    CONSTRAINT PK_webhook_subscription PRIMARY KEY (subscription_id),
+    CONSTRAINT FK_webhook_subscription_realm
+        FOREIGN KEY (realm_name)
+        REFERENCES realm (realm_name)
+        ON UPDATE CASCADE
+        ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS IX_webhook_subscription_realm ON webhook_subscription (realm_name);
+
+-- Queue of webhook events, one row per event and subscription
+CREATE TABLE IF NOT EXISTS webhook_delivery (
+    delivery_id      VARCHAR(36)   NOT NULL,
+    realm_name       VARCHAR(255)  NOT NULL,
+    subscription_id  VARCHAR(36)   NOT NULL,
+    event_id         VARCHAR(36)   NOT NULL,
+    event_type       VARCHAR(32)   NOT NULL,
+    payload          TEXT          NOT NULL,
+    status           VARCHAR(16)   NOT NULL,
+    attempts         INTEGER       NOT NULL,
+    next_attempt_at  BIGINT        NOT NULL,
+    last_error       VARCHAR(500),
+    created_at       BIGINT        NOT NULL,
+    delivered_at     BIGINT,
+
+    CONSTRAINT PK_webhook_delivery PRIMARY KEY (delivery_id),
+    CONSTRAINT FK_webhook_delivery_subscription
+        FOREIGN KEY (subscription_id)
+        REFERENCES webhook_subscription (subscription_id)
+        ON UPDATE CASCADE
+        ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS IX_webhook_delivery_due ON webhook_delivery (status, next_attempt_at);
+CREATE INDEX IF NOT EXISTS IX_webhook_delivery_realm ON webhook_delivery (realm_name, status);
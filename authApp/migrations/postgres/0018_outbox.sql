@@ -0,0 +1,25 @@
+-- Events recorded in the transaction of the change they are about, until the dispatcher
+-- publishes them. Events outlive the users they are about, so there are no foreign keys
+CREATE TABLE IF NOT EXISTS outbox_event (
+    id               BIGSERIAL     NOT NULL,
+    event_id         VARCHAR(36)   NOT NULL,
+    realm_name       VARCHAR(255)  NOT NULL,
+    aggregate_type   VARCHAR(32)   NOT NULL,
+    aggregate_id     VARCHAR(255)  NOT NULL,
+    event_type       VARCHAR(64)   NOT NULL,
+    payload          TEXT          NOT NULL,
+    occurred_at      BIGINT        NOT NULL,
+    attempts         INTEGER       NOT NULL,
+    next_attempt_at  BIGINT        NOT NULL,
+    last_error       VARCHAR(500),
+    published_at     BIGINT,
+
+    CONSTRAINT PK_outbox_event PRIMARY KEY (id),
+    CONSTRAINT UX_outbox_event_event_id UNIQUE (event_id)
+);
+
+CREATE INDEX IF NOT EXISTS IX_outbox_event_aggregate ON outbox_event (aggregate_type, aggregate_id, id);
+CREATE INDEX IF NOT EXISTS IX_outbox_event_due ON outbox_event (published_at, next_attempt_at);
+
+-- An event published again must not be delivered twice
+CREATE UNIQUE INDEX IF NOT EXISTS UX_webhook_delivery_event ON webhook_delivery (subscription_id, event_id);
@@ -0,0 +1,21 @@
+-- Erasing a user pseudonymises its audit events: the user id is replaced and the ip and detail
+-- cleared, while the chain link stays so the rest of the chain still verifies
+ALTER TABLE audit_event ADD COLUMN IF NOT EXISTS redacted_at BIGINT;
+
+CREATE OR REPLACE FUNCTION audit_event_append_only() RETURNS trigger AS $$
+BEGIN
+    IF OLD.redacted_at IS NULL AND NEW.redacted_at IS NOT NULL
+        AND NEW.id = OLD.id
+        AND NEW.realm_name = OLD.realm_name
+        AND NEW.event_type = OLD.event_type
+        AND NEW.occurred_at = OLD.occurred_at
+        AND NEW.sequence IS NOT DISTINCT FROM OLD.sequence
+        AND NEW.prev_hash IS NOT DISTINCT FROM OLD.prev_hash
+        AND NEW.entry_hash IS NOT DISTINCT FROM OLD.entry_hash
+        AND NEW.ip IS NULL
+        AND NEW.detail IS NULL THEN
+        RETURN NEW;
+    END IF;
+    RAISE EXCEPTION 'audit events are append-only';
+END;
+$$ LANGUAGE plpgsql;
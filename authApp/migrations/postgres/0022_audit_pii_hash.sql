@@ -0,0 +1,25 @@
+-- Chain entries hash their user id, ip and detail through a salted hash of their own, so erasing
+-- a user only deletes the salt and the rest of the entry still verifies. Entries chained before
+-- have neither and hash their content directly
+ALTER TABLE audit_event ADD COLUMN IF NOT EXISTS pii_hash VARCHAR(64);
+ALTER TABLE audit_event ADD COLUMN IF NOT EXISTS pii_salt VARCHAR(32);
+
+CREATE OR REPLACE FUNCTION audit_event_append_only() RETURNS trigger AS $$
+BEGIN
+    IF OLD.redacted_at IS NULL AND NEW.redacted_at IS NOT NULL
+        AND NEW.id = OLD.id
+        AND NEW.realm_name = OLD.realm_name
+        AND NEW.event_type = OLD.event_type
+        AND NEW.occurred_at = OLD.occurred_at
+        AND NEW.sequence IS NOT DISTINCT FROM OLD.sequence
+        AND NEW.prev_hash IS NOT DISTINCT FROM OLD.prev_hash
+        AND NEW.entry_hash IS NOT DISTINCT FROM OLD.entry_hash
+        AND NEW.pii_hash IS NOT DISTINCT FROM OLD.pii_hash
+        AND NEW.pii_salt IS NULL
+        AND NEW.ip IS NULL
+        AND NEW.detail IS NULL THEN
+        RETURN NEW;
+    END IF;
+    RAISE EXCEPTION 'audit events are append-only';
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,71 @@
+-- SQLite equivalent of docker/sql/init-scheme.sql
+
+-- 1) realm table (holds settings and configs)
+CREATE TABLE IF NOT EXISTS realm (
+    realm_name                              TEXT     NOT NULL,
+    is_confirmation_required                INTEGER  NOT NULL DEFAULT 0,
+    is_guest_allowed                        INTEGER  NOT NULL DEFAULT 0,
+    realm_salt_itr                          INTEGER  NOT NULL DEFAULT 10000,
+    authentication_token_duration_seconds   INTEGER  NOT NULL DEFAULT 900,    -- Example: 15min
+    refresh_token_duration_seconds          INTEGER  NOT NULL DEFAULT 604800, -- Example: 7d
+    password_reset_token_duration_seconds   INTEGER  NOT NULL DEFAULT 1800,   -- Example: 30min
+
+    CONSTRAINT PK_realm PRIMARY KEY (realm_name)
+);
+
+-- 2) realm_user table (stores user info for each realm)
+CREATE TABLE IF NOT EXISTS realm_user (
+    id           INTEGER  PRIMARY KEY AUTOINCREMENT,
+    user_id      TEXT     NOT NULL UNIQUE,
+    realm_name   TEXT     NOT NULL,
+    username     TEXT     NOT NULL,
+    auth_token   TEXT,
+    reset_token  TEXT,
+    expires_at   TEXT     NOT NULL,
+    is_god       INTEGER  NOT NULL,
+    role         TEXT     NOT NULL DEFAULT 'CUSTOMER',
+    password     TEXT     NOT NULL,
+    email        TEXT,
+    name         TEXT,
+
+    -- New column for forced password reset
+    pasword_reset_required INTEGER NOT NULL DEFAULT 0,
+
+    CONSTRAINT UQ_realm_username UNIQUE (realm_name, username),
+    CONSTRAINT FK_realm_user_realm
+        FOREIGN KEY (realm_name)
+        REFERENCES realm (realm_name)
+        ON UPDATE CASCADE
+        ON DELETE RESTRICT
+);
+
+-- 3) address table (optional, referencing realm_user)
+CREATE TABLE IF NOT EXISTS address (
+    address_id   TEXT NOT NULL,
+    user_id      TEXT NOT NULL,
+    street       TEXT NOT NULL,
+    city         TEXT,
+    post_code    TEXT,
+    country      TEXT,
+    country_code TEXT,
+
+    CONSTRAINT PK_address PRIMARY KEY (address_id),
+    CONSTRAINT FK_address_user
+        FOREIGN KEY (user_id)
+        REFERENCES realm_user (user_id)
+        ON UPDATE CASCADE
+        ON DELETE CASCADE
+);
+
+-- Example realm entries (seed data):
+INSERT OR IGNORE INTO realm (
+    realm_name,
+    is_confirmation_required,
+    is_guest_allowed,
+    realm_salt_itr,
+    authentication_token_duration_seconds,
+    refresh_token_duration_seconds,
+    password_reset_token_duration_seconds
+) VALUES
+('rj.wire', 1, 0, 15000, 900, 604800, 1800),
+('rj.haven', 0, 1, 12000, 900, 604800, 1800);
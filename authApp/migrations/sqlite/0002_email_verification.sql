@@ -0,0 +1,6 @@
+-- Email verification state, reset whenever the email changes
+ALTER TABLE realm_user ADD COLUMN email_verified INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE realm_user ADD COLUMN verification_token TEXT;
+
+-- An email can only belong to one user per realm
+CREATE UNIQUE INDEX IF NOT EXISTS UQ_realm_email ON realm_user (realm_name, email);
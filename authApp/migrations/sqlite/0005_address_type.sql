@@ -0,0 +1,10 @@
+-- Users may have several addresses, each billing or shipping with one default per type
+ALTER TABLE address ADD COLUMN address_type TEXT NOT NULL DEFAULT 'shipping';
+ALTER TABLE address ADD COLUMN is_default INTEGER NOT NULL DEFAULT 0;
+
+-- Until now a user had a single address, which becomes its default. Should a user have more,
+-- only the lowest id is marked, as every user may have one default per type
+UPDATE address SET is_default = 1
+WHERE address_id IN (SELECT MIN(address_id) FROM address GROUP BY user_id);
+
+CREATE INDEX IF NOT EXISTS IX_address_user ON address (user_id, address_type);
@@ -0,0 +1,8 @@
+-- Per-realm password policy. Character classes are comma separated (lowercase, uppercase, digit,
+-- symbol), the deny list holds one password per line and a NULL max repeat means unlimited
+ALTER TABLE realm ADD COLUMN password_min_length INTEGER NOT NULL DEFAULT 8;
+ALTER TABLE realm ADD COLUMN password_max_length INTEGER NOT NULL DEFAULT 128;
+ALTER TABLE realm ADD COLUMN password_required_classes TEXT NOT NULL DEFAULT '';
+ALTER TABLE realm ADD COLUMN password_max_repeated INTEGER;
+ALTER TABLE realm ADD COLUMN password_reject_user_details INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE realm ADD COLUMN password_deny_list TEXT NOT NULL DEFAULT '';
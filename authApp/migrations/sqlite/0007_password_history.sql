@@ -0,0 +1,21 @@
+-- Recent password hashes of each user, pruned to the realm's history size
+ALTER TABLE realm ADD COLUMN password_history_size INTEGER NOT NULL DEFAULT 5;
+
+CREATE TABLE IF NOT EXISTS password_history (
+    id             INTEGER  PRIMARY KEY AUTOINCREMENT,
+    user_id        TEXT     NOT NULL,
+    password_hash  TEXT     NOT NULL,
+    created_at     INTEGER  NOT NULL,
+
+    CONSTRAINT FK_password_history_user
+        FOREIGN KEY (user_id)
+        REFERENCES realm_user (user_id)
+        ON UPDATE CASCADE
+        ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS IX_password_history_user ON password_history (user_id, id);
+
+-- The current password is the first entry of every existing user
+INSERT INTO password_history (user_id, password_hash, created_at)
+SELECT user_id, password, created_at FROM realm_user;
@@ -0,0 +1,23 @@
+-- Per-realm brute-force lockout. A threshold of 0 disables lockout for that key type
+ALTER TABLE realm ADD COLUMN lockout_max_user_failures INTEGER NOT NULL DEFAULT 5;
+ALTER TABLE realm ADD COLUMN lockout_max_ip_failures INTEGER NOT NULL DEFAULT 20;
+ALTER TABLE realm ADD COLUMN lockout_failure_window_seconds INTEGER NOT NULL DEFAULT 900;
+ALTER TABLE realm ADD COLUMN lockout_duration_seconds INTEGER NOT NULL DEFAULT 300;
+ALTER TABLE realm ADD COLUMN lockout_max_duration_seconds INTEGER NOT NULL DEFAULT 86400;
+
+-- Failed login attempts, keyed by "user:<user_id>" or "ip:<address>"
+CREATE TABLE IF NOT EXISTS login_attempt (
+    realm_name       TEXT     NOT NULL,
+    attempt_key      TEXT     NOT NULL,
+    failures         INTEGER  NOT NULL DEFAULT 0,
+    lockouts         INTEGER  NOT NULL DEFAULT 0,
+    last_failure_at  INTEGER  NOT NULL,
+    locked_until     INTEGER,
+
+    CONSTRAINT PK_login_attempt PRIMARY KEY (realm_name, attempt_key),
+    CONSTRAINT FK_login_attempt_realm
+        FOREIGN KEY (realm_name)
+        REFERENCES realm (realm_name)
+        ON UPDATE CASCADE
+        ON DELETE CASCADE
+);
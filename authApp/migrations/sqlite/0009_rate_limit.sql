@@ -0,0 +1,20 @@
+-- Per-realm rate limits in requests per minute for each route class, 0 leaves a class unlimited
+ALTER TABLE realm ADD COLUMN rate_limit_login_per_minute INTEGER NOT NULL DEFAULT 30;
+ALTER TABLE realm ADD COLUMN rate_limit_registration_per_minute INTEGER NOT NULL DEFAULT 10;
+ALTER TABLE realm ADD COLUMN rate_limit_password_reset_per_minute INTEGER NOT NULL DEFAULT 10;
+ALTER TABLE realm ADD COLUMN rate_limit_admin_per_minute INTEGER NOT NULL DEFAULT 120;
+
+-- Token buckets of the storage backed rate limiter, updated_at in unix milliseconds
+CREATE TABLE IF NOT EXISTS rate_limit_bucket (
+    realm_name  TEXT     NOT NULL,
+    bucket_key  TEXT     NOT NULL,
+    tokens      REAL     NOT NULL,
+    updated_at  INTEGER  NOT NULL,
+
+    CONSTRAINT PK_rate_limit_bucket PRIMARY KEY (realm_name, bucket_key),
+    CONSTRAINT FK_rate_limit_bucket_realm
+        FOREIGN KEY (realm_name)
+        REFERENCES realm (realm_name)
+        ON UPDATE CASCADE
+        ON DELETE CASCADE
+);
@@ -0,0 +1,20 @@
+-- Roles that must use a second factor, comma separated (e.g. ADMIN); others may opt in
+ALTER TABLE realm ADD COLUMN mfa_required_roles TEXT NOT NULL DEFAULT '';
+
+-- Challenge issued by a password login of a user with a second factor
+ALTER TABLE realm_user ADD COLUMN mfa_token TEXT;
+
+-- TOTP enrollments, the secret sealed with the process' MFA key
+CREATE TABLE IF NOT EXISTS mfa_totp (
+    user_id         TEXT     NOT NULL,
+    secret          TEXT     NOT NULL,
+    activated_at    INTEGER,
+    last_used_step  INTEGER,
+
+    CONSTRAINT PK_mfa_totp PRIMARY KEY (user_id),
+    CONSTRAINT FK_mfa_totp_user
+        FOREIGN KEY (user_id)
+        REFERENCES realm_user (user_id)
+        ON UPDATE CASCADE
+        ON DELETE CASCADE
+);
@@ -0,0 +1,17 @@
+-- Single-use recovery codes of users with a second factor, SHA-256 hashed
+CREATE TABLE IF NOT EXISTS mfa_recovery_code (
+    id          INTEGER  PRIMARY KEY AUTOINCREMENT,
+    user_id     TEXT     NOT NULL,
+    code_hash   TEXT     NOT NULL,
+    created_at  INTEGER  NOT NULL,
+    used_at     INTEGER,
+    used_ip     TEXT,
+
+    CONSTRAINT FK_mfa_recovery_code_user
+        FOREIGN KEY (user_id)
+        REFERENCES realm_user (user_id)
+        ON UPDATE CASCADE
+        ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS IX_mfa_recovery_code_user ON mfa_recovery_code (user_id, code_hash);
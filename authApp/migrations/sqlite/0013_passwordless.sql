@@ -0,0 +1,21 @@
+-- Passwordless login of the realm: '' (disabled), 'magic_link' or 'email_code', how long an issued
+-- credential is valid and how many wrong guesses it survives
+ALTER TABLE realm ADD COLUMN passwordless_method TEXT NOT NULL DEFAULT '';
+ALTER TABLE realm ADD COLUMN passwordless_ttl_seconds INTEGER NOT NULL DEFAULT 600;
+ALTER TABLE realm ADD COLUMN passwordless_max_attempts INTEGER NOT NULL DEFAULT 5;
+
+-- The pending passwordless credential of a user, SHA-256 hashed and removed once redeemed
+CREATE TABLE IF NOT EXISTS passwordless_credential (
+    user_id      TEXT     NOT NULL,
+    method       TEXT     NOT NULL,
+    secret_hash  TEXT     NOT NULL,
+    expires_at   INTEGER  NOT NULL,
+    attempts     INTEGER  NOT NULL DEFAULT 0,
+
+    CONSTRAINT PK_passwordless_credential PRIMARY KEY (user_id),
+    CONSTRAINT FK_passwordless_credential_user
+        FOREIGN KEY (user_id)
+        REFERENCES realm_user (user_id)
+        ON UPDATE CASCADE
+        ON DELETE CASCADE
+);
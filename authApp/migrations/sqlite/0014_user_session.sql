@@ -0,0 +1,26 @@
+-- Login sessions, each with the SHA-256 hash of its current refresh token
+CREATE TABLE IF NOT EXISTS user_session (
+    session_id          TEXT     NOT NULL,
+    realm_name          TEXT     NOT NULL,
+    user_id             TEXT     NOT NULL,
+    refresh_token_hash  TEXT     NOT NULL,
+    user_agent          TEXT,
+    ip                  TEXT,
+    created_at          INTEGER  NOT NULL,
+    last_used_at        INTEGER  NOT NULL,
+    expires_at          INTEGER  NOT NULL,
+
+    CONSTRAINT PK_user_session PRIMARY KEY (session_id),
+    CONSTRAINT FK_user_session_realm
+        FOREIGN KEY (realm_name)
+        REFERENCES realm (realm_name)
+        ON UPDATE CASCADE
+        ON DELETE CASCADE,
+    CONSTRAINT FK_user_session_user
+        FOREIGN KEY (user_id)
+        REFERENCES realm_user (user_id)
+        ON UPDATE CASCADE
+        ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS IX_user_session_user ON user_session (user_id);
@@ -0,0 +1,19 @@
+-- Append-only audit trail. Events outlive the users they are about, so there are no foreign keys,
+-- and only the retention period deletes them
+CREATE TABLE IF NOT EXISTS audit_event (
+    id           INTEGER  NOT NULL PRIMARY KEY AUTOINCREMENT,
+    realm_name   TEXT     NOT NULL,
+    event_type   TEXT     NOT NULL,
+    user_id      TEXT,
+    ip           TEXT,
+    detail       TEXT,
+    occurred_at  INTEGER  NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS IX_audit_event_realm ON audit_event (realm_name, id);
+CREATE INDEX IF NOT EXISTS IX_audit_event_occurred_at ON audit_event (occurred_at);
+
+CREATE TRIGGER IF NOT EXISTS TR_audit_event_append_only BEFORE UPDATE ON audit_event
+BEGIN
+    SELECT RAISE(ABORT, 'audit events are append-only');
+END;
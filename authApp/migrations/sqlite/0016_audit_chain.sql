@@ -0,0 +1,17 @@
+-- Hash chain over the audit events of each realm. Events appended before have no link and stay
+-- outside the chain
+ALTER TABLE audit_event ADD COLUMN sequence INTEGER;
+ALTER TABLE audit_event ADD COLUMN prev_hash TEXT;
+ALTER TABLE audit_event ADD COLUMN entry_hash TEXT;
+
+CREATE UNIQUE INDEX IF NOT EXISTS UX_audit_event_sequence ON audit_event (realm_name, sequence);
+
+-- The newest entry of each chain, and the newest one removed by retention that the oldest kept
+-- entry links to
+CREATE TABLE IF NOT EXISTS audit_chain (
+    realm_name       TEXT     NOT NULL PRIMARY KEY,
+    head_sequence    INTEGER  NOT NULL,
+    head_hash        TEXT     NOT NULL,
+    anchor_sequence  INTEGER  NOT NULL,
+    anchor_hash      TEXT     NOT NULL
+);
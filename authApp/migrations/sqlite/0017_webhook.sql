@@ -0,0 +1,44 @@
+-- Webhook subscriptions of each realm, with their signing secret sealed by the secret cipher
+CREATE TABLE IF NOT EXISTS webhook_subscription (
+    subscription_id  TEXT     NOT NULL,
+    realm_name       TEXT     NOT NULL,
+    url              TEXT     NOT NULL,
+    sealed_secret    TEXT     NOT NULL,
+    event_types      TEXT     NOT NULL,
+    created_at       INTEGER  NOT NULL,
+
+    CONSTRAINT PK_webhook_subscription PRIMARY KEY (subscription_id),
+    CONSTRAINT FK_webhook_subscription_realm
+        FOREIGN KEY (realm_name)
+        REFERENCES realm (realm_name)
+        ON UPDATE CASCADE
+        ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS IX_webhook_subscription_realm ON webhook_subscription (realm_name);
+
+-- Queue of webhook events, one row per event and subscription
+CREATE TABLE IF NOT EXISTS webhook_delivery (
+    delivery_id      TEXT     NOT NULL,
+    realm_name       TEXT     NOT NULL,
+    subscription_id  TEXT     NOT NULL,
+    event_id         TEXT     NOT NULL,
+    event_type       TEXT     NOT NULL,
+    payload          TEXT     NOT NULL,
+    status           TEXT     NOT NULL,
+    attempts         INTEGER  NOT NULL,
+    next_attempt_at  INTEGER  NOT NULL,
+    last_error       TEXT,
+    created_at       INTEGER  NOT NULL,
+    delivered_at     INTEGER,
+
+    CONSTRAINT PK_webhook_delivery PRIMARY KEY (delivery_id),
+    CONSTRAINT FK_webhook_delivery_subscription
+        FOREIGN KEY (subscription_id)
+        REFERENCES webhook_subscription (subscription_id)
+        ON UPDATE CASCADE
+        ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS IX_webhook_delivery_due ON webhook_delivery (status, next_attempt_at);
+CREATE INDEX IF NOT EXISTS IX_webhook_delivery_realm ON webhook_delivery (realm_name, status);
@@ -0,0 +1,23 @@
+-- Events recorded in the transaction of the change they are about, until the dispatcher
+-- publishes them. Events outlive the users they are about, so there are no foreign keys
+CREATE TABLE IF NOT EXISTS outbox_event (
+    id               INTEGER  NOT NULL PRIMARY KEY AUTOINCREMENT,
+    event_id         TEXT     NOT NULL,
+    realm_name       TEXT     NOT NULL,
+    aggregate_type   TEXT     NOT NULL,
+    aggregate_id     TEXT     NOT NULL,
+    event_type       TEXT     NOT NULL,
+    payload          TEXT     NOT NULL,
+    occurred_at      INTEGER  NOT NULL,
+    attempts         INTEGER  NOT NULL,
+    next_attempt_at  INTEGER  NOT NULL,
+    last_error       TEXT,
+    published_at     INTEGER
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS UX_outbox_event_event_id ON outbox_event (event_id);
+CREATE INDEX IF NOT EXISTS IX_outbox_event_aggregate ON outbox_event (aggregate_type, aggregate_id, id);
+CREATE INDEX IF NOT EXISTS IX_outbox_event_due ON outbox_event (published_at, next_attempt_at);
+
+-- An event published again must not be delivered twice
+CREATE UNIQUE INDEX IF NOT EXISTS UX_webhook_delivery_event ON webhook_delivery (subscription_id, event_id);
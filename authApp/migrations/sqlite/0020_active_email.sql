@@ -0,0 +1,3 @@
+-- Disabled users keep their email, which must not stop someone else from registering it
+DROP INDEX IF EXISTS UQ_realm_email;
+CREATE UNIQUE INDEX UQ_realm_email ON realm_user (realm_name, email) WHERE deleted_at IS NULL;
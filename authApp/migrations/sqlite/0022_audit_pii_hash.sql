@@ -0,0 +1,25 @@
+-- Chain entries hash their user id, ip and detail through a salted hash of their own, so erasing
+-- a user only deletes the salt and the rest of the entry still verifies. Entries chained before
+-- have neither and hash their content directly
+ALTER TABLE audit_event ADD COLUMN pii_hash TEXT;
+ALTER TABLE audit_event ADD COLUMN pii_salt TEXT;
+
+DROP TRIGGER IF EXISTS TR_audit_event_append_only;
+CREATE TRIGGER TR_audit_event_append_only BEFORE UPDATE ON audit_event
+WHEN NOT (
+    OLD.redacted_at IS NULL AND NEW.redacted_at IS NOT NULL
+    AND NEW.id = OLD.id
+    AND NEW.realm_name = OLD.realm_name
+    AND NEW.event_type = OLD.event_type
+    AND NEW.occurred_at = OLD.occurred_at
+    AND NEW.sequence IS OLD.sequence
+    AND NEW.prev_hash IS OLD.prev_hash
+    AND NEW.entry_hash IS OLD.entry_hash
+    AND NEW.pii_hash IS OLD.pii_hash
+    AND NEW.pii_salt IS NULL
+    AND NEW.ip IS NULL
+    AND NEW.detail IS NULL
+)
+BEGIN
+    SELECT RAISE(ABORT, 'audit events are append-only');
+END;
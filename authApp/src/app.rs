@@ -1,3 +1,9 @@
+use crate::domain::infra::web::JsonErrorResponse;
+use crate::repository::StorageError;
+use actix_web::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use validator::{ValidationErrors, ValidationErrorsKind};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -6,4 +12,148 @@ pub enum Error {
 
     #[error("Incorrect password")]
     IncorrectPassword,
-}
\ No newline at end of file
+
+    /// A wrong second factor code at login.
+    #[error("Incorrect verification code")]
+    IncorrectCode,
+
+    /// A second factor secret no longer opens, e.g. after the MFA key changed.
+    #[error("Failed to decrypt second factor secret")]
+    SecretDecryption,
+
+    #[error("Invalid request: {0}")]
+    Validation(String),
+
+    /// A request body failed validation, keyed by the path of the offending field.
+    #[error("Invalid request fields")]
+    InvalidFields(FieldErrors),
+
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+
+    /// A message could not be handed to the delivery channel.
+    #[error("Failed to deliver message: {0}")]
+    Delivery(String),
+
+    /// A request without a valid access token of an open session.
+    #[error("Missing or invalid access token")]
+    Unauthenticated,
+
+    /// A refresh token of no session, or of a revoked or expired one.
+    #[error("Invalid or expired refresh token")]
+    InvalidRefreshToken,
+
+    /// A passkey assertion that failed verification at login.
+    #[error("Passkey was rejected: {0}")]
+    PasskeyRejected(String),
+
+    /// Too many failed logins for the user or the client IP.
+    #[error("Too many failed login attempts, retry in {retry_after} seconds")]
+    Locked { retry_after: u64 },
+
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+}
+
+/// Validation failures by field path, nested fields are joined with a dot, e.g.
+/// `address.post_code`.
+pub type FieldErrors = BTreeMap<String, Vec<FieldError>>;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FieldError {
+    /// Machine readable rule name, e.g. `length` or `email`.
+    pub code: String,
+    pub message: String,
+}
+
+impl Error {
+    /// A single failed rule on `field`.
+    pub fn field(field: &str, code: &str, message: String) -> Error {
+        let error = FieldError {
+            code: code.to_string(),
+            message,
+        };
+        Error::InvalidFields(BTreeMap::from([(field.to_string(), vec![error])]))
+    }
+
+    /// Moves field errors under `prefix`, for a DTO validated as part of an enclosing one.
+    pub fn nested(self, prefix: &str) -> Error {
+        match self {
+            Error::InvalidFields(fields) => Error::InvalidFields(
+                fields
+                    .into_iter()
+                    .map(|(field, errors)| (format!("{}.{}", prefix, field), errors))
+                    .collect(),
+            ),
+            other => other,
+        }
+    }
+}
+
+impl From<ValidationErrors> for Error {
+    fn from(errors: ValidationErrors) -> Self {
+        let mut fields = FieldErrors::new();
+        flatten_errors("", &errors, &mut fields);
+        Error::InvalidFields(fields)
+    }
+}
+
+fn flatten_errors(prefix: &str, errors: &ValidationErrors, fields: &mut FieldErrors) {
+    for (field, kind) in errors.errors() {
+        let path = match prefix {
+            "" => field.to_string(),
+            _ => format!("{}.{}", prefix, field),
+        };
+        match kind {
+            ValidationErrorsKind::Field(errors) => {
+                fields
+                    .entry(path)
+                    .or_default()
+                    .extend(errors.iter().map(|e| {
+                        FieldError {
+                            code: e.code.to_string(),
+                            message: e
+                                .message
+                                .as_ref()
+                                .map(|m| m.to_string())
+                                .unwrap_or_else(|| e.code.to_string()),
+                        }
+                    }))
+            }
+            ValidationErrorsKind::Struct(nested) => flatten_errors(&path, nested, fields),
+            ValidationErrorsKind::List(items) => {
+                for (index, nested) in items {
+                    flatten_errors(&format!("{}.{}", path, index), nested, fields);
+                }
+            }
+        }
+    }
+}
+
+impl From<Error> for JsonErrorResponse<Option<String>> {
+    fn from(err: Error) -> Self {
+        let status = match &err {
+            Error::Validation(_) => StatusCode::BAD_REQUEST,
+            Error::InvalidFields(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            Error::IncorrectPassword
+            | Error::IncorrectCode
+            | Error::InvalidRefreshToken
+            | Error::Unauthenticated
+            | Error::PasskeyRejected(_) => StatusCode::UNAUTHORIZED,
+            Error::Locked { .. } => StatusCode::LOCKED,
+            Error::Storage(StorageError::NotFound) => StatusCode::NOT_FOUND,
+            Error::Storage(StorageError::Conflict(_)) => StatusCode::CONFLICT,
+            Error::Delivery(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::PasswordHashing | Error::SecretDecryption | Error::Storage(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+        let response = JsonErrorResponse::new(None, err.to_string(), status);
+        match err {
+            Error::InvalidFields(errors) => response.with_errors(errors),
+            Error::Locked { retry_after } => response.with_retry_after(retry_after),
+            _ => response,
+        }
+    }
+}
@@ -0,0 +1,650 @@
+//! Audit trail of logins, registrations, password changes and admin operations. Events are only
+//! ever appended; the retention period of the process is the only thing that removes them, and
+//! erasing a user the only thing that changes them, see [`AuditRecord::redacted_at`].
+//!
+//! The events of a realm form a hash chain: each entry holds its sequence number, the hash of
+//! the previous entry and a hash over both and its own content, see [`entry_hash`]. Editing,
+//! reordering or deleting an entry breaks the chain at that point, and signed checkpoints of the
+//! chain head catch a rewrite of the whole chain or the removal of its newest entries.
+//!
+//! The personal data of an entry, its user id, IP and detail, enters the entry hash through a
+//! salted hash of its own, see [`pii_hash`]. Erasing the user deletes the salt with the data, so
+//! the hash reveals nothing of it, while the rest of the entry still verifies.
+
+use crate::app::Error;
+use crate::domain::realm::RealmName;
+use data_encoding::{BASE64, HEXLOWER};
+use ring::digest::{digest, SHA256};
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumString};
+
+pub const DEFAULT_AUDIT_PAGE_SIZE: u32 = 50;
+pub const MAX_AUDIT_PAGE_SIZE: u32 = 500;
+/// Previous hash of the first entry of a chain.
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Display, EnumString, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum AuditEventType {
+    /// A login of any kind that opened a session.
+    LoginSucceeded,
+    /// A wrong password, code or passkey, the detail says which.
+    LoginFailed,
+    UserLocked,
+    /// Failures from one client IP locked it for every user.
+    IpLocked,
+    UserRegistered,
+    UserUpdated,
+    /// The user confirmed their email address.
+    EmailConfirmed,
+    UserDisabled,
+    PasswordChanged,
+    PasswordResetRequested,
+    PasswordReset,
+    /// A recovery code stood in for the second factor of a login, the detail says how many are
+    /// left.
+    RecoveryCodeUsed,
+    /// An authenticator app was activated as second factor.
+    TotpEnrolled,
+    /// The authenticator app was removed together with the recovery codes.
+    TotpRemoved,
+    RecoveryCodesRegenerated,
+    PasskeyRegistered,
+    PasskeyRemoved,
+    SessionRevoked,
+    AdminUserUpdated,
+    AdminUserDisabled,
+    AdminUserErased,
+    AdminPasswordSet,
+    AdminUserUnlocked,
+    AdminSessionRevoked,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub realm: RealmName,
+    pub event_type: AuditEventType,
+    /// The user the event is about, unknown for logins with an unknown username.
+    pub user_id: Option<String>,
+    pub ip: Option<String>,
+    pub detail: Option<String>,
+    pub occurred_at: i64,
+}
+
+impl AuditEvent {
+    pub fn new(
+        realm: &RealmName,
+        event_type: AuditEventType,
+        user_id: Option<&str>,
+        occurred_at: i64,
+    ) -> AuditEvent {
+        AuditEvent {
+            realm: realm.clone(),
+            event_type,
+            user_id: user_id.map(str::to_string),
+            ip: None,
+            detail: None,
+            occurred_at,
+        }
+    }
+
+    pub fn with_ip(mut self, ip: Option<&str>) -> AuditEvent {
+        self.ip = ip.map(str::to_string);
+        self
+    }
+
+    pub fn with_detail(mut self, detail: impl Into<String>) -> AuditEvent {
+        self.detail = Some(detail.into());
+        self
+    }
+}
+
+/// An appended event with its id, which grows with every append.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub id: i64,
+    #[serde(flatten)]
+    pub event: AuditEvent,
+    /// `None` for events appended before the log was chained.
+    pub link: Option<ChainLink>,
+    /// When the event's user was erased. Its user id was then replaced by a pseudonym and its IP,
+    /// detail and PII salt cleared, so only its other content can be checked against its hash.
+    pub redacted_at: Option<i64>,
+}
+
+/// The place of an entry in its realm's chain.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChainLink {
+    /// 1 for the first entry of the realm, one more for every following one.
+    pub sequence: i64,
+    pub prev_hash: String,
+    pub entry_hash: String,
+    /// `None` for entries chained before PII was hashed apart, their entry hash covers it
+    /// directly, see [`legacy_entry_hash`].
+    pub pii_hash: Option<String>,
+    /// Random salt of `pii_hash`, cleared when the entry is redacted.
+    pub pii_salt: Option<String>,
+}
+
+impl ChainLink {
+    /// The link of `event` appended after the entry `prev_sequence` hashed to `prev_hash`.
+    pub fn next(prev_sequence: i64, prev_hash: &str, event: &AuditEvent) -> ChainLink {
+        let sequence = prev_sequence + 1;
+        let pii_salt = HEXLOWER.encode(&rand::random::<[u8; 16]>());
+        let pii_hash = pii_hash(event, &pii_salt);
+        ChainLink {
+            sequence,
+            prev_hash: prev_hash.to_string(),
+            entry_hash: entry_hash(event, sequence, &pii_hash, prev_hash),
+            pii_hash: Some(pii_hash),
+            pii_salt: Some(pii_salt),
+        }
+    }
+}
+
+/// Lowercase hex SHA-256 of the JSON array `[pii_salt, user_id, ip, detail]`, with `null` for
+/// absent values. The salt keeps guesses of the data from being checked against the hash once
+/// it is deleted.
+pub fn pii_hash(event: &AuditEvent, pii_salt: &str) -> String {
+    let content = serde_json::json!([pii_salt, event.user_id, event.ip, event.detail]);
+    HEXLOWER.encode(digest(&SHA256, content.to_string().as_bytes()).as_ref())
+}
+
+/// Lowercase hex SHA-256 of the JSON array `[realm, sequence, event_type, occurred_at, pii_hash,
+/// prev_hash]`. Auditors can recompute it from an export of the events.
+pub fn entry_hash(event: &AuditEvent, sequence: i64, pii_hash: &str, prev_hash: &str) -> String {
+    let content = serde_json::json!([
+        event.realm,
+        sequence,
+        event.event_type,
+        event.occurred_at,
+        pii_hash,
+        prev_hash,
+    ]);
+    HEXLOWER.encode(digest(&SHA256, content.to_string().as_bytes()).as_ref())
+}
+
+/// Hash of entries chained before [`pii_hash`]: lowercase hex SHA-256 of the JSON array `[realm,
+/// sequence, event_type, user_id, ip, detail, occurred_at, prev_hash]`, with `null` for absent
+/// values.
+pub fn legacy_entry_hash(event: &AuditEvent, sequence: i64, prev_hash: &str) -> String {
+    let content = serde_json::json!([
+        event.realm,
+        sequence,
+        event.event_type,
+        event.user_id,
+        event.ip,
+        event.detail,
+        event.occurred_at,
+        prev_hash,
+    ]);
+    HEXLOWER.encode(digest(&SHA256, content.to_string().as_bytes()).as_ref())
+}
+
+/// The ends of a realm's chain. The head is the newest entry. The anchor is the newest entry
+/// removed by retention, which the oldest kept entry links to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditChain {
+    pub realm: RealmName,
+    pub head_sequence: i64,
+    pub head_hash: String,
+    pub anchor_sequence: i64,
+    pub anchor_hash: String,
+}
+
+impl AuditChain {
+    /// The chain of a realm without entries.
+    pub fn empty(realm: &RealmName) -> AuditChain {
+        AuditChain {
+            realm: realm.clone(),
+            head_sequence: 0,
+            head_hash: GENESIS_HASH.to_string(),
+            anchor_sequence: 0,
+            anchor_hash: GENESIS_HASH.to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChainBreakReason {
+    /// The entry with the expected sequence number is gone.
+    MissingEntry,
+    /// The entry does not link to the one before it.
+    PreviousHashMismatch,
+    /// The entry's content was changed after it was hashed.
+    ContentHashMismatch,
+    /// The newest entry is not the recorded head of the chain.
+    HeadMismatch,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChainBreak {
+    pub sequence: i64,
+    /// Id of the offending entry, absent when it is missing.
+    pub id: Option<i64>,
+    pub reason: ChainBreakReason,
+}
+
+impl ChainBreak {
+    fn at(sequence: i64, record: Option<&AuditRecord>, reason: ChainBreakReason) -> ChainBreak {
+        ChainBreak {
+            sequence,
+            id: record.map(|record| record.id),
+            reason,
+        }
+    }
+}
+
+/// Checks the entries of a chain one at a time, oldest first, from its anchor to its head.
+pub struct ChainWalk {
+    next_sequence: i64,
+    prev_hash: String,
+    verified: u64,
+    redacted: u64,
+}
+
+impl ChainWalk {
+    pub fn new(chain: &AuditChain) -> ChainWalk {
+        ChainWalk {
+            next_sequence: chain.anchor_sequence + 1,
+            prev_hash: chain.anchor_hash.clone(),
+            verified: 0,
+            redacted: 0,
+        }
+    }
+
+    /// Entries checked so far.
+    pub fn verified(&self) -> u64 {
+        self.verified
+    }
+
+    /// Redacted entries among the checked ones, their PII was not checked.
+    pub fn redacted(&self) -> u64 {
+        self.redacted
+    }
+
+    pub fn step(&mut self, record: &AuditRecord) -> Result<(), ChainBreak> {
+        let expected = self.next_sequence;
+        let link = match &record.link {
+            Some(link) if link.sequence == expected => link,
+            _ => {
+                return Err(ChainBreak::at(
+                    expected,
+                    None,
+                    ChainBreakReason::MissingEntry,
+                ))
+            }
+        };
+        if link.prev_hash != self.prev_hash {
+            return Err(ChainBreak::at(
+                expected,
+                Some(record),
+                ChainBreakReason::PreviousHashMismatch,
+            ));
+        }
+        let redacted = record.redacted_at.is_some();
+        let content_matches = match &link.pii_hash {
+            Some(pii) => {
+                link.entry_hash == entry_hash(&record.event, expected, pii, &link.prev_hash)
+                    && (redacted
+                        || link
+                            .pii_salt
+                            .as_ref()
+                            .is_some_and(|salt| *pii == pii_hash(&record.event, salt)))
+            }
+            // Older entries hashed their PII directly, which redaction took away.
+            None => {
+                redacted
+                    || link.entry_hash
+                        == legacy_entry_hash(&record.event, expected, &link.prev_hash)
+            }
+        };
+        if !content_matches {
+            return Err(ChainBreak::at(
+                expected,
+                Some(record),
+                ChainBreakReason::ContentHashMismatch,
+            ));
+        }
+        if redacted {
+            self.redacted += 1;
+        }
+        self.next_sequence += 1;
+        self.prev_hash = link.entry_hash.clone();
+        self.verified += 1;
+        Ok(())
+    }
+
+    /// Checks that the walk reached the head of `chain`.
+    pub fn finish(&self, chain: &AuditChain) -> Result<(), ChainBreak> {
+        if self.next_sequence <= chain.head_sequence {
+            Err(ChainBreak::at(
+                self.next_sequence,
+                None,
+                ChainBreakReason::MissingEntry,
+            ))
+        } else if self.prev_hash != chain.head_hash {
+            Err(ChainBreak::at(
+                chain.head_sequence,
+                None,
+                ChainBreakReason::HeadMismatch,
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Response of `GET /api/admin/audit/verify`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChainVerification {
+    pub realm: RealmName,
+    /// Entries up to this one were removed by retention.
+    pub anchor_sequence: i64,
+    pub head_sequence: i64,
+    pub head_hash: String,
+    /// Entries checked before the first break, all of them when the chain is intact.
+    pub verified_entries: u64,
+    /// Entries among the verified ones whose user was erased, their PII is not checked.
+    pub redacted_entries: u64,
+    pub first_break: Option<ChainBreak>,
+}
+
+/// A statement that the chain of `realm` had the entry `sequence` hashed to `entry_hash` at
+/// `issued_at`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub realm: RealmName,
+    pub sequence: i64,
+    pub entry_hash: String,
+    pub issued_at: i64,
+}
+
+impl Checkpoint {
+    /// The signed bytes, the fields on separate lines after a fixed tag.
+    pub fn message(&self) -> String {
+        format!(
+            "audit-checkpoint\n{}\n{}\n{}\n{}",
+            self.realm, self.sequence, self.entry_hash, self.issued_at
+        )
+    }
+}
+
+/// A checkpoint with an Ed25519 signature of its [`Checkpoint::message`], both keys and signature
+/// in base64. Auditors keep these outside the service, pinning the public key.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SignedCheckpoint {
+    #[serde(flatten)]
+    pub checkpoint: Checkpoint,
+    pub public_key: String,
+    pub signature: String,
+}
+
+/// What became of a checkpoint's entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckpointStatus {
+    /// The entry is still in the chain with the same hash.
+    Consistent,
+    /// The checkpoint was not signed by this service's key.
+    InvalidSignature,
+    /// Retention has since removed the entry, so it cannot be compared.
+    Pruned,
+    /// The chain no longer reaches the entry.
+    Missing,
+    /// The entry's hash changed, the chain was rewritten.
+    Diverged,
+}
+
+/// Response of `POST /api/admin/audit/checkpoint/verify`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CheckpointVerification {
+    pub status: CheckpointStatus,
+}
+
+/// Signs chain checkpoints with Ed25519.
+pub struct CheckpointSigner {
+    key_pair: Ed25519KeyPair,
+}
+
+impl CheckpointSigner {
+    /// A signer from a 32 byte private key seed.
+    pub fn new(seed: &[u8]) -> Result<CheckpointSigner, String> {
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(seed)
+            .map_err(|_| "audit signing key must be 32 bytes".to_string())?;
+        Ok(CheckpointSigner { key_pair })
+    }
+
+    /// A signer with a random key, its checkpoints no longer verify after a restart.
+    pub fn ephemeral() -> CheckpointSigner {
+        CheckpointSigner::new(&rand::random::<[u8; 32]>()).unwrap()
+    }
+
+    pub fn public_key(&self) -> String {
+        BASE64.encode(self.key_pair.public_key().as_ref())
+    }
+
+    pub fn sign(&self, checkpoint: Checkpoint) -> SignedCheckpoint {
+        let signature = self.key_pair.sign(checkpoint.message().as_bytes());
+        SignedCheckpoint {
+            checkpoint,
+            public_key: self.public_key(),
+            signature: BASE64.encode(signature.as_ref()),
+        }
+    }
+
+    /// Whether `signed` carries a signature of this signer. The public key it names is ignored.
+    pub fn verify(&self, signed: &SignedCheckpoint) -> bool {
+        let signature = match BASE64.decode(signed.signature.as_bytes()) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+        UnparsedPublicKey::new(&ED25519, self.key_pair.public_key().as_ref())
+            .verify(signed.checkpoint.message().as_bytes(), &signature)
+            .is_ok()
+    }
+}
+
+/// Query string of `GET /api/admin/audit`, always scoped to the realm of the request. Filters
+/// are combined with AND.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct AuditQuery {
+    pub user_id: Option<String>,
+    pub event_type: Option<AuditEventType>,
+    /// Inclusive lower bound on the unix time of the event.
+    pub from: Option<i64>,
+    /// Exclusive upper bound on the unix time of the event.
+    pub to: Option<i64>,
+    pub limit: Option<u32>,
+    /// Only events with a lower id, pass `next_before` of the previous page.
+    pub before: Option<i64>,
+}
+
+impl AuditQuery {
+    pub fn page_size(&self) -> Result<u32, Error> {
+        match self.limit.unwrap_or(DEFAULT_AUDIT_PAGE_SIZE) {
+            limit @ 1..=MAX_AUDIT_PAGE_SIZE => Ok(limit),
+            _ => Err(Error::Validation(format!(
+                "limit must be between 1 and {}",
+                MAX_AUDIT_PAGE_SIZE
+            ))),
+        }
+    }
+
+    pub fn matches(&self, record: &AuditRecord) -> bool {
+        let event = &record.event;
+        self.user_id
+            .as_ref()
+            .is_none_or(|user_id| event.user_id.as_ref() == Some(user_id))
+            && self
+                .event_type
+                .is_none_or(|event_type| event.event_type == event_type)
+            && self.from.is_none_or(|from| event.occurred_at >= from)
+            && self.to.is_none_or(|to| event.occurred_at < to)
+            && self.before.is_none_or(|before| record.id < before)
+    }
+}
+
+/// Newest events first.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuditPage {
+    pub events: Vec<AuditRecord>,
+    /// Pass as `before` to fetch the next page, absent on the last page.
+    pub next_before: Option<i64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::audit::{
+        legacy_entry_hash, AuditChain, AuditEvent, AuditEventType, AuditRecord, ChainBreakReason,
+        ChainLink, ChainWalk, Checkpoint, CheckpointSigner, GENESIS_HASH,
+    };
+
+    fn chained(realm: &str, count: i64) -> (AuditChain, Vec<AuditRecord>) {
+        let realm = realm.to_string();
+        let mut chain = AuditChain::empty(&realm);
+        let mut records = Vec::new();
+        for id in 1..=count {
+            let event = AuditEvent::new(&realm, AuditEventType::LoginFailed, Some("ruru"), id);
+            let link = ChainLink::next(chain.head_sequence, &chain.head_hash, &event);
+            chain.head_sequence = link.sequence;
+            chain.head_hash = link.entry_hash.clone();
+            records.push(AuditRecord {
+                id,
+                event,
+                link: Some(link),
+                redacted_at: None,
+            });
+        }
+        (chain, records)
+    }
+
+    fn first_break(chain: &AuditChain, records: &[AuditRecord]) -> Option<(i64, ChainBreakReason)> {
+        let mut walk = ChainWalk::new(chain);
+        records
+            .iter()
+            .try_for_each(|record| walk.step(record))
+            .and_then(|_| walk.finish(chain))
+            .err()
+            .map(|found| (found.sequence, found.reason))
+    }
+
+    #[test]
+    fn test_chain_walk() {
+        let (chain, records) = chained("rj.wire", 4);
+        assert_eq!(records[0].link.as_ref().unwrap().prev_hash, GENESIS_HASH);
+        assert_eq!(first_break(&chain, &records), None);
+
+        let mut edited = records.clone();
+        edited[1].event.detail = Some("nothing happened".to_string());
+        assert_eq!(
+            first_break(&chain, &edited),
+            Some((2, ChainBreakReason::ContentHashMismatch))
+        );
+
+        let mut deleted = records.clone();
+        deleted.remove(2);
+        assert_eq!(
+            first_break(&chain, &deleted),
+            Some((3, ChainBreakReason::MissingEntry))
+        );
+        assert_eq!(
+            first_break(&chain, &records[..3]),
+            Some((4, ChainBreakReason::MissingEntry))
+        );
+
+        // A consistently rewritten entry does not end at the recorded head.
+        let mut rewritten = records.clone();
+        let prev_hash = records[2].link.as_ref().unwrap().entry_hash.clone();
+        let last = &mut rewritten[3];
+        last.event.detail = Some("nothing happened".to_string());
+        last.link = Some(ChainLink::next(3, &prev_hash, &last.event));
+        assert_eq!(
+            first_break(&chain, &rewritten),
+            Some((4, ChainBreakReason::HeadMismatch))
+        );
+
+        // The PII of redacted entries is gone with its salt, the rest is still checked.
+        let mut redacted = records.clone();
+        redacted[1].event.user_id = Some("pseudonym".to_string());
+        redacted[1].link.as_mut().unwrap().pii_salt = None;
+        assert_eq!(
+            first_break(&chain, &redacted),
+            Some((2, ChainBreakReason::ContentHashMismatch))
+        );
+        redacted[1].redacted_at = Some(10);
+        assert_eq!(first_break(&chain, &redacted), None);
+        let mut moved = redacted.clone();
+        moved[1].event.occurred_at += 1;
+        assert_eq!(
+            first_break(&chain, &moved),
+            Some((2, ChainBreakReason::ContentHashMismatch))
+        );
+        let mut retyped = redacted.clone();
+        retyped[1].event.event_type = AuditEventType::LoginSucceeded;
+        assert_eq!(
+            first_break(&chain, &retyped),
+            Some((2, ChainBreakReason::ContentHashMismatch))
+        );
+        redacted[1].link.as_mut().unwrap().prev_hash = GENESIS_HASH.to_string();
+        assert_eq!(
+            first_break(&chain, &redacted),
+            Some((2, ChainBreakReason::PreviousHashMismatch))
+        );
+
+        // Entries chained before PII was hashed apart still verify.
+        let mut legacy = records.clone();
+        let legacy_hash = legacy_entry_hash(&legacy[0].event, 1, GENESIS_HASH);
+        let link = legacy[0].link.as_mut().unwrap();
+        link.entry_hash = legacy_hash;
+        link.pii_hash = None;
+        link.pii_salt = None;
+        let legacy_head = link.entry_hash.clone();
+        let mut legacy_chain = AuditChain::empty(&chain.realm);
+        legacy_chain.head_sequence = 1;
+        legacy_chain.head_hash = legacy_head;
+        assert_eq!(first_break(&legacy_chain, &legacy[..1]), None);
+        legacy[0].event.detail = Some("nothing happened".to_string());
+        assert_eq!(
+            first_break(&legacy_chain, &legacy[..1]),
+            Some((1, ChainBreakReason::ContentHashMismatch))
+        );
+
+        // Retention moves the anchor, the kept entries still link to it.
+        let mut pruned = chain.clone();
+        pruned.anchor_sequence = 2;
+        pruned.anchor_hash = records[1].link.as_ref().unwrap().entry_hash.clone();
+        assert_eq!(first_break(&pruned, &records[2..]), None);
+        assert_eq!(
+            first_break(&pruned, &records[3..]),
+            Some((3, ChainBreakReason::MissingEntry))
+        );
+    }
+
+    #[test]
+    fn test_checkpoint_signature() {
+        let signer = CheckpointSigner::new(&[7; 32]).unwrap();
+        let checkpoint = Checkpoint {
+            realm: "rj.wire".to_string(),
+            sequence: 4,
+            entry_hash: "ab".repeat(32),
+            issued_at: 1_700_000_000,
+        };
+        let signed = signer.sign(checkpoint);
+        assert!(signer.verify(&signed));
+        assert_eq!(
+            signed.public_key,
+            CheckpointSigner::new(&[7; 32]).unwrap().public_key()
+        );
+
+        let mut forged = signed.clone();
+        forged.checkpoint.sequence = 5;
+        assert!(!signer.verify(&forged));
+        assert!(!CheckpointSigner::ephemeral().verify(&signed));
+        assert!(CheckpointSigner::new(&[7; 16]).is_err());
+    }
+}
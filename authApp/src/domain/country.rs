@@ -0,0 +1,362 @@
+//! ISO 3166-1 country table, embedded so addresses can be normalised without network access.
+
+/// A country with its ISO 3166-1 codes and common English name.
+#[derive(Debug, PartialEq)]
+pub struct Country {
+    pub alpha2: &'static str,
+    pub alpha3: &'static str,
+    pub name: &'static str,
+}
+
+/// Finds a country by alpha-2 code, alpha-3 code, name or common alias, ignoring case and
+/// surrounding whitespace.
+pub fn find(input: &str) -> Option<&'static Country> {
+    let key = input.split_whitespace().collect::<Vec<_>>().join(" ");
+    if key.is_empty() {
+        return None;
+    }
+    let alias = ALIASES
+        .iter()
+        .find(|(alias, _)| alias.eq_ignore_ascii_case(&key))
+        .map(|(_, alpha2)| *alpha2);
+
+    COUNTRIES.iter().find(|country| match alias {
+        Some(alpha2) => country.alpha2 == alpha2,
+        None => {
+            country.alpha2.eq_ignore_ascii_case(&key)
+                || country.alpha3.eq_ignore_ascii_case(&key)
+                || country.name.eq_ignore_ascii_case(&key)
+        }
+    })
+}
+
+/// Names in common use that differ from the table's, and codes that are reserved but not
+/// assigned (`UK`).
+const ALIASES: &[(&str, &str)] = &[
+    ("UK", "GB"),
+    ("Great Britain", "GB"),
+    ("Britain", "GB"),
+    ("England", "GB"),
+    ("Scotland", "GB"),
+    ("Wales", "GB"),
+    ("Northern Ireland", "GB"),
+    ("United Kingdom of Great Britain and Northern Ireland", "GB"),
+    ("USA", "US"),
+    ("America", "US"),
+    ("United States of America", "US"),
+    ("Holland", "NL"),
+    ("The Netherlands", "NL"),
+    ("Russian Federation", "RU"),
+    ("Republic of Korea", "KR"),
+    ("Democratic People's Republic of Korea", "KP"),
+    ("Viet Nam", "VN"),
+    ("Iran, Islamic Republic of", "IR"),
+    ("Syrian Arab Republic", "SY"),
+    ("Lao People's Democratic Republic", "LA"),
+    ("Republic of Moldova", "MD"),
+    ("Czech Republic", "CZ"),
+    ("Turkiye", "TR"),
+    ("Türkiye", "TR"),
+    ("Ivory Coast", "CI"),
+    ("Côte d'Ivoire", "CI"),
+    ("Macedonia", "MK"),
+    ("Swaziland", "SZ"),
+    ("Burma", "MM"),
+    ("East Timor", "TL"),
+    ("Vatican", "VA"),
+    ("Vatican City", "VA"),
+    ("Holy See", "VA"),
+    ("Cape Verde", "CV"),
+    ("Brunei Darussalam", "BN"),
+    ("Bolivia, Plurinational State of", "BO"),
+    ("Venezuela, Bolivarian Republic of", "VE"),
+    ("United Republic of Tanzania", "TZ"),
+    ("Democratic Republic of the Congo", "CD"),
+    ("DR Congo", "CD"),
+    ("Republic of the Congo", "CG"),
+    ("UAE", "AE"),
+];
+
+pub const COUNTRIES: &[Country] = &[
+    country("AD", "AND", "Andorra"),
+    country("AE", "ARE", "United Arab Emirates"),
+    country("AF", "AFG", "Afghanistan"),
+    country("AG", "ATG", "Antigua and Barbuda"),
+    country("AI", "AIA", "Anguilla"),
+    country("AL", "ALB", "Albania"),
+    country("AM", "ARM", "Armenia"),
+    country("AO", "AGO", "Angola"),
+    country("AQ", "ATA", "Antarctica"),
+    country("AR", "ARG", "Argentina"),
+    country("AS", "ASM", "American Samoa"),
+    country("AT", "AUT", "Austria"),
+    country("AU", "AUS", "Australia"),
+    country("AW", "ABW", "Aruba"),
+    country("AX", "ALA", "Åland Islands"),
+    country("AZ", "AZE", "Azerbaijan"),
+    country("BA", "BIH", "Bosnia and Herzegovina"),
+    country("BB", "BRB", "Barbados"),
+    country("BD", "BGD", "Bangladesh"),
+    country("BE", "BEL", "Belgium"),
+    country("BF", "BFA", "Burkina Faso"),
+    country("BG", "BGR", "Bulgaria"),
+    country("BH", "BHR", "Bahrain"),
+    country("BI", "BDI", "Burundi"),
+    country("BJ", "BEN", "Benin"),
+    country("BL", "BLM", "Saint Barthélemy"),
+    country("BM", "BMU", "Bermuda"),
+    country("BN", "BRN", "Brunei"),
+    country("BO", "BOL", "Bolivia"),
+    country("BQ", "BES", "Bonaire, Sint Eustatius and Saba"),
+    country("BR", "BRA", "Brazil"),
+    country("BS", "BHS", "Bahamas"),
+    country("BT", "BTN", "Bhutan"),
+    country("BV", "BVT", "Bouvet Island"),
+    country("BW", "BWA", "Botswana"),
+    country("BY", "BLR", "Belarus"),
+    country("BZ", "BLZ", "Belize"),
+    country("CA", "CAN", "Canada"),
+    country("CC", "CCK", "Cocos (Keeling) Islands"),
+    country("CD", "COD", "Congo, Democratic Republic of the"),
+    country("CF", "CAF", "Central African Republic"),
+    country("CG", "COG", "Congo"),
+    country("CH", "CHE", "Switzerland"),
+    country("CI", "CIV", "Cote d'Ivoire"),
+    country("CK", "COK", "Cook Islands"),
+    country("CL", "CHL", "Chile"),
+    country("CM", "CMR", "Cameroon"),
+    country("CN", "CHN", "China"),
+    country("CO", "COL", "Colombia"),
+    country("CR", "CRI", "Costa Rica"),
+    country("CU", "CUB", "Cuba"),
+    country("CV", "CPV", "Cabo Verde"),
+    country("CW", "CUW", "Curaçao"),
+    country("CX", "CXR", "Christmas Island"),
+    country("CY", "CYP", "Cyprus"),
+    country("CZ", "CZE", "Czechia"),
+    country("DE", "DEU", "Germany"),
+    country("DJ", "DJI", "Djibouti"),
+    country("DK", "DNK", "Denmark"),
+    country("DM", "DMA", "Dominica"),
+    country("DO", "DOM", "Dominican Republic"),
+    country("DZ", "DZA", "Algeria"),
+    country("EC", "ECU", "Ecuador"),
+    country("EE", "EST", "Estonia"),
+    country("EG", "EGY", "Egypt"),
+    country("EH", "ESH", "Western Sahara"),
+    country("ER", "ERI", "Eritrea"),
+    country("ES", "ESP", "Spain"),
+    country("ET", "ETH", "Ethiopia"),
+    country("FI", "FIN", "Finland"),
+    country("FJ", "FJI", "Fiji"),
+    country("FK", "FLK", "Falkland Islands"),
+    country("FM", "FSM", "Micronesia"),
+    country("FO", "FRO", "Faroe Islands"),
+    country("FR", "FRA", "France"),
+    country("GA", "GAB", "Gabon"),
+    country("GB", "GBR", "United Kingdom"),
+    country("GD", "GRD", "Grenada"),
+    country("GE", "GEO", "Georgia"),
+    country("GF", "GUF", "French Guiana"),
+    country("GG", "GGY", "Guernsey"),
+    country("GH", "GHA", "Ghana"),
+    country("GI", "GIB", "Gibraltar"),
+    country("GL", "GRL", "Greenland"),
+    country("GM", "GMB", "Gambia"),
+    country("GN", "GIN", "Guinea"),
+    country("GP", "GLP", "Guadeloupe"),
+    country("GQ", "GNQ", "Equatorial Guinea"),
+    country("GR", "GRC", "Greece"),
+    country("GS", "SGS", "South Georgia and the South Sandwich Islands"),
+    country("GT", "GTM", "Guatemala"),
+    country("GU", "GUM", "Guam"),
+    country("GW", "GNB", "Guinea-Bissau"),
+    country("GY", "GUY", "Guyana"),
+    country("HK", "HKG", "Hong Kong"),
+    country("HM", "HMD", "Heard Island and McDonald Islands"),
+    country("HN", "HND", "Honduras"),
+    country("HR", "HRV", "Croatia"),
+    country("HT", "HTI", "Haiti"),
+    country("HU", "HUN", "Hungary"),
+    country("ID", "IDN", "Indonesia"),
+    country("IE", "IRL", "Ireland"),
+    country("IL", "ISR", "Israel"),
+    country("IM", "IMN", "Isle of Man"),
+    country("IN", "IND", "India"),
+    country("IO", "IOT", "British Indian Ocean Territory"),
+    country("IQ", "IRQ", "Iraq"),
+    country("IR", "IRN", "Iran"),
+    country("IS", "ISL", "Iceland"),
+    country("IT", "ITA", "Italy"),
+    country("JE", "JEY", "Jersey"),
+    country("JM", "JAM", "Jamaica"),
+    country("JO", "JOR", "Jordan"),
+    country("JP", "JPN", "Japan"),
+    country("KE", "KEN", "Kenya"),
+    country("KG", "KGZ", "Kyrgyzstan"),
+    country("KH", "KHM", "Cambodia"),
+    country("KI", "KIR", "Kiribati"),
+    country("KM", "COM", "Comoros"),
+    country("KN", "KNA", "Saint Kitts and Nevis"),
+    country("KP", "PRK", "North Korea"),
+    country("KR", "KOR", "South Korea"),
+    country("KW", "KWT", "Kuwait"),
+    country("KY", "CYM", "Cayman Islands"),
+    country("KZ", "KAZ", "Kazakhstan"),
+    country("LA", "LAO", "Laos"),
+    country("LB", "LBN", "Lebanon"),
+    country("LC", "LCA", "Saint Lucia"),
+    country("LI", "LIE", "Liechtenstein"),
+    country("LK", "LKA", "Sri Lanka"),
+    country("LR", "LBR", "Liberia"),
+    country("LS", "LSO", "Lesotho"),
+    country("LT", "LTU", "Lithuania"),
+    country("LU", "LUX", "Luxembourg"),
+    country("LV", "LVA", "Latvia"),
+    country("LY", "LBY", "Libya"),
+    country("MA", "MAR", "Morocco"),
+    country("MC", "MCO", "Monaco"),
+    country("MD", "MDA", "Moldova"),
+    country("ME", "MNE", "Montenegro"),
+    country("MF", "MAF", "Saint Martin (French part)"),
+    country("MG", "MDG", "Madagascar"),
+    country("MH", "MHL", "Marshall Islands"),
+    country("MK", "MKD", "North Macedonia"),
+    country("ML", "MLI", "Mali"),
+    country("MM", "MMR", "Myanmar"),
+    country("MN", "MNG", "Mongolia"),
+    country("MO", "MAC", "Macao"),
+    country("MP", "MNP", "Northern Mariana Islands"),
+    country("MQ", "MTQ", "Martinique"),
+    country("MR", "MRT", "Mauritania"),
+    country("MS", "MSR", "Montserrat"),
+    country("MT", "MLT", "Malta"),
+    country("MU", "MUS", "Mauritius"),
+    country("MV", "MDV", "Maldives"),
+    country("MW", "MWI", "Malawi"),
+    country("MX", "MEX", "Mexico"),
+    country("MY", "MYS", "Malaysia"),
+    country("MZ", "MOZ", "Mozambique"),
+    country("NA", "NAM", "Namibia"),
+    country("NC", "NCL", "New Caledonia"),
+    country("NE", "NER", "Niger"),
+    country("NF", "NFK", "Norfolk Island"),
+    country("NG", "NGA", "Nigeria"),
+    country("NI", "NIC", "Nicaragua"),
+    country("NL", "NLD", "Netherlands"),
+    country("NO", "NOR", "Norway"),
+    country("NP", "NPL", "Nepal"),
+    country("NR", "NRU", "Nauru"),
+    country("NU", "NIU", "Niue"),
+    country("NZ", "NZL", "New Zealand"),
+    country("OM", "OMN", "Oman"),
+    country("PA", "PAN", "Panama"),
+    country("PE", "PER", "Peru"),
+    country("PF", "PYF", "French Polynesia"),
+    country("PG", "PNG", "Papua New Guinea"),
+    country("PH", "PHL", "Philippines"),
+    country("PK", "PAK", "Pakistan"),
+    country("PL", "POL", "Poland"),
+    country("PM", "SPM", "Saint Pierre and Miquelon"),
+    country("PN", "PCN", "Pitcairn"),
+    country("PR", "PRI", "Puerto Rico"),
+    country("PS", "PSE", "Palestine"),
+    country("PT", "PRT", "Portugal"),
+    country("PW", "PLW", "Palau"),
+    country("PY", "PRY", "Paraguay"),
+    country("QA", "QAT", "Qatar"),
+    country("RE", "REU", "Réunion"),
+    country("RO", "ROU", "Romania"),
+    country("RS", "SRB", "Serbia"),
+    country("RU", "RUS", "Russia"),
+    country("RW", "RWA", "Rwanda"),
+    country("SA", "SAU", "Saudi Arabia"),
+    country("SB", "SLB", "Solomon Islands"),
+    country("SC", "SYC", "Seychelles"),
+    country("SD", "SDN", "Sudan"),
+    country("SE", "SWE", "Sweden"),
+    country("SG", "SGP", "Singapore"),
+    country("SH", "SHN", "Saint Helena, Ascension and Tristan da Cunha"),
+    country("SI", "SVN", "Slovenia"),
+    country("SJ", "SJM", "Svalbard and Jan Mayen"),
+    country("SK", "SVK", "Slovakia"),
+    country("SL", "SLE", "Sierra Leone"),
+    country("SM", "SMR", "San Marino"),
+    country("SN", "SEN", "Senegal"),
+    country("SO", "SOM", "Somalia"),
+    country("SR", "SUR", "Suriname"),
+    country("SS", "SSD", "South Sudan"),
+    country("ST", "STP", "Sao Tome and Principe"),
+    country("SV", "SLV", "El Salvador"),
+    country("SX", "SXM", "Sint Maarten (Dutch part)"),
+    country("SY", "SYR", "Syria"),
+    country("SZ", "SWZ", "Eswatini"),
+    country("TC", "TCA", "Turks and Caicos Islands"),
+    country("TD", "TCD", "Chad"),
+    country("TF", "ATF", "French Southern Territories"),
+    country("TG", "TGO", "Togo"),
+    country("TH", "THA", "Thailand"),
+    country("TJ", "TJK", "Tajikistan"),
+    country("TK", "TKL", "Tokelau"),
+    country("TL", "TLS", "Timor-Leste"),
+    country("TM", "TKM", "Turkmenistan"),
+    country("TN", "TUN", "Tunisia"),
+    country("TO", "TON", "Tonga"),
+    country("TR", "TUR", "Turkey"),
+    country("TT", "TTO", "Trinidad and Tobago"),
+    country("TV", "TUV", "Tuvalu"),
+    country("TW", "TWN", "Taiwan"),
+    country("TZ", "TZA", "Tanzania"),
+    country("UA", "UKR", "Ukraine"),
+    country("UG", "UGA", "Uganda"),
+    country("UM", "UMI", "United States Minor Outlying Islands"),
+    country("US", "USA", "United States"),
+    country("UY", "URY", "Uruguay"),
+    country("UZ", "UZB", "Uzbekistan"),
+    country("VA", "VAT", "Vatican City State"),
+    country("VC", "VCT", "Saint Vincent and the Grenadines"),
+    country("VE", "VEN", "Venezuela"),
+    country("VG", "VGB", "British Virgin Islands"),
+    country("VI", "VIR", "U.S. Virgin Islands"),
+    country("VN", "VNM", "Vietnam"),
+    country("VU", "VUT", "Vanuatu"),
+    country("WF", "WLF", "Wallis and Futuna"),
+    country("WS", "WSM", "Samoa"),
+    country("YE", "YEM", "Yemen"),
+    country("YT", "MYT", "Mayotte"),
+    country("ZA", "ZAF", "South Africa"),
+    country("ZM", "ZMB", "Zambia"),
+    country("ZW", "ZWE", "Zimbabwe"),
+];
+
+const fn country(alpha2: &'static str, alpha3: &'static str, name: &'static str) -> Country {
+    Country {
+        alpha2,
+        alpha3,
+        name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::country::{find, COUNTRIES};
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_find_country() {
+        for input in ["GB", "gbr", "United Kingdom", " great  britain ", "UK"] {
+            assert_eq!(find(input).map(|c| c.alpha2), Some("GB"), "{}", input);
+        }
+        assert_eq!(find("USA").map(|c| c.alpha2), Some("US"));
+        assert_eq!(find("dk").map(|c| c.name), Some("Denmark"));
+        assert!(find("Atlantis").is_none());
+        assert!(find("").is_none());
+
+        let codes: HashSet<&str> = COUNTRIES
+            .iter()
+            .flat_map(|c| [c.alpha2, c.alpha3])
+            .collect();
+        assert_eq!(COUNTRIES.len(), 249);
+        assert_eq!(codes.len(), COUNTRIES.len() * 2, "codes must be unique");
+    }
+}
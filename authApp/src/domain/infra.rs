@@ -10,10 +10,11 @@ use std::fmt::{Debug, Display, Formatter};
 use strum_macros::Display;
 
 pub mod web {
-    use crate::domain::customer::{LoginRequest, LoginRequestArguments, User};
+    use crate::app::FieldErrors;
+    use crate::domain::customer::{LoginRequest, User};
     use crate::domain::realm::{Realm, RealmName, UserRealmSettings};
     use actix_web::body::BoxBody;
-    use actix_web::http::header::{ContentType, HeaderMap, HeaderValue};
+    use actix_web::http::header::{ContentType, HeaderMap, HeaderValue, RETRY_AFTER};
     use actix_web::http::StatusCode;
     use actix_web::web::Json;
     use actix_web::{HttpResponse, ResponseError};
@@ -41,19 +42,15 @@ pub mod web {
 
         fn get_realm(&self) -> Option<Self::Realm> {
             self.get("Realm")
-                .map(|realm| realm.to_str().unwrap_or_else(|_e| "|").to_string())
+                .map(|realm| realm.to_str().unwrap_or("|").to_string())
         }
     }
 
     pub mod auth {
-        use crate::domain::customer::LoginRequestArguments;
         use crate::domain::realm::{RealmName, UserRealmSettings};
-        use data_encoding::HEXUPPER;
         use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
         use serde::{Deserialize, Serialize};
-        use std::num::NonZeroU32;
         use ring::digest::SHA256;
-        use ring::pbkdf2 as pbk;
         use ring::rand::SecureRandom;
 
         type Token = String;
@@ -67,23 +64,6 @@ pub mod web {
             expiry: i64,
         }
 
-        pub fn verify_login(args: &LoginRequestArguments, realm: RealmName, iter: u32) -> bool {
-            let login_request = &args.login_request;
-            let salt = format!("{}|{}", &login_request.username, realm).into_bytes();
-
-            let decoded_pass = HEXUPPER.decode(args.user.hashed_pass.as_bytes()).unwrap();
-
-            let verified = pbk::verify(
-                pbk::PBKDF2_HMAC_SHA256,
-                NonZeroU32::new(iter).unwrap(),
-                &salt,
-                login_request.password.as_bytes(),
-                &decoded_pass,
-            );
-
-            verified.is_ok()
-        }
-
         trait Authorizer {
             //    type WebToken;
             fn get_auth_token(claim: &AppToken) -> Token;
@@ -94,12 +74,12 @@ pub mod web {
             // type WebToken = String;
             fn get_auth_token(claim: &AppToken) -> Token {
                 let header = Header::new(Algorithm::HS512);
-                return encode(
+                encode(
                     &header,
                     &claim,
                     &EncodingKey::from_secret("secret".as_ref()),
                 )
-                .unwrap();
+                .unwrap()
             }
         }
 
@@ -131,8 +111,7 @@ pub mod web {
                 };
 
                 let token = AppAuthorizer::get_auth_token(&claim);
-                print!("Token - {}\n", token);
-                assert_eq!(token.len(), 268);
+                assert_eq!(token.len(), 295);
             }
         }
     }
@@ -150,6 +129,10 @@ pub mod web {
         body: Option<T>,
         message: String,
         status_code: StatusCode,
+        /// Per-field failures of a 422, omitted from other responses.
+        errors: Option<FieldErrors>,
+        /// Seconds sent in a `Retry-After` header.
+        retry_after: Option<u64>,
     }
 
     impl<T> JsonErrorResponse<T> {
@@ -162,14 +145,28 @@ pub mod web {
                 body,
                 message,
                 status_code,
+                errors: None,
+                retry_after: None,
             }
         }
 
+        pub fn with_errors(mut self, errors: FieldErrors) -> JsonErrorResponse<T> {
+            self.errors = Some(errors);
+            self
+        }
+
+        pub fn with_retry_after(mut self, seconds: u64) -> JsonErrorResponse<T> {
+            self.retry_after = Some(seconds);
+            self
+        }
+
         pub fn build_error(message: String, status_code: StatusCode) -> JsonErrorResponse<T> {
             JsonErrorResponse {
                 body: None,
                 message,
                 status_code,
+                errors: None,
+                retry_after: None,
             }
         }
     }
@@ -179,11 +176,16 @@ pub mod web {
             let serialized_body = serde_json::to_string(&self.body).unwrap_or_default();
             write!(
                 f,
-                "{{\"status\": {}, \"message\": \"{}\", \"body\": {}}}",
+                "{{\"status\": {}, \"message\": \"{}\", \"body\": {}",
                 self.status_code.as_u16(),
                 self.message,
                 serialized_body
-            )
+            )?;
+            if let Some(errors) = &self.errors {
+                let serialized_errors = serde_json::to_string(errors).unwrap_or_default();
+                write!(f, ", \"errors\": {}", serialized_errors)?;
+            }
+            write!(f, "}}")
         }
     }
 
@@ -193,6 +195,8 @@ pub mod web {
                 .field("body", &self.body)
                 .field("message", &self.message)
                 .field("status_code", &self.status_code)
+                .field("errors", &self.errors)
+                .field("retry_after", &self.retry_after)
                 .finish()
         }
     }
@@ -202,9 +206,12 @@ pub mod web {
             self.status_code
         }
         fn error_response(&self) -> HttpResponse<BoxBody> {
-            HttpResponse::build(self.status_code)
-                .insert_header(ContentType::json())
-                .body(self.to_string())
+            let mut response = HttpResponse::build(self.status_code);
+            response.insert_header(ContentType::json());
+            if let Some(seconds) = self.retry_after {
+                response.insert_header((RETRY_AFTER, seconds));
+            }
+            response.body(self.to_string())
         }
     }
 
@@ -217,6 +224,7 @@ pub mod web {
     pub enum LoginError {
         MissingAppState,
         MissingRealmHeader,
+        UnknownRealm,
         DatabaseError(String),
         UserNotFound,
         AuthenticationFailed,
@@ -236,6 +244,11 @@ pub mod web {
                     "Must contain realm header".to_string(),
                     StatusCode::BAD_REQUEST,
                 ),
+                LoginError::UnknownRealm => JsonErrorResponse::new(
+                    None,
+                    "Unknown realm".to_string(),
+                    StatusCode::NOT_FOUND,
+                ),
                 LoginError::DatabaseError(e) => {
                     JsonErrorResponse::new(None, e, StatusCode::BAD_REQUEST)
                 }
@@ -0,0 +1,134 @@
+//! Brute-force protection for logins. Failed attempts are counted per user and per client IP,
+//! and a key that reaches its realm's threshold is locked for longer after every lockout.
+
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+/// Lockout thresholds of a realm.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LockoutPolicy {
+    /// Failed attempts on one user before it is locked, 0 disables user lockout.
+    pub max_user_failures: u32,
+    /// Failed attempts from one IP, across users, before it is locked, 0 disables IP lockout.
+    pub max_ip_failures: u32,
+    /// Failures further apart than this start counting from zero and reset the backoff.
+    pub failure_window: Duration,
+    /// Length of the first lockout, doubled for each further one.
+    pub lockout_duration: Duration,
+    pub max_lockout_duration: Duration,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        LockoutPolicy {
+            max_user_failures: 5,
+            max_ip_failures: 20,
+            failure_window: Duration::from_secs(15 * 60),
+            lockout_duration: Duration::from_secs(5 * 60),
+            max_lockout_duration: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+/// What failed attempts are counted against.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum AttemptKey {
+    User(String),
+    Ip(String),
+}
+
+impl Display for AttemptKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AttemptKey::User(user_id) => write!(f, "user:{}", user_id),
+            AttemptKey::Ip(ip) => write!(f, "ip:{}", ip),
+        }
+    }
+}
+
+/// Failed attempt state of one key. Times are unix seconds.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LoginAttempts {
+    /// Failures since the last lockout.
+    pub failures: u32,
+    /// Lockouts so far, drives the backoff.
+    pub lockouts: u32,
+    pub last_failure_at: i64,
+    pub locked_until: Option<i64>,
+}
+
+impl LoginAttempts {
+    /// Seconds until the key unlocks, `None` when it is not locked at `now`.
+    pub fn locked_for(&self, now: i64) -> Option<u64> {
+        self.locked_until
+            .filter(|until| *until > now)
+            .map(|until| (until - now) as u64)
+    }
+
+    /// Records a failure at `now`, locking the key once `max_failures` is reached.
+    pub fn record_failure(&mut self, now: i64, max_failures: u32, policy: &LockoutPolicy) {
+        // The quiet period counts from the end of the last lockout, not from the failure that
+        // caused it, so that lockouts longer than the window still back off.
+        let quiet_since = self.last_failure_at.max(self.locked_until.unwrap_or(0));
+        if now - quiet_since > policy.failure_window.as_secs() as i64 {
+            self.failures = 0;
+            self.lockouts = 0;
+        }
+
+        self.failures += 1;
+        self.last_failure_at = now;
+        if self.failures >= max_failures {
+            self.failures = 0;
+            self.lockouts += 1;
+            let backoff = 2u64.saturating_pow(self.lockouts - 1);
+            let seconds = policy
+                .lockout_duration
+                .as_secs()
+                .saturating_mul(backoff)
+                .min(policy.max_lockout_duration.as_secs());
+            self.locked_until = Some(now + seconds as i64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::lockout::{LockoutPolicy, LoginAttempts};
+
+    #[test]
+    fn test_lockout_backs_off() {
+        let policy = LockoutPolicy::default();
+        let mut attempts = LoginAttempts::default();
+        let mut now = 1_000_000;
+
+        for _ in 0..4 {
+            attempts.record_failure(now, 5, &policy);
+        }
+        assert_eq!(attempts.locked_for(now), None);
+        attempts.record_failure(now, 5, &policy);
+        assert_eq!(attempts.locked_for(now), Some(300));
+
+        // Unlocks by itself, then locks for twice as long.
+        now += 300;
+        assert_eq!(attempts.locked_for(now), None);
+        for _ in 0..5 {
+            attempts.record_failure(now, 5, &policy);
+        }
+        assert_eq!(attempts.locked_for(now), Some(600));
+
+        // A quiet window after the lockout resets the backoff.
+        now += 600 + 15 * 60 + 1;
+        attempts.record_failure(now, 5, &policy);
+        assert_eq!((attempts.failures, attempts.lockouts), (1, 0));
+
+        let capped = LockoutPolicy {
+            max_lockout_duration: policy.lockout_duration,
+            ..LockoutPolicy::default()
+        };
+        for _ in 0..10 {
+            attempts.record_failure(now, 5, &capped);
+        }
+        assert_eq!(attempts.locked_for(now), Some(300));
+    }
+}
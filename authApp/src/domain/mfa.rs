@@ -0,0 +1,325 @@
+//! Second login factors. Time-based one-time passwords follow RFC 6238 with the common
+//! authenticator app parameters: HMAC-SHA1, 6 digits and a 30 second period. Their secrets are
+//! encrypted at rest, see [`SecretCipher`]. Single-use recovery codes stand in for a lost device.
+
+use crate::domain::customer::Role;
+use data_encoding::{BASE32_NOPAD, BASE64, HEXLOWER};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::digest::{digest, SHA256};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use strum_macros::Display;
+
+pub const TOTP_DIGITS: u32 = 6;
+pub const TOTP_PERIOD: i64 = 30;
+/// Steps either side of the current one that are still accepted, for clock drift.
+const TOTP_SKEW: i64 = 1;
+const TOTP_SECRET_LEN: usize = 20;
+pub const RECOVERY_CODE_COUNT: usize = 10;
+
+/// Second factor rules of a realm. Users of other roles may enroll but need not.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct MfaPolicy {
+    pub required_roles: Vec<Role>,
+}
+
+impl MfaPolicy {
+    pub fn is_required(&self, role: &Role) -> bool {
+        self.required_roles.contains(role)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Display, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum MfaMethod {
+    #[default]
+    Totp,
+    RecoveryCode,
+}
+
+/// Answer of a login that passed the password check of a user with a second factor. The token
+/// is exchanged for the access token together with a code of one of `methods`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MfaChallenge {
+    pub mfa_token: String,
+    pub methods: Vec<MfaMethod>,
+}
+
+/// Answer of a login that passed the password check of a user whose role requires a second
+/// factor they have not enrolled. The token only reaches the enrollment endpoints, until
+/// `expires_at`, and the user logs in again once a factor is active.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MfaEnrollment {
+    pub enrollment_token: String,
+    pub expires_at: i64,
+}
+
+/// A user's TOTP secret, sealed with [`SecretCipher`]. Codes only count as a second factor once
+/// the enrollment is activated with a first valid code.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TotpEnrollment {
+    pub sealed_secret: String,
+    pub activated_at: Option<i64>,
+    /// Step of the last accepted code, older and equal steps are refused so each code works once.
+    pub last_used_step: Option<i64>,
+}
+
+/// Returned once at enrollment, for the user's authenticator app.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TotpSetup {
+    /// Base32 without padding.
+    pub secret: String,
+    pub otpauth_uri: String,
+    pub recovery_codes: Vec<String>,
+}
+
+/// A stored recovery code. Only the hash is kept, the code itself is shown once.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecoveryCode {
+    pub code_hash: String,
+    pub created_at: i64,
+    /// When and from where the code was used, `None` while it is unused.
+    pub used_at: Option<i64>,
+    pub used_ip: Option<String>,
+}
+
+impl RecoveryCode {
+    pub fn new(code: &str, created_at: i64) -> RecoveryCode {
+        RecoveryCode {
+            code_hash: hash_recovery_code(code),
+            created_at,
+            used_at: None,
+            used_ip: None,
+        }
+    }
+}
+
+/// Returned once whenever recovery codes are (re)generated.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RecoveryCodeUse {
+    pub used_at: i64,
+    pub ip: Option<String>,
+}
+
+/// The current set of recovery codes of a user, without the codes.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RecoveryCodeStatus {
+    pub remaining: usize,
+    pub uses: Vec<RecoveryCodeUse>,
+}
+
+impl RecoveryCodeStatus {
+    pub fn of(codes: &[RecoveryCode]) -> RecoveryCodeStatus {
+        let mut uses: Vec<RecoveryCodeUse> = codes
+            .iter()
+            .filter_map(|code| {
+                code.used_at.map(|used_at| RecoveryCodeUse {
+                    used_at,
+                    ip: code.used_ip.clone(),
+                })
+            })
+            .collect();
+        uses.sort_by_key(|code_use| code_use.used_at);
+        RecoveryCodeStatus {
+            remaining: codes.len() - uses.len(),
+            uses,
+        }
+    }
+}
+
+pub fn generate_totp_secret() -> Vec<u8> {
+    rand::random::<[u8; TOTP_SECRET_LEN]>().to_vec()
+}
+
+/// Codes like `4f9a2-c81b0`, 40 random bits each.
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code = HEXLOWER.encode(&rand::random::<[u8; 5]>());
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect()
+}
+
+/// SHA-256 of the code, ignoring case, dashes and whitespace. The codes are random enough that
+/// a slow password hash is not needed.
+pub fn hash_recovery_code(code: &str) -> String {
+    let normalised: String = code
+        .chars()
+        .filter(|c| *c != '-' && !c.is_whitespace())
+        .flat_map(char::to_lowercase)
+        .collect();
+    HEXLOWER.encode(digest(&SHA256, normalised.as_bytes()).as_ref())
+}
+
+/// The code of `secret` for the time step `step`.
+pub fn totp_code(secret: &[u8], step: i64) -> u32 {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let digest = hmac::sign(&key, &(step as u64).to_be_bytes());
+    let bytes = digest.as_ref();
+    let offset = (bytes[bytes.len() - 1] & 0x0f) as usize;
+    let truncated = u32::from_be_bytes([
+        bytes[offset] & 0x7f,
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ]);
+    truncated % 10u32.pow(TOTP_DIGITS)
+}
+
+/// The time step `code` belongs to at `now` (unix seconds), allowing for clock drift. Steps up to
+/// `last_used` are refused.
+pub fn verify_totp(secret: &[u8], code: &str, now: i64, last_used: Option<i64>) -> Option<i64> {
+    if code.len() != TOTP_DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let current = now / TOTP_PERIOD;
+    (current - TOTP_SKEW..=current + TOTP_SKEW)
+        .filter(|step| last_used.is_none_or(|last| *step > last))
+        .find(|step| totp_code(secret, *step) == code)
+}
+
+/// The `otpauth://` URI authenticator apps read from a QR code, labelled with the realm and the
+/// account name.
+pub fn otpauth_uri(issuer: &str, account: &str, secret: &[u8]) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+        issuer = percent_encode(issuer),
+        account = percent_encode(account),
+        secret = BASE32_NOPAD.encode(secret),
+        digits = TOTP_DIGITS,
+        period = TOTP_PERIOD,
+    )
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Encrypts second factor secrets with AES-256-GCM. The owner's id is authenticated with the
+/// ciphertext, so a sealed secret copied to another user does not open.
+pub struct SecretCipher {
+    key: LessSafeKey,
+}
+
+impl SecretCipher {
+    /// A cipher with a 32 byte `key`.
+    pub fn new(key: &[u8]) -> Result<SecretCipher, String> {
+        let key = UnboundKey::new(&AES_256_GCM, key)
+            .map_err(|_| "MFA encryption key must be 32 bytes".to_string())?;
+        Ok(SecretCipher {
+            key: LessSafeKey::new(key),
+        })
+    }
+
+    /// A cipher with a random key, secrets sealed with it are lost on restart.
+    pub fn ephemeral() -> SecretCipher {
+        SecretCipher::new(&rand::random::<[u8; 32]>()).unwrap()
+    }
+
+    /// Base64 of the nonce followed by the ciphertext and tag.
+    pub fn seal(&self, owner: &str, secret: &[u8]) -> String {
+        let nonce = rand::random::<[u8; NONCE_LEN]>();
+        let mut sealed = secret.to_vec();
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(owner.as_bytes()),
+                &mut sealed,
+            )
+            .expect("AES-GCM sealing of a short secret does not fail");
+        BASE64.encode(&[&nonce[..], &sealed].concat())
+    }
+
+    /// The secret sealed for `owner`, `None` when it was sealed with another key or owner.
+    pub fn open(&self, owner: &str, sealed: &str) -> Option<Vec<u8>> {
+        let mut sealed = BASE64.decode(sealed.as_bytes()).ok()?;
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at_mut(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+        let secret = self
+            .key
+            .open_in_place(nonce, Aad::from(owner.as_bytes()), ciphertext)
+            .ok()?;
+        Some(secret.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::mfa::{
+        generate_recovery_codes, hash_recovery_code, otpauth_uri, totp_code, verify_totp,
+        RecoveryCode, RecoveryCodeStatus, SecretCipher, RECOVERY_CODE_COUNT,
+    };
+
+    #[test]
+    fn test_totp() {
+        // RFC 6238 appendix B, SHA1 truncated to 6 digits.
+        let secret = b"12345678901234567890";
+        assert_eq!(totp_code(secret, 59 / 30), 287082);
+        assert_eq!(totp_code(secret, 1111111109 / 30), 81804);
+        assert_eq!(totp_code(secret, 1234567890 / 30), 5924);
+        assert_eq!(totp_code(secret, 2000000000 / 30), 279037);
+
+        let now = 1234567890;
+        let step = now / 30;
+        assert_eq!(verify_totp(secret, "005924", now, None), Some(step));
+        assert_eq!(verify_totp(secret, "005924", now + 30, None), Some(step));
+        assert_eq!(verify_totp(secret, "005924", now + 60, None), None);
+        assert_eq!(verify_totp(secret, "005924", now, Some(step)), None);
+        assert_eq!(verify_totp(secret, "5924", now, None), None);
+
+        assert_eq!(
+            otpauth_uri("rj.wire", "ru ru", secret),
+            "otpauth://totp/rj.wire:ru%20ru?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ\
+            &issuer=rj.wire&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+
+    #[test]
+    fn test_secret_cipher() {
+        let cipher = SecretCipher::ephemeral();
+        let sealed = cipher.seal("user-1", b"secret");
+        assert_ne!(cipher.seal("user-1", b"secret"), sealed);
+        assert_eq!(cipher.open("user-1", &sealed), Some(b"secret".to_vec()));
+        assert_eq!(cipher.open("user-2", &sealed), None);
+        assert_eq!(SecretCipher::ephemeral().open("user-1", &sealed), None);
+    }
+
+    #[test]
+    fn test_recovery_codes() {
+        let codes = generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        assert!(codes.iter().all(|code| code.len() == 11));
+        assert_eq!(hash_recovery_code("4F9A2 C81B0"), hash_recovery_code("4f9a2-c81b0"));
+        assert_ne!(hash_recovery_code(&codes[0]), hash_recovery_code(&codes[1]));
+
+        let mut stored: Vec<RecoveryCode> =
+            codes.iter().map(|code| RecoveryCode::new(code, 100)).collect();
+        stored[3].used_at = Some(300);
+        stored[1].used_at = Some(200);
+        let status = RecoveryCodeStatus::of(&stored);
+        assert_eq!(status.remaining, RECOVERY_CODE_COUNT - 2);
+        assert_eq!(
+            status.uses.iter().map(|u| u.used_at).collect::<Vec<_>>(),
+            vec![200, 300]
+        );
+    }
+}
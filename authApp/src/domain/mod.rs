@@ -1,5 +1,17 @@
+pub mod audit;
+pub mod country;
 pub mod infra;
+pub mod lockout;
+pub mod mfa;
+pub mod outbox;
+pub mod passwordless;
+pub mod postcode;
+pub mod rate_limit;
 pub mod realm;
+pub mod session;
+pub mod validation;
+pub mod webauthn;
+pub mod webhook;
 
 pub mod customer {
     use actix_web::body::MessageBody;
@@ -11,9 +23,14 @@ pub mod customer {
     use std::str::FromStr;
     use strum_macros::EnumString;
     use mysql::prelude::FromValue;    
+    use crate::app::Error;
+    use crate::domain::validation::not_blank;
+    use crate::domain::{country, postcode};
+    use validator::Validate;
 
     
-    #[derive(Serialize, Deserialize, FromValue, EnumString, Clone, Debug)]
+    #[allow(clippy::upper_case_acronyms)]
+    #[derive(Serialize, Deserialize, FromValue, EnumString, Clone, Debug, PartialEq)]
     #[mysql(is_string)]
     pub enum Role {
         ADMIN,
@@ -36,12 +53,6 @@ pub mod customer {
         pub password: String,
     }
 
-    #[derive(Serialize, Deserialize, Clone, Debug)]
-    pub struct LoginRequestArguments {
-        pub login_request: LoginRequest,
-        pub user: User,
-    }
-
     #[derive(Serialize, Deserialize, Clone, Debug)]
     pub struct User {
         pub user_id: String,
@@ -50,24 +61,165 @@ pub mod customer {
         pub role: Role,
     }
 
+    /// A user as returned to API clients, without credentials.
     #[derive(Serialize, Deserialize, Clone, Debug)]
-    pub struct UserWithAddress {
+    pub struct UserProfile {
         pub user_id: String,
         pub username: String,
+        pub name: Option<String>,
+        pub email: Option<String>,
+        pub email_verified: bool,
         pub role: Role,
-        pub address: Address,
+        pub addresses: Vec<UserAddress>,
+        /// Unix time the user was created.
+        pub created_at: i64,
+        pub status: UserStatus,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum UserStatus {
+        #[default]
+        Active,
+        /// Soft-deleted, see `DeletionMode::Disable`.
+        Disabled,
     }
 
+    /// One page of a user listing.
     #[derive(Serialize, Deserialize, Clone, Debug)]
+    pub struct UserPage {
+        pub users: Vec<UserProfile>,
+        /// Number of users matching the filters, across all pages.
+        pub total: u64,
+        /// Pass as `cursor` to fetch the next page, absent on the last page.
+        pub next_cursor: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug, Validate)]
     pub struct Address {
+        #[validate(
+            custom(function = not_blank),
+            length(max = 200, message = "must be at most 200 characters")
+        )]
         pub street: String,
+        #[validate(custom(function = not_blank))]
         pub country: String,
+        #[validate(
+            custom(function = not_blank),
+            length(max = 100, message = "must be at most 100 characters")
+        )]
         pub city: String,
+        #[validate(length(max = 20, message = "must be at most 20 characters"))]
         pub post_code: String,
     }
 
+    impl Address {
+        /// Normalises the country and then the postcode, see [`Address::normalise_country`]
+        /// and [`Address::normalise_post_code`].
+        pub fn normalise(&mut self) -> Result<(), Error> {
+            self.normalise_country()?;
+            self.normalise_post_code()
+        }
+
+        /// Replaces the country, given as a name, alpha-2 or alpha-3 code, with its ISO 3166-1
+        /// alpha-2 code.
+        pub fn normalise_country(&mut self) -> Result<(), Error> {
+            match country::find(&self.country) {
+                Some(country) => {
+                    self.country = country.alpha2.to_string();
+                    Ok(())
+                }
+                None => Err(Error::field(
+                    "country",
+                    "unknown_country",
+                    format!("unknown country {}", self.country),
+                )),
+            }
+        }
+
+        /// Validates the postcode against the rules of the (normalised) country and rewrites
+        /// it in canonical form.
+        pub fn normalise_post_code(&mut self) -> Result<(), Error> {
+            self.post_code = postcode::normalise(&self.country, &self.post_code)
+                .map_err(|message| Error::field("post_code", "post_code", message))?;
+            Ok(())
+        }
+
+        /// The address as printed on a label, following the conventions of its country.
+        pub fn lines(&self) -> Vec<String> {
+            let country = country::find(&self.country);
+            let locality = match country.map(|c| c.alpha2) {
+                // Royal Mail wants the post town in capitals, with the postcode on its own line.
+                Some("GB") => vec![self.city.to_uppercase(), self.post_code.clone()],
+                Some("US") => vec![format!("{} {}", self.city, self.post_code)],
+                _ => vec![format!("{} {}", self.post_code, self.city)],
+            };
+
+            let mut lines = vec![self.street.clone()];
+            lines.extend(locality);
+            lines.push(
+                country
+                    .map(|c| c.name.to_string())
+                    .unwrap_or_else(|| self.country.clone())
+                    .to_uppercase(),
+            );
+            lines
+                .into_iter()
+                .map(|line| line.trim().to_string())
+                .filter(|line| !line.is_empty())
+                .collect()
+        }
+    }
+
+    /// Multi-line rendering of an address, see [`Address::lines`].
+    #[derive(Serialize, Deserialize, Clone, Debug)]
+    pub struct FormattedAddress {
+        pub lines: Vec<String>,
+        /// `lines` joined with newlines.
+        pub text: String,
+    }
+
+    impl From<&Address> for FormattedAddress {
+        fn from(address: &Address) -> Self {
+            let lines = address.lines();
+            FormattedAddress {
+                text: lines.join("\n"),
+                lines,
+            }
+        }
+    }
+
+    #[derive(Serialize, Deserialize, EnumString, Clone, Copy, Debug, Default, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    #[strum(serialize_all = "lowercase")]
+    pub enum AddressType {
+        Billing,
+        #[default]
+        Shipping,
+    }
+
+    impl Display for AddressType {
+        fn fmt(&self, f: &mut FMT_Formatter<'_>) -> std::fmt::Result {
+            match *self {
+                AddressType::Billing => write!(f, "billing"),
+                AddressType::Shipping => write!(f, "shipping"),
+            }
+        }
+    }
+
+    /// One of a user's addresses. Each user has at most one default address per type.
+    #[derive(Serialize, Deserialize, Clone, Debug)]
+    pub struct UserAddress {
+        pub address_id: String,
+        pub address_type: AddressType,
+        pub is_default: bool,
+        #[serde(flatten)]
+        pub address: Address,
+    }
+
     pub mod dto {
-        use crate::domain::customer::Address;
+        use crate::domain::customer::{Address, AddressType, Role, UserProfile, UserStatus};
+        use data_encoding::BASE64URL_NOPAD;
         use crate::domain::realm::{Realm, RealmName};
         use mysql::prelude::FromValue;
         use pbkdf2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
@@ -75,49 +227,373 @@ pub mod customer {
         use rand::rngs::OsRng;
         use serde::{Deserialize, Serialize};
         use crate::app::Error;
+        use crate::domain::mfa::MfaMethod;
+        use crate::domain::realm::PasswordPolicy;
+        use crate::domain::validation::{not_blank, username_charset, ValidateRequest};
+        use validator::{Validate, ValidationError, ValidationErrors};
 
-        #[derive(Serialize, Deserialize, Clone, Debug)]
+        #[derive(Serialize, Deserialize, Clone, Debug, Validate)]
         pub struct CreateUser {
+            #[validate(
+                length(min = 3, max = 32, message = "must be between 3 and 32 characters"),
+                custom(function = username_charset)
+            )]
             pub username: String,
+            /// Checked against the password policy of the realm.
             pub password: String,
+            #[validate(
+                custom(function = not_blank),
+                length(max = 100, message = "must be at most 100 characters")
+            )]
             pub name: String,
+            #[validate(range(min = 0, max = 150, message = "must be between 0 and 150"))]
             pub age: i32,
+            #[validate(custom(function = valid_email))]
             pub email: String,
+            #[validate(nested)]
             pub address: Address,
         }
 
+        impl ValidateRequest for CreateUser {
+            fn validate_in_realm(&self, policy: &PasswordPolicy, errors: &mut ValidationErrors) {
+                for error in policy.check(&self.password, &self.username, Some(&self.email)) {
+                    errors.add("password", error);
+                }
+            }
+        }
+
         impl CreateUser {
-            pub fn hash_password(&mut self, realm: &RealmName) {
-                let salt_format = format!("{}|{}", &self.username, realm).into_bytes();
-                let salt = SaltString::encode_b64(salt_format.as_slice()).unwrap();
-                match Pbkdf2.hash_password(self.password.as_bytes(), &salt) {
-                    Ok(x) => {
-                        println!("Hashed pwd to: {}", &self.password);
-                        self.password = String::from(x.to_string().as_str());
-                    }
-                    Err(_) => {
-                        println!("Failed to hash password");
-                    }
-                };
+            /// Replaces the plain password with its PHC hash and returns the hash.
+            pub fn hash_password(&mut self, realm: &RealmName) -> Result<String, Error> {
+                let hash = hash_password(&self.username, realm, &self.password)?;
+                self.password = hash.clone();
+                Ok(hash)
             }
         }
 
+        /// PHC hash of `password` for the user `username` of `realm`.
+        pub fn hash_password(username: &str, realm: &str, password: &str) -> Result<String, Error> {
+            let salt_format = format!("{}|{}", username, realm).into_bytes();
+            let salt = SaltString::encode_b64(salt_format.as_slice())
+                .map_err(|_| Error::PasswordHashing)?;
+            Ok(Pbkdf2
+                .hash_password(password.as_bytes(), &salt)
+                .map_err(|_| Error::PasswordHashing)?
+                .to_string())
+        }
+
+        /// Whether `password` matches the PHC string `hash`.
+        pub fn verify_password(password: &str, hash: &str) -> bool {
+            PasswordHash::new(hash)
+                .is_ok_and(|parsed| Pbkdf2.verify_password(password.as_bytes(), &parsed).is_ok())
+        }
+
+        /// Whether `previous` is a hash of `password`, whose own hash is `hash`. Hashes sharing
+        /// the salt and parameters are compared as is, which spares a key derivation per entry.
+        pub fn is_same_password(password: &str, hash: &str, previous: &str) -> bool {
+            match (PasswordHash::new(hash), PasswordHash::new(previous)) {
+                (Ok(new), Ok(old))
+                    if new.algorithm == old.algorithm
+                        && new.salt == old.salt
+                        && new.params == old.params =>
+                {
+                    new.hash == old.hash
+                }
+                _ => verify_password(password, previous),
+            }
+        }
+
+        const MAX_FIELD_LENGTH: usize = 100;
+
+        /// Partial update of a user, fields left out of the request are not changed. Addresses
+        /// are managed through their own endpoints.
+        #[derive(Serialize, Deserialize, Clone, Debug, Default, Validate)]
+        #[serde(deny_unknown_fields)]
+        pub struct UpdateUser {
+            #[validate(
+                custom(function = not_blank),
+                length(max = 100, message = "must be at most 100 characters")
+            )]
+            pub name: Option<String>,
+            #[validate(custom(function = valid_email))]
+            pub email: Option<String>,
+            pub role: Option<Role>,
+        }
+
+        impl ValidateRequest for UpdateUser {}
+
+        /// Body of `PUT /api/customer/{user_id}/password`. The new password is checked against
+        /// the realm's policy once the user is loaded.
+        #[derive(Serialize, Deserialize, Clone, Debug, Validate)]
+        pub struct ChangePassword {
+            #[validate(length(min = 1, message = "must not be empty"))]
+            pub current_password: String,
+            pub new_password: String,
+        }
+
+        impl ValidateRequest for ChangePassword {}
+
+        /// Body of `PUT /api/admin/customer/{user_id}/password`.
+        #[derive(Serialize, Deserialize, Clone, Debug, Validate)]
+        pub struct SetPassword {
+            pub password: String,
+        }
+
+        impl ValidateRequest for SetPassword {}
+
+        /// Body of `POST /api/customer/password-reset`.
+        #[derive(Serialize, Deserialize, Clone, Debug, Validate)]
+        pub struct PasswordResetRequest {
+            #[validate(length(min = 1, message = "must not be empty"))]
+            pub username: String,
+        }
+
+        impl ValidateRequest for PasswordResetRequest {}
+
+        /// Body of `POST /api/customer/password-reset/confirm`, `token` is the one issued by the
+        /// reset request.
+        #[derive(Serialize, Deserialize, Clone, Debug, Validate)]
+        pub struct PasswordReset {
+            #[validate(length(min = 1, message = "must not be empty"))]
+            pub username: String,
+            #[validate(length(min = 1, message = "must not be empty"))]
+            pub token: String,
+            pub new_password: String,
+        }
+
+        impl ValidateRequest for PasswordReset {}
+
+        /// Body of `POST /api/customer/email/confirm`, `token` is the one issued when the email
+        /// was set.
+        #[derive(Serialize, Deserialize, Clone, Debug, Validate)]
+        pub struct EmailConfirmation {
+            #[validate(length(min = 1, message = "must not be empty"))]
+            pub username: String,
+            #[validate(length(min = 1, message = "must not be empty"))]
+            pub token: String,
+        }
+
+        impl ValidateRequest for EmailConfirmation {}
+
+        /// Body of `POST /api/customer/{user_id}/mfa/totp/verify`.
+        #[derive(Serialize, Deserialize, Clone, Debug, Validate)]
+        pub struct TotpCode {
+            #[validate(length(min = 1, message = "must not be empty"))]
+            pub code: String,
+        }
+
+        impl ValidateRequest for TotpCode {}
+
+        /// Proof that the caller still holds the account before a second factor is changed:
+        /// the current password or a code of the active authenticator app.
+        #[derive(Serialize, Deserialize, Clone, Debug, Default, Validate)]
+        pub struct Reauthentication {
+            pub password: Option<String>,
+            pub code: Option<String>,
+        }
+
+        impl ValidateRequest for Reauthentication {}
+
+        /// Body of `POST /api/realm/{realm}/login/mfa`, `mfa_token` is the one of the challenge
+        /// returned by the password login. `code` is a TOTP code unless `method` says otherwise.
+        #[derive(Serialize, Deserialize, Clone, Debug, Validate)]
+        pub struct MfaLogin {
+            #[validate(length(min = 1, message = "must not be empty"))]
+            pub username: String,
+            #[validate(length(min = 1, message = "must not be empty"))]
+            pub mfa_token: String,
+            #[validate(length(min = 1, message = "must not be empty"))]
+            pub code: String,
+            #[serde(default)]
+            pub method: MfaMethod,
+        }
+
+        impl ValidateRequest for MfaLogin {}
+
+        /// Body of `POST /api/customer/{user_id}/addresses` and of the address update.
         #[derive(Serialize, Deserialize, Clone, Debug)]
-        pub struct UserMetadata {
+        pub struct AddressData {
+            #[serde(flatten)]
+            pub address: Address,
+            #[serde(default)]
+            pub address_type: AddressType,
+            /// Makes this the default address of its type, replacing the previous default.
+            #[serde(default)]
+            pub is_default: bool,
+        }
+
+        // The address is flattened into the body, so its fields are reported without a prefix.
+        impl Validate for AddressData {
+            fn validate(&self) -> Result<(), ValidationErrors> {
+                self.address.validate()
+            }
+        }
+
+        impl ValidateRequest for AddressData {}
+
+        fn valid_email(email: &str) -> Result<(), ValidationError> {
+            match is_valid_email(email) {
+                true => Ok(()),
+                false => Err(ValidationError::new("email").with_message("is not a valid email".into())),
+            }
+        }
+
+        fn is_valid_email(email: &str) -> bool {
+            if email.len() > MAX_FIELD_LENGTH || email.chars().any(char::is_whitespace) {
+                return false;
+            }
+            match email.split_once('@') {
+                Some((local, domain)) => {
+                    !local.is_empty()
+                        && !domain.contains('@')
+                        && domain.split('.').count() > 1
+                        && domain.split('.').all(|part| !part.is_empty())
+                }
+                None => false,
+            }
+        }
+
+        /// How a user is removed by `DELETE /api/customer/{user_id}`.
+        #[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+        #[serde(rename_all = "lowercase")]
+        pub enum DeletionMode {
+            /// Disables the user but keeps the record, so audit history stays intact.
+            #[default]
+            Disable,
+            /// Removes the user and everything attached to it. Admin only.
+            Erase,
+        }
+
+        #[derive(Serialize, Deserialize, Clone, Debug, Default)]
+        pub struct DeleteUser {
+            #[serde(default)]
+            pub mode: DeletionMode,
+        }
+
+        pub const DEFAULT_PAGE_SIZE: u32 = 20;
+        pub const MAX_PAGE_SIZE: u32 = 100;
+
+        #[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+        #[serde(rename_all = "snake_case")]
+        pub enum UserSort {
+            #[default]
+            Username,
+            CreatedAt,
+        }
+
+        #[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+        #[serde(rename_all = "lowercase")]
+        pub enum SortOrder {
+            #[default]
+            Asc,
+            Desc,
+        }
+
+        /// Query string of `GET /api/customer`. Every filter is optional and they are combined
+        /// with AND; by default only active users are listed.
+        #[derive(Serialize, Deserialize, Clone, Debug, Default)]
+        #[serde(deny_unknown_fields)]
+        pub struct UserQuery {
+            pub role: Option<Role>,
+            pub email: Option<String>,
+            pub username_prefix: Option<String>,
+            /// Inclusive lower bound on the unix creation time.
+            pub created_after: Option<i64>,
+            /// Exclusive upper bound on the unix creation time.
+            pub created_before: Option<i64>,
+            #[serde(default)]
+            pub status: UserStatus,
+            #[serde(default)]
+            pub sort: UserSort,
+            #[serde(default)]
+            pub order: SortOrder,
+            pub limit: Option<u32>,
+            pub cursor: Option<String>,
+        }
+
+        impl UserQuery {
+            pub fn page_size(&self) -> Result<u32, Error> {
+                match self.limit.unwrap_or(DEFAULT_PAGE_SIZE) {
+                    limit @ 1..=MAX_PAGE_SIZE => Ok(limit),
+                    _ => Err(Error::Validation(format!(
+                        "limit must be between 1 and {}",
+                        MAX_PAGE_SIZE
+                    ))),
+                }
+            }
+        }
+
+        /// Sort key of the cursor, its type follows the sort it was issued for.
+        #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+        #[serde(untagged)]
+        pub enum CursorKey {
+            Number(i64),
+            Text(String),
+        }
+
+        /// Position after the last user of a page. Ties on the sort key are broken by user id.
+        #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+        pub struct PageCursor {
+            pub key: CursorKey,
             pub user_id: String,
-            pub address: Option<Address>,
+        }
+
+        impl PageCursor {
+            pub fn after(user: &UserProfile, sort: UserSort) -> PageCursor {
+                let key = match sort {
+                    UserSort::Username => CursorKey::Text(user.username.clone()),
+                    UserSort::CreatedAt => CursorKey::Number(user.created_at),
+                };
+                PageCursor {
+                    key,
+                    user_id: user.user_id.clone(),
+                }
+            }
+
+            pub fn encode(&self) -> String {
+                BASE64URL_NOPAD.encode(&serde_json::to_vec(self).unwrap_or_default())
+            }
+
+            /// Decodes a cursor, rejecting ones issued for a different sort.
+            pub fn decode(cursor: &str, sort: UserSort) -> Result<PageCursor, Error> {
+                let invalid = || Error::Validation("invalid cursor".to_string());
+                let bytes = BASE64URL_NOPAD
+                    .decode(cursor.as_bytes())
+                    .map_err(|_| invalid())?;
+                let cursor: PageCursor = serde_json::from_slice(&bytes).map_err(|_| invalid())?;
+                match (&cursor.key, sort) {
+                    (CursorKey::Text(_), UserSort::Username)
+                    | (CursorKey::Number(_), UserSort::CreatedAt) => Ok(cursor),
+                    _ => Err(invalid()),
+                }
+            }
+        }
+
+        /// Updatable user details, fields left as `None` are not changed.
+        #[derive(Serialize, Deserialize, Clone, Debug, Default)]
+        pub struct UserMetadata {
+            pub name: Option<String>,
+            pub email: Option<String>,
+            pub email_verified: Option<bool>,
+            pub role: Option<Role>,
+            /// PHC hash of a new password.
+            pub password: Option<String>,
         }
     }
 }
 
+#[cfg(test)]
 mod test {
-    use crate::domain::customer::dto::CreateUser;
+    use crate::domain::customer::dto::{CreateUser, UpdateUser};
+    use crate::app::Error;
     use crate::domain::customer::Address;
-    use crate::domain::realm::RealmName;
+    use crate::domain::realm::{PasswordPolicy, RealmName};
+    use crate::domain::validation::ValidateRequest;
     use pbkdf2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
     use pbkdf2::Pbkdf2;
     use rand::rngs::OsRng;
     use std::num::NonZeroU32;
+    use validator::Validate;
 
     #[test]
     fn test_hashing_password() {
@@ -134,19 +610,104 @@ mod test {
                 post_code: "W1 2DE".to_string(),
             },
         };
-        let iter = NonZeroU32::new(4026).unwrap();
         let realm = &"rj.nitro".to_string();
         let current_pass = user.password.clone();
 
-        println!("Current Pass: {}", &current_pass);
-        let salt = format!("{}|{}", &user.username, realm).into_bytes();
-        let password_hash  =  user.hash_password(&realm).unwrap();
+        let password_hash  =  user.hash_password(realm).unwrap();
 
-        println!("Hashed Pass: {}", &password_hash);
 
         // Verify password against PHC string
         let parsed_hash = PasswordHash::new(&password_hash).unwrap();
-        assert!(Pbkdf2.verify_password(&current_pass.as_bytes(), &parsed_hash).is_ok());
+        assert!(Pbkdf2.verify_password(current_pass.as_bytes(), &parsed_hash).is_ok());
+
+    }
+
+    #[test]
+    fn test_normalise_and_format_address() {
+        let mut address = Address {
+            street: "10 Downing Street".to_string(),
+            country: "Great Britain".to_string(),
+            city: "London".to_string(),
+            post_code: "sw1a2aa".to_string(),
+        };
+        address.normalise().unwrap();
+        assert_eq!(address.country, "GB");
+        assert_eq!(address.post_code, "SW1A 2AA");
+        assert_eq!(address.lines(), vec!["10 Downing Street", "LONDON", "SW1A 2AA", "UNITED KINGDOM"]);
+
+        let mut address = Address {
+            street: "Amaliegade 18".to_string(),
+            country: "DNK".to_string(),
+            city: "København K".to_string(),
+            post_code: "DK-1256".to_string(),
+        };
+        address.normalise().unwrap();
+        assert_eq!(address.lines(), vec!["Amaliegade 18", "1256 København K", "DENMARK"]);
+
+        address.post_code = "12".to_string();
+        assert!(address.normalise().is_err());
+    }
+
+    #[test]
+    fn test_create_user_validation() {
+        let mut user = CreateUser {
+            username: "ru ru!".to_string(),
+            password: "short".to_string(),
+            name: " ".to_string(),
+            age: -1,
+            email: "ruru@nitro".to_string(),
+            address: Address {
+                street: "".to_string(),
+                country: "UK".to_string(),
+                city: "London".to_string(),
+                post_code: "W1 2DE".to_string(),
+            },
+        };
+        let fields = match user.validate_request(&PasswordPolicy::default()) {
+            Err(Error::InvalidFields(fields)) => fields,
+            other => panic!("expected field errors, got {:?}", other),
+        };
+        let names: Vec<&str> = fields.keys().map(String::as_str).collect();
+        assert_eq!(names, vec!["address.street", "age", "email", "name", "password", "username"]);
+        assert_eq!(fields["password"][0].code, "password_length");
+
+        user.username = "ru.ru".to_string();
+        user.password = "passw0rd".to_string();
+        user.name = "RuRu".to_string();
+        user.age = 21;
+        user.email = "ruru@nitro.com".to_string();
+        user.address.street = "The Street".to_string();
+        assert!(user.validate_request(&PasswordPolicy::default()).is_ok());
 
+        let strict = PasswordPolicy {
+            min_length: 12,
+            ..PasswordPolicy::default()
+        };
+        assert!(user.validate_request(&strict).is_err());
+    }
+
+    #[test]
+    fn test_update_user_validation() {
+        let valid = UpdateUser {
+            name: Some("Ru Ru".to_string()),
+            email: Some("ruru@nitro.com".to_string()),
+            ..UpdateUser::default()
+        };
+        assert!(valid.validate().is_ok());
+        assert!(UpdateUser::default().validate().is_ok());
+
+        for email in ["ruru", "ruru@", "@nitro.com", "ruru@nitro", "ru ru@nitro.com", "ruru@nitro..com"] {
+            let update = UpdateUser {
+                email: Some(email.to_string()),
+                ..UpdateUser::default()
+            };
+            assert!(update.validate().is_err(), "{} should be rejected", email);
+        }
+
+        let blank_name = UpdateUser {
+            name: Some("  ".to_string()),
+            ..UpdateUser::default()
+        };
+        assert!(blank_name.validate().is_err());
     }
 }
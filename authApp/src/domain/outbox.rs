@@ -0,0 +1,75 @@
+//! Transactional outbox. Domain changes record their events in the same transaction, and a
+//! dispatcher publishes them once committed, so nothing is published for a change that rolled
+//! back and nothing committed goes unpublished. Publishing is at least once: consumers should
+//! deduplicate on the event id, which stays the same across retries.
+//!
+//! Events of one aggregate, e.g. one user, are published in the order they were recorded. An
+//! event that fails to publish holds back the later events of its aggregate until it goes
+//! through.
+
+use crate::domain::realm::RealmName;
+use crate::domain::webhook::{RetryPolicy, WebhookEventType, MAX_DELIVERY_ERROR_LENGTH};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+pub const USER_AGGREGATE: &str = "user";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OutboxEvent {
+    pub event_id: String,
+    pub realm: RealmName,
+    pub aggregate_type: String,
+    pub aggregate_id: String,
+    pub event_type: String,
+    pub payload: serde_json::Value,
+    pub occurred_at: i64,
+}
+
+impl OutboxEvent {
+    /// A lifecycle event of a user.
+    pub fn user(
+        realm: &RealmName,
+        event_type: WebhookEventType,
+        user_id: &str,
+        payload: serde_json::Value,
+        occurred_at: i64,
+    ) -> OutboxEvent {
+        OutboxEvent {
+            event_id: Uuid::new_v4().to_string(),
+            realm: realm.clone(),
+            aggregate_type: USER_AGGREGATE.to_string(),
+            aggregate_id: user_id.to_string(),
+            event_type: event_type.to_string(),
+            payload,
+            occurred_at,
+        }
+    }
+}
+
+/// An event as kept in the outbox, with its publishing state.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutboxRecord {
+    /// Position in the outbox, which orders the events of an aggregate.
+    pub id: i64,
+    pub event: OutboxEvent,
+    pub attempts: u32,
+    pub next_attempt_at: i64,
+    pub last_error: Option<String>,
+    pub published_at: Option<i64>,
+}
+
+impl OutboxRecord {
+    pub fn published(&mut self, now: i64) {
+        self.attempts += 1;
+        self.published_at = Some(now);
+        self.last_error = None;
+    }
+
+    /// Schedules the next attempt after a failed one. Events are retried until published, so
+    /// only the delays of `policy` apply.
+    pub fn failed(&mut self, error: &str, now: i64, policy: &RetryPolicy) {
+        self.attempts += 1;
+        self.last_error = Some(error.chars().take(MAX_DELIVERY_ERROR_LENGTH).collect());
+        self.next_attempt_at = now + policy.delay(self.attempts).as_secs() as i64;
+    }
+}
@@ -0,0 +1,156 @@
+//! Passwordless login by email, with either magic links or 6 digit codes depending on the realm.
+//! Each issued credential is short-lived, single-use and only stored as a SHA-256 hash, so the
+//! stored rows cannot be redeemed themselves.
+
+use crate::domain::realm::RealmName;
+use crate::domain::validation::ValidateRequest;
+use data_encoding::{BASE64URL_NOPAD, HEXLOWER};
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use strum_macros::{Display, EnumString};
+use validator::Validate;
+
+pub const EMAIL_CODE_DIGITS: usize = 6;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Display, EnumString, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum PasswordlessMethod {
+    /// A link carrying a random token, rendered by the delivery channel.
+    MagicLink,
+    /// A code the user types in.
+    EmailCode,
+}
+
+/// Passwordless login rules of a realm, disabled without a method.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PasswordlessPolicy {
+    pub method: Option<PasswordlessMethod>,
+    /// How long an issued credential can be redeemed.
+    pub credential_duration: Duration,
+    /// Wrong guesses an issued credential survives.
+    pub max_attempts: u32,
+}
+
+impl Default for PasswordlessPolicy {
+    fn default() -> Self {
+        PasswordlessPolicy {
+            method: None,
+            credential_duration: Duration::from_secs(10 * 60),
+            max_attempts: 5,
+        }
+    }
+}
+
+/// The pending credential of a user, a new request replaces it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PasswordlessCredential {
+    pub method: PasswordlessMethod,
+    pub secret_hash: String,
+    pub expires_at: i64,
+    /// Wrong guesses so far.
+    pub attempts: u32,
+}
+
+impl PasswordlessCredential {
+    /// A new credential for `user_id` with its secret, which only the delivered message carries.
+    pub fn issue(
+        user_id: &str,
+        method: PasswordlessMethod,
+        now: i64,
+        valid_for: Duration,
+    ) -> (PasswordlessCredential, String) {
+        let secret = match method {
+            PasswordlessMethod::MagicLink => BASE64URL_NOPAD.encode(&rand::random::<[u8; 32]>()),
+            PasswordlessMethod::EmailCode => format!(
+                "{:0width$}",
+                rand::random_range(0..10u32.pow(EMAIL_CODE_DIGITS as u32)),
+                width = EMAIL_CODE_DIGITS
+            ),
+        };
+        let credential = PasswordlessCredential {
+            method,
+            secret_hash: hash_passwordless_secret(user_id, &secret),
+            expires_at: now + valid_for.as_secs() as i64,
+            attempts: 0,
+        };
+        (credential, secret)
+    }
+}
+
+/// SHA-256 of the secret salted with the user id, so equal codes of two users differ.
+pub fn hash_passwordless_secret(user_id: &str, secret: &str) -> String {
+    let salted = format!("{}:{}", user_id, secret.trim());
+    HEXLOWER.encode(digest(&SHA256, salted.as_bytes()).as_ref())
+}
+
+/// What a delivery channel sends to the user's email address.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PasswordlessMessage {
+    pub realm: RealmName,
+    pub user_id: String,
+    pub username: String,
+    pub email: String,
+    pub method: PasswordlessMethod,
+    /// The code, or the token of the magic link.
+    pub secret: String,
+    pub expires_at: i64,
+}
+
+/// Body of `POST /api/realm/{realm}/login/passwordless/request`.
+#[derive(Serialize, Deserialize, Clone, Debug, Validate)]
+pub struct PasswordlessRequest {
+    #[validate(length(min = 1, message = "must not be empty"))]
+    pub username: String,
+}
+
+impl ValidateRequest for PasswordlessRequest {}
+
+/// Body of `POST /api/realm/{realm}/login/passwordless`, `code` is the emailed code or the token
+/// of the magic link.
+#[derive(Serialize, Deserialize, Clone, Debug, Validate)]
+pub struct PasswordlessLogin {
+    #[validate(length(min = 1, message = "must not be empty"))]
+    pub username: String,
+    #[validate(length(min = 1, message = "must not be empty"))]
+    pub code: String,
+}
+
+impl ValidateRequest for PasswordlessLogin {}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::passwordless::{
+        hash_passwordless_secret, PasswordlessCredential, PasswordlessMethod,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn test_issue_credentials() {
+        let valid_for = Duration::from_secs(600);
+        let (credential, code) =
+            PasswordlessCredential::issue("user", PasswordlessMethod::EmailCode, 100, valid_for);
+        assert_eq!(code.len(), 6);
+        assert!(code.chars().all(|c| c.is_ascii_digit()));
+        assert_eq!(credential.expires_at, 700);
+        assert_eq!(credential.attempts, 0);
+        assert_eq!(
+            credential.secret_hash,
+            hash_passwordless_secret("user", &format!(" {}\n", code))
+        );
+        assert_ne!(
+            credential.secret_hash,
+            hash_passwordless_secret("other", &code)
+        );
+
+        let (credential, token) =
+            PasswordlessCredential::issue("user", PasswordlessMethod::MagicLink, 100, valid_for);
+        assert_eq!(token.len(), 43);
+        assert_eq!(credential.method, PasswordlessMethod::MagicLink);
+        assert_eq!(
+            credential.secret_hash,
+            hash_passwordless_secret("user", &token)
+        );
+    }
+}
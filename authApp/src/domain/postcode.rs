@@ -0,0 +1,111 @@
+//! Postcode validation and canonical formatting for the countries we ship to. Countries without
+//! rules keep the postcode as given, apart from case and spacing.
+
+/// Canonical form of `post_code` for the ISO 3166-1 alpha-2 `country`, or the reason it is
+/// invalid.
+pub fn normalise(country: &str, post_code: &str) -> Result<String, String> {
+    let compact: String = post_code
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_uppercase();
+    match country {
+        "GB" => uk(&compact).ok_or_else(|| format!("{} is not a valid UK postcode", post_code)),
+        "US" => us(&compact).ok_or_else(|| format!("{} is not a valid US ZIP code", post_code)),
+        "DK" => dk(&compact).ok_or_else(|| format!("{} is not a valid Danish postcode", post_code)),
+        _ => Ok(post_code
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_uppercase()),
+    }
+}
+
+/// `SW1A 1AA`: an outward code of 2 to 4 characters, a space and a digit followed by two letters.
+fn uk(compact: &str) -> Option<String> {
+    if compact == "GIR0AA" {
+        return Some("GIR 0AA".to_string());
+    }
+    if !(5..=7).contains(&compact.len()) || !compact.is_ascii() {
+        return None;
+    }
+    let (outward, inward) = compact.split_at(compact.len() - 3);
+
+    let inward_chars: Vec<char> = inward.chars().collect();
+    let inward_ok = inward_chars[0].is_ascii_digit()
+        && inward_chars[1..]
+            .iter()
+            .all(|c| c.is_ascii_alphabetic() && !"CIKMOV".contains(*c));
+
+    // A9, A99, AA9, AA99, A9A or AA9A
+    let shape: String = outward
+        .chars()
+        .map(|c| if c.is_ascii_digit() { '9' } else { 'A' })
+        .collect();
+    let outward_ok = ["A9", "A99", "AA9", "AA99", "A9A", "AA9A"].contains(&shape.as_str());
+
+    match inward_ok && outward_ok {
+        true => Some(format!("{} {}", outward, inward)),
+        false => None,
+    }
+}
+
+/// `12345` or ZIP+4 `12345-6789`.
+fn us(compact: &str) -> Option<String> {
+    let digits: String = compact.chars().filter(|c| *c != '-').collect();
+    if !digits.chars().all(|c| c.is_ascii_digit()) || compact.matches('-').count() > 1 {
+        return None;
+    }
+    match (digits.len(), compact.find('-')) {
+        (5, None) => Some(digits),
+        (9, None) | (9, Some(5)) => Some(format!("{}-{}", &digits[..5], &digits[5..])),
+        _ => None,
+    }
+}
+
+/// Four digits, optionally written with a `DK-` prefix.
+fn dk(compact: &str) -> Option<String> {
+    let digits = compact.strip_prefix("DK-").unwrap_or(compact);
+    match digits.len() == 4 && digits.chars().all(|c| c.is_ascii_digit()) {
+        true => Some(digits.to_string()),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::postcode::normalise;
+
+    #[test]
+    fn test_normalise_postcode() {
+        let valid = [
+            ("GB", "sw1a1aa", "SW1A 1AA"),
+            ("GB", " W1  2DE ", "W1 2DE"),
+            ("GB", "m11ae", "M1 1AE"),
+            ("GB", "EC1A 1BB", "EC1A 1BB"),
+            ("US", "90210", "90210"),
+            ("US", "902101234", "90210-1234"),
+            ("US", "90210-1234", "90210-1234"),
+            ("DK", "DK-2100", "2100"),
+            ("DK", "8000", "8000"),
+            ("FR", " 75008  paris ", "75008 PARIS"),
+        ];
+        for (country, input, expected) in valid {
+            assert_eq!(normalise(country, input).as_deref(), Ok(expected), "{}", input);
+        }
+
+        let invalid = [
+            ("GB", "W1 2CI"),
+            ("GB", "12345"),
+            ("GB", "SW1A1AAA"),
+            ("US", "9021"),
+            ("US", "90210-12"),
+            ("US", "9021O"),
+            ("DK", "210"),
+            ("DK", "DK-21000"),
+        ];
+        for (country, input) in invalid {
+            assert!(normalise(country, input).is_err(), "{} {}", country, input);
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! Token-bucket rate limits. Every realm allows a number of requests per minute for each class of
+//! route, spent from a bucket per client that refills evenly over the minute.
+
+use serde::{Deserialize, Serialize};
+use strum_macros::Display;
+
+/// Routes sharing a limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Display, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum RouteClass {
+    /// Login routes and token refreshes.
+    Login,
+    Registration,
+    /// Routes that email the user, password resets and passwordless login requests.
+    PasswordReset,
+    Admin,
+}
+
+/// Requests per minute of each route class, 0 leaves the class unlimited.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RateLimits {
+    pub login: u32,
+    pub registration: u32,
+    pub password_reset: u32,
+    pub admin: u32,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            login: 30,
+            registration: 10,
+            password_reset: 10,
+            admin: 120,
+        }
+    }
+}
+
+impl RateLimits {
+    pub fn per_minute(&self, class: RouteClass) -> u32 {
+        match class {
+            RouteClass::Login => self.login,
+            RouteClass::Registration => self.registration,
+            RouteClass::PasswordReset => self.password_reset,
+            RouteClass::Admin => self.admin,
+        }
+    }
+}
+
+/// Milliseconds after which any bucket left alone is full again, like a bucket never used, so
+/// idle ones can be dropped.
+pub const BUCKET_IDLE_MILLIS: i64 = 60_000;
+
+/// A bucket holding up to `per_minute` tokens. Times are unix milliseconds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TokenBucket {
+    pub tokens: f64,
+    pub updated_at: i64,
+}
+
+impl TokenBucket {
+    pub fn full(per_minute: u32, now: i64) -> TokenBucket {
+        TokenBucket {
+            tokens: per_minute as f64,
+            updated_at: now,
+        }
+    }
+
+    /// Refills the bucket up to `now` and takes a token, or returns the seconds until one is
+    /// available.
+    pub fn take(&mut self, per_minute: u32, now: i64) -> Result<(), u64> {
+        let capacity = per_minute as f64;
+        let elapsed = (now - self.updated_at).max(0) as f64;
+        self.tokens = (self.tokens + elapsed * capacity / 60_000.0).min(capacity);
+        self.updated_at = now.max(self.updated_at);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait_millis = (1.0 - self.tokens) * 60_000.0 / capacity;
+            Err((wait_millis / 1000.0).ceil().max(1.0) as u64)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::rate_limit::TokenBucket;
+
+    #[test]
+    fn test_token_bucket() {
+        let now = 1_700_000_000_000;
+        let mut bucket = TokenBucket::full(6, now);
+        for _ in 0..6 {
+            assert_eq!(bucket.take(6, now), Ok(()));
+        }
+        // Six a minute refill one token every ten seconds.
+        assert_eq!(bucket.take(6, now), Err(10));
+        assert_eq!(bucket.take(6, now + 4_000), Err(6));
+        assert_eq!(bucket.take(6, now + 10_000), Ok(()));
+
+        // Never holds more than a minute's worth.
+        let later = now + 3_600_000;
+        for _ in 0..6 {
+            assert_eq!(bucket.take(6, later), Ok(()));
+        }
+        assert!(bucket.take(6, later).is_err());
+    }
+}
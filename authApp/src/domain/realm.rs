@@ -1,6 +1,12 @@
+use crate::domain::lockout::LockoutPolicy;
+use crate::domain::mfa::MfaPolicy;
+use crate::domain::passwordless::PasswordlessPolicy;
+use crate::domain::rate_limit::RateLimits;
+use crate::domain::webauthn::WebAuthnConfig;
 use serde::{Deserialize, Serialize};
 use std::time::Duration;
-use strum_macros::Display;
+use strum_macros::{Display, EnumString};
+use validator::ValidationError;
 
 type ID = String;
 pub type RealmName = String;
@@ -23,6 +29,160 @@ pub trait RealmSettings {
     fn get_refresh_token_duration(&self) -> Duration;
 
     fn get_password_reset_token_duration(&self) -> Duration;
+
+    fn password_policy(&self) -> &PasswordPolicy;
+
+    fn lockout_policy(&self) -> &LockoutPolicy;
+
+    fn rate_limits(&self) -> &RateLimits;
+
+    fn mfa_policy(&self) -> &MfaPolicy;
+
+    fn webauthn_config(&self) -> &WebAuthnConfig;
+
+    fn passwordless_policy(&self) -> &PasswordlessPolicy;
+}
+
+/// Kinds of character a password can be required to contain.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Display, EnumString)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum CharacterClass {
+    Lowercase,
+    Uppercase,
+    Digit,
+    /// Anything that is not a letter or digit.
+    Symbol,
+}
+
+impl CharacterClass {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            CharacterClass::Lowercase => c.is_lowercase(),
+            CharacterClass::Uppercase => c.is_uppercase(),
+            CharacterClass::Digit => c.is_ascii_digit(),
+            CharacterClass::Symbol => !c.is_alphanumeric(),
+        }
+    }
+}
+
+/// Rules a new password must satisfy in a realm, served to UIs by
+/// `GET /api/realm/{realm}/password-policy`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    pub required_classes: Vec<CharacterClass>,
+    /// Longest run of one repeated character, unlimited when absent.
+    pub max_repeated: Option<usize>,
+    /// Rejects passwords containing the username or the local part of the email.
+    pub reject_user_details: bool,
+    /// Passwords refused regardless of the other rules, compared case-insensitively.
+    pub deny_list: Vec<String>,
+    /// Number of recent passwords, the current one included, a new password may not repeat.
+    pub history_size: usize,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_length: 8,
+            max_length: 128,
+            required_classes: Vec::new(),
+            max_repeated: None,
+            reject_user_details: true,
+            deny_list: Vec::new(),
+            history_size: 5,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    /// Every rule `password` of the user `username` breaks, empty when it is acceptable.
+    pub fn check(
+        &self,
+        password: &str,
+        username: &str,
+        email: Option<&str>,
+    ) -> Vec<ValidationError> {
+        let mut errors = Vec::new();
+        let rule = |code: &'static str, message: String| {
+            ValidationError::new(code).with_message(message.into())
+        };
+
+        let length = password.chars().count();
+        if length < self.min_length || length > self.max_length {
+            errors.push(rule(
+                "password_length",
+                format!(
+                    "must be between {} and {} characters",
+                    self.min_length, self.max_length
+                ),
+            ));
+        }
+
+        for class in &self.required_classes {
+            if !password.chars().any(|c| class.matches(c)) {
+                errors.push(rule(
+                    "password_character_class",
+                    format!("must contain a {} character", class),
+                ));
+            }
+        }
+
+        if let Some(max_repeated) = self.max_repeated {
+            if longest_run(password) > max_repeated {
+                errors.push(rule(
+                    "password_repeated",
+                    format!(
+                        "must not repeat a character more than {} times in a row",
+                        max_repeated
+                    ),
+                ));
+            }
+        }
+
+        let lowercase = password.to_lowercase();
+        if self.reject_user_details {
+            let local_part = email.and_then(|e| e.split('@').next());
+            let contains_details = [Some(username), local_part]
+                .iter()
+                .flatten()
+                .filter(|detail| detail.chars().count() >= 3)
+                .any(|detail| lowercase.contains(&detail.to_lowercase()));
+            if contains_details {
+                errors.push(rule(
+                    "password_user_details",
+                    "must not contain the username or email".to_string(),
+                ));
+            }
+        }
+
+        if self
+            .deny_list
+            .iter()
+            .any(|denied| denied.to_lowercase() == lowercase)
+        {
+            errors.push(rule(
+                "password_denied",
+                "is too common, choose another password".to_string(),
+            ));
+        }
+
+        errors
+    }
+}
+
+fn longest_run(value: &str) -> usize {
+    let mut longest = 0;
+    let mut run = 0;
+    let mut previous = None;
+    for c in value.chars() {
+        run = if previous == Some(c) { run + 1 } else { 1 };
+        longest = longest.max(run);
+        previous = Some(c);
+    }
+    longest
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -30,6 +190,7 @@ pub struct UserRealmSettings {
     pub is_confirmation_required: bool,
 }
 
+#[derive(Clone, Debug)]
 pub struct InternalRealmSettings {
     pub is_confirmation_required: bool,
     pub is_guest_allowed: bool,
@@ -37,30 +198,94 @@ pub struct InternalRealmSettings {
     pub authentication_token_duration: Duration,
     pub refresh_token_duration: Duration,
     pub password_reset_token_duration: Duration,
+    pub password_policy: PasswordPolicy,
+    pub lockout_policy: LockoutPolicy,
+    pub rate_limits: RateLimits,
+    pub mfa_policy: MfaPolicy,
+    pub webauthn: WebAuthnConfig,
+    pub passwordless_policy: PasswordlessPolicy,
 }
 
 impl RealmSettings for InternalRealmSettings {
     fn is_confirmation_required(&self) -> bool {
-        self.is_confirmation_required.clone()
+        self.is_confirmation_required
     }
 
     fn realm_salt_itr(&self) -> u32 {
-        return self.realm_salt_itr;
+        self.realm_salt_itr
     }
 
     fn is_guest_allowed(&self) -> bool {
-        self.is_guest_allowed.clone()
+        self.is_guest_allowed
     }
 
     fn get_authentication_token_duration(&self) -> Duration {
-        self.authentication_token_duration.clone()
+        self.authentication_token_duration
     }
 
     fn get_refresh_token_duration(&self) -> Duration {
-        self.refresh_token_duration.clone()
+        self.refresh_token_duration
     }
 
     fn get_password_reset_token_duration(&self) -> Duration {
-        self.password_reset_token_duration.clone()
+        self.password_reset_token_duration
+    }
+
+    fn password_policy(&self) -> &PasswordPolicy {
+        &self.password_policy
+    }
+
+    fn lockout_policy(&self) -> &LockoutPolicy {
+        &self.lockout_policy
+    }
+
+    fn rate_limits(&self) -> &RateLimits {
+        &self.rate_limits
+    }
+
+    fn mfa_policy(&self) -> &MfaPolicy {
+        &self.mfa_policy
+    }
+
+    fn webauthn_config(&self) -> &WebAuthnConfig {
+        &self.webauthn
+    }
+
+    fn passwordless_policy(&self) -> &PasswordlessPolicy {
+        &self.passwordless_policy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::realm::{CharacterClass, PasswordPolicy};
+
+    #[test]
+    fn test_password_policy() {
+        let policy = PasswordPolicy {
+            min_length: 10,
+            required_classes: vec![CharacterClass::Uppercase, CharacterClass::Digit],
+            max_repeated: Some(2),
+            deny_list: vec!["Password123".to_string()],
+            ..PasswordPolicy::default()
+        };
+        let codes = |password: &str| -> Vec<String> {
+            policy
+                .check(password, "ruru", Some("kiki@nitro.com"))
+                .iter()
+                .map(|e| e.code.to_string())
+                .collect()
+        };
+
+        assert!(codes("Correct4Horse").is_empty());
+        assert_eq!(codes("short1A"), vec!["password_length"]);
+        assert_eq!(
+            codes("nouppercaseordigits"),
+            vec!["password_character_class", "password_character_class"]
+        );
+        assert_eq!(codes("Baaad4Horse"), vec!["password_repeated"]);
+        assert_eq!(codes("RuRu4Horses"), vec!["password_user_details"]);
+        assert_eq!(codes("Kiki4Horses"), vec!["password_user_details"]);
+        assert_eq!(codes("PASSWORD123"), vec!["password_denied"]);
     }
 }
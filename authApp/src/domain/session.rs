@@ -0,0 +1,324 @@
+//! Login sessions. Each successful login opens one, and the refresh token handed out with it is
+//! bound to it: `{session_id}.{secret}`, of which only a SHA-256 hash of the secret is stored.
+//! Refreshing rotates the secret, and revoking the session makes its refresh token worthless.
+//!
+//! Access tokens are short lived HS256 JWTs naming the user and the session they were issued for,
+//! see [`AccessTokenKey`]. They are only honoured while that session exists.
+
+use crate::app::Error;
+use crate::domain::customer::Role;
+use crate::domain::realm::RealmName;
+use crate::domain::validation::ValidateRequest;
+use data_encoding::{BASE64URL_NOPAD, HEXLOWER};
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use uuid::Uuid;
+use validator::Validate;
+
+/// Longest user agent kept with a session, longer ones are cut.
+pub const MAX_USER_AGENT_LENGTH: usize = 255;
+
+/// Where a login came from, as far as the request tells.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+impl ClientInfo {
+    pub fn new(user_agent: Option<&str>, ip: Option<String>) -> ClientInfo {
+        ClientInfo {
+            user_agent: user_agent.map(|agent| agent.chars().take(MAX_USER_AGENT_LENGTH).collect()),
+            ip,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Session {
+    pub session_id: String,
+    pub realm: RealmName,
+    pub user_id: String,
+    pub refresh_token_hash: String,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: i64,
+    pub last_used_at: i64,
+    /// When the current refresh token stops working, pushed back by every refresh.
+    pub expires_at: i64,
+}
+
+impl Session {
+    /// A new session of `user_id` with its refresh token, which only the login response carries.
+    pub fn open(
+        realm: &RealmName,
+        user_id: &str,
+        client: ClientInfo,
+        now: i64,
+        valid_for: Duration,
+    ) -> (Session, String) {
+        let session_id = Uuid::new_v4().to_string();
+        let secret = refresh_secret();
+        let token = refresh_token(&session_id, &secret);
+        let session = Session {
+            session_id,
+            realm: realm.clone(),
+            user_id: user_id.to_string(),
+            refresh_token_hash: hash_refresh_secret(&secret),
+            user_agent: client.user_agent,
+            ip: client.ip,
+            created_at: now,
+            last_used_at: now,
+            expires_at: now + valid_for.as_secs() as i64,
+        };
+        (session, token)
+    }
+}
+
+/// A session as listed to its user or an admin.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub session_id: String,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: i64,
+    pub last_used_at: i64,
+    pub expires_at: i64,
+}
+
+impl From<&Session> for SessionInfo {
+    fn from(session: &Session) -> Self {
+        SessionInfo {
+            session_id: session.session_id.clone(),
+            user_agent: session.user_agent.clone(),
+            ip: session.ip.clone(),
+            created_at: session.created_at,
+            last_used_at: session.last_used_at,
+            expires_at: session.expires_at,
+        }
+    }
+}
+
+/// Response of a successful login or refresh.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SessionTokens {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub session_id: String,
+    /// When the refresh token expires unless it is used before.
+    pub refresh_expires_at: i64,
+}
+
+/// Body of `POST /api/realm/{realm}/token/refresh`.
+#[derive(Serialize, Deserialize, Clone, Debug, Validate)]
+pub struct RefreshRequest {
+    #[validate(length(min = 1, message = "must not be empty"))]
+    pub refresh_token: String,
+}
+
+impl ValidateRequest for RefreshRequest {}
+
+/// The caller of a request, named by its access token.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Principal {
+    pub user_id: String,
+    pub realm: RealmName,
+    pub session_id: String,
+    /// The user's role as stored, not as it was when the token was issued.
+    pub role: Role,
+    pub scope: TokenScope,
+}
+
+impl Principal {
+    /// Refuses callers acting on the resources of another user.
+    pub fn require_user(&self, user_id: &str) -> Result<(), Error> {
+        if self.user_id == user_id {
+            Ok(())
+        } else {
+            Err(Error::Forbidden("not your account".to_string()))
+        }
+    }
+
+    /// Refuses callers who are not admins.
+    pub fn require_admin(&self) -> Result<(), Error> {
+        match self.role {
+            Role::ADMIN => Ok(()),
+            _ => Err(Error::Forbidden("admin role required".to_string())),
+        }
+    }
+}
+
+/// What an access token may be used for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenScope {
+    /// Everything its user may do.
+    #[default]
+    Full,
+    /// Only enrolling a second factor, issued at the login of a user whose role requires one
+    /// they do not have yet.
+    MfaEnrollment,
+}
+
+/// Claims of an access token.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AccessClaims {
+    /// The user id.
+    pub sub: String,
+    pub realm: RealmName,
+    /// The session the token was issued for.
+    pub sid: String,
+    pub iat: i64,
+    pub exp: i64,
+    /// Missing in tokens issued before scopes existed, which were all full.
+    #[serde(default)]
+    pub scope: TokenScope,
+}
+
+/// Signs and checks access tokens, all instances must share its key.
+pub struct AccessTokenKey {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+}
+
+impl AccessTokenKey {
+    /// A key from at least 32 bytes of secret.
+    pub fn new(secret: &[u8]) -> Result<AccessTokenKey, String> {
+        if secret.len() < 32 {
+            return Err("access token key must be at least 32 bytes".to_string());
+        }
+        Ok(AccessTokenKey {
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+        })
+    }
+
+    /// A key with random secret, its tokens stop working after a restart.
+    pub fn ephemeral() -> AccessTokenKey {
+        AccessTokenKey::new(&rand::random::<[u8; 32]>()).unwrap()
+    }
+
+    /// An access token of the session's user, valid for `valid_for` from `now`.
+    pub fn issue(&self, session: &Session, now: i64, valid_for: Duration) -> String {
+        self.issue_scoped(session, TokenScope::Full, now, valid_for)
+    }
+
+    /// Like [`AccessTokenKey::issue`], for a token that may only be used for `scope`.
+    pub fn issue_scoped(
+        &self,
+        session: &Session,
+        scope: TokenScope,
+        now: i64,
+        valid_for: Duration,
+    ) -> String {
+        let claims = AccessClaims {
+            sub: session.user_id.clone(),
+            realm: session.realm.clone(),
+            sid: session.session_id.clone(),
+            iat: now,
+            exp: now + valid_for.as_secs() as i64,
+            scope,
+        };
+        encode(&Header::new(Algorithm::HS256), &claims, &self.encoding)
+            .expect("access token claims serialise")
+    }
+
+    /// The claims of `token` if this key signed it and it has not expired.
+    pub fn verify(&self, token: &str) -> Option<AccessClaims> {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.leeway = 0;
+        decode::<AccessClaims>(token.trim(), &self.decoding, &validation)
+            .ok()
+            .map(|data| data.claims)
+    }
+}
+
+pub fn refresh_secret() -> String {
+    BASE64URL_NOPAD.encode(&rand::random::<[u8; 32]>())
+}
+
+pub fn refresh_token(session_id: &str, secret: &str) -> String {
+    format!("{}.{}", session_id, secret)
+}
+
+/// The session id and secret of a refresh token.
+pub fn split_refresh_token(token: &str) -> Option<(&str, &str)> {
+    token
+        .trim()
+        .split_once('.')
+        .filter(|(session_id, secret)| !session_id.is_empty() && !secret.is_empty())
+}
+
+pub fn hash_refresh_secret(secret: &str) -> String {
+    HEXLOWER.encode(digest(&SHA256, secret.as_bytes()).as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::session::{
+        hash_refresh_secret, split_refresh_token, AccessTokenKey, ClientInfo, Session, TokenScope,
+        MAX_USER_AGENT_LENGTH,
+    };
+    use chrono::Utc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_open_session() {
+        let agent = "x".repeat(300);
+        let client = ClientInfo::new(Some(&agent), Some("10.0.0.1".to_string()));
+        let realm = "rj.wire".to_string();
+        let (session, token) = Session::open(&realm, "user", client, 100, Duration::from_secs(60));
+        assert_eq!(session.expires_at, 160);
+        assert_eq!(session.last_used_at, 100);
+        assert_eq!(
+            session.user_agent.as_ref().map(String::len),
+            Some(MAX_USER_AGENT_LENGTH)
+        );
+
+        let (session_id, secret) = split_refresh_token(&token).unwrap();
+        assert_eq!(session_id, session.session_id);
+        assert_eq!(session.refresh_token_hash, hash_refresh_secret(secret));
+        assert!(split_refresh_token("no-secret.").is_none());
+        assert!(split_refresh_token("garbage").is_none());
+    }
+
+    #[test]
+    fn test_access_token() {
+        let realm = "rj.wire".to_string();
+        let now = Utc::now().timestamp();
+        let (session, _) = Session::open(
+            &realm,
+            "user",
+            ClientInfo::default(),
+            now,
+            Duration::from_secs(60),
+        );
+        let key = AccessTokenKey::new(&[3; 32]).unwrap();
+        let token = key.issue(&session, now, Duration::from_secs(60));
+
+        let claims = key.verify(&token).unwrap();
+        assert_eq!(claims.sub, "user");
+        assert_eq!(claims.realm, realm);
+        assert_eq!(claims.sid, session.session_id);
+        assert_eq!(claims.exp, now + 60);
+        assert_eq!(claims.scope, TokenScope::Full);
+        let scoped = key.issue_scoped(
+            &session,
+            TokenScope::MfaEnrollment,
+            now,
+            Duration::from_secs(60),
+        );
+        assert_eq!(
+            key.verify(&scoped).unwrap().scope,
+            TokenScope::MfaEnrollment
+        );
+
+        assert!(AccessTokenKey::ephemeral().verify(&token).is_none());
+        assert!(key.verify("a").is_none());
+        let expired = key.issue(&session, now - 120, Duration::from_secs(60));
+        assert!(key.verify(&expired).is_none());
+        assert!(AccessTokenKey::new(&[3; 16]).is_err());
+    }
+}
@@ -0,0 +1,62 @@
+//! Checks run on request bodies before they reach the service. The static rules are declared on
+//! the DTOs with `#[derive(Validate)]`, rules that depend on the realm go in
+//! [`ValidateRequest::validate_in_realm`].
+
+use crate::app::Error;
+use crate::domain::realm::PasswordPolicy;
+use validator::{Validate, ValidationError, ValidationErrors};
+
+pub trait ValidateRequest: Validate {
+    /// Adds the failures of the realm specific rules to `errors`.
+    fn validate_in_realm(&self, _policy: &PasswordPolicy, _errors: &mut ValidationErrors) {}
+
+    /// Runs the declarative rules and then the realm specific ones.
+    fn validate_request(&self, policy: &PasswordPolicy) -> Result<(), Error> {
+        let mut errors = self.validate().err().unwrap_or_default();
+        self.validate_in_realm(policy, &mut errors);
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors.into()),
+        }
+    }
+}
+
+/// Checks a new `password` of a user against the realm's `policy`, reporting failures on `field`.
+pub fn check_password(
+    policy: &PasswordPolicy,
+    field: &'static str,
+    password: &str,
+    username: &str,
+    email: Option<&str>,
+) -> Result<(), Error> {
+    let mut errors = ValidationErrors::new();
+    for error in policy.check(password, username, email) {
+        errors.add(field, error);
+    }
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(errors.into()),
+    }
+}
+
+pub fn not_blank(value: &str) -> Result<(), ValidationError> {
+    match value.trim().is_empty() {
+        true => Err(ValidationError::new("blank").with_message("must not be blank".into())),
+        false => Ok(()),
+    }
+}
+
+/// Letters, digits, `.`, `_` and `-`, starting with a letter or digit.
+pub fn username_charset(username: &str) -> Result<(), ValidationError> {
+    let valid = username.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+    match valid {
+        true => Ok(()),
+        false => Err(ValidationError::new("charset").with_message(
+            "may only contain letters, digits, '.', '_' and '-', starting with a letter or digit"
+                .into(),
+        )),
+    }
+}
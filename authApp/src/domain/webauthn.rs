@@ -0,0 +1,887 @@
+//! Passkeys: the registration and authentication ceremonies of WebAuthn Level 2. Only the `none`
+//! attestation format is accepted, so the make of an authenticator is not checked and its public
+//! key is trusted on first use. Signatures may be ES256, EdDSA or RS256.
+
+use crate::domain::validation::ValidateRequest;
+use data_encoding::BASE64URL_NOPAD;
+use ring::digest::{digest, SHA256};
+use ring::signature::{
+    RsaPublicKeyComponents, UnparsedPublicKey, ECDSA_P256_SHA256_ASN1, ED25519,
+    RSA_PKCS1_2048_8192_SHA256,
+};
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumString};
+use validator::Validate;
+
+/// How long the challenge of a ceremony can be answered, in seconds.
+pub const CHALLENGE_TIMEOUT: i64 = 5 * 60;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_ATTESTED_CREDENTIAL: u8 = 0x40;
+
+/// COSE algorithm identifiers, in order of preference.
+const ALG_ES256: i64 = -7;
+const ALG_EDDSA: i64 = -8;
+const ALG_RS256: i64 = -257;
+
+/// Relying party of a realm. Passkeys are disabled while `rp_id` is unset.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct WebAuthnConfig {
+    /// Domain the credentials are scoped to, e.g. `example.com`.
+    pub rp_id: Option<String>,
+    /// Origins the ceremonies may run on, e.g. `https://login.example.com`.
+    pub origins: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Display, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum Ceremony {
+    Registration,
+    Authentication,
+}
+
+/// A challenge handed out with the options of a ceremony. It is answered once, registrations
+/// and logins started for a username are bound to that user.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingChallenge {
+    pub challenge: String,
+    pub ceremony: Ceremony,
+    pub user_id: Option<String>,
+    pub expires_at: i64,
+}
+
+impl PendingChallenge {
+    pub fn new(ceremony: Ceremony, user_id: Option<String>, now: i64) -> PendingChallenge {
+        PendingChallenge {
+            challenge: BASE64URL_NOPAD.encode(&rand::random::<[u8; 32]>()),
+            ceremony,
+            user_id,
+            expires_at: now + CHALLENGE_TIMEOUT,
+        }
+    }
+}
+
+/// A registered credential. The id is base64url, the public key a COSE key.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Passkey {
+    pub credential_id: String,
+    pub user_id: String,
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+    pub name: Option<String>,
+    pub created_at: i64,
+    pub last_used_at: Option<i64>,
+}
+
+/// A passkey as listed to its user.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PasskeyInfo {
+    pub credential_id: String,
+    pub name: Option<String>,
+    pub created_at: i64,
+    pub last_used_at: Option<i64>,
+}
+
+impl From<&Passkey> for PasskeyInfo {
+    fn from(passkey: &Passkey) -> Self {
+        PasskeyInfo {
+            credential_id: passkey.credential_id.clone(),
+            name: passkey.name.clone(),
+            created_at: passkey.created_at,
+            last_used_at: passkey.last_used_at,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RelyingParty {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserEntity {
+    /// Base64url of the user id, returned as the user handle of assertions.
+    pub id: String,
+    pub name: String,
+    pub display_name: String,
+}
+
+/// The WebAuthn user handle of a user, the base64url of its id.
+pub fn user_handle(user_id: &str) -> String {
+    BASE64URL_NOPAD.encode(user_id.as_bytes())
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CredentialParameters {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub alg: i64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CredentialDescriptor {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub id: String,
+}
+
+impl CredentialDescriptor {
+    pub fn of(passkey: &Passkey) -> CredentialDescriptor {
+        CredentialDescriptor {
+            kind: "public-key".to_string(),
+            id: passkey.credential_id.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticatorSelection {
+    pub resident_key: String,
+    pub user_verification: String,
+}
+
+/// `PublicKeyCredentialCreationOptions` for `navigator.credentials.create`, binary fields are
+/// base64url.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreationOptions {
+    pub challenge: String,
+    pub rp: RelyingParty,
+    pub user: UserEntity,
+    pub pub_key_cred_params: Vec<CredentialParameters>,
+    pub timeout: u64,
+    pub attestation: String,
+    pub exclude_credentials: Vec<CredentialDescriptor>,
+    pub authenticator_selection: AuthenticatorSelection,
+}
+
+impl CreationOptions {
+    pub fn new(
+        challenge: &PendingChallenge,
+        rp: RelyingParty,
+        user: UserEntity,
+        registered: &[Passkey],
+    ) -> CreationOptions {
+        CreationOptions {
+            challenge: challenge.challenge.clone(),
+            rp,
+            user,
+            pub_key_cred_params: [ALG_ES256, ALG_EDDSA, ALG_RS256]
+                .iter()
+                .map(|alg| CredentialParameters {
+                    kind: "public-key".to_string(),
+                    alg: *alg,
+                })
+                .collect(),
+            timeout: CHALLENGE_TIMEOUT as u64 * 1000,
+            attestation: "none".to_string(),
+            exclude_credentials: registered.iter().map(CredentialDescriptor::of).collect(),
+            authenticator_selection: AuthenticatorSelection {
+                resident_key: "preferred".to_string(),
+                user_verification: "preferred".to_string(),
+            },
+        }
+    }
+}
+
+/// `PublicKeyCredentialRequestOptions` for `navigator.credentials.get`. Without allowed
+/// credentials the authenticator offers its discoverable ones.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestOptions {
+    pub challenge: String,
+    pub rp_id: String,
+    pub timeout: u64,
+    pub allow_credentials: Vec<CredentialDescriptor>,
+    pub user_verification: String,
+}
+
+impl RequestOptions {
+    pub fn new(challenge: &PendingChallenge, rp_id: &str, allowed: &[Passkey]) -> RequestOptions {
+        RequestOptions {
+            challenge: challenge.challenge.clone(),
+            rp_id: rp_id.to_string(),
+            timeout: CHALLENGE_TIMEOUT as u64 * 1000,
+            allow_credentials: allowed.iter().map(CredentialDescriptor::of).collect(),
+            user_verification: "preferred".to_string(),
+        }
+    }
+}
+
+/// Body of `POST /api/realm/{realm}/login/passkey/options`, without a username any
+/// discoverable credential of the realm may answer.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Validate)]
+pub struct PasskeyLoginStart {
+    pub username: Option<String>,
+}
+
+impl ValidateRequest for PasskeyLoginStart {}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub attestation_object: String,
+}
+
+/// The JSON of the `PublicKeyCredential` returned by `navigator.credentials.create`, with an
+/// optional name for the passkey.
+#[derive(Serialize, Deserialize, Clone, Debug, Validate)]
+pub struct RegistrationCredential {
+    #[validate(length(min = 1, message = "must not be empty"))]
+    pub id: String,
+    pub response: AttestationResponse,
+    #[validate(length(max = 100, message = "must be at most 100 characters"))]
+    pub name: Option<String>,
+}
+
+impl ValidateRequest for RegistrationCredential {}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+    pub user_handle: Option<String>,
+}
+
+/// The JSON of the `PublicKeyCredential` returned by `navigator.credentials.get`.
+#[derive(Serialize, Deserialize, Clone, Debug, Validate)]
+pub struct AuthenticationCredential {
+    #[validate(length(min = 1, message = "must not be empty"))]
+    pub id: String,
+    pub response: AssertionResponse,
+}
+
+impl ValidateRequest for AuthenticationCredential {}
+
+/// The `clientDataJSON` of a response, which names the challenge it answers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientData {
+    pub kind: String,
+    pub challenge: String,
+    pub origin: String,
+    raw: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+struct RawClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+impl ClientData {
+    pub fn decode(client_data_json: &str) -> Result<ClientData, String> {
+        let raw = decode_base64url(client_data_json, "clientDataJSON")?;
+        let parsed: RawClientData =
+            serde_json::from_slice(&raw).map_err(|_| "clientDataJSON is not valid".to_string())?;
+        Ok(ClientData {
+            kind: parsed.kind,
+            challenge: parsed.challenge,
+            origin: parsed.origin,
+            raw,
+        })
+    }
+
+    fn check(&self, kind: &str, config: &WebAuthnConfig) -> Result<(), String> {
+        if self.kind != kind {
+            return Err(format!("expected a {} response", kind));
+        }
+        if !config.origins.contains(&self.origin) {
+            return Err(format!("origin {} is not allowed", self.origin));
+        }
+        Ok(())
+    }
+}
+
+/// A credential that passed [`verify_registration`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct VerifiedCredential {
+    pub credential_id: String,
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+}
+
+/// Checks the attestation of a new credential against the realm's relying party. The client data
+/// must already be matched to a pending registration challenge.
+pub fn verify_registration(
+    config: &WebAuthnConfig,
+    rp_id: &str,
+    client_data: &ClientData,
+    credential: &RegistrationCredential,
+) -> Result<VerifiedCredential, String> {
+    client_data.check("webauthn.create", config)?;
+    let object = decode_base64url(&credential.response.attestation_object, "attestationObject")?;
+    let (object, _) = cbor::decode(&object)?;
+    match object.get_text("fmt").and_then(cbor::Value::as_text) {
+        Some("none") => {}
+        Some(other) => return Err(format!("attestation format {} is not supported", other)),
+        None => return Err("attestationObject has no format".to_string()),
+    }
+    let auth_data = object
+        .get_text("authData")
+        .and_then(cbor::Value::as_bytes)
+        .ok_or("attestationObject has no authData")?;
+    let auth_data = AuthenticatorData::parse(auth_data)?;
+    auth_data.check(rp_id)?;
+
+    let attested = auth_data
+        .attested
+        .ok_or("authData has no attested credential")?;
+    if BASE64URL_NOPAD.encode(&attested.credential_id) != credential.id {
+        return Err("credential id does not match the attested one".to_string());
+    }
+    CoseKey::parse(&attested.public_key)?;
+    Ok(VerifiedCredential {
+        credential_id: credential.id.clone(),
+        public_key: attested.public_key,
+        sign_count: auth_data.sign_count,
+    })
+}
+
+/// Checks an assertion of `passkey` and returns its new signature counter. A counter that does
+/// not grow hints at a cloned authenticator, unless the authenticator keeps no counter at all.
+pub fn verify_assertion(
+    config: &WebAuthnConfig,
+    rp_id: &str,
+    client_data: &ClientData,
+    credential: &AuthenticationCredential,
+    passkey: &Passkey,
+) -> Result<u32, String> {
+    client_data.check("webauthn.get", config)?;
+    let raw_auth_data =
+        decode_base64url(&credential.response.authenticator_data, "authenticatorData")?;
+    let auth_data = AuthenticatorData::parse(&raw_auth_data)?;
+    auth_data.check(rp_id)?;
+
+    let signature = decode_base64url(&credential.response.signature, "signature")?;
+    let signed = [
+        raw_auth_data.as_slice(),
+        digest(&SHA256, &client_data.raw).as_ref(),
+    ]
+    .concat();
+    if !CoseKey::parse(&passkey.public_key)?.verify(&signed, &signature) {
+        return Err("signature does not match the passkey".to_string());
+    }
+
+    let counted = auth_data.sign_count != 0 || passkey.sign_count != 0;
+    if counted && auth_data.sign_count <= passkey.sign_count {
+        return Err("signature counter did not increase".to_string());
+    }
+    Ok(auth_data.sign_count)
+}
+
+fn decode_base64url(value: &str, field: &str) -> Result<Vec<u8>, String> {
+    BASE64URL_NOPAD
+        .decode(value.trim_end_matches('=').as_bytes())
+        .map_err(|_| format!("{} is not base64url", field))
+}
+
+struct AttestedCredential {
+    credential_id: Vec<u8>,
+    public_key: Vec<u8>,
+}
+
+struct AuthenticatorData {
+    rp_id_hash: Vec<u8>,
+    flags: u8,
+    sign_count: u32,
+    attested: Option<AttestedCredential>,
+}
+
+impl AuthenticatorData {
+    fn parse(data: &[u8]) -> Result<AuthenticatorData, String> {
+        let truncated = || "authData is truncated".to_string();
+        if data.len() < 37 {
+            return Err(truncated());
+        }
+        let flags = data[32];
+        let attested = match flags & FLAG_ATTESTED_CREDENTIAL {
+            0 => None,
+            _ => {
+                // After the 16 byte AAGUID come the id's length, the id and the COSE key.
+                let rest = data.get(55..).ok_or_else(truncated)?;
+                let id_len = u16::from_be_bytes([data[53], data[54]]) as usize;
+                let credential_id = rest.get(..id_len).ok_or_else(truncated)?;
+                let key = &rest[id_len..];
+                let (_, key_len) = cbor::decode(key)?;
+                Some(AttestedCredential {
+                    credential_id: credential_id.to_vec(),
+                    public_key: key[..key_len].to_vec(),
+                })
+            }
+        };
+        Ok(AuthenticatorData {
+            rp_id_hash: data[..32].to_vec(),
+            flags,
+            sign_count: u32::from_be_bytes([data[33], data[34], data[35], data[36]]),
+            attested,
+        })
+    }
+
+    fn check(&self, rp_id: &str) -> Result<(), String> {
+        if self.rp_id_hash != digest(&SHA256, rp_id.as_bytes()).as_ref() {
+            return Err("credential belongs to another relying party".to_string());
+        }
+        if self.flags & FLAG_USER_PRESENT == 0 {
+            return Err("user was not present".to_string());
+        }
+        Ok(())
+    }
+}
+
+enum CoseKey {
+    Es256 { point: Vec<u8> },
+    EdDsa { x: Vec<u8> },
+    Rs256 { n: Vec<u8>, e: Vec<u8> },
+}
+
+impl CoseKey {
+    fn parse(key: &[u8]) -> Result<CoseKey, String> {
+        let (key, _) = cbor::decode(key)?;
+        let bytes = |label: i64| {
+            key.get_int(label)
+                .and_then(cbor::Value::as_bytes)
+                .map(<[u8]>::to_vec)
+                .ok_or_else(|| format!("COSE key has no parameter {}", label))
+        };
+        let kty = key.get_int(1).and_then(cbor::Value::as_int);
+        let alg = key.get_int(3).and_then(cbor::Value::as_int);
+        let crv = key.get_int(-1).and_then(cbor::Value::as_int);
+        match (kty, alg) {
+            (Some(2), Some(ALG_ES256)) if crv == Some(1) => {
+                let (x, y) = (bytes(-2)?, bytes(-3)?);
+                if x.len() != 32 || y.len() != 32 {
+                    return Err("P-256 coordinates must be 32 bytes".to_string());
+                }
+                Ok(CoseKey::Es256 {
+                    point: [&[0x04], x.as_slice(), y.as_slice()].concat(),
+                })
+            }
+            (Some(1), Some(ALG_EDDSA)) if crv == Some(6) => Ok(CoseKey::EdDsa { x: bytes(-2)? }),
+            (Some(3), Some(ALG_RS256)) => Ok(CoseKey::Rs256 {
+                n: bytes(-1)?,
+                e: bytes(-2)?,
+            }),
+            _ => Err("public key algorithm is not supported".to_string()),
+        }
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        match self {
+            CoseKey::Es256 { point } => UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, point)
+                .verify(message, signature)
+                .is_ok(),
+            CoseKey::EdDsa { x } => UnparsedPublicKey::new(&ED25519, x)
+                .verify(message, signature)
+                .is_ok(),
+            CoseKey::Rs256 { n, e } => RsaPublicKeyComponents { n, e }
+                .verify(&RSA_PKCS1_2048_8192_SHA256, message, signature)
+                .is_ok(),
+        }
+    }
+}
+
+/// The subset of CBOR (RFC 8949) that authenticators emit: definite lengths, integers, byte and
+/// text strings, arrays, maps and simple values.
+mod cbor {
+    use std::convert::{TryFrom, TryInto};
+
+    const MAX_DEPTH: usize = 16;
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum Value {
+        Int(i64),
+        Bytes(Vec<u8>),
+        Text(String),
+        Array(Vec<Value>),
+        Map(Vec<(Value, Value)>),
+        Bool(bool),
+        Null,
+    }
+
+    impl Value {
+        fn get(&self, key: &Value) -> Option<&Value> {
+            match self {
+                Value::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+                _ => None,
+            }
+        }
+
+        pub fn get_text(&self, key: &str) -> Option<&Value> {
+            self.get(&Value::Text(key.to_string()))
+        }
+
+        pub fn get_int(&self, key: i64) -> Option<&Value> {
+            self.get(&Value::Int(key))
+        }
+
+        pub fn as_int(&self) -> Option<i64> {
+            match self {
+                Value::Int(value) => Some(*value),
+                _ => None,
+            }
+        }
+
+        pub fn as_bytes(&self) -> Option<&[u8]> {
+            match self {
+                Value::Bytes(value) => Some(value),
+                _ => None,
+            }
+        }
+
+        pub fn as_text(&self) -> Option<&str> {
+            match self {
+                Value::Text(value) => Some(value),
+                _ => None,
+            }
+        }
+    }
+
+    /// The first value of `input` and the number of bytes it took.
+    pub fn decode(input: &[u8]) -> Result<(Value, usize), String> {
+        let mut reader = Reader { input, pos: 0 };
+        let value = reader.value(0)?;
+        Ok((value, reader.pos))
+    }
+
+    struct Reader<'a> {
+        input: &'a [u8],
+        pos: usize,
+    }
+
+    impl<'a> Reader<'a> {
+        fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+            let end = self
+                .pos
+                .checked_add(len)
+                .filter(|end| *end <= self.input.len())
+                .ok_or("CBOR is truncated")?;
+            let bytes = &self.input[self.pos..end];
+            self.pos = end;
+            Ok(bytes)
+        }
+
+        fn head(&mut self) -> Result<(u8, u64), String> {
+            let initial = self.take(1)?[0];
+            let argument = match initial & 0x1f {
+                info @ 0..=23 => info as u64,
+                24 => self.take(1)?[0] as u64,
+                25 => u16::from_be_bytes(self.take(2)?.try_into().unwrap()) as u64,
+                26 => u32::from_be_bytes(self.take(4)?.try_into().unwrap()) as u64,
+                27 => u64::from_be_bytes(self.take(8)?.try_into().unwrap()),
+                _ => return Err("indefinite length CBOR is not supported".to_string()),
+            };
+            Ok((initial >> 5, argument))
+        }
+
+        /// A length or item count, which cannot exceed the remaining input.
+        fn length(&self, argument: u64) -> Result<usize, String> {
+            usize::try_from(argument)
+                .ok()
+                .filter(|len| *len <= self.input.len() - self.pos)
+                .ok_or_else(|| "CBOR is truncated".to_string())
+        }
+
+        fn value(&mut self, depth: usize) -> Result<Value, String> {
+            if depth > MAX_DEPTH {
+                return Err("CBOR is nested too deeply".to_string());
+            }
+            let out_of_range = |_| "CBOR integer is out of range".to_string();
+            let (major, argument) = self.head()?;
+            match major {
+                0 => i64::try_from(argument).map(Value::Int).map_err(out_of_range),
+                1 => i64::try_from(argument)
+                    .map(|n| Value::Int(-1 - n))
+                    .map_err(out_of_range),
+                2 => {
+                    let len = self.length(argument)?;
+                    Ok(Value::Bytes(self.take(len)?.to_vec()))
+                }
+                3 => {
+                    let len = self.length(argument)?;
+                    String::from_utf8(self.take(len)?.to_vec())
+                        .map(Value::Text)
+                        .map_err(|_| "CBOR text is not UTF-8".to_string())
+                }
+                4 => {
+                    let len = self.length(argument)?;
+                    (0..len)
+                        .map(|_| self.value(depth + 1))
+                        .collect::<Result<_, _>>()
+                        .map(Value::Array)
+                }
+                5 => {
+                    let len = self.length(argument)?;
+                    (0..len)
+                        .map(|_| Ok((self.value(depth + 1)?, self.value(depth + 1)?)))
+                        .collect::<Result<_, String>>()
+                        .map(Value::Map)
+                }
+                // Tags carry nothing the ceremonies need.
+                6 => self.value(depth + 1),
+                _ => match argument {
+                    20 => Ok(Value::Bool(false)),
+                    21 => Ok(Value::Bool(true)),
+                    22 | 23 => Ok(Value::Null),
+                    _ => Err("CBOR floats and simple values are not supported".to_string()),
+                },
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub fn encode(value: &Value) -> Vec<u8> {
+        fn head(major: u8, argument: u64, out: &mut Vec<u8>) {
+            match argument {
+                0..=23 => out.push(major << 5 | argument as u8),
+                24..=0xff => out.extend([major << 5 | 24, argument as u8]),
+                0x100..=0xffff => {
+                    out.push(major << 5 | 25);
+                    out.extend((argument as u16).to_be_bytes());
+                }
+                _ => {
+                    out.push(major << 5 | 26);
+                    out.extend((argument as u32).to_be_bytes());
+                }
+            }
+        }
+        fn write(value: &Value, out: &mut Vec<u8>) {
+            match value {
+                Value::Int(n) if *n >= 0 => head(0, *n as u64, out),
+                Value::Int(n) => head(1, (-1 - *n) as u64, out),
+                Value::Bytes(bytes) => {
+                    head(2, bytes.len() as u64, out);
+                    out.extend(bytes);
+                }
+                Value::Text(text) => {
+                    head(3, text.len() as u64, out);
+                    out.extend(text.as_bytes());
+                }
+                Value::Array(items) => {
+                    head(4, items.len() as u64, out);
+                    items.iter().for_each(|item| write(item, out));
+                }
+                Value::Map(entries) => {
+                    head(5, entries.len() as u64, out);
+                    for (key, value) in entries {
+                        write(key, out);
+                        write(value, out);
+                    }
+                }
+                Value::Bool(b) => head(7, if *b { 21 } else { 20 }, out),
+                Value::Null => head(7, 22, out),
+            }
+        }
+        let mut out = Vec::new();
+        write(value, &mut out);
+        out
+    }
+}
+
+/// A software authenticator with a P-256 key, for tests of the ceremonies.
+#[cfg(test)]
+pub struct SoftAuthenticator {
+    key_pair: ring::signature::EcdsaKeyPair,
+    pub credential_id: Vec<u8>,
+    pub rp_id: String,
+    pub origin: String,
+    pub sign_count: u32,
+}
+
+#[cfg(test)]
+impl SoftAuthenticator {
+    pub fn new(rp_id: &str, origin: &str) -> SoftAuthenticator {
+        use ring::signature::{EcdsaKeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        SoftAuthenticator {
+            key_pair: EcdsaKeyPair::from_pkcs8(
+                &ECDSA_P256_SHA256_ASN1_SIGNING,
+                pkcs8.as_ref(),
+                &rng,
+            )
+            .unwrap(),
+            credential_id: rand::random::<[u8; 16]>().to_vec(),
+            rp_id: rp_id.to_string(),
+            origin: origin.to_string(),
+            sign_count: 0,
+        }
+    }
+
+    fn client_data(&self, kind: &str, challenge: &str) -> Vec<u8> {
+        serde_json::json!({ "type": kind, "challenge": challenge, "origin": self.origin })
+            .to_string()
+            .into_bytes()
+    }
+
+    fn auth_data(&self, attested: Option<Vec<u8>>) -> Vec<u8> {
+        let flags = FLAG_USER_PRESENT
+            | match attested {
+                Some(_) => FLAG_ATTESTED_CREDENTIAL,
+                None => 0,
+            };
+        [
+            digest(&SHA256, self.rp_id.as_bytes()).as_ref(),
+            &[flags],
+            &self.sign_count.to_be_bytes(),
+            &attested.unwrap_or_default(),
+        ]
+        .concat()
+    }
+
+    pub fn register(&mut self, challenge: &str) -> RegistrationCredential {
+        use cbor::Value;
+        use ring::signature::KeyPair;
+        let point = self.key_pair.public_key().as_ref();
+        let cose_key = cbor::encode(&Value::Map(vec![
+            (Value::Int(1), Value::Int(2)),
+            (Value::Int(3), Value::Int(ALG_ES256)),
+            (Value::Int(-1), Value::Int(1)),
+            (Value::Int(-2), Value::Bytes(point[1..33].to_vec())),
+            (Value::Int(-3), Value::Bytes(point[33..].to_vec())),
+        ]));
+        let attested = [
+            &[0u8; 16][..],
+            &(self.credential_id.len() as u16).to_be_bytes(),
+            &self.credential_id,
+            &cose_key,
+        ]
+        .concat();
+        let object = cbor::encode(&Value::Map(vec![
+            (Value::Text("fmt".into()), Value::Text("none".into())),
+            (Value::Text("attStmt".into()), Value::Map(vec![])),
+            (
+                Value::Text("authData".into()),
+                Value::Bytes(self.auth_data(Some(attested))),
+            ),
+        ]));
+        RegistrationCredential {
+            id: BASE64URL_NOPAD.encode(&self.credential_id),
+            response: AttestationResponse {
+                client_data_json: BASE64URL_NOPAD
+                    .encode(&self.client_data("webauthn.create", challenge)),
+                attestation_object: BASE64URL_NOPAD.encode(&object),
+            },
+            name: Some("Soft key".to_string()),
+        }
+    }
+
+    pub fn authenticate(&mut self, challenge: &str) -> AuthenticationCredential {
+        self.sign_count += 1;
+        let client_data = self.client_data("webauthn.get", challenge);
+        let auth_data = self.auth_data(None);
+        let signed = [
+            auth_data.as_slice(),
+            digest(&SHA256, &client_data).as_ref(),
+        ]
+        .concat();
+        let rng = ring::rand::SystemRandom::new();
+        let signature = self.key_pair.sign(&rng, &signed).unwrap();
+        AuthenticationCredential {
+            id: BASE64URL_NOPAD.encode(&self.credential_id),
+            response: AssertionResponse {
+                client_data_json: BASE64URL_NOPAD.encode(&client_data),
+                authenticator_data: BASE64URL_NOPAD.encode(&auth_data),
+                signature: BASE64URL_NOPAD.encode(signature.as_ref()),
+                user_handle: None,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::webauthn::{
+        cbor, verify_assertion, verify_registration, Ceremony, ClientData, Passkey,
+        PendingChallenge, SoftAuthenticator, WebAuthnConfig,
+    };
+
+    #[test]
+    fn test_cbor() {
+        let value = cbor::Value::Map(vec![
+            (cbor::Value::Int(-257), cbor::Value::Bytes(vec![1; 300])),
+            (
+                cbor::Value::Text("list".into()),
+                cbor::Value::Array(vec![cbor::Value::Bool(true), cbor::Value::Null]),
+            ),
+        ]);
+        let mut encoded = cbor::encode(&value);
+        let len = encoded.len();
+        encoded.push(0xff);
+        assert_eq!(cbor::decode(&encoded), Ok((value, len)));
+
+        assert!(cbor::decode(&encoded[..len - 1]).is_err());
+        // A byte string claiming more bytes than there are.
+        assert!(cbor::decode(&[0x5b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]).is_err());
+        assert!(cbor::decode(&[0x81; 64]).is_err());
+    }
+
+    #[test]
+    fn test_ceremonies() {
+        let config = WebAuthnConfig {
+            rp_id: Some("example.com".to_string()),
+            origins: vec!["https://example.com".to_string()],
+        };
+        let mut authenticator = SoftAuthenticator::new("example.com", "https://example.com");
+        let challenge = PendingChallenge::new(Ceremony::Registration, None, 0);
+
+        let credential = authenticator.register(&challenge.challenge);
+        let client_data = ClientData::decode(&credential.response.client_data_json).unwrap();
+        assert_eq!(client_data.challenge, challenge.challenge);
+        let verified =
+            verify_registration(&config, "example.com", &client_data, &credential).unwrap();
+        assert_eq!(verified.credential_id, credential.id);
+        assert!(verify_registration(&config, "other.com", &client_data, &credential).is_err());
+
+        let mut passkey = Passkey {
+            credential_id: verified.credential_id,
+            user_id: "user".to_string(),
+            public_key: verified.public_key,
+            sign_count: verified.sign_count,
+            name: None,
+            created_at: 0,
+            last_used_at: None,
+        };
+        let assertion = authenticator.authenticate("challenge");
+        let client_data = ClientData::decode(&assertion.response.client_data_json).unwrap();
+        let count = verify_assertion(&config, "example.com", &client_data, &assertion, &passkey);
+        assert_eq!(count, Ok(1));
+
+        // Replaying an assertion does not move the counter.
+        passkey.sign_count = 1;
+        let replayed = verify_assertion(&config, "example.com", &client_data, &assertion, &passkey);
+        assert!(replayed.is_err());
+
+        let mut forged = authenticator.authenticate("challenge");
+        forged.response.signature = authenticator
+            .authenticate("other")
+            .response
+            .signature;
+        let client_data = ClientData::decode(&forged.response.client_data_json).unwrap();
+        assert!(verify_assertion(&config, "example.com", &client_data, &forged, &passkey).is_err());
+
+        let strange_origin = WebAuthnConfig {
+            origins: vec!["https://evil.com".to_string()],
+            ..config
+        };
+        let assertion = authenticator.authenticate("challenge");
+        let client_data = ClientData::decode(&assertion.response.client_data_json).unwrap();
+        let result = verify_assertion(
+            &strange_origin,
+            "example.com",
+            &client_data,
+            &assertion,
+            &passkey,
+        );
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,1527 @@
+use crate::domain::audit::{AuditChain, AuditEvent, AuditQuery, AuditRecord, ChainLink};
+use crate::domain::customer::dto::{
+    AddressData, CreateUser, CursorKey, PageCursor, SortOrder, UserMetadata, UserQuery, UserSort,
+};
+use crate::domain::customer::{Role, User, UserAddress, UserProfile, UserStatus};
+use crate::domain::lockout::{AttemptKey, LockoutPolicy, LoginAttempts};
+use crate::domain::mfa::{MfaPolicy, RecoveryCode, TotpEnrollment};
+use crate::domain::outbox::{OutboxEvent, OutboxRecord};
+use crate::domain::passwordless::{PasswordlessCredential, PasswordlessPolicy};
+use crate::domain::rate_limit::{RateLimits, TokenBucket};
+use crate::domain::realm::{InternalRealmSettings, PasswordPolicy, RealmName};
+use crate::domain::session::Session;
+use crate::domain::webauthn::{Passkey, PendingChallenge, WebAuthnConfig};
+use crate::domain::webhook::{DeliveryStatus, WebhookDelivery, WebhookSubscription};
+use crate::repository::{
+    AddressRepository, AuditStore, LoginAttemptStore, MfaStore, OutboxStore, PasskeyStore,
+    PasswordHistoryStore, PasswordlessStore, RateLimitBucketStore, RealmStore, Repository,
+    SessionStore, Storage, StorageError, StorageResult, StorageTx, TokenKind, TokenStore,
+    UserRepository, WebhookStore,
+};
+use chrono::Utc;
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+#[derive(Clone, Debug)]
+struct UserRecord {
+    realm: RealmName,
+    user: User,
+    name: Option<String>,
+    email: Option<String>,
+    email_verified: bool,
+    pending_email: Option<String>,
+    created_at: i64,
+    deleted_at: Option<i64>,
+}
+
+impl UserRecord {
+    fn is_active_in(&self, realm: &RealmName) -> bool {
+        &self.realm == realm && self.deleted_at.is_none()
+    }
+
+    fn matches(&self, realm: &RealmName, query: &UserQuery) -> bool {
+        let status = match self.deleted_at {
+            Some(_) => UserStatus::Disabled,
+            None => UserStatus::Active,
+        };
+        &self.realm == realm
+            && status == query.status
+            && query
+                .role
+                .as_ref()
+                .is_none_or(|role| role == &self.user.role)
+            && query
+                .email
+                .as_ref()
+                .is_none_or(|email| self.email.as_ref() == Some(email))
+            && query
+                .username_prefix
+                .as_ref()
+                .is_none_or(|prefix| self.user.username.starts_with(prefix.as_str()))
+            && query
+                .created_after
+                .is_none_or(|after| self.created_at >= after)
+            && query
+                .created_before
+                .is_none_or(|before| self.created_at < before)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct AddressRecord {
+    user_id: String,
+    address: UserAddress,
+}
+
+#[derive(Clone, Default)]
+struct MemoryState {
+    realms: Vec<(RealmName, InternalRealmSettings)>,
+    users: Vec<UserRecord>,
+    addresses: Vec<AddressRecord>,
+    tokens: HashMap<(String, TokenKind), String>,
+    /// `(user_id, hash)`, oldest first.
+    password_history: Vec<(String, String)>,
+    login_attempts: HashMap<(RealmName, AttemptKey), LoginAttempts>,
+    rate_limit_buckets: HashMap<(RealmName, String), TokenBucket>,
+    totp: HashMap<String, TotpEnrollment>,
+    recovery_codes: HashMap<String, Vec<RecoveryCode>>,
+    passkeys: Vec<Passkey>,
+    webauthn_challenges: Vec<(RealmName, PendingChallenge)>,
+    passwordless: HashMap<String, PasswordlessCredential>,
+    sessions: Vec<Session>,
+    /// Oldest first, ids taken from `audit_sequence`.
+    audit_events: Vec<AuditRecord>,
+    audit_sequence: i64,
+    audit_chains: HashMap<RealmName, AuditChain>,
+    /// Oldest first.
+    webhook_subscriptions: Vec<WebhookSubscription>,
+    /// Oldest first.
+    webhook_deliveries: Vec<WebhookDelivery>,
+    /// Oldest first, ids taken from `outbox_sequence`.
+    outbox: Vec<OutboxRecord>,
+    outbox_sequence: i64,
+}
+
+/// Keeps everything in process memory. Meant for tests and local demos, nothing survives a restart.
+pub struct InMemoryStorage {
+    state: Mutex<MemoryState>,
+}
+
+impl InMemoryStorage {
+    pub fn new() -> InMemoryStorage {
+        InMemoryStorage {
+            state: Mutex::new(MemoryState::default()),
+        }
+    }
+
+    /// Storage seeded with the default realms.
+    pub fn with_default_realms() -> InMemoryStorage {
+        let storage = InMemoryStorage::new();
+        for realm in ["rj.fg", "rj.wire", "rj.haven", "rj.fa"] {
+            storage.add_realm(realm.to_string(), default_realm_settings());
+        }
+        storage
+    }
+
+    pub fn add_realm(&self, realm: RealmName, settings: InternalRealmSettings) {
+        let mut state = self.state.lock().unwrap();
+        state.realms.retain(|(name, _)| name != &realm);
+        state.realms.push((realm, settings));
+    }
+}
+
+impl Default for InMemoryStorage {
+    fn default() -> Self {
+        InMemoryStorage::new()
+    }
+}
+
+pub fn default_realm_settings() -> InternalRealmSettings {
+    InternalRealmSettings {
+        is_confirmation_required: false,
+        is_guest_allowed: false,
+        realm_salt_itr: 10000,
+        authentication_token_duration: Duration::new(120, 0),
+        refresh_token_duration: Duration::new(60, 0),
+        password_reset_token_duration: Duration::new(30, 0),
+        password_policy: PasswordPolicy::default(),
+        lockout_policy: LockoutPolicy::default(),
+        rate_limits: RateLimits::default(),
+        mfa_policy: MfaPolicy::default(),
+        webauthn: WebAuthnConfig::default(),
+        passwordless_policy: PasswordlessPolicy::default(),
+    }
+}
+
+impl Storage for InMemoryStorage {
+    fn begin(&self) -> StorageResult<Box<dyn StorageTx + '_>> {
+        let state = self
+            .state
+            .lock()
+            .map_err(|e| StorageError::Backend(e.to_string()))?;
+        let snapshot = state.clone();
+        Ok(Box::new(MemoryTx { state, snapshot }))
+    }
+}
+
+/// Holds the storage lock for the whole transaction; `snapshot` is restored on rollback.
+pub struct MemoryTx<'a> {
+    state: MutexGuard<'a, MemoryState>,
+    snapshot: MemoryState,
+}
+
+impl<'a> StorageTx for MemoryTx<'a> {
+    fn users(&mut self) -> Box<dyn UserRepository + '_> {
+        Box::new(UserStorage {
+            state: &mut self.state,
+        })
+    }
+
+    fn addresses(&mut self) -> Box<dyn AddressRepository + '_> {
+        Box::new(AddressStorage {
+            state: &mut self.state,
+        })
+    }
+
+    fn commit(self: Box<Self>) -> StorageResult<()> {
+        Ok(())
+    }
+
+    fn rollback(self: Box<Self>) -> StorageResult<()> {
+        let mut tx = *self;
+        *tx.state = tx.snapshot;
+        Ok(())
+    }
+}
+
+impl MemoryState {
+    fn user(&self, realm: &RealmName, user_id: &str) -> Option<&UserRecord> {
+        self.users
+            .iter()
+            .find(|r| r.is_active_in(realm) && r.user.user_id == user_id)
+    }
+
+    fn profile(&self, record: &UserRecord) -> UserProfile {
+        UserProfile {
+            user_id: record.user.user_id.clone(),
+            username: record.user.username.clone(),
+            name: record.name.clone(),
+            email: record.email.clone(),
+            email_verified: record.email_verified,
+            role: record.user.role.clone(),
+            addresses: self.user_addresses(&record.user.user_id),
+            created_at: record.created_at,
+            status: match record.deleted_at {
+                Some(_) => UserStatus::Disabled,
+                None => UserStatus::Active,
+            },
+        }
+    }
+
+    /// Whether an active user other than `except_user` has `email`, disabled users release it.
+    fn email_taken(&self, realm: &RealmName, email: &str, except_user: Option<&String>) -> bool {
+        self.users.iter().any(|r| {
+            r.is_active_in(realm)
+                && r.email.as_deref() == Some(email)
+                && Some(&r.user.user_id) != except_user
+        })
+    }
+
+    fn address(&self, realm: &RealmName, address_id: &str) -> Option<&AddressRecord> {
+        self.addresses
+            .iter()
+            .find(|a| a.address.address_id == address_id && self.user(realm, &a.user_id).is_some())
+    }
+
+    /// Addresses of a user, grouped by type with the default first.
+    fn user_addresses(&self, user_id: &str) -> Vec<UserAddress> {
+        let mut addresses: Vec<UserAddress> = self
+            .addresses
+            .iter()
+            .filter(|a| a.user_id == user_id)
+            .map(|a| a.address.clone())
+            .collect();
+        addresses.sort_by_key(|a| {
+            (
+                a.address_type.to_string(),
+                !a.is_default,
+                a.address_id.clone(),
+            )
+        });
+        addresses
+    }
+
+    /// Makes `address` the only default address of its type.
+    fn clear_other_defaults(&mut self, user_id: &str, address: &UserAddress) {
+        for record in self.addresses.iter_mut() {
+            if record.user_id == user_id
+                && record.address.address_type == address.address_type
+                && record.address.address_id != address.address_id
+            {
+                record.address.is_default = false;
+            }
+        }
+    }
+}
+
+pub struct UserStorage<'t> {
+    state: &'t mut MemoryState,
+}
+
+pub struct AddressStorage<'t> {
+    state: &'t mut MemoryState,
+}
+
+impl<'t> Repository<User> for UserStorage<'t> {
+    type ID = String;
+    type CreationData = CreateUser;
+    type UpdateData = UserMetadata;
+
+    fn get(&mut self, realm: &RealmName, id: &Self::ID) -> StorageResult<Option<User>> {
+        Ok(self.state.user(realm, id).map(|r| r.user.clone()))
+    }
+
+    fn list(&mut self, realm: &RealmName) -> StorageResult<Vec<User>> {
+        Ok(self
+            .state
+            .users
+            .iter()
+            .filter(|r| r.is_active_in(realm))
+            .map(|r| r.user.clone())
+            .collect())
+    }
+
+    fn create(&mut self, realm: &RealmName, data: Self::CreationData) -> StorageResult<Self::ID> {
+        if self
+            .state
+            .users
+            .iter()
+            .any(|r| &r.realm == realm && r.user.username == data.username)
+        {
+            return Err(StorageError::Conflict(format!(
+                "username {} already exists in realm {}",
+                data.username, realm
+            )));
+        }
+        if self.state.email_taken(realm, &data.email, None) {
+            return Err(StorageError::Conflict(format!(
+                "email {} already exists in realm {}",
+                data.email, realm
+            )));
+        }
+
+        let user_id = Uuid::new_v4().to_string();
+        self.state.users.push(UserRecord {
+            realm: realm.clone(),
+            user: User {
+                user_id: user_id.clone(),
+                username: data.username,
+                hashed_pass: data.password,
+                role: Role::CUSTOMER,
+            },
+            name: Some(data.name),
+            email: Some(data.email),
+            email_verified: false,
+            pending_email: None,
+            created_at: Utc::now().timestamp(),
+            deleted_at: None,
+        });
+        Ok(user_id)
+    }
+
+    fn update(
+        &mut self,
+        realm: &RealmName,
+        id: &Self::ID,
+        data: Self::UpdateData,
+    ) -> StorageResult<()> {
+        if self.state.user(realm, id).is_none() {
+            return Err(StorageError::NotFound);
+        }
+        if let Some(email) = &data.email {
+            if self.state.email_taken(realm, email, Some(id)) {
+                return Err(StorageError::Conflict(format!(
+                    "email {} already exists in realm {}",
+                    email, realm
+                )));
+            }
+        }
+
+        let record = self
+            .state
+            .users
+            .iter_mut()
+            .find(|r| &r.user.user_id == id)
+            .ok_or(StorageError::NotFound)?;
+        if let Some(name) = data.name {
+            record.name = Some(name);
+        }
+        if let Some(email) = data.email {
+            record.email = Some(email);
+        }
+        if let Some(email_verified) = data.email_verified {
+            record.email_verified = email_verified;
+        }
+        if let Some(role) = data.role {
+            record.user.role = role;
+        }
+        if let Some(password) = data.password {
+            record.user.hashed_pass = password;
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self, realm: &RealmName, id: &Self::ID) -> StorageResult<()> {
+        if !self
+            .state
+            .users
+            .iter()
+            .any(|r| &r.realm == realm && &r.user.user_id == id)
+        {
+            return Err(StorageError::NotFound);
+        }
+
+        let state = &mut self.state;
+        state.users.retain(|r| &r.user.user_id != id);
+        state.addresses.retain(|a| &a.user_id != id);
+        state.tokens.retain(|(owner, _), _| owner != id);
+        state.password_history.retain(|(owner, _)| owner != id);
+        state.totp.remove(id);
+        state.recovery_codes.remove(id);
+        state.passkeys.retain(|passkey| &passkey.user_id != id);
+        state.passwordless.remove(id);
+        state.sessions.retain(|session| &session.user_id != id);
+        Ok(())
+    }
+}
+
+impl<'t> UserRepository for UserStorage<'t> {
+    fn disable(&mut self, realm: &RealmName, id: &str, deleted_at: i64) -> StorageResult<()> {
+        let record = self
+            .state
+            .users
+            .iter_mut()
+            .find(|r| r.is_active_in(realm) && r.user.user_id == id)
+            .ok_or(StorageError::NotFound)?;
+        record.deleted_at = Some(deleted_at);
+        record.pending_email = None;
+        self.state.tokens.retain(|(owner, _), _| owner != id);
+        Ok(())
+    }
+
+    fn get_profile(&mut self, realm: &RealmName, id: &str) -> StorageResult<Option<UserProfile>> {
+        let state = &self.state;
+        Ok(state.user(realm, id).map(|r| state.profile(r)))
+    }
+
+    fn get_by_name(&mut self, realm: &RealmName, username: &str) -> StorageResult<Option<User>> {
+        Ok(self
+            .state
+            .users
+            .iter()
+            .find(|r| r.user.username == username && r.is_active_in(realm))
+            .map(|r| r.user.clone()))
+    }
+
+    fn search(
+        &mut self,
+        realm: &RealmName,
+        query: &UserQuery,
+        after: Option<&PageCursor>,
+        limit: u32,
+    ) -> StorageResult<Vec<UserProfile>> {
+        let state = &self.state;
+        let mut users: Vec<UserProfile> = state
+            .users
+            .iter()
+            .filter(|r| r.matches(realm, query))
+            .map(|r| state.profile(r))
+            .collect();
+
+        let key = |user: &UserProfile| match query.sort {
+            UserSort::Username => CursorKey::Text(user.username.clone()),
+            UserSort::CreatedAt => CursorKey::Number(user.created_at),
+        };
+        let position = |user: &UserProfile| (key(user), user.user_id.clone());
+        users.sort_by_key(position);
+        if query.order == SortOrder::Desc {
+            users.reverse();
+        }
+        if let Some(cursor) = after {
+            let cursor_position = (cursor.key.clone(), cursor.user_id.clone());
+            users.retain(|user| match query.order {
+                SortOrder::Asc => position(user) > cursor_position,
+                SortOrder::Desc => position(user) < cursor_position,
+            });
+        }
+        users.truncate(limit as usize);
+        Ok(users)
+    }
+
+    fn count(&mut self, realm: &RealmName, query: &UserQuery) -> StorageResult<u64> {
+        Ok(self
+            .state
+            .users
+            .iter()
+            .filter(|r| r.matches(realm, query))
+            .count() as u64)
+    }
+}
+
+impl<'t> Repository<UserAddress> for AddressStorage<'t> {
+    type ID = String;
+    type CreationData = (AddressData, String);
+    type UpdateData = AddressData;
+
+    fn get(&mut self, realm: &RealmName, id: &Self::ID) -> StorageResult<Option<UserAddress>> {
+        Ok(self.state.address(realm, id).map(|a| a.address.clone()))
+    }
+
+    fn list(&mut self, realm: &RealmName) -> StorageResult<Vec<UserAddress>> {
+        let state = &self.state;
+        Ok(state
+            .addresses
+            .iter()
+            .filter(|a| state.user(realm, &a.user_id).is_some())
+            .map(|a| a.address.clone())
+            .collect())
+    }
+
+    fn create(&mut self, realm: &RealmName, data: Self::CreationData) -> StorageResult<Self::ID> {
+        let (data, user_id) = data;
+        if self.state.user(realm, &user_id).is_none() {
+            return Err(StorageError::NotFound);
+        }
+
+        let address = UserAddress {
+            address_id: Uuid::new_v4().to_string(),
+            address_type: data.address_type,
+            is_default: data.is_default,
+            address: data.address,
+        };
+        if address.is_default {
+            self.state.clear_other_defaults(&user_id, &address);
+        }
+        let address_id = address.address_id.clone();
+        self.state
+            .addresses
+            .push(AddressRecord { user_id, address });
+        Ok(address_id)
+    }
+
+    fn update(
+        &mut self,
+        realm: &RealmName,
+        id: &Self::ID,
+        data: Self::UpdateData,
+    ) -> StorageResult<()> {
+        let user_id = self
+            .state
+            .address(realm, id)
+            .map(|a| a.user_id.clone())
+            .ok_or(StorageError::NotFound)?;
+
+        let address = UserAddress {
+            address_id: id.clone(),
+            address_type: data.address_type,
+            is_default: data.is_default,
+            address: data.address,
+        };
+        if address.is_default {
+            self.state.clear_other_defaults(&user_id, &address);
+        }
+        for record in self.state.addresses.iter_mut() {
+            if &record.address.address_id == id {
+                record.address = address.clone();
+            }
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self, realm: &RealmName, id: &Self::ID) -> StorageResult<()> {
+        if self.state.address(realm, id).is_none() {
+            return Err(StorageError::NotFound);
+        }
+
+        self.state.addresses.retain(|a| &a.address.address_id != id);
+        Ok(())
+    }
+}
+
+impl<'t> AddressRepository for AddressStorage<'t> {
+    fn list_for_user(
+        &mut self,
+        realm: &RealmName,
+        user_id: &str,
+    ) -> StorageResult<Vec<UserAddress>> {
+        Ok(match self.state.user(realm, user_id) {
+            Some(_) => self.state.user_addresses(user_id),
+            None => Vec::new(),
+        })
+    }
+}
+
+impl<'a> RealmStore for MemoryTx<'a> {
+    fn get_realm_settings(
+        &mut self,
+        realm: &RealmName,
+    ) -> StorageResult<Option<InternalRealmSettings>> {
+        Ok(self
+            .state
+            .realms
+            .iter()
+            .find(|(name, _)| name == realm)
+            .map(|(_, settings)| settings.clone()))
+    }
+
+    fn list_realm_settings(&mut self) -> StorageResult<Vec<(RealmName, InternalRealmSettings)>> {
+        Ok(self.state.realms.clone())
+    }
+}
+
+impl<'a> TokenStore for MemoryTx<'a> {
+    fn store_token(&mut self, user_id: &str, kind: TokenKind, token: &str) -> StorageResult<()> {
+        self.state
+            .tokens
+            .insert((user_id.to_string(), kind), token.to_string());
+        Ok(())
+    }
+
+    fn find_token(&mut self, user_id: &str, kind: TokenKind) -> StorageResult<Option<String>> {
+        Ok(self.state.tokens.get(&(user_id.to_string(), kind)).cloned())
+    }
+
+    fn revoke_token(&mut self, user_id: &str, kind: TokenKind) -> StorageResult<()> {
+        self.state.tokens.remove(&(user_id.to_string(), kind));
+        Ok(())
+    }
+
+    fn consume_token(
+        &mut self,
+        user_id: &str,
+        kind: TokenKind,
+        token: &str,
+    ) -> StorageResult<bool> {
+        let key = (user_id.to_string(), kind);
+        let matches = self
+            .state
+            .tokens
+            .get(&key)
+            .is_some_and(|stored| bool::from(stored.as_bytes().ct_eq(token.as_bytes())));
+        if matches {
+            self.state.tokens.remove(&key);
+        }
+        Ok(matches)
+    }
+
+    fn stage_email(&mut self, user_id: &str, email: Option<&str>) -> StorageResult<()> {
+        if let Some(record) = self
+            .state
+            .users
+            .iter_mut()
+            .find(|r| r.user.user_id == user_id)
+        {
+            record.pending_email = email.map(str::to_string);
+        }
+        Ok(())
+    }
+
+    fn staged_email(&mut self, user_id: &str) -> StorageResult<Option<String>> {
+        Ok(self
+            .state
+            .users
+            .iter()
+            .find(|r| r.user.user_id == user_id)
+            .and_then(|r| r.pending_email.clone()))
+    }
+}
+
+impl<'a> PasswordHistoryStore for MemoryTx<'a> {
+    fn password_history(&mut self, user_id: &str, limit: usize) -> StorageResult<Vec<String>> {
+        Ok(self
+            .state
+            .password_history
+            .iter()
+            .rev()
+            .filter(|(owner, _)| owner == user_id)
+            .take(limit)
+            .map(|(_, hash)| hash.clone())
+            .collect())
+    }
+
+    fn push_password_history(
+        &mut self,
+        user_id: &str,
+        hash: &str,
+        keep: usize,
+    ) -> StorageResult<()> {
+        let history = &mut self.state.password_history;
+        history.push((user_id.to_string(), hash.to_string()));
+        let mut newer = history.iter().filter(|(owner, _)| owner == user_id).count();
+        history.retain(|(owner, _)| {
+            if owner != user_id {
+                return true;
+            }
+            newer -= 1;
+            newer < keep
+        });
+        Ok(())
+    }
+}
+
+impl<'a> LoginAttemptStore for MemoryTx<'a> {
+    fn get_attempts(
+        &mut self,
+        realm: &RealmName,
+        key: &AttemptKey,
+    ) -> StorageResult<Option<LoginAttempts>> {
+        Ok(self
+            .state
+            .login_attempts
+            .get(&(realm.clone(), key.clone()))
+            .cloned())
+    }
+
+    fn put_attempts(
+        &mut self,
+        realm: &RealmName,
+        key: &AttemptKey,
+        attempts: &LoginAttempts,
+    ) -> StorageResult<()> {
+        self.state
+            .login_attempts
+            .insert((realm.clone(), key.clone()), attempts.clone());
+        Ok(())
+    }
+
+    fn clear_attempts(&mut self, realm: &RealmName, key: &AttemptKey) -> StorageResult<()> {
+        self.state
+            .login_attempts
+            .remove(&(realm.clone(), key.clone()));
+        Ok(())
+    }
+}
+
+impl<'a> RateLimitBucketStore for MemoryTx<'a> {
+    fn get_bucket(&mut self, realm: &RealmName, key: &str) -> StorageResult<Option<TokenBucket>> {
+        Ok(self
+            .state
+            .rate_limit_buckets
+            .get(&(realm.clone(), key.to_string()))
+            .cloned())
+    }
+
+    fn put_bucket(
+        &mut self,
+        realm: &RealmName,
+        key: &str,
+        bucket: &TokenBucket,
+    ) -> StorageResult<()> {
+        self.state
+            .rate_limit_buckets
+            .insert((realm.clone(), key.to_string()), bucket.clone());
+        Ok(())
+    }
+
+    fn delete_idle_buckets(&mut self, updated_before: i64) -> StorageResult<()> {
+        self.state
+            .rate_limit_buckets
+            .retain(|_, bucket| bucket.updated_at >= updated_before);
+        Ok(())
+    }
+}
+
+impl<'a> MfaStore for MemoryTx<'a> {
+    fn get_totp(&mut self, user_id: &str) -> StorageResult<Option<TotpEnrollment>> {
+        Ok(self.state.totp.get(user_id).cloned())
+    }
+
+    fn put_totp(&mut self, user_id: &str, enrollment: &TotpEnrollment) -> StorageResult<()> {
+        self.state
+            .totp
+            .insert(user_id.to_string(), enrollment.clone());
+        Ok(())
+    }
+
+    fn delete_totp(&mut self, user_id: &str) -> StorageResult<()> {
+        self.state.totp.remove(user_id);
+        Ok(())
+    }
+
+    fn use_totp_step(&mut self, user_id: &str, step: i64) -> StorageResult<bool> {
+        let usable = self.state.totp.get_mut(user_id).filter(|totp| {
+            totp.activated_at.is_some() && totp.last_used_step.is_none_or(|last| last < step)
+        });
+        match usable {
+            Some(totp) => {
+                totp.last_used_step = Some(step);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn replace_recovery_codes(
+        &mut self,
+        user_id: &str,
+        codes: &[RecoveryCode],
+    ) -> StorageResult<()> {
+        self.state
+            .recovery_codes
+            .insert(user_id.to_string(), codes.to_vec());
+        Ok(())
+    }
+
+    fn list_recovery_codes(&mut self, user_id: &str) -> StorageResult<Vec<RecoveryCode>> {
+        Ok(self
+            .state
+            .recovery_codes
+            .get(user_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn use_recovery_code(
+        &mut self,
+        user_id: &str,
+        code_hash: &str,
+        used_at: i64,
+        used_ip: Option<&str>,
+    ) -> StorageResult<bool> {
+        let unused = self
+            .state
+            .recovery_codes
+            .get_mut(user_id)
+            .and_then(|codes| {
+                codes
+                    .iter_mut()
+                    .find(|code| code.code_hash == code_hash && code.used_at.is_none())
+            });
+        match unused {
+            Some(code) => {
+                code.used_at = Some(used_at);
+                code.used_ip = used_ip.map(str::to_string);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+impl<'a> PasskeyStore for MemoryTx<'a> {
+    fn put_challenge(
+        &mut self,
+        realm: &RealmName,
+        challenge: &PendingChallenge,
+        now: i64,
+    ) -> StorageResult<()> {
+        let challenges = &mut self.state.webauthn_challenges;
+        challenges.retain(|(name, pending)| name != realm || pending.expires_at >= now);
+        challenges.push((realm.clone(), challenge.clone()));
+        Ok(())
+    }
+
+    fn take_challenge(
+        &mut self,
+        realm: &RealmName,
+        challenge: &str,
+    ) -> StorageResult<Option<PendingChallenge>> {
+        let challenges = &mut self.state.webauthn_challenges;
+        let position = challenges
+            .iter()
+            .position(|(name, pending)| name == realm && pending.challenge == challenge);
+        Ok(position.map(|position| challenges.remove(position).1))
+    }
+
+    fn add_passkey(&mut self, passkey: &Passkey) -> StorageResult<()> {
+        let passkeys = &mut self.state.passkeys;
+        if passkeys
+            .iter()
+            .any(|stored| stored.credential_id == passkey.credential_id)
+        {
+            return Err(StorageError::Conflict(
+                "passkey is already registered".to_string(),
+            ));
+        }
+        passkeys.push(passkey.clone());
+        Ok(())
+    }
+
+    fn find_passkey(
+        &mut self,
+        realm: &RealmName,
+        credential_id: &str,
+    ) -> StorageResult<Option<Passkey>> {
+        let state = &self.state;
+        Ok(state
+            .passkeys
+            .iter()
+            .find(|passkey| {
+                passkey.credential_id == credential_id
+                    && state
+                        .users
+                        .iter()
+                        .any(|r| r.user.user_id == passkey.user_id && r.is_active_in(realm))
+            })
+            .cloned())
+    }
+
+    fn list_passkeys(&mut self, user_id: &str) -> StorageResult<Vec<Passkey>> {
+        Ok(self
+            .state
+            .passkeys
+            .iter()
+            .filter(|passkey| passkey.user_id == user_id)
+            .cloned()
+            .collect())
+    }
+
+    fn record_passkey_use(
+        &mut self,
+        credential_id: &str,
+        sign_count: u32,
+        used_at: i64,
+    ) -> StorageResult<()> {
+        if let Some(passkey) = self
+            .state
+            .passkeys
+            .iter_mut()
+            .find(|passkey| passkey.credential_id == credential_id)
+        {
+            passkey.sign_count = sign_count;
+            passkey.last_used_at = Some(used_at);
+        }
+        Ok(())
+    }
+
+    fn delete_passkey(&mut self, user_id: &str, credential_id: &str) -> StorageResult<()> {
+        let passkeys = &mut self.state.passkeys;
+        let before = passkeys.len();
+        passkeys
+            .retain(|passkey| passkey.user_id != user_id || passkey.credential_id != credential_id);
+        match passkeys.len() < before {
+            true => Ok(()),
+            false => Err(StorageError::NotFound),
+        }
+    }
+}
+
+impl<'a> PasswordlessStore for MemoryTx<'a> {
+    fn get_passwordless(&mut self, user_id: &str) -> StorageResult<Option<PasswordlessCredential>> {
+        Ok(self.state.passwordless.get(user_id).cloned())
+    }
+
+    fn put_passwordless(
+        &mut self,
+        user_id: &str,
+        credential: &PasswordlessCredential,
+    ) -> StorageResult<()> {
+        self.state
+            .passwordless
+            .insert(user_id.to_string(), credential.clone());
+        Ok(())
+    }
+
+    fn redeem_passwordless(
+        &mut self,
+        user_id: &str,
+        secret_hash: &str,
+        now: i64,
+    ) -> StorageResult<bool> {
+        let passwordless = &mut self.state.passwordless;
+        let redeemable = passwordless.get(user_id).is_some_and(|credential| {
+            credential.secret_hash == secret_hash && credential.expires_at >= now
+        });
+        if redeemable {
+            passwordless.remove(user_id);
+        }
+        Ok(redeemable)
+    }
+
+    fn record_passwordless_failure(
+        &mut self,
+        user_id: &str,
+        max_attempts: u32,
+    ) -> StorageResult<()> {
+        let passwordless = &mut self.state.passwordless;
+        if let Some(credential) = passwordless.get_mut(user_id) {
+            credential.attempts += 1;
+            if credential.attempts >= max_attempts {
+                passwordless.remove(user_id);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> SessionStore for MemoryTx<'a> {
+    fn create_session(&mut self, session: &Session) -> StorageResult<()> {
+        let sessions = &mut self.state.sessions;
+        if sessions.iter().any(|s| s.session_id == session.session_id) {
+            return Err(StorageError::Conflict(session.session_id.clone()));
+        }
+        sessions.retain(|s| s.user_id != session.user_id || s.expires_at >= session.created_at);
+        sessions.push(session.clone());
+        Ok(())
+    }
+
+    fn find_session(&mut self, session_id: &str) -> StorageResult<Option<Session>> {
+        Ok(self
+            .state
+            .sessions
+            .iter()
+            .find(|session| session.session_id == session_id)
+            .cloned())
+    }
+
+    fn list_sessions(&mut self, user_id: &str, now: i64) -> StorageResult<Vec<Session>> {
+        let mut sessions: Vec<Session> = self
+            .state
+            .sessions
+            .iter()
+            .filter(|session| session.user_id == user_id && session.expires_at >= now)
+            .cloned()
+            .collect();
+        sessions.sort_by(|a, b| {
+            b.last_used_at
+                .cmp(&a.last_used_at)
+                .then_with(|| a.session_id.cmp(&b.session_id))
+        });
+        Ok(sessions)
+    }
+
+    fn rotate_session(
+        &mut self,
+        session_id: &str,
+        current_hash: &str,
+        next_hash: &str,
+        used_at: i64,
+        expires_at: i64,
+    ) -> StorageResult<bool> {
+        match self.state.sessions.iter_mut().find(|session| {
+            session.session_id == session_id && session.refresh_token_hash == current_hash
+        }) {
+            Some(session) => {
+                session.refresh_token_hash = next_hash.to_string();
+                session.last_used_at = used_at;
+                session.expires_at = expires_at;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn delete_session(&mut self, user_id: &str, session_id: &str) -> StorageResult<()> {
+        let sessions = &mut self.state.sessions;
+        let before = sessions.len();
+        sessions.retain(|session| session.user_id != user_id || session.session_id != session_id);
+        match sessions.len() < before {
+            true => Ok(()),
+            false => Err(StorageError::NotFound),
+        }
+    }
+
+    fn delete_sessions(&mut self, user_id: &str, keep: Option<&str>) -> StorageResult<()> {
+        self.state.sessions.retain(|session| {
+            session.user_id != user_id || Some(session.session_id.as_str()) == keep
+        });
+        Ok(())
+    }
+}
+
+impl<'a> AuditStore for MemoryTx<'a> {
+    fn append_audit_event(&mut self, event: &AuditEvent) -> StorageResult<()> {
+        let chain = self
+            .state
+            .audit_chains
+            .entry(event.realm.clone())
+            .or_insert_with(|| AuditChain::empty(&event.realm));
+        let link = ChainLink::next(chain.head_sequence, &chain.head_hash, event);
+        chain.head_sequence = link.sequence;
+        chain.head_hash = link.entry_hash.clone();
+        self.state.audit_sequence += 1;
+        let id = self.state.audit_sequence;
+        self.state.audit_events.push(AuditRecord {
+            id,
+            event: event.clone(),
+            link: Some(link),
+            redacted_at: None,
+        });
+        Ok(())
+    }
+
+    fn query_audit_events(
+        &mut self,
+        realm: &RealmName,
+        query: &AuditQuery,
+        limit: u32,
+    ) -> StorageResult<Vec<AuditRecord>> {
+        Ok(self
+            .state
+            .audit_events
+            .iter()
+            .rev()
+            .filter(|record| &record.event.realm == realm && query.matches(record))
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    fn audit_chain(&mut self, realm: &RealmName) -> StorageResult<Option<AuditChain>> {
+        Ok(self.state.audit_chains.get(realm).cloned())
+    }
+
+    fn audit_chains(&mut self) -> StorageResult<Vec<AuditChain>> {
+        let mut chains: Vec<AuditChain> = self.state.audit_chains.values().cloned().collect();
+        chains.sort_by(|a, b| a.realm.cmp(&b.realm));
+        Ok(chains)
+    }
+
+    fn audit_chain_entries(
+        &mut self,
+        realm: &RealmName,
+        after_sequence: i64,
+        limit: u32,
+    ) -> StorageResult<Vec<AuditRecord>> {
+        Ok(self
+            .state
+            .audit_events
+            .iter()
+            .filter(|record| &record.event.realm == realm)
+            .filter(|record| {
+                record
+                    .link
+                    .as_ref()
+                    .is_some_and(|link| link.sequence > after_sequence)
+            })
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    fn first_audit_sequence_since(
+        &mut self,
+        realm: &RealmName,
+        since: i64,
+    ) -> StorageResult<Option<i64>> {
+        Ok(self
+            .state
+            .audit_events
+            .iter()
+            .filter(|record| &record.event.realm == realm && record.event.occurred_at >= since)
+            .filter_map(|record| record.link.as_ref().map(|link| link.sequence))
+            .min())
+    }
+
+    fn truncate_audit_chain(
+        &mut self,
+        realm: &RealmName,
+        anchor: &ChainLink,
+    ) -> StorageResult<u64> {
+        let events = &mut self.state.audit_events;
+        let count = events.len();
+        events.retain(|record| {
+            &record.event.realm != realm
+                || record
+                    .link
+                    .as_ref()
+                    .is_none_or(|link| link.sequence > anchor.sequence)
+        });
+        let removed = (count - events.len()) as u64;
+        if let Some(chain) = self.state.audit_chains.get_mut(realm) {
+            chain.anchor_sequence = anchor.sequence;
+            chain.anchor_hash = anchor.entry_hash.clone();
+        }
+        Ok(removed)
+    }
+
+    fn prune_audit_events(&mut self, before: i64) -> StorageResult<u64> {
+        let events = &mut self.state.audit_events;
+        let count = events.len();
+        events.retain(|record| record.link.is_some() || record.event.occurred_at >= before);
+        Ok((count - events.len()) as u64)
+    }
+
+    fn redact_audit_events(
+        &mut self,
+        realm: &RealmName,
+        user_id: &str,
+        pseudonym: &str,
+        redacted_at: i64,
+    ) -> StorageResult<u64> {
+        let mut redacted = 0;
+        for record in self.state.audit_events.iter_mut().filter(|record| {
+            &record.event.realm == realm && record.event.user_id.as_deref() == Some(user_id)
+        }) {
+            record.event.user_id = Some(pseudonym.to_string());
+            record.event.ip = None;
+            record.event.detail = None;
+            if let Some(link) = record.link.as_mut() {
+                link.pii_salt = None;
+            }
+            record.redacted_at = Some(redacted_at);
+            redacted += 1;
+        }
+        Ok(redacted)
+    }
+}
+
+impl<'a> WebhookStore for MemoryTx<'a> {
+    fn create_subscription(&mut self, subscription: &WebhookSubscription) -> StorageResult<()> {
+        let subscriptions = &mut self.state.webhook_subscriptions;
+        if subscriptions
+            .iter()
+            .any(|s| s.subscription_id == subscription.subscription_id)
+        {
+            return Err(StorageError::Conflict(subscription.subscription_id.clone()));
+        }
+        subscriptions.push(subscription.clone());
+        Ok(())
+    }
+
+    fn list_subscriptions(&mut self, realm: &RealmName) -> StorageResult<Vec<WebhookSubscription>> {
+        Ok(self
+            .state
+            .webhook_subscriptions
+            .iter()
+            .filter(|subscription| &subscription.realm == realm)
+            .cloned()
+            .collect())
+    }
+
+    fn find_subscription(
+        &mut self,
+        subscription_id: &str,
+    ) -> StorageResult<Option<WebhookSubscription>> {
+        Ok(self
+            .state
+            .webhook_subscriptions
+            .iter()
+            .find(|subscription| subscription.subscription_id == subscription_id)
+            .cloned())
+    }
+
+    fn delete_subscription(
+        &mut self,
+        realm: &RealmName,
+        subscription_id: &str,
+    ) -> StorageResult<()> {
+        let subscriptions = &mut self.state.webhook_subscriptions;
+        let before = subscriptions.len();
+        subscriptions.retain(|s| &s.realm != realm || s.subscription_id != subscription_id);
+        if subscriptions.len() == before {
+            return Err(StorageError::NotFound);
+        }
+        self.state
+            .webhook_deliveries
+            .retain(|delivery| delivery.subscription_id != subscription_id);
+        Ok(())
+    }
+
+    fn enqueue_delivery(&mut self, delivery: &WebhookDelivery) -> StorageResult<()> {
+        let deliveries = &mut self.state.webhook_deliveries;
+        if deliveries
+            .iter()
+            .any(|d| d.delivery_id == delivery.delivery_id)
+        {
+            return Err(StorageError::Conflict(delivery.delivery_id.clone()));
+        }
+        if deliveries.iter().any(|d| {
+            d.subscription_id == delivery.subscription_id && d.event_id == delivery.event_id
+        }) {
+            return Ok(());
+        }
+        deliveries.push(delivery.clone());
+        Ok(())
+    }
+
+    fn claim_due_deliveries(
+        &mut self,
+        now: i64,
+        lease_until: i64,
+        limit: u32,
+    ) -> StorageResult<Vec<WebhookDelivery>> {
+        let mut due: Vec<&mut WebhookDelivery> = self
+            .state
+            .webhook_deliveries
+            .iter_mut()
+            .filter(|d| d.status == DeliveryStatus::Pending && d.next_attempt_at <= now)
+            .collect();
+        due.sort_by_key(|d| (d.next_attempt_at, d.created_at));
+        Ok(due
+            .into_iter()
+            .take(limit as usize)
+            .map(|delivery| {
+                let claimed = delivery.clone();
+                delivery.next_attempt_at = lease_until;
+                claimed
+            })
+            .collect())
+    }
+
+    fn update_delivery(&mut self, delivery: &WebhookDelivery) -> StorageResult<()> {
+        match self
+            .state
+            .webhook_deliveries
+            .iter_mut()
+            .find(|d| d.delivery_id == delivery.delivery_id)
+        {
+            Some(stored) => {
+                stored.status = delivery.status;
+                stored.attempts = delivery.attempts;
+                stored.next_attempt_at = delivery.next_attempt_at;
+                stored.last_error = delivery.last_error.clone();
+                stored.delivered_at = delivery.delivered_at;
+                Ok(())
+            }
+            None => Err(StorageError::NotFound),
+        }
+    }
+
+    fn find_delivery(
+        &mut self,
+        realm: &RealmName,
+        delivery_id: &str,
+    ) -> StorageResult<Option<WebhookDelivery>> {
+        Ok(self
+            .state
+            .webhook_deliveries
+            .iter()
+            .find(|delivery| &delivery.realm == realm && delivery.delivery_id == delivery_id)
+            .cloned())
+    }
+
+    fn list_deliveries(
+        &mut self,
+        realm: &RealmName,
+        status: DeliveryStatus,
+        limit: u32,
+    ) -> StorageResult<Vec<WebhookDelivery>> {
+        Ok(self
+            .state
+            .webhook_deliveries
+            .iter()
+            .rev()
+            .filter(|delivery| &delivery.realm == realm && delivery.status == status)
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    fn find_deliveries_containing(
+        &mut self,
+        realm: &RealmName,
+        text: &str,
+    ) -> StorageResult<Vec<WebhookDelivery>> {
+        Ok(self
+            .state
+            .webhook_deliveries
+            .iter()
+            .filter(|delivery| &delivery.realm == realm && delivery.payload.contains(text))
+            .cloned()
+            .collect())
+    }
+
+    fn replace_delivery_payload(&mut self, delivery_id: &str, payload: &str) -> StorageResult<()> {
+        match self
+            .state
+            .webhook_deliveries
+            .iter_mut()
+            .find(|d| d.delivery_id == delivery_id)
+        {
+            Some(stored) => {
+                stored.payload = payload.to_string();
+                Ok(())
+            }
+            None => Err(StorageError::NotFound),
+        }
+    }
+}
+
+impl<'a> OutboxStore for MemoryTx<'a> {
+    fn append_outbox_event(&mut self, event: &OutboxEvent) -> StorageResult<()> {
+        if self
+            .state
+            .outbox
+            .iter()
+            .any(|record| record.event.event_id == event.event_id)
+        {
+            return Err(StorageError::Conflict(event.event_id.clone()));
+        }
+        self.state.outbox_sequence += 1;
+        let record = OutboxRecord {
+            id: self.state.outbox_sequence,
+            event: event.clone(),
+            attempts: 0,
+            next_attempt_at: event.occurred_at,
+            last_error: None,
+            published_at: None,
+        };
+        self.state.outbox.push(record);
+        Ok(())
+    }
+
+    fn claim_outbox_events(
+        &mut self,
+        now: i64,
+        lease_until: i64,
+        limit: u32,
+    ) -> StorageResult<Vec<OutboxRecord>> {
+        let mut heads = Vec::new();
+        let mut claimed = Vec::new();
+        for record in self.state.outbox.iter_mut() {
+            if record.published_at.is_some() {
+                continue;
+            }
+            let aggregate = (
+                record.event.aggregate_type.clone(),
+                record.event.aggregate_id.clone(),
+            );
+            // Only the oldest unpublished event of an aggregate may go.
+            if heads.contains(&aggregate) {
+                continue;
+            }
+            heads.push(aggregate);
+            if record.next_attempt_at <= now && claimed.len() < limit as usize {
+                claimed.push(record.clone());
+                record.next_attempt_at = lease_until;
+            }
+        }
+        Ok(claimed)
+    }
+
+    fn update_outbox_event(&mut self, record: &OutboxRecord) -> StorageResult<()> {
+        match self.state.outbox.iter_mut().find(|r| r.id == record.id) {
+            Some(stored) => {
+                stored.attempts = record.attempts;
+                stored.next_attempt_at = record.next_attempt_at;
+                stored.last_error = record.last_error.clone();
+                stored.published_at = record.published_at;
+                Ok(())
+            }
+            None => Err(StorageError::NotFound),
+        }
+    }
+
+    fn prune_outbox(&mut self, before: i64) -> StorageResult<u64> {
+        let outbox = &mut self.state.outbox;
+        let count = outbox.len();
+        outbox.retain(|record| record.published_at.is_none_or(|at| at >= before));
+        Ok((count - outbox.len()) as u64)
+    }
+
+    fn redact_outbox_events(
+        &mut self,
+        realm: &RealmName,
+        aggregate_type: &str,
+        aggregate_id: &str,
+        payload: &serde_json::Value,
+    ) -> StorageResult<u64> {
+        let mut redacted = 0;
+        for record in self.state.outbox.iter_mut().filter(|record| {
+            &record.event.realm == realm
+                && record.event.aggregate_type == aggregate_type
+                && record.event.aggregate_id == aggregate_id
+        }) {
+            record.event.payload = payload.clone();
+            redacted += 1;
+        }
+        Ok(redacted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::customer::dto::{AddressData, CreateUser, UserQuery};
+    use crate::domain::customer::{Address, AddressType};
+    use crate::repository::memory::InMemoryStorage;
+    use crate::repository::{Storage, StorageError, TokenKind};
+
+    fn create_user(username: &str) -> CreateUser {
+        CreateUser {
+            username: username.to_string(),
+            password: "hashed".to_string(),
+            name: "Ru Ru".to_string(),
+            age: 30,
+            email: "ruru@nitro.com".to_string(),
+            address: Address {
+                street: "The Street".to_string(),
+                country: "UK".to_string(),
+                city: "London".to_string(),
+                post_code: "W1 2DE".to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_rollback_discards_writes() {
+        let storage: &dyn Storage = &InMemoryStorage::with_default_realms();
+        let realm = "rj.wire".to_string();
+
+        let result: Result<(), StorageError> = storage.in_transaction(|tx| {
+            tx.users().create(&realm, create_user("ruru"))?;
+            Err(StorageError::NotFound)
+        });
+        assert!(result.is_err());
+
+        let user = storage
+            .in_transaction(|tx| tx.users().get_by_name(&realm, "ruru"))
+            .unwrap();
+        assert!(user.is_none());
+    }
+
+    #[test]
+    fn test_duplicate_username_in_realm_conflicts() {
+        let storage: &dyn Storage = &InMemoryStorage::with_default_realms();
+        let realm = "rj.wire".to_string();
+
+        storage
+            .in_transaction(|tx| tx.users().create(&realm, create_user("ruru")))
+            .unwrap();
+        let duplicate = storage.in_transaction(|tx| tx.users().create(&realm, create_user("ruru")));
+        assert!(matches!(duplicate, Err(StorageError::Conflict(_))));
+
+        let other_realm = "rj.haven".to_string();
+        assert!(storage
+            .in_transaction(|tx| tx.users().create(&other_realm, create_user("ruru")))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_delete_user_removes_addresses_and_tokens() {
+        let storage: &dyn Storage = &InMemoryStorage::with_default_realms();
+        let realm = "rj.wire".to_string();
+        let data = create_user("ruru");
+
+        let user_id = storage
+            .in_transaction(|tx| {
+                let user_id = tx.users().create(&realm, data.clone())?;
+                let address = AddressData {
+                    address: data.address.clone(),
+                    address_type: AddressType::Shipping,
+                    is_default: true,
+                };
+                tx.addresses().create(&realm, (address, user_id.clone()))?;
+                tx.store_token(&user_id, TokenKind::Authentication, "token")?;
+                Ok(user_id)
+            })
+            .unwrap();
+
+        storage
+            .in_transaction(|tx| tx.users().delete(&realm, &user_id))
+            .unwrap();
+
+        let (users, token) = storage
+            .in_transaction(|tx| {
+                let users = tx.users().search(&realm, &UserQuery::default(), None, 10)?;
+                let token = tx.find_token(&user_id, TokenKind::Authentication)?;
+                Ok((users, token))
+            })
+            .unwrap();
+        assert!(users.is_empty());
+        assert!(token.is_none());
+    }
+}
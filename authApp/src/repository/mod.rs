@@ -1,247 +1,519 @@
-use crate::domain::customer::dto::{CreateUser, UserMetadata};
-use crate::domain::customer::{Address, Role, User, UserWithAddress};
-use crate::domain::realm::RealmName;
-use crate::Principal;
-use mysql::error::Result;
-use mysql::prelude::Queryable;
-use mysql::{params, Transaction};
-use uuid::Uuid;
+use crate::domain::audit::{AuditChain, AuditEvent, AuditQuery, AuditRecord, ChainLink};
+use crate::domain::customer::dto::{AddressData, CreateUser, PageCursor, UserMetadata, UserQuery};
+use crate::domain::customer::{User, UserAddress, UserProfile};
+use crate::domain::lockout::{AttemptKey, LoginAttempts};
+use crate::domain::mfa::{RecoveryCode, TotpEnrollment};
+use crate::domain::outbox::{OutboxEvent, OutboxRecord};
+use crate::domain::passwordless::PasswordlessCredential;
+use crate::domain::rate_limit::TokenBucket;
+use crate::domain::realm::{InternalRealmSettings, RealmName};
+use crate::domain::session::Session;
+use crate::domain::webauthn::{Passkey, PendingChallenge};
+use crate::domain::webhook::{DeliveryStatus, WebhookDelivery, WebhookSubscription};
+use strum_macros::Display;
 
+pub mod memory;
+pub mod mysql_storage;
+pub mod postgres_storage;
+pub mod rate_limit;
 pub mod realm;
+mod sql;
+pub mod sqlite_storage;
 
-type CountryCode = String;
+#[cfg(test)]
+mod tests;
 
+pub type StorageResult<T> = std::result::Result<T, StorageError>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum StorageError {
+    #[error("Storage backend failure: {0}")]
+    Backend(String),
+
+    #[error("Record not found")]
+    NotFound,
+
+    #[error("Conflicting record: {0}")]
+    Conflict(String),
+}
+
+/// A storage backend. All reads and writes go through a transaction obtained from [`Storage::begin`].
+pub trait Storage: Send + Sync {
+    fn begin(&self) -> StorageResult<Box<dyn StorageTx + '_>>;
+}
+
+impl dyn Storage + '_ {
+    /// Runs `action` in a transaction, committing on success and rolling back on error.
+    pub fn in_transaction<R>(
+        &self,
+        action: impl FnOnce(&mut dyn StorageTx) -> StorageResult<R>,
+    ) -> StorageResult<R> {
+        let mut tx = self.begin()?;
+        match action(tx.as_mut()) {
+            Ok(res) => {
+                tx.commit()?;
+                Ok(res)
+            }
+            Err(e) => {
+                if let Err(rollback_err) = tx.rollback() {
+                    println!("failed to rollback transaction: {}", rollback_err);
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+pub trait StorageTx:
+    RealmStore
+    + TokenStore
+    + PasswordHistoryStore
+    + LoginAttemptStore
+    + RateLimitBucketStore
+    + MfaStore
+    + PasskeyStore
+    + PasswordlessStore
+    + SessionStore
+    + AuditStore
+    + WebhookStore
+    + OutboxStore
+{
+    fn users(&mut self) -> Box<dyn UserRepository + '_>;
+
+    fn addresses(&mut self) -> Box<dyn AddressRepository + '_>;
+
+    fn commit(self: Box<Self>) -> StorageResult<()>;
+
+    fn rollback(self: Box<Self>) -> StorageResult<()>;
+}
+
+/// CRUD access to one kind of record, bound to the transaction it was obtained from.
+///
+/// Every operation is scoped to a realm: records of another realm behave as if they did not
+/// exist, so `update` and `delete` return [`StorageError::NotFound`] for them.
 pub trait Repository<T> {
-    type CreationData;
-    type UpdateMetaData;
     type ID;
+    type CreationData;
+    type UpdateData;
 
-    fn create_from(data: Self::CreationData, realm: &RealmName, tx: &mut Transaction) -> Result<Self::ID>;
-
-    fn update(data: Self::UpdateMetaData, tx: &mut Transaction) -> bool;
-
-    fn delete(id: Self::ID, tx: &mut Transaction) -> ();
-}
-
-pub struct UserStorage {}
-
-pub struct AddressStorage {}
-
-impl UserStorage {
-    pub fn get_user(user_id: &String, tx: &mut Transaction) -> Result<Option<User>> {
-        tx.exec_first(
-            "SELECT \
-                    user_id, \
-                    name,\
-                    password, \
-                    role \
-                    FROM realm_user \
-                    WHERE user_id = :user_id",
-            params! { "user_id" => user_id },
-        )
-        .map(|row| {
-            //Unpack Option
-            row.map(|(user_id, name, password, role)| User {
-                user_id,
-                username: name,
-                hashed_pass: password,
-                role,
-            })
-        })
-    }
+    fn get(&mut self, realm: &RealmName, id: &Self::ID) -> StorageResult<Option<T>>;
 
-    pub fn get_user_by_name(
-        username: &String,
-        realm: &RealmName,
-        tx: &mut Transaction,
-    ) -> Result<Option<User>> {
-        tx.exec_first(
-            "SELECT \
-                    user_id, \
-                    username,\
-                    password, \
-                    role \
-                    FROM ream_user \
-                    WHERE username = :username\
-                    AND realm = :realm",
-            params! {
-                "username" => username,
-                "realm" => realm
-            },
-        )
-        .map(|row| {
-            //Unpack Option
-            row.map(|(user_id, name, password, role)| User {
-                user_id,
-                username: name,
-                hashed_pass: password,
-                role,
-            })
-        })
-    }
+    fn list(&mut self, realm: &RealmName) -> StorageResult<Vec<T>>;
 
-    pub fn get_users(tx: &mut Transaction) -> Result<Vec<UserWithAddress>> {
-        tx.query_map(
-            "SELECT \
-            u.user_id, \
-            u.name,\
-            u.role, \
-            a.street, \
-            a.city, \
-            a.post_code, \
-            a.country \
-            FROM realm_user u \
-            INNER JOIN address a on u.user_id = a.user_id ",
-            |(id, name, role, street, city, post_code, country)| UserWithAddress {
-                user_id: id,
-                role,
-                username: name,
-                address: Address {
-                    street,
-                    country,
-                    city,
-                    post_code,
-                },
-            },
-        )
-    }
+    fn create(&mut self, realm: &RealmName, data: Self::CreationData) -> StorageResult<Self::ID>;
+
+    fn update(
+        &mut self,
+        realm: &RealmName,
+        id: &Self::ID,
+        data: Self::UpdateData,
+    ) -> StorageResult<()>;
+
+    fn delete(&mut self, realm: &RealmName, id: &Self::ID) -> StorageResult<()>;
 }
 
-impl Repository<User> for UserStorage {
-    type CreationData = CreateUser;
-    type UpdateMetaData = UserMetadata;
-    type ID = String;
-
-    fn create_from(data: Self::CreationData, realm: &RealmName, tx: &mut Transaction) -> Result<Self::ID> {
-        let user_id = Uuid::new_v4().to_string();
-
-        tx.exec_drop(
-            "INSERT INTO realm_user (realm_name, user_id, username, role, name, password, email) \
-                      VALUES (:realm, :user_id, :username, :role, :name, :password, :email)",
-            params! {
-            "realm" => realm,
-            "user_id" => &user_id,
-            "username" => &data.username,
-            "role" => Role::CUSTOMER.to_string(),
-            "name" => &data.name,
-            "password" => &data.password,
-            "email" => &data.email,
-            "expired_at" => chrono::DateTime(),
-            },
-        )
-        .expect("Failed to create user");
-
-        return Ok(user_id);
-    }
+/// Users, keyed by user id. The password in [`CreateUser`] must already be hashed.
+pub trait UserRepository:
+    Repository<User, ID = String, CreationData = CreateUser, UpdateData = UserMetadata>
+{
+    fn get_by_name(&mut self, realm: &RealmName, username: &str) -> StorageResult<Option<User>>;
 
-    fn update(data: Self::UpdateMetaData, tx: &mut Transaction) -> bool {
-        AddressStorage::update((data.address, data.user_id), tx)
-    }
+    fn get_profile(&mut self, realm: &RealmName, id: &str) -> StorageResult<Option<UserProfile>>;
 
-    fn delete(id: Self::ID, tx: &mut Transaction) -> () {
-        tx.exec_drop(
-            "DELETE FROM USER WHERE user_id = :user_id",
-            params! { "user_id" => id },
-        )
-        .expect("Failed to delete user");
-        // match to print logs
-    }
+    /// Up to `limit` users matching `query`, in its sort order and starting after `after`.
+    fn search(
+        &mut self,
+        realm: &RealmName,
+        query: &UserQuery,
+        after: Option<&PageCursor>,
+        limit: u32,
+    ) -> StorageResult<Vec<UserProfile>>;
+
+    /// Number of users matching the filters of `query`, ignoring pagination.
+    fn count(&mut self, realm: &RealmName, query: &UserQuery) -> StorageResult<u64>;
+
+    /// Soft-deletes a user: the row is kept but hidden from every other query and its tokens are
+    /// revoked. [`Repository::delete`] erases the user, disabled or not.
+    fn disable(&mut self, realm: &RealmName, id: &str, deleted_at: i64) -> StorageResult<()>;
 }
 
-impl AddressStorage {
-    // fn map_to_country_code(country: String) -> CountryCode {
-    //     match country.as_ref().map(String::as_ref) {
-    //         String::from("United Kingdom") => "UK".to_string(),
-    //         String::from("Great Britain") => "UK".to_string(),
-    //         String::from("Denmark") => "DK".to_string()
-    //         // "Russia" => "RU",
-    //         // "America" => "USA",
-    //     }
-    // }
-}
-
-impl Repository<Address> for AddressStorage {
-    type CreationData = (Address, String);
-    type UpdateMetaData = (Option<Address>, String);
-    type ID = String;
-
-    fn create_from(data: Self::CreationData, _: &RealmName, tx: &mut Transaction) -> Result<Self::ID> {
-        let address = &data.0;
-        let user_id: String = data.1;
-        let address_id = Uuid::new_v4().to_string();
-
-        tx.exec_drop(
-            "INSERT INTO address (\
-            address_id, \
-            user_id, \
-            street, \
-            post_code, \
-            country, \
-            city, \
-            country_code) \
-            VALUES (\
-             :address_id, \
-             :user_id, \
-             :street, \
-             :city, \
-             :post_code, \
-             :country, \
-             :country_code)",
-            params! {
-            "address_id" => &address_id,
-            "user_id" => &user_id,
-            "street" => &address.street,
-            "city" => &address.city,
-            "post_code" => &address.post_code,
-            "country" => &address.country,
-            "country_code" => "UK".to_string() },
-        )
-        .expect("Failed to insert user address");
-
-        return Ok(address_id);
-    }
+/// Addresses, keyed by address id. Created from the address and the id of the owning user.
+///
+/// Storing an address with `is_default` set clears the flag on the user's other addresses of the
+/// same type.
+pub trait AddressRepository:
+    Repository<UserAddress, ID = String, CreationData = (AddressData, String), UpdateData = AddressData>
+{
+    /// Addresses of an active user, grouped by type with the default first.
+    fn list_for_user(
+        &mut self,
+        realm: &RealmName,
+        user_id: &str,
+    ) -> StorageResult<Vec<UserAddress>>;
+}
 
-    fn update(data: Self::UpdateMetaData, tx: &mut Transaction) -> bool {
-        let maybe_address = data.0;
-        let user_id: String = data.1;
-
-        match maybe_address {
-            Some(address) => {
-                let result = tx.exec_drop(
-                    "UPDATE Address
-                SET street = :street,
-                    post_code = :post_code,
-                    country = :country,
-                WHERE user_id = :user_id",
-                    (
-                        "street",
-                        &address.street,
-                        "post_code",
-                        &address.post_code,
-                        "country",
-                        &address.country,
-                        "user_id",
-                        &user_id,
-                    ),
-                );
-
-                match result {
-                    Ok(_) => true,
-                    Err(x) => {
-                        println!("error encountered for user {}, \n {}", user_id, x);
-                        return false;
-                    }
-                }
-            }
-            None => true,
-        }
-    }
+pub trait RealmStore {
+    fn get_realm_settings(
+        &mut self,
+        realm: &RealmName,
+    ) -> StorageResult<Option<InternalRealmSettings>>;
 
-    fn delete(id: Self::ID, tx: &mut Transaction) -> () {
-        tx.exec_drop(
-            "DELETE FROM ADDRESS WHERE address_id = :address_id",
-            ("address_id", id),
-        )
-        .expect("Failed to delete address");
-    }
+    fn list_realm_settings(&mut self) -> StorageResult<Vec<(RealmName, InternalRealmSettings)>>;
+}
+
+#[derive(Display, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TokenKind {
+    Authentication,
+    PasswordReset,
+    EmailVerification,
+    MfaChallenge,
+}
+
+pub trait TokenStore {
+    fn store_token(&mut self, user_id: &str, kind: TokenKind, token: &str) -> StorageResult<()>;
+
+    fn find_token(&mut self, user_id: &str, kind: TokenKind) -> StorageResult<Option<String>>;
+
+    fn revoke_token(&mut self, user_id: &str, kind: TokenKind) -> StorageResult<()>;
+
+    /// Removes the user's token of `kind` if it is `token`, `false` when it is not. A token can
+    /// only be consumed once, also by concurrent transactions.
+    fn consume_token(&mut self, user_id: &str, kind: TokenKind, token: &str)
+        -> StorageResult<bool>;
+
+    /// Keeps a new email of the user until it is confirmed with the user's
+    /// [`TokenKind::EmailVerification`] token, `None` drops it.
+    fn stage_email(&mut self, user_id: &str, email: Option<&str>) -> StorageResult<()>;
+
+    fn staged_email(&mut self, user_id: &str) -> StorageResult<Option<String>>;
+}
+
+/// Hashes of the passwords a user has had, including the current one. Erasing the user erases
+/// its history.
+pub trait PasswordHistoryStore {
+    /// The newest `limit` hashes, newest first.
+    fn password_history(&mut self, user_id: &str, limit: usize) -> StorageResult<Vec<String>>;
+
+    /// Records `hash` as the newest password and prunes all but the newest `keep` hashes.
+    fn push_password_history(
+        &mut self,
+        user_id: &str,
+        hash: &str,
+        keep: usize,
+    ) -> StorageResult<()>;
+}
+
+/// Failed login attempts per realm and key. Kept in storage rather than in process so that every
+/// instance sees the same lockouts.
+pub trait LoginAttemptStore {
+    fn get_attempts(
+        &mut self,
+        realm: &RealmName,
+        key: &AttemptKey,
+    ) -> StorageResult<Option<LoginAttempts>>;
+
+    /// Stores `attempts`, replacing any previous state of the key.
+    fn put_attempts(
+        &mut self,
+        realm: &RealmName,
+        key: &AttemptKey,
+        attempts: &LoginAttempts,
+    ) -> StorageResult<()>;
+
+    fn clear_attempts(&mut self, realm: &RealmName, key: &AttemptKey) -> StorageResult<()>;
+}
+
+/// Token buckets of the storage backed rate limiter, keyed per realm. Reads lock the bucket
+/// where the backend supports it, so that concurrent requests take tokens one after another.
+pub trait RateLimitBucketStore {
+    fn get_bucket(&mut self, realm: &RealmName, key: &str) -> StorageResult<Option<TokenBucket>>;
+
+    fn put_bucket(
+        &mut self,
+        realm: &RealmName,
+        key: &str,
+        bucket: &TokenBucket,
+    ) -> StorageResult<()>;
+
+    /// Deletes the buckets of every realm last updated before `updated_before`.
+    fn delete_idle_buckets(&mut self, updated_before: i64) -> StorageResult<()>;
+}
+
+/// Second factor enrollments of users. Erasing the user erases them.
+pub trait MfaStore {
+    fn get_totp(&mut self, user_id: &str) -> StorageResult<Option<TotpEnrollment>>;
+
+    /// Stores `enrollment`, replacing any previous one of the user.
+    fn put_totp(&mut self, user_id: &str, enrollment: &TotpEnrollment) -> StorageResult<()>;
+
+    fn delete_totp(&mut self, user_id: &str) -> StorageResult<()>;
+
+    /// Records `step` as the last step of the user's active TOTP, `false` when it is not past
+    /// the last one. A step can only be used once, also by concurrent transactions.
+    fn use_totp_step(&mut self, user_id: &str, step: i64) -> StorageResult<bool>;
+
+    /// Replaces every recovery code of the user, used ones included.
+    fn replace_recovery_codes(
+        &mut self,
+        user_id: &str,
+        codes: &[RecoveryCode],
+    ) -> StorageResult<()>;
+
+    fn list_recovery_codes(&mut self, user_id: &str) -> StorageResult<Vec<RecoveryCode>>;
+
+    /// Marks the unused code with `code_hash` as used, `false` when there is none. A code can
+    /// only be marked once, also by concurrent transactions.
+    fn use_recovery_code(
+        &mut self,
+        user_id: &str,
+        code_hash: &str,
+        used_at: i64,
+        used_ip: Option<&str>,
+    ) -> StorageResult<bool>;
+}
+
+/// Passkeys of users and the challenges of ceremonies in progress. Erasing the user erases its
+/// passkeys.
+pub trait PasskeyStore {
+    /// Stores `challenge` and drops the realm's challenges that expired before `now`.
+    fn put_challenge(
+        &mut self,
+        realm: &RealmName,
+        challenge: &PendingChallenge,
+        now: i64,
+    ) -> StorageResult<()>;
+
+    /// Removes and returns a challenge, so that each is answered once.
+    fn take_challenge(
+        &mut self,
+        realm: &RealmName,
+        challenge: &str,
+    ) -> StorageResult<Option<PendingChallenge>>;
+
+    /// Stores a new passkey, [`StorageError::Conflict`] when its credential id is taken.
+    fn add_passkey(&mut self, passkey: &Passkey) -> StorageResult<()>;
+
+    /// A passkey of an active user of `realm`.
+    fn find_passkey(
+        &mut self,
+        realm: &RealmName,
+        credential_id: &str,
+    ) -> StorageResult<Option<Passkey>>;
+
+    fn list_passkeys(&mut self, user_id: &str) -> StorageResult<Vec<Passkey>>;
+
+    fn record_passkey_use(
+        &mut self,
+        credential_id: &str,
+        sign_count: u32,
+        used_at: i64,
+    ) -> StorageResult<()>;
+
+    /// [`StorageError::NotFound`] when the user has no such passkey.
+    fn delete_passkey(&mut self, user_id: &str, credential_id: &str) -> StorageResult<()>;
+}
+
+/// The pending passwordless credential of each user. Erasing the user erases it.
+pub trait PasswordlessStore {
+    fn get_passwordless(&mut self, user_id: &str) -> StorageResult<Option<PasswordlessCredential>>;
+
+    /// Replaces the pending credential of the user.
+    fn put_passwordless(
+        &mut self,
+        user_id: &str,
+        credential: &PasswordlessCredential,
+    ) -> StorageResult<()>;
+
+    /// Removes the pending credential when its hash matches and it has not expired by `now`.
+    /// Returns whether it did, so that concurrent redemptions succeed once.
+    fn redeem_passwordless(
+        &mut self,
+        user_id: &str,
+        secret_hash: &str,
+        now: i64,
+    ) -> StorageResult<bool>;
+
+    /// Counts a wrong guess, the credential is removed once it reached `max_attempts`.
+    fn record_passwordless_failure(
+        &mut self,
+        user_id: &str,
+        max_attempts: u32,
+    ) -> StorageResult<()>;
+}
+
+/// Login sessions and the refresh tokens bound to them. Erasing the user erases its sessions.
+pub trait SessionStore {
+    /// Stores a new session, dropping the sessions of the user that expired before it started.
+    fn create_session(&mut self, session: &Session) -> StorageResult<()>;
+
+    fn find_session(&mut self, session_id: &str) -> StorageResult<Option<Session>>;
+
+    /// The sessions of the user that have not expired by `now`, most recently used first.
+    fn list_sessions(&mut self, user_id: &str, now: i64) -> StorageResult<Vec<Session>>;
+
+    /// Replaces the refresh token of the session when `current_hash` is still its token, and
+    /// returns whether it did, so that each refresh token is used once.
+    fn rotate_session(
+        &mut self,
+        session_id: &str,
+        current_hash: &str,
+        next_hash: &str,
+        used_at: i64,
+        expires_at: i64,
+    ) -> StorageResult<bool>;
+
+    /// [`StorageError::NotFound`] when the user has no such session.
+    fn delete_session(&mut self, user_id: &str, session_id: &str) -> StorageResult<()>;
+
+    /// Deletes every session of the user but `keep`, so that its tokens stop working.
+    fn delete_sessions(&mut self, user_id: &str, keep: Option<&str>) -> StorageResult<()>;
+}
+
+/// The audit trail. Events are only appended, and only changed to erase a user's personal data;
+/// pruning by age is the only removal.
+pub trait AuditStore {
+    /// Appends `event` to the head of its realm's chain. Appends to one realm are serialised, so
+    /// the chain never forks.
+    fn append_audit_event(&mut self, event: &AuditEvent) -> StorageResult<()>;
+
+    /// Events of the realm matching `query`, newest first, at most `limit` of them.
+    fn query_audit_events(
+        &mut self,
+        realm: &RealmName,
+        query: &AuditQuery,
+        limit: u32,
+    ) -> StorageResult<Vec<AuditRecord>>;
+
+    /// `None` while the realm has no chained events.
+    fn audit_chain(&mut self, realm: &RealmName) -> StorageResult<Option<AuditChain>>;
+
+    fn audit_chains(&mut self) -> StorageResult<Vec<AuditChain>>;
+
+    /// Chained events of the realm after the entry `after_sequence`, oldest first.
+    fn audit_chain_entries(
+        &mut self,
+        realm: &RealmName,
+        after_sequence: i64,
+        limit: u32,
+    ) -> StorageResult<Vec<AuditRecord>>;
+
+    /// Sequence number of the oldest chained event of the realm that occurred at or after `since`.
+    fn first_audit_sequence_since(
+        &mut self,
+        realm: &RealmName,
+        since: i64,
+    ) -> StorageResult<Option<i64>>;
+
+    /// Removes the chained events of the realm up to `anchor`, which the chain is anchored at
+    /// from then on. Returns how many were removed.
+    fn truncate_audit_chain(&mut self, realm: &RealmName, anchor: &ChainLink)
+        -> StorageResult<u64>;
+
+    /// Removes the events appended before the log was chained that occurred before `before`,
+    /// returns how many.
+    fn prune_audit_events(&mut self, before: i64) -> StorageResult<u64>;
+
+    /// Replaces the user id of the user's events in the realm with `pseudonym`, clears their IP
+    /// and detail and marks them redacted at `redacted_at`. Returns how many were redacted; their
+    /// links stay as they are.
+    fn redact_audit_events(
+        &mut self,
+        realm: &RealmName,
+        user_id: &str,
+        pseudonym: &str,
+        redacted_at: i64,
+    ) -> StorageResult<u64>;
+}
+
+/// Webhook subscriptions of realms and the queue of their deliveries.
+pub trait WebhookStore {
+    fn create_subscription(&mut self, subscription: &WebhookSubscription) -> StorageResult<()>;
+
+    /// Oldest first.
+    fn list_subscriptions(&mut self, realm: &RealmName) -> StorageResult<Vec<WebhookSubscription>>;
+
+    fn find_subscription(
+        &mut self,
+        subscription_id: &str,
+    ) -> StorageResult<Option<WebhookSubscription>>;
+
+    /// Removes the subscription with its queued deliveries. [`StorageError::NotFound`] when the
+    /// realm has no such subscription.
+    fn delete_subscription(
+        &mut self,
+        realm: &RealmName,
+        subscription_id: &str,
+    ) -> StorageResult<()>;
+
+    /// Does nothing when the subscription has a delivery of the event already, so an event
+    /// published again is not sent twice.
+    fn enqueue_delivery(&mut self, delivery: &WebhookDelivery) -> StorageResult<()>;
+
+    /// Pending deliveries of every realm that are due at `now`, the longest waiting first. They
+    /// are leased until `lease_until`, so that other instances skip them meanwhile.
+    fn claim_due_deliveries(
+        &mut self,
+        now: i64,
+        lease_until: i64,
+        limit: u32,
+    ) -> StorageResult<Vec<WebhookDelivery>>;
+
+    /// Stores the status, attempts and schedule of a delivery.
+    fn update_delivery(&mut self, delivery: &WebhookDelivery) -> StorageResult<()>;
+
+    fn find_delivery(
+        &mut self,
+        realm: &RealmName,
+        delivery_id: &str,
+    ) -> StorageResult<Option<WebhookDelivery>>;
+
+    /// Deliveries of the realm in `status`, newest first.
+    fn list_deliveries(
+        &mut self,
+        realm: &RealmName,
+        status: DeliveryStatus,
+        limit: u32,
+    ) -> StorageResult<Vec<WebhookDelivery>>;
+
+    /// Deliveries of the realm whose payload contains `text`, in any status.
+    fn find_deliveries_containing(
+        &mut self,
+        realm: &RealmName,
+        text: &str,
+    ) -> StorageResult<Vec<WebhookDelivery>>;
+
+    /// Replaces the body of a delivery, retries and replays send the new one.
+    fn replace_delivery_payload(&mut self, delivery_id: &str, payload: &str) -> StorageResult<()>;
+}
+
+/// Events recorded with the domain changes they are about, until they are published.
+pub trait OutboxStore {
+    /// Records the event, due at once.
+    fn append_outbox_event(&mut self, event: &OutboxEvent) -> StorageResult<()>;
+
+    /// Unpublished events due at `now` that are the oldest unpublished one of their aggregate, in
+    /// outbox order. They are leased until `lease_until`, so that other instances skip them and
+    /// the later events of their aggregates meanwhile.
+    fn claim_outbox_events(
+        &mut self,
+        now: i64,
+        lease_until: i64,
+        limit: u32,
+    ) -> StorageResult<Vec<OutboxRecord>>;
+
+    /// Stores the attempts, schedule and publishing time of an event.
+    fn update_outbox_event(&mut self, record: &OutboxRecord) -> StorageResult<()>;
+
+    /// Removes the events published before `before`, returns how many.
+    fn prune_outbox(&mut self, before: i64) -> StorageResult<u64>;
+
+    /// Replaces the payloads of the aggregate's events of the realm with `payload`, published
+    /// or not. Returns how many were replaced.
+    fn redact_outbox_events(
+        &mut self,
+        realm: &RealmName,
+        aggregate_type: &str,
+        aggregate_id: &str,
+        payload: &serde_json::Value,
+    ) -> StorageResult<u64>;
 }
@@ -0,0 +1,1990 @@
+use crate::db::DB;
+use crate::domain::audit::{
+    AuditChain, AuditEvent, AuditQuery, AuditRecord, ChainLink, GENESIS_HASH,
+};
+use crate::domain::customer::dto::{AddressData, CreateUser, PageCursor, UserMetadata, UserQuery};
+use crate::domain::customer::{
+    Address, AddressType, Role, User, UserAddress, UserProfile, UserStatus,
+};
+use crate::domain::lockout::{AttemptKey, LoginAttempts};
+use crate::domain::mfa::{RecoveryCode, TotpEnrollment};
+use crate::domain::outbox::{OutboxEvent, OutboxRecord};
+use crate::domain::passwordless::PasswordlessCredential;
+use crate::domain::rate_limit::TokenBucket;
+use crate::domain::realm::{InternalRealmSettings, RealmName};
+use crate::domain::session::Session;
+use crate::domain::webauthn::{Passkey, PendingChallenge};
+use crate::domain::webhook::{DeliveryStatus, WebhookDelivery, WebhookSubscription};
+use crate::repository::sql::{
+    audit_chain, audit_filter, audit_record, event_types_text, in_list, lockout_policy, mfa_policy,
+    outbox_record, passkey, password_policy, passwordless_credential, passwordless_policy,
+    pending_challenge, public_key_text, rate_limits, user_search_filter, user_search_order,
+    webauthn_config, webhook_delivery, webhook_subscription, AuditChainColumns, AuditColumns,
+    OutboxColumns, SqlValue, WebhookDeliveryColumns, WebhookSubscriptionColumns,
+    ORDER_USER_ADDRESS, OUTBOX_HEAD_FILTER, SELECT_AUDIT_CHAIN, SELECT_AUDIT_EVENT,
+    SELECT_LOGIN_ATTEMPT, SELECT_OUTBOX_EVENT, SELECT_PASSKEY, SELECT_SESSION, SELECT_USER_ADDRESS,
+    SELECT_USER_SUMMARY, SELECT_WEBAUTHN_CHALLENGE, SELECT_WEBHOOK_DELIVERY,
+    SELECT_WEBHOOK_SUBSCRIPTION,
+};
+use crate::repository::{
+    AddressRepository, AuditStore, LoginAttemptStore, MfaStore, OutboxStore, PasskeyStore,
+    PasswordHistoryStore, PasswordlessStore, RateLimitBucketStore, RealmStore, Repository,
+    SessionStore, Storage, StorageError, StorageResult, StorageTx, TokenKind, TokenStore,
+    UserRepository, WebhookStore,
+};
+use chrono::Utc;
+use mysql::prelude::Queryable;
+use mysql::{params, Params, PooledConn, Row, Transaction, TxOpts, Value};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+const ER_DUP_ENTRY: u16 = 1062;
+const ER_NO_REFERENCED_ROW: u16 = 1452;
+
+const MIGRATIONS: &[(i64, &str)] = &[
+    (1, include_str!("../../migrations/mysql/0001_init.sql")),
+    (
+        2,
+        include_str!("../../migrations/mysql/0002_email_verification.sql"),
+    ),
+    (
+        3,
+        include_str!("../../migrations/mysql/0003_soft_delete.sql"),
+    ),
+    (
+        4,
+        include_str!("../../migrations/mysql/0004_user_created_at.sql"),
+    ),
+    (
+        5,
+        include_str!("../../migrations/mysql/0005_address_type.sql"),
+    ),
+    (
+        6,
+        include_str!("../../migrations/mysql/0006_password_policy.sql"),
+    ),
+    (
+        7,
+        include_str!("../../migrations/mysql/0007_password_history.sql"),
+    ),
+    (
+        8,
+        include_str!("../../migrations/mysql/0008_login_lockout.sql"),
+    ),
+    (
+        9,
+        include_str!("../../migrations/mysql/0009_rate_limit.sql"),
+    ),
+    (10, include_str!("../../migrations/mysql/0010_mfa_totp.sql")),
+    (
+        11,
+        include_str!("../../migrations/mysql/0011_mfa_recovery_code.sql"),
+    ),
+    (12, include_str!("../../migrations/mysql/0012_webauthn.sql")),
+    (
+        13,
+        include_str!("../../migrations/mysql/0013_passwordless.sql"),
+    ),
+    (
+        14,
+        include_str!("../../migrations/mysql/0014_user_session.sql"),
+    ),
+    (
+        15,
+        include_str!("../../migrations/mysql/0015_audit_event.sql"),
+    ),
+    (
+        16,
+        include_str!("../../migrations/mysql/0016_audit_chain.sql"),
+    ),
+    (17, include_str!("../../migrations/mysql/0017_webhook.sql")),
+    (18, include_str!("../../migrations/mysql/0018_outbox.sql")),
+    (19, include_str!("../../migrations/mysql/0019_erasure.sql")),
+    (
+        20,
+        include_str!("../../migrations/mysql/0020_active_email.sql"),
+    ),
+    (
+        21,
+        include_str!("../../migrations/mysql/0021_pending_email.sql"),
+    ),
+    (
+        22,
+        include_str!("../../migrations/mysql/0022_audit_pii_hash.sql"),
+    ),
+];
+
+/// Named lock held while migrating, see [`migrate`].
+const MIGRATION_LOCK: &str = "auth_schema_migrations";
+
+pub struct MySqlStorage {
+    db: Arc<DB>,
+}
+
+impl MySqlStorage {
+    /// Wraps the pool of `db` and applies pending migrations.
+    pub fn open(db: Arc<DB>) -> StorageResult<MySqlStorage> {
+        migrate(&mut db.pool.get_conn()?)?;
+        Ok(MySqlStorage { db })
+    }
+}
+
+fn migrate(conn: &mut PooledConn) -> StorageResult<()> {
+    conn.query_drop(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (\
+            version INT NOT NULL PRIMARY KEY, \
+            applied_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP)",
+    )?;
+    // Serialises concurrent instances starting up against the same database.
+    let locked: Option<Option<i64>> =
+        conn.exec_first("SELECT GET_LOCK(?, 60)", (MIGRATION_LOCK,))?;
+    if locked.flatten() != Some(1) {
+        return Err(StorageError::Backend(
+            "timed out waiting for the schema migration lock".to_string(),
+        ));
+    }
+    let applied = apply_migrations(conn);
+    conn.exec_drop("SELECT RELEASE_LOCK(?)", (MIGRATION_LOCK,))?;
+    applied
+}
+
+/// Runs the statements of `sql`, reading every result back as `query_drop` would hide the
+/// errors of all but the first statement.
+fn execute_script(conn: &mut PooledConn, sql: &str) -> StorageResult<()> {
+    let mut result = conn.query_iter(sql)?;
+    while let Some(set) = result.iter() {
+        for row in set {
+            row?;
+        }
+    }
+    Ok(())
+}
+
+fn apply_migrations(conn: &mut PooledConn) -> StorageResult<()> {
+    let current: Option<i64> =
+        conn.query_first("SELECT COALESCE(MAX(version), 0) FROM schema_migrations")?;
+    let current = current.unwrap_or(0);
+
+    for (version, sql) in MIGRATIONS.iter().filter(|(version, _)| *version > current) {
+        // MySQL commits schema changes implicitly, so a failing migration is not rolled back.
+        execute_script(conn, sql)?;
+        conn.exec_drop(
+            "INSERT INTO schema_migrations (version) VALUES (?)",
+            (version,),
+        )?;
+        println!("applied mysql migration {}", version);
+    }
+    Ok(())
+}
+
+impl From<mysql::Error> for StorageError {
+    fn from(err: mysql::Error) -> Self {
+        match &err {
+            mysql::Error::MySqlError(e)
+                if e.code == ER_DUP_ENTRY || e.code == ER_NO_REFERENCED_ROW =>
+            {
+                StorageError::Conflict(err.to_string())
+            }
+            _ => StorageError::Backend(err.to_string()),
+        }
+    }
+}
+
+impl Storage for MySqlStorage {
+    fn begin(&self) -> StorageResult<Box<dyn StorageTx + '_>> {
+        let tx = self.db.pool.start_transaction(TxOpts::default())?;
+        Ok(Box::new(MySqlTx { tx }))
+    }
+}
+
+pub struct MySqlTx {
+    tx: Transaction<'static>,
+}
+
+impl StorageTx for MySqlTx {
+    fn users(&mut self) -> Box<dyn UserRepository + '_> {
+        Box::new(UserStorage { tx: &mut self.tx })
+    }
+
+    fn addresses(&mut self) -> Box<dyn AddressRepository + '_> {
+        Box::new(AddressStorage { tx: &mut self.tx })
+    }
+
+    fn commit(self: Box<Self>) -> StorageResult<()> {
+        Ok(self.tx.commit()?)
+    }
+
+    fn rollback(self: Box<Self>) -> StorageResult<()> {
+        Ok(self.tx.rollback()?)
+    }
+}
+
+impl RealmStore for MySqlTx {
+    fn get_realm_settings(
+        &mut self,
+        realm: &RealmName,
+    ) -> StorageResult<Option<InternalRealmSettings>> {
+        Ok(self
+            .list_realm_settings()?
+            .into_iter()
+            .find(|(name, _)| name == realm)
+            .map(|(_, settings)| settings))
+    }
+
+    fn list_realm_settings(&mut self) -> StorageResult<Vec<(RealmName, InternalRealmSettings)>> {
+        let rows: Vec<Row> = self.tx.query(
+            "SELECT \
+            realm_name, \
+            is_confirmation_required, \
+            is_guest_allowed, \
+            realm_salt_itr, \
+            authentication_token_duration_seconds, \
+            refresh_token_duration_seconds, \
+            password_reset_token_duration_seconds, \
+            password_min_length, \
+            password_max_length, \
+            password_required_classes, \
+            password_max_repeated, \
+            password_reject_user_details, \
+            password_deny_list, \
+            password_history_size, \
+            lockout_max_user_failures, \
+            lockout_max_ip_failures, \
+            lockout_failure_window_seconds, \
+            lockout_duration_seconds, \
+            lockout_max_duration_seconds, \
+            rate_limit_login_per_minute, \
+            rate_limit_registration_per_minute, \
+            rate_limit_password_reset_per_minute, \
+            rate_limit_admin_per_minute, \
+            mfa_required_roles, \
+            webauthn_rp_id, \
+            webauthn_origins, \
+            passwordless_method, \
+            passwordless_ttl_seconds, \
+            passwordless_max_attempts \
+            FROM realm",
+        )?;
+        Ok(rows.iter().map(map_realm_settings).collect())
+    }
+}
+
+// Read by index, the row is wider than the tuples `FromRow` supports.
+fn map_realm_settings(row: &Row) -> (RealmName, InternalRealmSettings) {
+    let seconds = |idx: usize| Duration::from_secs(row.get::<u64, _>(idx).unwrap_or_default());
+    (
+        row.get(0).unwrap_or_default(),
+        InternalRealmSettings {
+            is_confirmation_required: row.get(1).unwrap_or_default(),
+            is_guest_allowed: row.get(2).unwrap_or_default(),
+            realm_salt_itr: row.get(3).unwrap_or_default(),
+            authentication_token_duration: seconds(4),
+            refresh_token_duration: seconds(5),
+            password_reset_token_duration: seconds(6),
+            password_policy: password_policy(
+                row.get(7).unwrap_or_default(),
+                row.get(8).unwrap_or_default(),
+                &row.get::<String, _>(9).unwrap_or_default(),
+                row.get(10).unwrap_or_default(),
+                row.get(11).unwrap_or_default(),
+                &row.get::<Option<String>, _>(12)
+                    .flatten()
+                    .unwrap_or_default(),
+                row.get(13).unwrap_or_default(),
+            ),
+            lockout_policy: lockout_policy(
+                row.get(14).unwrap_or_default(),
+                row.get(15).unwrap_or_default(),
+                row.get(16).unwrap_or_default(),
+                row.get(17).unwrap_or_default(),
+                row.get(18).unwrap_or_default(),
+            ),
+            rate_limits: rate_limits(
+                row.get(19).unwrap_or_default(),
+                row.get(20).unwrap_or_default(),
+                row.get(21).unwrap_or_default(),
+                row.get(22).unwrap_or_default(),
+            ),
+            mfa_policy: mfa_policy(&row.get::<String, _>(23).unwrap_or_default()),
+            webauthn: webauthn_config(
+                row.get::<Option<String>, _>(24).flatten(),
+                &row.get::<String, _>(25).unwrap_or_default(),
+            ),
+            passwordless_policy: passwordless_policy(
+                &row.get::<String, _>(26).unwrap_or_default(),
+                row.get(27).unwrap_or_default(),
+                row.get(28).unwrap_or_default(),
+            ),
+        },
+    )
+}
+
+fn token_column(kind: TokenKind) -> &'static str {
+    match kind {
+        TokenKind::Authentication => "auth_token",
+        TokenKind::PasswordReset => "reset_token",
+        TokenKind::EmailVerification => "verification_token",
+        TokenKind::MfaChallenge => "mfa_token",
+    }
+}
+
+impl TokenStore for MySqlTx {
+    fn store_token(&mut self, user_id: &str, kind: TokenKind, token: &str) -> StorageResult<()> {
+        self.tx.exec_drop(
+            format!(
+                "UPDATE realm_user SET {} = :token WHERE user_id = :user_id",
+                token_column(kind)
+            ),
+            params! { "token" => token, "user_id" => user_id },
+        )?;
+        Ok(())
+    }
+
+    fn find_token(&mut self, user_id: &str, kind: TokenKind) -> StorageResult<Option<String>> {
+        let token: Option<Option<String>> = self.tx.exec_first(
+            format!(
+                "SELECT {} FROM realm_user WHERE user_id = :user_id",
+                token_column(kind)
+            ),
+            params! { "user_id" => user_id },
+        )?;
+        Ok(token.flatten())
+    }
+
+    fn revoke_token(&mut self, user_id: &str, kind: TokenKind) -> StorageResult<()> {
+        self.tx.exec_drop(
+            format!(
+                "UPDATE realm_user SET {} = NULL WHERE user_id = :user_id",
+                token_column(kind)
+            ),
+            params! { "user_id" => user_id },
+        )?;
+        Ok(())
+    }
+
+    fn consume_token(
+        &mut self,
+        user_id: &str,
+        kind: TokenKind,
+        token: &str,
+    ) -> StorageResult<bool> {
+        self.tx.exec_drop(
+            format!(
+                "UPDATE realm_user SET {0} = NULL WHERE user_id = :user_id AND {0} = :token",
+                token_column(kind)
+            ),
+            params! { "user_id" => user_id, "token" => token },
+        )?;
+        Ok(self.tx.affected_rows() > 0)
+    }
+
+    fn stage_email(&mut self, user_id: &str, email: Option<&str>) -> StorageResult<()> {
+        self.tx.exec_drop(
+            "UPDATE realm_user SET pending_email = :email WHERE user_id = :user_id",
+            params! { "email" => email, "user_id" => user_id },
+        )?;
+        Ok(())
+    }
+
+    fn staged_email(&mut self, user_id: &str) -> StorageResult<Option<String>> {
+        let email: Option<Option<String>> = self.tx.exec_first(
+            "SELECT pending_email FROM realm_user WHERE user_id = :user_id",
+            params! { "user_id" => user_id },
+        )?;
+        Ok(email.flatten())
+    }
+}
+
+impl PasswordHistoryStore for MySqlTx {
+    fn password_history(&mut self, user_id: &str, limit: usize) -> StorageResult<Vec<String>> {
+        Ok(self.tx.exec(
+            "SELECT password_hash FROM password_history WHERE user_id = :user_id \
+            ORDER BY id DESC LIMIT :limit",
+            params! { "user_id" => user_id, "limit" => limit as u64 },
+        )?)
+    }
+
+    fn push_password_history(
+        &mut self,
+        user_id: &str,
+        hash: &str,
+        keep: usize,
+    ) -> StorageResult<()> {
+        self.tx.exec_drop(
+            "INSERT INTO password_history (user_id, password_hash, created_at) \
+            VALUES (:user_id, :hash, :created_at)",
+            params! { "user_id" => user_id, "hash" => hash, "created_at" => Utc::now().timestamp() },
+        )?;
+        // MySQL takes no LIMIT in an IN subquery, nor the target table outside a derived one.
+        self.tx.exec_drop(
+            "DELETE FROM password_history WHERE user_id = :user_id AND id NOT IN \
+            (SELECT id FROM (SELECT id FROM password_history WHERE user_id = :user_id \
+            ORDER BY id DESC LIMIT :keep) AS newest)",
+            params! { "user_id" => user_id, "keep" => keep as u64 },
+        )?;
+        Ok(())
+    }
+}
+
+impl LoginAttemptStore for MySqlTx {
+    fn get_attempts(
+        &mut self,
+        realm: &RealmName,
+        key: &AttemptKey,
+    ) -> StorageResult<Option<LoginAttempts>> {
+        let row: Option<(u32, u32, i64, Option<i64>)> = self.tx.exec_first(
+            format!(
+                "{} WHERE realm_name = :realm AND attempt_key = :attempt_key",
+                SELECT_LOGIN_ATTEMPT
+            ),
+            params! { "realm" => realm, "attempt_key" => key.to_string() },
+        )?;
+        Ok(row.map(
+            |(failures, lockouts, last_failure_at, locked_until)| LoginAttempts {
+                failures,
+                lockouts,
+                last_failure_at,
+                locked_until,
+            },
+        ))
+    }
+
+    fn put_attempts(
+        &mut self,
+        realm: &RealmName,
+        key: &AttemptKey,
+        attempts: &LoginAttempts,
+    ) -> StorageResult<()> {
+        self.tx.exec_drop(
+            "INSERT INTO login_attempt \
+            (realm_name, attempt_key, failures, lockouts, last_failure_at, locked_until) \
+            VALUES (:realm, :attempt_key, :failures, :lockouts, :last_failure_at, :locked_until) \
+            ON DUPLICATE KEY UPDATE \
+            failures = VALUES(failures), \
+            lockouts = VALUES(lockouts), \
+            last_failure_at = VALUES(last_failure_at), \
+            locked_until = VALUES(locked_until)",
+            params! {
+                "realm" => realm,
+                "attempt_key" => key.to_string(),
+                "failures" => attempts.failures,
+                "lockouts" => attempts.lockouts,
+                "last_failure_at" => attempts.last_failure_at,
+                "locked_until" => attempts.locked_until,
+            },
+        )?;
+        Ok(())
+    }
+
+    fn clear_attempts(&mut self, realm: &RealmName, key: &AttemptKey) -> StorageResult<()> {
+        self.tx.exec_drop(
+            "DELETE FROM login_attempt WHERE realm_name = :realm AND attempt_key = :attempt_key",
+            params! { "realm" => realm, "attempt_key" => key.to_string() },
+        )?;
+        Ok(())
+    }
+}
+
+impl RateLimitBucketStore for MySqlTx {
+    fn get_bucket(&mut self, realm: &RealmName, key: &str) -> StorageResult<Option<TokenBucket>> {
+        let row: Option<(f64, i64)> = self.tx.exec_first(
+            "SELECT tokens, updated_at FROM rate_limit_bucket \
+            WHERE realm_name = :realm AND bucket_key = :bucket_key FOR UPDATE",
+            params! { "realm" => realm, "bucket_key" => key },
+        )?;
+        Ok(row.map(|(tokens, updated_at)| TokenBucket { tokens, updated_at }))
+    }
+
+    fn put_bucket(
+        &mut self,
+        realm: &RealmName,
+        key: &str,
+        bucket: &TokenBucket,
+    ) -> StorageResult<()> {
+        self.tx.exec_drop(
+            "INSERT INTO rate_limit_bucket (realm_name, bucket_key, tokens, updated_at) \
+            VALUES (:realm, :bucket_key, :tokens, :updated_at) \
+            ON DUPLICATE KEY UPDATE \
+            tokens = VALUES(tokens), \
+            updated_at = VALUES(updated_at)",
+            params! {
+                "realm" => realm,
+                "bucket_key" => key,
+                "tokens" => bucket.tokens,
+                "updated_at" => bucket.updated_at,
+            },
+        )?;
+        Ok(())
+    }
+
+    fn delete_idle_buckets(&mut self, updated_before: i64) -> StorageResult<()> {
+        self.tx.exec_drop(
+            "DELETE FROM rate_limit_bucket WHERE updated_at < :updated_before",
+            params! { "updated_before" => updated_before },
+        )?;
+        Ok(())
+    }
+}
+
+impl MfaStore for MySqlTx {
+    fn get_totp(&mut self, user_id: &str) -> StorageResult<Option<TotpEnrollment>> {
+        let row: Option<(String, Option<i64>, Option<i64>)> = self.tx.exec_first(
+            "SELECT secret, activated_at, last_used_step FROM mfa_totp WHERE user_id = :user_id",
+            params! { "user_id" => user_id },
+        )?;
+        Ok(row.map(
+            |(sealed_secret, activated_at, last_used_step)| TotpEnrollment {
+                sealed_secret,
+                activated_at,
+                last_used_step,
+            },
+        ))
+    }
+
+    fn put_totp(&mut self, user_id: &str, enrollment: &TotpEnrollment) -> StorageResult<()> {
+        self.tx.exec_drop(
+            "INSERT INTO mfa_totp (user_id, secret, activated_at, last_used_step) \
+            VALUES (:user_id, :secret, :activated_at, :last_used_step) \
+            ON DUPLICATE KEY UPDATE \
+            secret = VALUES(secret), \
+            activated_at = VALUES(activated_at), \
+            last_used_step = VALUES(last_used_step)",
+            params! {
+                "user_id" => user_id,
+                "secret" => &enrollment.sealed_secret,
+                "activated_at" => enrollment.activated_at,
+                "last_used_step" => enrollment.last_used_step,
+            },
+        )?;
+        Ok(())
+    }
+
+    fn delete_totp(&mut self, user_id: &str) -> StorageResult<()> {
+        self.tx.exec_drop(
+            "DELETE FROM mfa_totp WHERE user_id = :user_id",
+            params! { "user_id" => user_id },
+        )?;
+        Ok(())
+    }
+
+    fn use_totp_step(&mut self, user_id: &str, step: i64) -> StorageResult<bool> {
+        self.tx.exec_drop(
+            "UPDATE mfa_totp SET last_used_step = :step \
+            WHERE user_id = :user_id AND activated_at IS NOT NULL \
+            AND (last_used_step IS NULL OR last_used_step < :step)",
+            params! { "user_id" => user_id, "step" => step },
+        )?;
+        Ok(self.tx.affected_rows() > 0)
+    }
+
+    fn replace_recovery_codes(
+        &mut self,
+        user_id: &str,
+        codes: &[RecoveryCode],
+    ) -> StorageResult<()> {
+        self.tx.exec_drop(
+            "DELETE FROM mfa_recovery_code WHERE user_id = :user_id",
+            params! { "user_id" => user_id },
+        )?;
+        for code in codes {
+            self.tx.exec_drop(
+                "INSERT INTO mfa_recovery_code (user_id, code_hash, created_at, used_at, used_ip) \
+                VALUES (:user_id, :code_hash, :created_at, :used_at, :used_ip)",
+                params! {
+                    "user_id" => user_id,
+                    "code_hash" => &code.code_hash,
+                    "created_at" => code.created_at,
+                    "used_at" => code.used_at,
+                    "used_ip" => &code.used_ip,
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    fn list_recovery_codes(&mut self, user_id: &str) -> StorageResult<Vec<RecoveryCode>> {
+        let rows: Vec<(String, i64, Option<i64>, Option<String>)> = self.tx.exec(
+            "SELECT code_hash, created_at, used_at, used_ip FROM mfa_recovery_code \
+            WHERE user_id = :user_id ORDER BY id",
+            params! { "user_id" => user_id },
+        )?;
+        Ok(rows
+            .into_iter()
+            .map(|(code_hash, created_at, used_at, used_ip)| RecoveryCode {
+                code_hash,
+                created_at,
+                used_at,
+                used_ip,
+            })
+            .collect())
+    }
+
+    fn use_recovery_code(
+        &mut self,
+        user_id: &str,
+        code_hash: &str,
+        used_at: i64,
+        used_ip: Option<&str>,
+    ) -> StorageResult<bool> {
+        self.tx.exec_drop(
+            "UPDATE mfa_recovery_code SET used_at = :used_at, used_ip = :used_ip \
+            WHERE user_id = :user_id AND code_hash = :code_hash AND used_at IS NULL",
+            params! {
+                "user_id" => user_id,
+                "code_hash" => code_hash,
+                "used_at" => used_at,
+                "used_ip" => used_ip,
+            },
+        )?;
+        Ok(self.tx.affected_rows() > 0)
+    }
+}
+
+impl PasskeyStore for MySqlTx {
+    fn put_challenge(
+        &mut self,
+        realm: &RealmName,
+        challenge: &PendingChallenge,
+        now: i64,
+    ) -> StorageResult<()> {
+        self.tx.exec_drop(
+            "DELETE FROM webauthn_challenge WHERE realm_name = :realm AND expires_at < :now",
+            params! { "realm" => realm, "now" => now },
+        )?;
+        self.tx.exec_drop(
+            "INSERT INTO webauthn_challenge (realm_name, challenge, ceremony, user_id, expires_at) \
+            VALUES (:realm, :challenge, :ceremony, :user_id, :expires_at)",
+            params! {
+                "realm" => realm,
+                "challenge" => &challenge.challenge,
+                "ceremony" => challenge.ceremony.to_string(),
+                "user_id" => &challenge.user_id,
+                "expires_at" => challenge.expires_at,
+            },
+        )?;
+        Ok(())
+    }
+
+    fn take_challenge(
+        &mut self,
+        realm: &RealmName,
+        challenge: &str,
+    ) -> StorageResult<Option<PendingChallenge>> {
+        let row: Option<(String, String, Option<String>, i64)> = self.tx.exec_first(
+            format!(
+                "{} WHERE realm_name = :realm AND challenge = :challenge FOR UPDATE",
+                SELECT_WEBAUTHN_CHALLENGE
+            ),
+            params! { "realm" => realm, "challenge" => challenge },
+        )?;
+        self.tx.exec_drop(
+            "DELETE FROM webauthn_challenge WHERE realm_name = :realm AND challenge = :challenge",
+            params! { "realm" => realm, "challenge" => challenge },
+        )?;
+        row.map(|(challenge, ceremony, user_id, expires_at)| {
+            pending_challenge(challenge, &ceremony, user_id, expires_at)
+        })
+        .transpose()
+    }
+
+    fn add_passkey(&mut self, passkey: &Passkey) -> StorageResult<()> {
+        self.tx.exec_drop(
+            "INSERT INTO webauthn_credential \
+            (credential_id, user_id, public_key, sign_count, name, created_at, last_used_at) \
+            VALUES (:credential_id, :user_id, :public_key, :sign_count, :name, :created_at, :last_used_at)",
+            params! {
+                "credential_id" => &passkey.credential_id,
+                "user_id" => &passkey.user_id,
+                "public_key" => public_key_text(&passkey.public_key),
+                "sign_count" => passkey.sign_count,
+                "name" => &passkey.name,
+                "created_at" => passkey.created_at,
+                "last_used_at" => passkey.last_used_at,
+            },
+        )?;
+        Ok(())
+    }
+
+    fn find_passkey(
+        &mut self,
+        realm: &RealmName,
+        credential_id: &str,
+    ) -> StorageResult<Option<Passkey>> {
+        let row: Option<PasskeyRow> = self.tx.exec_first(
+            format!(
+                "{} JOIN realm_user u ON u.user_id = c.user_id \
+                WHERE c.credential_id = :credential_id AND u.realm_name = :realm \
+                AND u.deleted_at IS NULL",
+                SELECT_PASSKEY
+            ),
+            params! { "credential_id" => credential_id, "realm" => realm },
+        )?;
+        row.map(map_passkey).transpose()
+    }
+
+    fn list_passkeys(&mut self, user_id: &str) -> StorageResult<Vec<Passkey>> {
+        let rows: Vec<PasskeyRow> = self.tx.exec(
+            format!(
+                "{} WHERE c.user_id = :user_id ORDER BY c.created_at, c.credential_id",
+                SELECT_PASSKEY
+            ),
+            params! { "user_id" => user_id },
+        )?;
+        rows.into_iter().map(map_passkey).collect()
+    }
+
+    fn record_passkey_use(
+        &mut self,
+        credential_id: &str,
+        sign_count: u32,
+        used_at: i64,
+    ) -> StorageResult<()> {
+        self.tx.exec_drop(
+            "UPDATE webauthn_credential SET sign_count = :sign_count, last_used_at = :used_at \
+            WHERE credential_id = :credential_id",
+            params! {
+                "credential_id" => credential_id,
+                "sign_count" => sign_count,
+                "used_at" => used_at,
+            },
+        )?;
+        Ok(())
+    }
+
+    fn delete_passkey(&mut self, user_id: &str, credential_id: &str) -> StorageResult<()> {
+        self.tx.exec_drop(
+            "DELETE FROM webauthn_credential WHERE user_id = :user_id AND credential_id = :credential_id",
+            params! { "user_id" => user_id, "credential_id" => credential_id },
+        )?;
+        match self.tx.affected_rows() {
+            0 => Err(StorageError::NotFound),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl PasswordlessStore for MySqlTx {
+    fn get_passwordless(&mut self, user_id: &str) -> StorageResult<Option<PasswordlessCredential>> {
+        let row: Option<(String, String, i64, i64)> = self.tx.exec_first(
+            "SELECT method, secret_hash, expires_at, attempts FROM passwordless_credential \
+            WHERE user_id = :user_id",
+            params! { "user_id" => user_id },
+        )?;
+        row.map(|(method, secret_hash, expires_at, attempts)| {
+            passwordless_credential(&method, secret_hash, expires_at, attempts)
+        })
+        .transpose()
+    }
+
+    fn put_passwordless(
+        &mut self,
+        user_id: &str,
+        credential: &PasswordlessCredential,
+    ) -> StorageResult<()> {
+        self.tx.exec_drop(
+            "INSERT INTO passwordless_credential (user_id, method, secret_hash, expires_at, attempts) \
+            VALUES (:user_id, :method, :secret_hash, :expires_at, :attempts) \
+            ON DUPLICATE KEY UPDATE \
+            method = VALUES(method), \
+            secret_hash = VALUES(secret_hash), \
+            expires_at = VALUES(expires_at), \
+            attempts = VALUES(attempts)",
+            params! {
+                "user_id" => user_id,
+                "method" => credential.method.to_string(),
+                "secret_hash" => &credential.secret_hash,
+                "expires_at" => credential.expires_at,
+                "attempts" => credential.attempts,
+            },
+        )?;
+        Ok(())
+    }
+
+    fn redeem_passwordless(
+        &mut self,
+        user_id: &str,
+        secret_hash: &str,
+        now: i64,
+    ) -> StorageResult<bool> {
+        self.tx.exec_drop(
+            "DELETE FROM passwordless_credential \
+            WHERE user_id = :user_id AND secret_hash = :secret_hash AND expires_at >= :now",
+            params! {
+                "user_id" => user_id,
+                "secret_hash" => secret_hash,
+                "now" => now,
+            },
+        )?;
+        Ok(self.tx.affected_rows() > 0)
+    }
+
+    fn record_passwordless_failure(
+        &mut self,
+        user_id: &str,
+        max_attempts: u32,
+    ) -> StorageResult<()> {
+        self.tx.exec_drop(
+            "UPDATE passwordless_credential SET attempts = attempts + 1 WHERE user_id = :user_id",
+            params! { "user_id" => user_id },
+        )?;
+        self.tx.exec_drop(
+            "DELETE FROM passwordless_credential \
+            WHERE user_id = :user_id AND attempts >= :max_attempts",
+            params! { "user_id" => user_id, "max_attempts" => max_attempts },
+        )?;
+        Ok(())
+    }
+}
+
+impl SessionStore for MySqlTx {
+    fn create_session(&mut self, session: &Session) -> StorageResult<()> {
+        self.tx.exec_drop(
+            "DELETE FROM user_session WHERE user_id = :user_id AND expires_at < :now",
+            params! { "user_id" => &session.user_id, "now" => session.created_at },
+        )?;
+        self.tx.exec_drop(
+            "INSERT INTO user_session \
+            (session_id, realm_name, user_id, refresh_token_hash, user_agent, ip, created_at, \
+            last_used_at, expires_at) \
+            VALUES (:session_id, :realm, :user_id, :refresh_token_hash, :user_agent, :ip, \
+            :created_at, :last_used_at, :expires_at)",
+            params! {
+                "session_id" => &session.session_id,
+                "realm" => &session.realm,
+                "user_id" => &session.user_id,
+                "refresh_token_hash" => &session.refresh_token_hash,
+                "user_agent" => &session.user_agent,
+                "ip" => &session.ip,
+                "created_at" => session.created_at,
+                "last_used_at" => session.last_used_at,
+                "expires_at" => session.expires_at,
+            },
+        )?;
+        Ok(())
+    }
+
+    fn find_session(&mut self, session_id: &str) -> StorageResult<Option<Session>> {
+        let row: Option<SessionRow> = self.tx.exec_first(
+            format!("{} WHERE session_id = :session_id", SELECT_SESSION),
+            params! { "session_id" => session_id },
+        )?;
+        Ok(row.map(map_session))
+    }
+
+    fn list_sessions(&mut self, user_id: &str, now: i64) -> StorageResult<Vec<Session>> {
+        let rows: Vec<SessionRow> = self.tx.exec(
+            format!(
+                "{} WHERE user_id = :user_id AND expires_at >= :now \
+                ORDER BY last_used_at DESC, session_id",
+                SELECT_SESSION
+            ),
+            params! { "user_id" => user_id, "now" => now },
+        )?;
+        Ok(rows.into_iter().map(map_session).collect())
+    }
+
+    fn rotate_session(
+        &mut self,
+        session_id: &str,
+        current_hash: &str,
+        next_hash: &str,
+        used_at: i64,
+        expires_at: i64,
+    ) -> StorageResult<bool> {
+        self.tx.exec_drop(
+            "UPDATE user_session \
+            SET refresh_token_hash = :next_hash, last_used_at = :used_at, expires_at = :expires_at \
+            WHERE session_id = :session_id AND refresh_token_hash = :current_hash",
+            params! {
+                "session_id" => session_id,
+                "current_hash" => current_hash,
+                "next_hash" => next_hash,
+                "used_at" => used_at,
+                "expires_at" => expires_at,
+            },
+        )?;
+        Ok(self.tx.affected_rows() > 0)
+    }
+
+    fn delete_session(&mut self, user_id: &str, session_id: &str) -> StorageResult<()> {
+        self.tx.exec_drop(
+            "DELETE FROM user_session WHERE user_id = :user_id AND session_id = :session_id",
+            params! { "user_id" => user_id, "session_id" => session_id },
+        )?;
+        match self.tx.affected_rows() {
+            0 => Err(StorageError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    fn delete_sessions(&mut self, user_id: &str, keep: Option<&str>) -> StorageResult<()> {
+        self.tx.exec_drop(
+            "DELETE FROM user_session WHERE user_id = :user_id \
+            AND (:keep IS NULL OR session_id <> :keep)",
+            params! { "user_id" => user_id, "keep" => keep },
+        )?;
+        Ok(())
+    }
+}
+
+type SessionRow = (
+    String,
+    String,
+    String,
+    String,
+    Option<String>,
+    Option<String>,
+    i64,
+    i64,
+    i64,
+);
+
+fn map_session(
+    (
+        session_id,
+        realm,
+        user_id,
+        refresh_token_hash,
+        user_agent,
+        ip,
+        created_at,
+        last_used_at,
+        expires_at,
+    ): SessionRow,
+) -> Session {
+    Session {
+        session_id,
+        realm,
+        user_id,
+        refresh_token_hash,
+        user_agent,
+        ip,
+        created_at,
+        last_used_at,
+        expires_at,
+    }
+}
+
+impl AuditStore for MySqlTx {
+    fn append_audit_event(&mut self, event: &AuditEvent) -> StorageResult<()> {
+        // The locked chain row serialises the appends to a realm until commit. It is only
+        // inserted when missing, as the shared lock of a duplicate insert could deadlock.
+        let lock_head = "SELECT head_sequence, head_hash FROM audit_chain \
+            WHERE realm_name = :realm FOR UPDATE";
+        let mut head: Option<(i64, String)> = self
+            .tx
+            .exec_first(lock_head, params! { "realm" => &event.realm })?;
+        if head.is_none() {
+            self.tx.exec_drop(
+                "INSERT IGNORE INTO audit_chain \
+                (realm_name, head_sequence, head_hash, anchor_sequence, anchor_hash) \
+                VALUES (:realm, 0, :genesis, 0, :genesis)",
+                params! { "realm" => &event.realm, "genesis" => GENESIS_HASH },
+            )?;
+            head = self
+                .tx
+                .exec_first(lock_head, params! { "realm" => &event.realm })?;
+        }
+        let (head_sequence, head_hash) = head.ok_or(StorageError::NotFound)?;
+        let link = ChainLink::next(head_sequence, &head_hash, event);
+        self.tx.exec_drop(
+            "INSERT INTO audit_event \
+            (realm_name, event_type, user_id, ip, detail, occurred_at, sequence, prev_hash, entry_hash, \
+            pii_hash, pii_salt) \
+            VALUES (:realm, :event_type, :user_id, :ip, :detail, :occurred_at, \
+            :sequence, :prev_hash, :entry_hash, :pii_hash, :pii_salt)",
+            params! {
+                "realm" => &event.realm,
+                "event_type" => event.event_type.to_string(),
+                "user_id" => &event.user_id,
+                "ip" => &event.ip,
+                "detail" => &event.detail,
+                "occurred_at" => event.occurred_at,
+                "sequence" => link.sequence,
+                "prev_hash" => &link.prev_hash,
+                "entry_hash" => &link.entry_hash,
+                "pii_hash" => &link.pii_hash,
+                "pii_salt" => &link.pii_salt,
+            },
+        )?;
+        self.tx.exec_drop(
+            "UPDATE audit_chain SET head_sequence = :sequence, head_hash = :entry_hash \
+            WHERE realm_name = :realm",
+            params! {
+                "realm" => &event.realm,
+                "sequence" => link.sequence,
+                "entry_hash" => &link.entry_hash,
+            },
+        )?;
+        Ok(())
+    }
+
+    fn query_audit_events(
+        &mut self,
+        realm: &RealmName,
+        query: &AuditQuery,
+        limit: u32,
+    ) -> StorageResult<Vec<AuditRecord>> {
+        let (filter, mut params) = audit_filter(realm, query, |_| "?".to_string());
+        params.push(SqlValue::Int(limit as i64));
+        let rows: Vec<Row> = self.tx.exec(
+            format!("{} {} ORDER BY id DESC LIMIT ?", SELECT_AUDIT_EVENT, filter),
+            as_params(params),
+        )?;
+        rows.iter().map(audit_columns).map(audit_record).collect()
+    }
+
+    fn audit_chain(&mut self, realm: &RealmName) -> StorageResult<Option<AuditChain>> {
+        let row: Option<AuditChainColumns> = self.tx.exec_first(
+            format!("{} WHERE realm_name = :realm", SELECT_AUDIT_CHAIN),
+            params! { "realm" => realm },
+        )?;
+        Ok(row.map(audit_chain))
+    }
+
+    fn audit_chains(&mut self) -> StorageResult<Vec<AuditChain>> {
+        let rows: Vec<AuditChainColumns> = self
+            .tx
+            .query(format!("{} ORDER BY realm_name", SELECT_AUDIT_CHAIN))?;
+        Ok(rows.into_iter().map(audit_chain).collect())
+    }
+
+    fn audit_chain_entries(
+        &mut self,
+        realm: &RealmName,
+        after_sequence: i64,
+        limit: u32,
+    ) -> StorageResult<Vec<AuditRecord>> {
+        let rows: Vec<Row> = self.tx.exec(
+            format!(
+                "{} WHERE realm_name = :realm AND sequence > :after ORDER BY sequence LIMIT :limit",
+                SELECT_AUDIT_EVENT
+            ),
+            params! { "realm" => realm, "after" => after_sequence, "limit" => limit },
+        )?;
+        rows.iter().map(audit_columns).map(audit_record).collect()
+    }
+
+    fn first_audit_sequence_since(
+        &mut self,
+        realm: &RealmName,
+        since: i64,
+    ) -> StorageResult<Option<i64>> {
+        let sequence: Option<Option<i64>> = self.tx.exec_first(
+            "SELECT MIN(sequence) FROM audit_event \
+            WHERE realm_name = :realm AND occurred_at >= :since",
+            params! { "realm" => realm, "since" => since },
+        )?;
+        Ok(sequence.flatten())
+    }
+
+    fn truncate_audit_chain(
+        &mut self,
+        realm: &RealmName,
+        anchor: &ChainLink,
+    ) -> StorageResult<u64> {
+        self.tx.exec_drop(
+            "DELETE FROM audit_event WHERE realm_name = :realm AND sequence <= :sequence",
+            params! { "realm" => realm, "sequence" => anchor.sequence },
+        )?;
+        let removed = self.tx.affected_rows();
+        self.tx.exec_drop(
+            "UPDATE audit_chain SET anchor_sequence = :sequence, anchor_hash = :entry_hash \
+            WHERE realm_name = :realm",
+            params! {
+                "realm" => realm,
+                "sequence" => anchor.sequence,
+                "entry_hash" => &anchor.entry_hash,
+            },
+        )?;
+        Ok(removed)
+    }
+
+    fn prune_audit_events(&mut self, before: i64) -> StorageResult<u64> {
+        self.tx.exec_drop(
+            "DELETE FROM audit_event WHERE sequence IS NULL AND occurred_at < :before",
+            params! { "before" => before },
+        )?;
+        Ok(self.tx.affected_rows())
+    }
+
+    fn redact_audit_events(
+        &mut self,
+        realm: &RealmName,
+        user_id: &str,
+        pseudonym: &str,
+        redacted_at: i64,
+    ) -> StorageResult<u64> {
+        self.tx.exec_drop(
+            "UPDATE audit_event \
+            SET user_id = :pseudonym, ip = NULL, detail = NULL, pii_salt = NULL, \
+            redacted_at = :redacted_at \
+            WHERE realm_name = :realm AND user_id = :user_id",
+            params! {
+                "realm" => realm,
+                "user_id" => user_id,
+                "pseudonym" => pseudonym,
+                "redacted_at" => redacted_at,
+            },
+        )?;
+        Ok(self.tx.affected_rows())
+    }
+}
+
+// Read by index, the row is wider than the tuples `FromRow` supports.
+fn audit_columns(row: &Row) -> AuditColumns {
+    (
+        row.get(0).unwrap_or_default(),
+        row.get(1).unwrap_or_default(),
+        row.get(2).unwrap_or_default(),
+        row.get(3).flatten(),
+        row.get(4).flatten(),
+        row.get(5).flatten(),
+        row.get(6).unwrap_or_default(),
+        row.get(7).flatten(),
+        row.get(8).flatten(),
+        row.get(9).flatten(),
+        row.get(10).flatten(),
+        row.get(11).flatten(),
+        row.get(12).flatten(),
+    )
+}
+
+impl WebhookStore for MySqlTx {
+    fn create_subscription(&mut self, subscription: &WebhookSubscription) -> StorageResult<()> {
+        self.tx.exec_drop(
+            "INSERT INTO webhook_subscription \
+            (subscription_id, realm_name, url, sealed_secret, event_types, created_at) \
+            VALUES (:subscription_id, :realm, :url, :sealed_secret, :event_types, :created_at)",
+            params! {
+                "subscription_id" => &subscription.subscription_id,
+                "realm" => &subscription.realm,
+                "url" => &subscription.url,
+                "sealed_secret" => &subscription.sealed_secret,
+                "event_types" => event_types_text(&subscription.event_types),
+                "created_at" => subscription.created_at,
+            },
+        )?;
+        Ok(())
+    }
+
+    fn list_subscriptions(&mut self, realm: &RealmName) -> StorageResult<Vec<WebhookSubscription>> {
+        let rows: Vec<WebhookSubscriptionColumns> = self.tx.exec(
+            format!(
+                "{} WHERE realm_name = :realm ORDER BY created_at, subscription_id",
+                SELECT_WEBHOOK_SUBSCRIPTION
+            ),
+            params! { "realm" => realm },
+        )?;
+        Ok(rows.into_iter().map(webhook_subscription).collect())
+    }
+
+    fn find_subscription(
+        &mut self,
+        subscription_id: &str,
+    ) -> StorageResult<Option<WebhookSubscription>> {
+        let row: Option<WebhookSubscriptionColumns> = self.tx.exec_first(
+            format!(
+                "{} WHERE subscription_id = :subscription_id",
+                SELECT_WEBHOOK_SUBSCRIPTION
+            ),
+            params! { "subscription_id" => subscription_id },
+        )?;
+        Ok(row.map(webhook_subscription))
+    }
+
+    fn delete_subscription(
+        &mut self,
+        realm: &RealmName,
+        subscription_id: &str,
+    ) -> StorageResult<()> {
+        self.tx.exec_drop(
+            "DELETE FROM webhook_subscription \
+            WHERE realm_name = :realm AND subscription_id = :subscription_id",
+            params! { "realm" => realm, "subscription_id" => subscription_id },
+        )?;
+        match self.tx.affected_rows() {
+            0 => Err(StorageError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    fn enqueue_delivery(&mut self, delivery: &WebhookDelivery) -> StorageResult<()> {
+        // Ignored if the subscription has the event already.
+        self.tx.exec_drop(
+            "INSERT IGNORE INTO webhook_delivery \
+            (delivery_id, realm_name, subscription_id, event_id, event_type, payload, status, \
+            attempts, next_attempt_at, last_error, created_at, delivered_at) \
+            VALUES (:delivery_id, :realm, :subscription_id, :event_id, :event_type, :payload, \
+            :status, :attempts, :next_attempt_at, :last_error, :created_at, :delivered_at)",
+            params! {
+                "delivery_id" => &delivery.delivery_id,
+                "realm" => &delivery.realm,
+                "subscription_id" => &delivery.subscription_id,
+                "event_id" => &delivery.event_id,
+                "event_type" => delivery.event_type.to_string(),
+                "payload" => &delivery.payload,
+                "status" => delivery.status.to_string(),
+                "attempts" => delivery.attempts,
+                "next_attempt_at" => delivery.next_attempt_at,
+                "last_error" => &delivery.last_error,
+                "created_at" => delivery.created_at,
+                "delivered_at" => delivery.delivered_at,
+            },
+        )?;
+        Ok(())
+    }
+
+    fn claim_due_deliveries(
+        &mut self,
+        now: i64,
+        lease_until: i64,
+        limit: u32,
+    ) -> StorageResult<Vec<WebhookDelivery>> {
+        // Rows claimed by a concurrent dispatcher are skipped rather than waited for.
+        let rows: Vec<WebhookDeliveryColumns> = self.tx.exec(
+            format!(
+                "{} WHERE status = :status AND next_attempt_at <= :now \
+                ORDER BY next_attempt_at, created_at LIMIT :limit FOR UPDATE SKIP LOCKED",
+                SELECT_WEBHOOK_DELIVERY
+            ),
+            params! {
+                "status" => DeliveryStatus::Pending.to_string(),
+                "now" => now,
+                "limit" => limit,
+            },
+        )?;
+        let deliveries = rows
+            .into_iter()
+            .map(webhook_delivery)
+            .collect::<StorageResult<Vec<_>>>()?;
+        for delivery in &deliveries {
+            self.tx.exec_drop(
+                "UPDATE webhook_delivery SET next_attempt_at = :lease_until \
+                WHERE delivery_id = :delivery_id",
+                params! { "delivery_id" => &delivery.delivery_id, "lease_until" => lease_until },
+            )?;
+        }
+        Ok(deliveries)
+    }
+
+    fn update_delivery(&mut self, delivery: &WebhookDelivery) -> StorageResult<()> {
+        // affected_rows() only counts changed rows, so it cannot tell a missing delivery apart.
+        let found: Option<String> = self.tx.exec_first(
+            "SELECT delivery_id FROM webhook_delivery WHERE delivery_id = :delivery_id",
+            params! { "delivery_id" => &delivery.delivery_id },
+        )?;
+        if found.is_none() {
+            return Err(StorageError::NotFound);
+        }
+        self.tx.exec_drop(
+            "UPDATE webhook_delivery \
+            SET status = :status, attempts = :attempts, next_attempt_at = :next_attempt_at, \
+            last_error = :last_error, delivered_at = :delivered_at \
+            WHERE delivery_id = :delivery_id",
+            params! {
+                "delivery_id" => &delivery.delivery_id,
+                "status" => delivery.status.to_string(),
+                "attempts" => delivery.attempts,
+                "next_attempt_at" => delivery.next_attempt_at,
+                "last_error" => &delivery.last_error,
+                "delivered_at" => delivery.delivered_at,
+            },
+        )?;
+        Ok(())
+    }
+
+    fn find_delivery(
+        &mut self,
+        realm: &RealmName,
+        delivery_id: &str,
+    ) -> StorageResult<Option<WebhookDelivery>> {
+        let row: Option<WebhookDeliveryColumns> = self.tx.exec_first(
+            format!(
+                "{} WHERE realm_name = :realm AND delivery_id = :delivery_id",
+                SELECT_WEBHOOK_DELIVERY
+            ),
+            params! { "realm" => realm, "delivery_id" => delivery_id },
+        )?;
+        row.map(webhook_delivery).transpose()
+    }
+
+    fn list_deliveries(
+        &mut self,
+        realm: &RealmName,
+        status: DeliveryStatus,
+        limit: u32,
+    ) -> StorageResult<Vec<WebhookDelivery>> {
+        let rows: Vec<WebhookDeliveryColumns> = self.tx.exec(
+            format!(
+                "{} WHERE realm_name = :realm AND status = :status \
+                ORDER BY created_at DESC, delivery_id LIMIT :limit",
+                SELECT_WEBHOOK_DELIVERY
+            ),
+            params! { "realm" => realm, "status" => status.to_string(), "limit" => limit },
+        )?;
+        rows.into_iter().map(webhook_delivery).collect()
+    }
+
+    fn find_deliveries_containing(
+        &mut self,
+        realm: &RealmName,
+        text: &str,
+    ) -> StorageResult<Vec<WebhookDelivery>> {
+        let rows: Vec<WebhookDeliveryColumns> = self.tx.exec(
+            format!(
+                "{} WHERE realm_name = :realm AND LOCATE(:text, payload) > 0 ORDER BY created_at",
+                SELECT_WEBHOOK_DELIVERY
+            ),
+            params! { "realm" => realm, "text" => text },
+        )?;
+        rows.into_iter().map(webhook_delivery).collect()
+    }
+
+    fn replace_delivery_payload(&mut self, delivery_id: &str, payload: &str) -> StorageResult<()> {
+        let found: Option<String> = self.tx.exec_first(
+            "SELECT delivery_id FROM webhook_delivery WHERE delivery_id = :delivery_id",
+            params! { "delivery_id" => delivery_id },
+        )?;
+        if found.is_none() {
+            return Err(StorageError::NotFound);
+        }
+        self.tx.exec_drop(
+            "UPDATE webhook_delivery SET payload = :payload WHERE delivery_id = :delivery_id",
+            params! { "delivery_id" => delivery_id, "payload" => payload },
+        )?;
+        Ok(())
+    }
+}
+
+impl OutboxStore for MySqlTx {
+    fn append_outbox_event(&mut self, event: &OutboxEvent) -> StorageResult<()> {
+        self.tx.exec_drop(
+            "INSERT INTO outbox_event \
+            (event_id, realm_name, aggregate_type, aggregate_id, event_type, payload, \
+            occurred_at, attempts, next_attempt_at) \
+            VALUES (:event_id, :realm, :aggregate_type, :aggregate_id, :event_type, :payload, \
+            :occurred_at, 0, :occurred_at)",
+            params! {
+                "event_id" => &event.event_id,
+                "realm" => &event.realm,
+                "aggregate_type" => &event.aggregate_type,
+                "aggregate_id" => &event.aggregate_id,
+                "event_type" => &event.event_type,
+                "payload" => event.payload.to_string(),
+                "occurred_at" => event.occurred_at,
+            },
+        )?;
+        Ok(())
+    }
+
+    fn claim_outbox_events(
+        &mut self,
+        now: i64,
+        lease_until: i64,
+        limit: u32,
+    ) -> StorageResult<Vec<OutboxRecord>> {
+        // An event claimed by a concurrent dispatcher is skipped, and holds back the rest of its
+        // aggregate as it is still unpublished.
+        let rows: Vec<OutboxColumns> = self.tx.exec(
+            format!(
+                "{} WHERE {} AND next_attempt_at <= :now \
+                ORDER BY id LIMIT :limit FOR UPDATE SKIP LOCKED",
+                SELECT_OUTBOX_EVENT, OUTBOX_HEAD_FILTER
+            ),
+            params! { "now" => now, "limit" => limit },
+        )?;
+        let records = rows
+            .into_iter()
+            .map(outbox_record)
+            .collect::<StorageResult<Vec<_>>>()?;
+        for record in &records {
+            self.tx.exec_drop(
+                "UPDATE outbox_event SET next_attempt_at = :lease_until WHERE id = :id",
+                params! { "id" => record.id, "lease_until" => lease_until },
+            )?;
+        }
+        Ok(records)
+    }
+
+    fn update_outbox_event(&mut self, record: &OutboxRecord) -> StorageResult<()> {
+        // affected_rows() only counts changed rows, so it cannot tell a missing event apart.
+        let found: Option<i64> = self.tx.exec_first(
+            "SELECT id FROM outbox_event WHERE id = :id",
+            params! { "id" => record.id },
+        )?;
+        if found.is_none() {
+            return Err(StorageError::NotFound);
+        }
+        self.tx.exec_drop(
+            "UPDATE outbox_event \
+            SET attempts = :attempts, next_attempt_at = :next_attempt_at, \
+            last_error = :last_error, published_at = :published_at \
+            WHERE id = :id",
+            params! {
+                "id" => record.id,
+                "attempts" => record.attempts,
+                "next_attempt_at" => record.next_attempt_at,
+                "last_error" => &record.last_error,
+                "published_at" => record.published_at,
+            },
+        )?;
+        Ok(())
+    }
+
+    fn prune_outbox(&mut self, before: i64) -> StorageResult<u64> {
+        self.tx.exec_drop(
+            "DELETE FROM outbox_event WHERE published_at < :before",
+            params! { "before" => before },
+        )?;
+        Ok(self.tx.affected_rows())
+    }
+
+    fn redact_outbox_events(
+        &mut self,
+        realm: &RealmName,
+        aggregate_type: &str,
+        aggregate_id: &str,
+        payload: &serde_json::Value,
+    ) -> StorageResult<u64> {
+        self.tx.exec_drop(
+            "UPDATE outbox_event SET payload = :payload \
+            WHERE realm_name = :realm AND aggregate_type = :aggregate_type \
+            AND aggregate_id = :aggregate_id",
+            params! {
+                "realm" => realm,
+                "aggregate_type" => aggregate_type,
+                "aggregate_id" => aggregate_id,
+                "payload" => payload.to_string(),
+            },
+        )?;
+        Ok(self.tx.affected_rows())
+    }
+}
+
+type PasskeyRow = (
+    String,
+    String,
+    String,
+    i64,
+    Option<String>,
+    i64,
+    Option<i64>,
+);
+
+fn map_passkey(
+    (credential_id, user_id, public_key, sign_count, name, created_at, last_used_at): PasskeyRow,
+) -> StorageResult<Passkey> {
+    passkey(
+        credential_id,
+        user_id,
+        &public_key,
+        sign_count,
+        name,
+        created_at,
+        last_used_at,
+    )
+}
+
+type SummaryRow = (
+    String,
+    String,
+    Option<String>,
+    Option<String>,
+    bool,
+    Role,
+    i64,
+    Option<i64>,
+);
+
+fn map_summary(
+    (user_id, username, name, email, email_verified, role, created_at, deleted_at): SummaryRow,
+) -> UserProfile {
+    UserProfile {
+        user_id,
+        username,
+        name,
+        email,
+        email_verified,
+        role,
+        addresses: Vec::new(),
+        created_at,
+        status: match deleted_at {
+            Some(_) => UserStatus::Disabled,
+            None => UserStatus::Active,
+        },
+    }
+}
+
+fn as_params(params: Vec<SqlValue>) -> Params {
+    Params::Positional(
+        params
+            .into_iter()
+            .map(|param| match param {
+                SqlValue::Text(value) => Value::from(value),
+                SqlValue::Int(value) => Value::from(value),
+            })
+            .collect(),
+    )
+}
+
+pub struct UserStorage<'t> {
+    tx: &'t mut Transaction<'static>,
+}
+
+pub struct AddressStorage<'t> {
+    tx: &'t mut Transaction<'static>,
+}
+
+const SELECT_USER: &str = "SELECT \
+    user_id, \
+    username, \
+    password, \
+    role \
+    FROM realm_user WHERE deleted_at IS NULL";
+
+fn map_user((user_id, username, password, role): (String, String, String, Role)) -> User {
+    User {
+        user_id,
+        username,
+        hashed_pass: password,
+        role,
+    }
+}
+
+impl<'t> Repository<User> for UserStorage<'t> {
+    type ID = String;
+    type CreationData = CreateUser;
+    type UpdateData = UserMetadata;
+
+    fn get(&mut self, realm: &RealmName, id: &Self::ID) -> StorageResult<Option<User>> {
+        Ok(self
+            .tx
+            .exec_first(
+                format!(
+                    "{} AND user_id = :user_id AND realm_name = :realm",
+                    SELECT_USER
+                ),
+                params! { "user_id" => id, "realm" => realm },
+            )?
+            .map(map_user))
+    }
+
+    fn list(&mut self, realm: &RealmName) -> StorageResult<Vec<User>> {
+        Ok(self.tx.exec_map(
+            format!("{} AND realm_name = :realm", SELECT_USER),
+            params! { "realm" => realm },
+            map_user,
+        )?)
+    }
+
+    fn create(&mut self, realm: &RealmName, data: Self::CreationData) -> StorageResult<Self::ID> {
+        let user_id = Uuid::new_v4().to_string();
+
+        self.tx.exec_drop(
+            "INSERT INTO realm_user (realm_name, user_id, username, role, name, password, email, expires_at, is_god, created_at) \
+            VALUES (:realm, :user_id, :username, :role, :name, :password, :email, DATE_ADD(NOW(), INTERVAL 1 YEAR), 0, :created_at)",
+            params! {
+                "realm" => realm,
+                "user_id" => &user_id,
+                "username" => &data.username,
+                "role" => Role::CUSTOMER.to_string(),
+                "name" => &data.name,
+                "password" => &data.password,
+                "email" => &data.email,
+                "created_at" => Utc::now().timestamp(),
+            },
+        )?;
+
+        Ok(user_id)
+    }
+
+    fn update(
+        &mut self,
+        realm: &RealmName,
+        id: &Self::ID,
+        data: Self::UpdateData,
+    ) -> StorageResult<()> {
+        if self.get(realm, id)?.is_none() {
+            return Err(StorageError::NotFound);
+        }
+
+        self.tx.exec_drop(
+            "UPDATE realm_user \
+            SET name = COALESCE(:name, name), \
+                email = COALESCE(:email, email), \
+                email_verified = COALESCE(:email_verified, email_verified), \
+                role = COALESCE(:role, role), \
+                password = COALESCE(:password, password) \
+            WHERE user_id = :user_id AND realm_name = :realm",
+            params! {
+                "name" => &data.name,
+                "email" => &data.email,
+                "email_verified" => data.email_verified,
+                "role" => data.role.as_ref().map(Role::to_string),
+                "password" => &data.password,
+                "user_id" => id,
+                "realm" => realm,
+            },
+        )?;
+        Ok(())
+    }
+
+    fn delete(&mut self, realm: &RealmName, id: &Self::ID) -> StorageResult<()> {
+        self.tx.exec_drop(
+            "DELETE FROM realm_user WHERE user_id = :user_id AND realm_name = :realm",
+            params! { "user_id" => id, "realm" => realm },
+        )?;
+
+        match self.tx.affected_rows() {
+            0 => Err(StorageError::NotFound),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<'t> UserStorage<'t> {
+    fn query_summaries(
+        &mut self,
+        sql: &str,
+        params: Vec<SqlValue>,
+    ) -> StorageResult<Vec<UserProfile>> {
+        let users = self.tx.exec_map(sql, as_params(params), map_summary)?;
+        self.with_addresses(users)
+    }
+
+    /// Fills in the addresses of each user with a separate query, so users without one are kept.
+    fn with_addresses(&mut self, mut users: Vec<UserProfile>) -> StorageResult<Vec<UserProfile>> {
+        if users.is_empty() {
+            return Ok(users);
+        }
+        let rows: Vec<(String, UserAddressRow)> = self.tx.exec_map(
+            format!(
+                "{} WHERE user_id IN {} {}",
+                SELECT_USER_ADDRESS,
+                in_list(1, users.len(), |_| "?".to_string()),
+                ORDER_USER_ADDRESS
+            ),
+            as_params(
+                users
+                    .iter()
+                    .map(|u| SqlValue::Text(u.user_id.clone()))
+                    .collect(),
+            ),
+            |(user_id, address_id, address_type, is_default, street, city, post_code, country)| {
+                (
+                    user_id,
+                    (
+                        address_id,
+                        address_type,
+                        is_default,
+                        street,
+                        city,
+                        post_code,
+                        country,
+                    ),
+                )
+            },
+        )?;
+
+        for (user_id, row) in rows {
+            if let Some(user) = users.iter_mut().find(|u| u.user_id == user_id) {
+                user.addresses.push(map_user_address(row)?);
+            }
+        }
+        Ok(users)
+    }
+}
+
+impl<'t> UserRepository for UserStorage<'t> {
+    fn disable(&mut self, realm: &RealmName, id: &str, deleted_at: i64) -> StorageResult<()> {
+        self.tx.exec_drop(
+            "UPDATE realm_user \
+            SET deleted_at = :deleted_at, \
+                auth_token = NULL, \
+                reset_token = NULL, \
+                verification_token = NULL, \
+                mfa_token = NULL, \
+                pending_email = NULL \
+            WHERE user_id = :user_id AND realm_name = :realm AND deleted_at IS NULL",
+            params! { "deleted_at" => deleted_at, "user_id" => id, "realm" => realm },
+        )?;
+
+        match self.tx.affected_rows() {
+            0 => Err(StorageError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    fn get_profile(&mut self, realm: &RealmName, id: &str) -> StorageResult<Option<UserProfile>> {
+        let users = self.query_summaries(
+            &format!(
+                "{} WHERE user_id = ? AND realm_name = ? AND deleted_at IS NULL",
+                SELECT_USER_SUMMARY
+            ),
+            vec![
+                SqlValue::Text(id.to_string()),
+                SqlValue::Text(realm.clone()),
+            ],
+        )?;
+        Ok(users.into_iter().next())
+    }
+
+    fn get_by_name(&mut self, realm: &RealmName, username: &str) -> StorageResult<Option<User>> {
+        Ok(self
+            .tx
+            .exec_first(
+                format!(
+                    "{} AND username = :username AND realm_name = :realm",
+                    SELECT_USER
+                ),
+                params! { "username" => username, "realm" => realm },
+            )?
+            .map(map_user))
+    }
+
+    fn search(
+        &mut self,
+        realm: &RealmName,
+        query: &UserQuery,
+        after: Option<&PageCursor>,
+        limit: u32,
+    ) -> StorageResult<Vec<UserProfile>> {
+        let (filter, mut params) = user_search_filter(realm, query, after, |_| "?".to_string());
+        params.push(SqlValue::Int(limit as i64));
+        self.query_summaries(
+            &format!(
+                "{} {} {} LIMIT ?",
+                SELECT_USER_SUMMARY,
+                filter,
+                user_search_order(query)
+            ),
+            params,
+        )
+    }
+
+    fn count(&mut self, realm: &RealmName, query: &UserQuery) -> StorageResult<u64> {
+        let (filter, params) = user_search_filter(realm, query, None, |_| "?".to_string());
+        let count: Option<u64> = self.tx.exec_first(
+            format!("SELECT COUNT(*) FROM realm_user {}", filter),
+            as_params(params),
+        )?;
+        Ok(count.unwrap_or_default())
+    }
+}
+
+const SELECT_ADDRESS: &str = "SELECT \
+    a.address_id, \
+    a.address_type, \
+    a.is_default, \
+    a.street, \
+    a.city, \
+    a.post_code, \
+    a.country \
+    FROM address a \
+    INNER JOIN realm_user u on u.user_id = a.user_id \
+    WHERE u.deleted_at IS NULL";
+
+type UserAddressRow = (
+    String,
+    String,
+    bool,
+    String,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+);
+
+fn map_user_address(
+    (address_id, address_type, is_default, street, city, post_code, country): UserAddressRow,
+) -> StorageResult<UserAddress> {
+    Ok(UserAddress {
+        address_id,
+        address_type: AddressType::from_str(&address_type)
+            .map_err(|e| StorageError::Backend(e.to_string()))?,
+        is_default,
+        address: Address {
+            street,
+            country: country.unwrap_or_default(),
+            city: city.unwrap_or_default(),
+            post_code: post_code.unwrap_or_default(),
+        },
+    })
+}
+
+impl<'t> AddressStorage<'t> {
+    /// Makes `address_id` the only default address of its type.
+    fn clear_other_defaults(
+        &mut self,
+        user_id: &str,
+        address_type: AddressType,
+        address_id: &str,
+    ) -> StorageResult<()> {
+        self.tx.exec_drop(
+            "UPDATE address SET is_default = 0 \
+            WHERE user_id = :user_id AND address_type = :address_type AND address_id <> :address_id",
+            params! {
+                "user_id" => user_id,
+                "address_type" => address_type.to_string(),
+                "address_id" => address_id,
+            },
+        )?;
+        Ok(())
+    }
+}
+
+impl<'t> Repository<UserAddress> for AddressStorage<'t> {
+    type ID = String;
+    type CreationData = (AddressData, String);
+    type UpdateData = AddressData;
+
+    fn get(&mut self, realm: &RealmName, id: &Self::ID) -> StorageResult<Option<UserAddress>> {
+        self.tx
+            .exec_first(
+                format!(
+                    "{} AND a.address_id = :address_id AND u.realm_name = :realm",
+                    SELECT_ADDRESS
+                ),
+                params! { "address_id" => id, "realm" => realm },
+            )?
+            .map(map_user_address)
+            .transpose()
+    }
+
+    fn list(&mut self, realm: &RealmName) -> StorageResult<Vec<UserAddress>> {
+        self.tx
+            .exec(
+                format!("{} AND u.realm_name = :realm", SELECT_ADDRESS),
+                params! { "realm" => realm },
+            )?
+            .into_iter()
+            .map(map_user_address)
+            .collect()
+    }
+
+    fn create(&mut self, realm: &RealmName, data: Self::CreationData) -> StorageResult<Self::ID> {
+        let (data, user_id) = data;
+        let owner: Option<String> = self.tx.exec_first(
+            "SELECT user_id FROM realm_user WHERE user_id = :user_id AND realm_name = :realm AND deleted_at IS NULL",
+            params! { "user_id" => &user_id, "realm" => realm },
+        )?;
+        if owner.is_none() {
+            return Err(StorageError::NotFound);
+        }
+
+        let address_id = Uuid::new_v4().to_string();
+        let address = &data.address;
+        self.tx.exec_drop(
+            "INSERT INTO address (\
+            address_id, \
+            user_id, \
+            street, \
+            city, \
+            post_code, \
+            country, \
+            country_code, \
+            address_type, \
+            is_default) \
+            VALUES (\
+             :address_id, \
+             :user_id, \
+             :street, \
+             :city, \
+             :post_code, \
+             :country, \
+             :country_code, \
+             :address_type, \
+             :is_default)",
+            params! {
+            "address_id" => &address_id,
+            "user_id" => &user_id,
+            "street" => &address.street,
+            "city" => &address.city,
+            "post_code" => &address.post_code,
+            "country" => &address.country,
+            "country_code" => &address.country,
+            "address_type" => data.address_type.to_string(),
+            "is_default" => data.is_default },
+        )?;
+        if data.is_default {
+            self.clear_other_defaults(&user_id, data.address_type, &address_id)?;
+        }
+
+        Ok(address_id)
+    }
+
+    fn update(
+        &mut self,
+        realm: &RealmName,
+        id: &Self::ID,
+        data: Self::UpdateData,
+    ) -> StorageResult<()> {
+        if self.get(realm, id)?.is_none() {
+            return Err(StorageError::NotFound);
+        }
+
+        let address = &data.address;
+        self.tx.exec_drop(
+            "UPDATE address \
+            SET street = :street, \
+                city = :city, \
+                post_code = :post_code, \
+                country = :country, \
+                address_type = :address_type, \
+                is_default = :is_default \
+            WHERE address_id = :address_id",
+            params! {
+                "street" => &address.street,
+                "city" => &address.city,
+                "post_code" => &address.post_code,
+                "country" => &address.country,
+                "address_type" => data.address_type.to_string(),
+                "is_default" => data.is_default,
+                "address_id" => id,
+            },
+        )?;
+        if data.is_default {
+            let user_id: Option<String> = self.tx.exec_first(
+                "SELECT user_id FROM address WHERE address_id = :address_id",
+                params! { "address_id" => id },
+            )?;
+            let user_id = user_id.ok_or(StorageError::NotFound)?;
+            self.clear_other_defaults(&user_id, data.address_type, id)?;
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self, realm: &RealmName, id: &Self::ID) -> StorageResult<()> {
+        if self.get(realm, id)?.is_none() {
+            return Err(StorageError::NotFound);
+        }
+
+        self.tx.exec_drop(
+            "DELETE FROM address WHERE address_id = :address_id",
+            params! { "address_id" => id },
+        )?;
+        Ok(())
+    }
+}
+
+impl<'t> AddressRepository for AddressStorage<'t> {
+    fn list_for_user(
+        &mut self,
+        realm: &RealmName,
+        user_id: &str,
+    ) -> StorageResult<Vec<UserAddress>> {
+        self.tx
+            .exec(
+                format!(
+                    "{} AND a.user_id = :user_id AND u.realm_name = :realm {}",
+                    SELECT_ADDRESS, ORDER_USER_ADDRESS
+                ),
+                params! { "user_id" => user_id, "realm" => realm },
+            )?
+            .into_iter()
+            .map(map_user_address)
+            .collect()
+    }
+}
@@ -0,0 +1,1931 @@
+use crate::config::PoolConfig;
+use crate::domain::audit::{
+    AuditChain, AuditEvent, AuditQuery, AuditRecord, ChainLink, GENESIS_HASH,
+};
+use crate::domain::customer::dto::{AddressData, CreateUser, PageCursor, UserMetadata, UserQuery};
+use crate::domain::customer::{
+    Address, AddressType, Role, User, UserAddress, UserProfile, UserStatus,
+};
+use crate::domain::lockout::{AttemptKey, LoginAttempts};
+use crate::domain::mfa::{RecoveryCode, TotpEnrollment};
+use crate::domain::outbox::{OutboxEvent, OutboxRecord};
+use crate::domain::passwordless::PasswordlessCredential;
+use crate::domain::rate_limit::TokenBucket;
+use crate::domain::realm::{InternalRealmSettings, RealmName};
+use crate::domain::session::Session;
+use crate::domain::webauthn::{Passkey, PendingChallenge};
+use crate::domain::webhook::{DeliveryStatus, WebhookDelivery, WebhookSubscription};
+use crate::repository::sql::{
+    audit_chain, audit_filter, audit_record, event_types_text, in_list, lockout_policy, mfa_policy,
+    outbox_record, passkey, password_policy, passwordless_credential, passwordless_policy,
+    pending_challenge, public_key_text, rate_limits, user_search_filter, user_search_order,
+    webauthn_config, webhook_delivery, webhook_subscription, SqlValue, ORDER_USER_ADDRESS,
+    OUTBOX_HEAD_FILTER, SELECT_AUDIT_CHAIN, SELECT_AUDIT_EVENT, SELECT_LOGIN_ATTEMPT,
+    SELECT_OUTBOX_EVENT, SELECT_PASSKEY, SELECT_SESSION, SELECT_USER_ADDRESS, SELECT_USER_SUMMARY,
+    SELECT_WEBAUTHN_CHALLENGE, SELECT_WEBHOOK_DELIVERY, SELECT_WEBHOOK_SUBSCRIPTION,
+};
+use crate::repository::{
+    AddressRepository, AuditStore, LoginAttemptStore, MfaStore, OutboxStore, PasskeyStore,
+    PasswordHistoryStore, PasswordlessStore, RateLimitBucketStore, RealmStore, Repository,
+    SessionStore, Storage, StorageError, StorageResult, StorageTx, TokenKind, TokenStore,
+    UserRepository, WebhookStore,
+};
+use chrono::Utc;
+use postgres::error::SqlState;
+use postgres::types::ToSql;
+use postgres::{Client, NoTls, Row};
+use r2d2::{Pool, PooledConnection};
+use r2d2_postgres::PostgresConnectionManager;
+use std::str::FromStr;
+use std::time::Duration;
+use uuid::Uuid;
+
+type Manager = PostgresConnectionManager<NoTls>;
+
+/// Schema migrations, applied in order. Never edit a released migration, add a new one instead.
+const MIGRATIONS: &[(i32, &str)] = &[
+    (1, include_str!("../../migrations/postgres/0001_init.sql")),
+    (
+        2,
+        include_str!("../../migrations/postgres/0002_email_verification.sql"),
+    ),
+    (
+        3,
+        include_str!("../../migrations/postgres/0003_soft_delete.sql"),
+    ),
+    (
+        4,
+        include_str!("../../migrations/postgres/0004_user_created_at.sql"),
+    ),
+    (
+        5,
+        include_str!("../../migrations/postgres/0005_address_type.sql"),
+    ),
+    (
+        6,
+        include_str!("../../migrations/postgres/0006_password_policy.sql"),
+    ),
+    (
+        7,
+        include_str!("../../migrations/postgres/0007_password_history.sql"),
+    ),
+    (
+        8,
+        include_str!("../../migrations/postgres/0008_login_lockout.sql"),
+    ),
+    (
+        9,
+        include_str!("../../migrations/postgres/0009_rate_limit.sql"),
+    ),
+    (
+        10,
+        include_str!("../../migrations/postgres/0010_mfa_totp.sql"),
+    ),
+    (
+        11,
+        include_str!("../../migrations/postgres/0011_mfa_recovery_code.sql"),
+    ),
+    (
+        12,
+        include_str!("../../migrations/postgres/0012_webauthn.sql"),
+    ),
+    (
+        13,
+        include_str!("../../migrations/postgres/0013_passwordless.sql"),
+    ),
+    (
+        14,
+        include_str!("../../migrations/postgres/0014_user_session.sql"),
+    ),
+    (
+        15,
+        include_str!("../../migrations/postgres/0015_audit_event.sql"),
+    ),
+    (
+        16,
+        include_str!("../../migrations/postgres/0016_audit_chain.sql"),
+    ),
+    (
+        17,
+        include_str!("../../migrations/postgres/0017_webhook.sql"),
+    ),
+    (
+        18,
+        include_str!("../../migrations/postgres/0018_outbox.sql"),
+    ),
+    (
+        19,
+        include_str!("../../migrations/postgres/0019_erasure.sql"),
+    ),
+    (
+        20,
+        include_str!("../../migrations/postgres/0020_active_email.sql"),
+    ),
+    (
+        21,
+        include_str!("../../migrations/postgres/0021_pending_email.sql"),
+    ),
+    (
+        22,
+        include_str!("../../migrations/postgres/0022_audit_pii_hash.sql"),
+    ),
+];
+
+pub struct PostgresStorage {
+    pool: Pool<Manager>,
+}
+
+impl PostgresStorage {
+    /// Connects to `url`, builds the connection pool and applies pending migrations.
+    pub fn connect(url: &str, pool_config: &PoolConfig) -> StorageResult<PostgresStorage> {
+        let manager = Manager::new(
+            url.parse()
+                .map_err(|e: postgres::Error| StorageError::Backend(e.to_string()))?,
+            NoTls,
+        );
+        let pool = Pool::builder()
+            .max_size(pool_config.max_size)
+            .min_idle(pool_config.min_idle)
+            .connection_timeout(pool_config.connection_timeout)
+            .build(manager)
+            .map_err(|e| StorageError::Backend(e.to_string()))?;
+
+        let mut conn = pool
+            .get()
+            .map_err(|e| StorageError::Backend(e.to_string()))?;
+        migrate(&mut conn)?;
+
+        Ok(PostgresStorage { pool })
+    }
+}
+
+fn migrate(conn: &mut PooledConnection<Manager>) -> Result<(), postgres::Error> {
+    conn.batch_execute(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (\
+            version INT NOT NULL PRIMARY KEY, \
+            applied_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP)",
+    )?;
+
+    let mut tx = conn.transaction()?;
+    // Serialises concurrent instances starting up against the same database.
+    tx.batch_execute("LOCK TABLE schema_migrations IN EXCLUSIVE MODE")?;
+    let current: i32 = tx
+        .query_one(
+            "SELECT COALESCE(MAX(version), 0) FROM schema_migrations",
+            &[],
+        )?
+        .get(0);
+
+    for (version, sql) in MIGRATIONS.iter().filter(|(version, _)| *version > current) {
+        tx.batch_execute(sql)?;
+        tx.execute(
+            "INSERT INTO schema_migrations (version) VALUES ($1)",
+            &[version],
+        )?;
+        println!("applied postgres migration {}", version);
+    }
+    tx.commit()
+}
+
+impl From<postgres::Error> for StorageError {
+    fn from(err: postgres::Error) -> Self {
+        match err.code() {
+            Some(&SqlState::UNIQUE_VIOLATION) | Some(&SqlState::FOREIGN_KEY_VIOLATION) => {
+                StorageError::Conflict(err.to_string())
+            }
+            _ => StorageError::Backend(err.to_string()),
+        }
+    }
+}
+
+impl Storage for PostgresStorage {
+    fn begin(&self) -> StorageResult<Box<dyn StorageTx + '_>> {
+        let mut conn = self
+            .pool
+            .get()
+            .map_err(|e| StorageError::Backend(e.to_string()))?;
+        conn.batch_execute("BEGIN")?;
+        Ok(Box::new(PostgresTx {
+            conn,
+            finished: false,
+        }))
+    }
+}
+
+/// A transaction on a pooled connection. Rolled back on drop unless committed.
+pub struct PostgresTx {
+    conn: PooledConnection<Manager>,
+    finished: bool,
+}
+
+impl PostgresTx {
+    fn finish(&mut self, statement: &str) -> StorageResult<()> {
+        self.conn.batch_execute(statement)?;
+        self.finished = true;
+        Ok(())
+    }
+}
+
+impl Drop for PostgresTx {
+    fn drop(&mut self) {
+        if !self.finished {
+            if let Err(e) = self.conn.batch_execute("ROLLBACK") {
+                println!("failed to rollback postgres transaction: {}", e);
+            }
+        }
+    }
+}
+
+impl StorageTx for PostgresTx {
+    fn users(&mut self) -> Box<dyn UserRepository + '_> {
+        Box::new(UserStorage {
+            conn: &mut self.conn,
+        })
+    }
+
+    fn addresses(&mut self) -> Box<dyn AddressRepository + '_> {
+        Box::new(AddressStorage {
+            conn: &mut self.conn,
+        })
+    }
+
+    fn commit(mut self: Box<Self>) -> StorageResult<()> {
+        self.finish("COMMIT")
+    }
+
+    fn rollback(mut self: Box<Self>) -> StorageResult<()> {
+        self.finish("ROLLBACK")
+    }
+}
+
+pub struct UserStorage<'t> {
+    conn: &'t mut Client,
+}
+
+pub struct AddressStorage<'t> {
+    conn: &'t mut Client,
+}
+
+fn parse_role(value: &str) -> StorageResult<Role> {
+    Role::from_str(value).map_err(|e| StorageError::Backend(e.to_string()))
+}
+
+fn parse_address_type(value: &str) -> StorageResult<AddressType> {
+    AddressType::from_str(value).map_err(|e| StorageError::Backend(e.to_string()))
+}
+
+const SELECT_USER: &str =
+    "SELECT user_id, username, password, role FROM realm_user WHERE deleted_at IS NULL";
+
+fn map_user(row: &Row) -> StorageResult<User> {
+    Ok(User {
+        user_id: row.get(0),
+        username: row.get(1),
+        hashed_pass: row.get(2),
+        role: parse_role(row.get(3))?,
+    })
+}
+
+impl<'t> Repository<User> for UserStorage<'t> {
+    type ID = String;
+    type CreationData = CreateUser;
+    type UpdateData = UserMetadata;
+
+    fn get(&mut self, realm: &RealmName, id: &Self::ID) -> StorageResult<Option<User>> {
+        self.conn
+            .query_opt(
+                &format!("{} AND user_id = $1 AND realm_name = $2", SELECT_USER),
+                &[id, realm],
+            )?
+            .map(|row| map_user(&row))
+            .transpose()
+    }
+
+    fn list(&mut self, realm: &RealmName) -> StorageResult<Vec<User>> {
+        self.conn
+            .query(&format!("{} AND realm_name = $1", SELECT_USER), &[realm])?
+            .iter()
+            .map(map_user)
+            .collect()
+    }
+
+    fn create(&mut self, realm: &RealmName, data: Self::CreationData) -> StorageResult<Self::ID> {
+        let user_id = Uuid::new_v4().to_string();
+
+        self.conn.execute(
+            "INSERT INTO realm_user (realm_name, user_id, username, role, name, password, email, expires_at, is_god, created_at) \
+            VALUES ($1, $2, $3, $4, $5, $6, $7, NOW() + INTERVAL '1 year', FALSE, $8)",
+            &[
+                realm,
+                &user_id,
+                &data.username,
+                &Role::CUSTOMER.to_string(),
+                &data.name,
+                &data.password,
+                &data.email,
+                &Utc::now().timestamp(),
+            ],
+        )?;
+
+        Ok(user_id)
+    }
+
+    fn update(
+        &mut self,
+        realm: &RealmName,
+        id: &Self::ID,
+        data: Self::UpdateData,
+    ) -> StorageResult<()> {
+        if self.get(realm, id)?.is_none() {
+            return Err(StorageError::NotFound);
+        }
+
+        self.conn.execute(
+            "UPDATE realm_user \
+            SET name = COALESCE($1, name), \
+                email = COALESCE($2, email), \
+                email_verified = COALESCE($3, email_verified), \
+                role = COALESCE($4, role), \
+                password = COALESCE($5, password) \
+            WHERE user_id = $6 AND realm_name = $7",
+            &[
+                &data.name,
+                &data.email,
+                &data.email_verified,
+                &data.role.as_ref().map(Role::to_string),
+                &data.password,
+                id,
+                realm,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn delete(&mut self, realm: &RealmName, id: &Self::ID) -> StorageResult<()> {
+        let deleted = self.conn.execute(
+            "DELETE FROM realm_user WHERE user_id = $1 AND realm_name = $2",
+            &[id, realm],
+        )?;
+        match deleted {
+            0 => Err(StorageError::NotFound),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn map_summary(row: &Row) -> StorageResult<UserProfile> {
+    let deleted_at: Option<i64> = row.get(7);
+    Ok(UserProfile {
+        user_id: row.get(0),
+        username: row.get(1),
+        name: row.get(2),
+        email: row.get(3),
+        email_verified: row.get(4),
+        role: parse_role(row.get(5))?,
+        addresses: Vec::new(),
+        created_at: row.get(6),
+        status: match deleted_at {
+            Some(_) => UserStatus::Disabled,
+            None => UserStatus::Active,
+        },
+    })
+}
+
+fn as_params(params: &[SqlValue]) -> Vec<&(dyn ToSql + Sync)> {
+    params
+        .iter()
+        .map(|param| match param {
+            SqlValue::Text(value) => value as &(dyn ToSql + Sync),
+            SqlValue::Int(value) => value as &(dyn ToSql + Sync),
+        })
+        .collect()
+}
+
+impl<'t> UserStorage<'t> {
+    fn query_summaries(
+        &mut self,
+        sql: &str,
+        params: &[SqlValue],
+    ) -> StorageResult<Vec<UserProfile>> {
+        let users = self
+            .conn
+            .query(sql, &as_params(params))?
+            .iter()
+            .map(map_summary)
+            .collect::<StorageResult<Vec<_>>>()?;
+        self.with_addresses(users)
+    }
+
+    /// Fills in the addresses of each user with a separate query, so users without one are kept.
+    fn with_addresses(&mut self, mut users: Vec<UserProfile>) -> StorageResult<Vec<UserProfile>> {
+        if users.is_empty() {
+            return Ok(users);
+        }
+        let ids: Vec<SqlValue> = users
+            .iter()
+            .map(|u| SqlValue::Text(u.user_id.clone()))
+            .collect();
+        let rows = self.conn.query(
+            &format!(
+                "{} WHERE user_id IN {} {}",
+                SELECT_USER_ADDRESS,
+                in_list(1, ids.len(), |n| format!("${}", n)),
+                ORDER_USER_ADDRESS
+            ),
+            &as_params(&ids),
+        )?;
+
+        for row in rows.iter() {
+            let user_id: String = row.get(0);
+            if let Some(user) = users.iter_mut().find(|u| u.user_id == user_id) {
+                user.addresses.push(map_user_address(row, 1)?);
+            }
+        }
+        Ok(users)
+    }
+}
+
+impl<'t> UserRepository for UserStorage<'t> {
+    fn disable(&mut self, realm: &RealmName, id: &str, deleted_at: i64) -> StorageResult<()> {
+        let disabled = self.conn.execute(
+            "UPDATE realm_user \
+            SET deleted_at = $1, auth_token = NULL, reset_token = NULL, verification_token = NULL, \
+            mfa_token = NULL, pending_email = NULL \
+            WHERE user_id = $2 AND realm_name = $3 AND deleted_at IS NULL",
+            &[&deleted_at, &id, realm],
+        )?;
+        match disabled {
+            0 => Err(StorageError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    fn get_profile(&mut self, realm: &RealmName, id: &str) -> StorageResult<Option<UserProfile>> {
+        let users = self.query_summaries(
+            &format!(
+                "{} WHERE user_id = $1 AND realm_name = $2 AND deleted_at IS NULL",
+                SELECT_USER_SUMMARY
+            ),
+            &[
+                SqlValue::Text(id.to_string()),
+                SqlValue::Text(realm.clone()),
+            ],
+        )?;
+        Ok(users.into_iter().next())
+    }
+
+    fn get_by_name(&mut self, realm: &RealmName, username: &str) -> StorageResult<Option<User>> {
+        self.conn
+            .query_opt(
+                &format!("{} AND username = $1 AND realm_name = $2", SELECT_USER),
+                &[&username, realm],
+            )?
+            .map(|row| map_user(&row))
+            .transpose()
+    }
+
+    fn search(
+        &mut self,
+        realm: &RealmName,
+        query: &UserQuery,
+        after: Option<&PageCursor>,
+        limit: u32,
+    ) -> StorageResult<Vec<UserProfile>> {
+        let (filter, mut params) = user_search_filter(realm, query, after, |n| format!("${}", n));
+        params.push(SqlValue::Int(limit as i64));
+        self.query_summaries(
+            &format!(
+                "{} {} {} LIMIT ${}",
+                SELECT_USER_SUMMARY,
+                filter,
+                user_search_order(query),
+                params.len()
+            ),
+            &params,
+        )
+    }
+
+    fn count(&mut self, realm: &RealmName, query: &UserQuery) -> StorageResult<u64> {
+        let (filter, params) = user_search_filter(realm, query, None, |n| format!("${}", n));
+        let count: i64 = self
+            .conn
+            .query_one(
+                &format!("SELECT COUNT(*) FROM realm_user {}", filter),
+                &as_params(&params),
+            )?
+            .get(0);
+        Ok(count as u64)
+    }
+}
+
+const SELECT_ADDRESS: &str = "SELECT \
+    a.address_id, a.address_type, a.is_default, a.street, a.city, a.post_code, a.country \
+    FROM address a \
+    INNER JOIN realm_user u on u.user_id = a.user_id \
+    WHERE u.deleted_at IS NULL";
+
+fn map_address(row: &Row, first: usize) -> Address {
+    Address {
+        street: row.get(first),
+        city: row.get::<_, Option<String>>(first + 1).unwrap_or_default(),
+        post_code: row.get::<_, Option<String>>(first + 2).unwrap_or_default(),
+        country: row.get::<_, Option<String>>(first + 3).unwrap_or_default(),
+    }
+}
+
+fn map_user_address(row: &Row, first: usize) -> StorageResult<UserAddress> {
+    Ok(UserAddress {
+        address_id: row.get(first),
+        address_type: parse_address_type(row.get(first + 1))?,
+        is_default: row.get(first + 2),
+        address: map_address(row, first + 3),
+    })
+}
+
+impl<'t> AddressStorage<'t> {
+    /// Makes `address_id` the only default address of its type.
+    fn clear_other_defaults(
+        &mut self,
+        user_id: &str,
+        address_type: AddressType,
+        address_id: &str,
+    ) -> StorageResult<()> {
+        self.conn.execute(
+            "UPDATE address SET is_default = FALSE \
+            WHERE user_id = $1 AND address_type = $2 AND address_id <> $3",
+            &[&user_id, &address_type.to_string(), &address_id],
+        )?;
+        Ok(())
+    }
+}
+
+impl<'t> Repository<UserAddress> for AddressStorage<'t> {
+    type ID = String;
+    type CreationData = (AddressData, String);
+    type UpdateData = AddressData;
+
+    fn get(&mut self, realm: &RealmName, id: &Self::ID) -> StorageResult<Option<UserAddress>> {
+        self.conn
+            .query_opt(
+                &format!(
+                    "{} AND a.address_id = $1 AND u.realm_name = $2",
+                    SELECT_ADDRESS
+                ),
+                &[id, realm],
+            )?
+            .map(|row| map_user_address(&row, 0))
+            .transpose()
+    }
+
+    fn list(&mut self, realm: &RealmName) -> StorageResult<Vec<UserAddress>> {
+        self.conn
+            .query(
+                &format!("{} AND u.realm_name = $1", SELECT_ADDRESS),
+                &[realm],
+            )?
+            .iter()
+            .map(|row| map_user_address(row, 0))
+            .collect()
+    }
+
+    fn create(&mut self, realm: &RealmName, data: Self::CreationData) -> StorageResult<Self::ID> {
+        let (data, user_id) = data;
+        let owner = self.conn.query_opt(
+            "SELECT user_id FROM realm_user WHERE user_id = $1 AND realm_name = $2 AND deleted_at IS NULL",
+            &[&user_id, realm],
+        )?;
+        if owner.is_none() {
+            return Err(StorageError::NotFound);
+        }
+
+        let address_id = Uuid::new_v4().to_string();
+        let address = &data.address;
+        self.conn.execute(
+            "INSERT INTO address (address_id, user_id, street, city, post_code, country, country_code, address_type, is_default) \
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+            &[
+                &address_id,
+                &user_id,
+                &address.street,
+                &address.city,
+                &address.post_code,
+                &address.country,
+                &address.country,
+                &data.address_type.to_string(),
+                &data.is_default,
+            ],
+        )?;
+        if data.is_default {
+            self.clear_other_defaults(&user_id, data.address_type, &address_id)?;
+        }
+
+        Ok(address_id)
+    }
+
+    fn update(
+        &mut self,
+        realm: &RealmName,
+        id: &Self::ID,
+        data: Self::UpdateData,
+    ) -> StorageResult<()> {
+        if self.get(realm, id)?.is_none() {
+            return Err(StorageError::NotFound);
+        }
+
+        let address = &data.address;
+        let row = self.conn.query_one(
+            "UPDATE address \
+            SET street = $1, city = $2, post_code = $3, country = $4, address_type = $5, is_default = $6 \
+            WHERE address_id = $7 \
+            RETURNING user_id",
+            &[
+                &address.street,
+                &address.city,
+                &address.post_code,
+                &address.country,
+                &data.address_type.to_string(),
+                &data.is_default,
+                id,
+            ],
+        )?;
+        if data.is_default {
+            let user_id: String = row.get(0);
+            self.clear_other_defaults(&user_id, data.address_type, id)?;
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self, realm: &RealmName, id: &Self::ID) -> StorageResult<()> {
+        if self.get(realm, id)?.is_none() {
+            return Err(StorageError::NotFound);
+        }
+
+        self.conn
+            .execute("DELETE FROM address WHERE address_id = $1", &[id])?;
+        Ok(())
+    }
+}
+
+impl<'t> AddressRepository for AddressStorage<'t> {
+    fn list_for_user(
+        &mut self,
+        realm: &RealmName,
+        user_id: &str,
+    ) -> StorageResult<Vec<UserAddress>> {
+        self.conn
+            .query(
+                &format!(
+                    "{} AND a.user_id = $1 AND u.realm_name = $2 {}",
+                    SELECT_ADDRESS, ORDER_USER_ADDRESS
+                ),
+                &[&user_id, realm],
+            )?
+            .iter()
+            .map(|row| map_user_address(row, 0))
+            .collect()
+    }
+}
+
+fn map_realm_settings(row: &Row) -> (RealmName, InternalRealmSettings) {
+    let seconds = |idx: usize| Duration::from_secs(row.get::<_, i32>(idx) as u64);
+    (
+        row.get(0),
+        InternalRealmSettings {
+            is_confirmation_required: row.get(1),
+            is_guest_allowed: row.get(2),
+            realm_salt_itr: row.get::<_, i32>(3) as u32,
+            authentication_token_duration: seconds(4),
+            refresh_token_duration: seconds(5),
+            password_reset_token_duration: seconds(6),
+            password_policy: password_policy(
+                row.get::<_, i32>(7).into(),
+                row.get::<_, i32>(8).into(),
+                row.get(9),
+                row.get::<_, Option<i32>>(10).map(i64::from),
+                row.get(11),
+                row.get(12),
+                row.get::<_, i32>(13).into(),
+            ),
+            lockout_policy: lockout_policy(
+                row.get::<_, i32>(14).into(),
+                row.get::<_, i32>(15).into(),
+                row.get::<_, i32>(16).into(),
+                row.get::<_, i32>(17).into(),
+                row.get::<_, i32>(18).into(),
+            ),
+            rate_limits: rate_limits(
+                row.get::<_, i32>(19).into(),
+                row.get::<_, i32>(20).into(),
+                row.get::<_, i32>(21).into(),
+                row.get::<_, i32>(22).into(),
+            ),
+            mfa_policy: mfa_policy(row.get(23)),
+            webauthn: webauthn_config(row.get(24), row.get(25)),
+            passwordless_policy: passwordless_policy(
+                row.get(26),
+                row.get::<_, i32>(27).into(),
+                row.get::<_, i32>(28).into(),
+            ),
+        },
+    )
+}
+
+const SELECT_REALM_SETTINGS: &str = "SELECT \
+    realm_name, \
+    is_confirmation_required, \
+    is_guest_allowed, \
+    realm_salt_itr, \
+    authentication_token_duration_seconds, \
+    refresh_token_duration_seconds, \
+    password_reset_token_duration_seconds, \
+    password_min_length, \
+    password_max_length, \
+    password_required_classes, \
+    password_max_repeated, \
+    password_reject_user_details, \
+    password_deny_list, \
+    password_history_size, \
+    lockout_max_user_failures, \
+    lockout_max_ip_failures, \
+    lockout_failure_window_seconds, \
+    lockout_duration_seconds, \
+    lockout_max_duration_seconds, \
+    rate_limit_login_per_minute, \
+    rate_limit_registration_per_minute, \
+    rate_limit_password_reset_per_minute, \
+    rate_limit_admin_per_minute, \
+    mfa_required_roles, \
+    webauthn_rp_id, \
+    webauthn_origins, \
+    passwordless_method, \
+    passwordless_ttl_seconds, \
+    passwordless_max_attempts \
+    FROM realm";
+
+impl RealmStore for PostgresTx {
+    fn get_realm_settings(
+        &mut self,
+        realm: &RealmName,
+    ) -> StorageResult<Option<InternalRealmSettings>> {
+        Ok(self
+            .conn
+            .query_opt(
+                &format!("{} WHERE realm_name = $1", SELECT_REALM_SETTINGS),
+                &[realm],
+            )?
+            .map(|row| map_realm_settings(&row).1))
+    }
+
+    fn list_realm_settings(&mut self) -> StorageResult<Vec<(RealmName, InternalRealmSettings)>> {
+        Ok(self
+            .conn
+            .query(SELECT_REALM_SETTINGS, &[])?
+            .iter()
+            .map(map_realm_settings)
+            .collect())
+    }
+}
+
+fn token_column(kind: TokenKind) -> &'static str {
+    match kind {
+        TokenKind::Authentication => "auth_token",
+        TokenKind::PasswordReset => "reset_token",
+        TokenKind::EmailVerification => "verification_token",
+        TokenKind::MfaChallenge => "mfa_token",
+    }
+}
+
+impl TokenStore for PostgresTx {
+    fn store_token(&mut self, user_id: &str, kind: TokenKind, token: &str) -> StorageResult<()> {
+        self.conn.execute(
+            &format!(
+                "UPDATE realm_user SET {} = $1 WHERE user_id = $2",
+                token_column(kind)
+            ),
+            &[&token, &user_id],
+        )?;
+        Ok(())
+    }
+
+    fn find_token(&mut self, user_id: &str, kind: TokenKind) -> StorageResult<Option<String>> {
+        Ok(self
+            .conn
+            .query_opt(
+                &format!(
+                    "SELECT {} FROM realm_user WHERE user_id = $1",
+                    token_column(kind)
+                ),
+                &[&user_id],
+            )?
+            .and_then(|row| row.get(0)))
+    }
+
+    fn revoke_token(&mut self, user_id: &str, kind: TokenKind) -> StorageResult<()> {
+        self.conn.execute(
+            &format!(
+                "UPDATE realm_user SET {} = NULL WHERE user_id = $1",
+                token_column(kind)
+            ),
+            &[&user_id],
+        )?;
+        Ok(())
+    }
+
+    fn consume_token(
+        &mut self,
+        user_id: &str,
+        kind: TokenKind,
+        token: &str,
+    ) -> StorageResult<bool> {
+        let consumed = self.conn.execute(
+            &format!(
+                "UPDATE realm_user SET {0} = NULL WHERE user_id = $1 AND {0} = $2",
+                token_column(kind)
+            ),
+            &[&user_id, &token],
+        )?;
+        Ok(consumed > 0)
+    }
+
+    fn stage_email(&mut self, user_id: &str, email: Option<&str>) -> StorageResult<()> {
+        self.conn.execute(
+            "UPDATE realm_user SET pending_email = $1 WHERE user_id = $2",
+            &[&email, &user_id],
+        )?;
+        Ok(())
+    }
+
+    fn staged_email(&mut self, user_id: &str) -> StorageResult<Option<String>> {
+        Ok(self
+            .conn
+            .query_opt(
+                "SELECT pending_email FROM realm_user WHERE user_id = $1",
+                &[&user_id],
+            )?
+            .and_then(|row| row.get(0)))
+    }
+}
+
+impl PasswordHistoryStore for PostgresTx {
+    fn password_history(&mut self, user_id: &str, limit: usize) -> StorageResult<Vec<String>> {
+        Ok(self
+            .conn
+            .query(
+                "SELECT password_hash FROM password_history WHERE user_id = $1 ORDER BY id DESC LIMIT $2",
+                &[&user_id, &(limit as i64)],
+            )?
+            .iter()
+            .map(|row| row.get(0))
+            .collect())
+    }
+
+    fn push_password_history(
+        &mut self,
+        user_id: &str,
+        hash: &str,
+        keep: usize,
+    ) -> StorageResult<()> {
+        self.conn.execute(
+            "INSERT INTO password_history (user_id, password_hash, created_at) VALUES ($1, $2, $3)",
+            &[&user_id, &hash, &Utc::now().timestamp()],
+        )?;
+        self.conn.execute(
+            "DELETE FROM password_history WHERE user_id = $1 AND id NOT IN \
+            (SELECT id FROM password_history WHERE user_id = $1 ORDER BY id DESC LIMIT $2)",
+            &[&user_id, &(keep as i64)],
+        )?;
+        Ok(())
+    }
+}
+
+impl LoginAttemptStore for PostgresTx {
+    fn get_attempts(
+        &mut self,
+        realm: &RealmName,
+        key: &AttemptKey,
+    ) -> StorageResult<Option<LoginAttempts>> {
+        Ok(self
+            .conn
+            .query_opt(
+                &format!(
+                    "{} WHERE realm_name = $1 AND attempt_key = $2",
+                    SELECT_LOGIN_ATTEMPT
+                ),
+                &[realm, &key.to_string()],
+            )?
+            .map(|row| LoginAttempts {
+                failures: row.get::<_, i32>(0) as u32,
+                lockouts: row.get::<_, i32>(1) as u32,
+                last_failure_at: row.get(2),
+                locked_until: row.get(3),
+            }))
+    }
+
+    fn put_attempts(
+        &mut self,
+        realm: &RealmName,
+        key: &AttemptKey,
+        attempts: &LoginAttempts,
+    ) -> StorageResult<()> {
+        self.conn.execute(
+            "INSERT INTO login_attempt \
+            (realm_name, attempt_key, failures, lockouts, last_failure_at, locked_until) \
+            VALUES ($1, $2, $3, $4, $5, $6) \
+            ON CONFLICT (realm_name, attempt_key) DO UPDATE SET \
+            failures = excluded.failures, \
+            lockouts = excluded.lockouts, \
+            last_failure_at = excluded.last_failure_at, \
+            locked_until = excluded.locked_until",
+            &[
+                realm,
+                &key.to_string(),
+                &(attempts.failures as i32),
+                &(attempts.lockouts as i32),
+                &attempts.last_failure_at,
+                &attempts.locked_until,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn clear_attempts(&mut self, realm: &RealmName, key: &AttemptKey) -> StorageResult<()> {
+        self.conn.execute(
+            "DELETE FROM login_attempt WHERE realm_name = $1 AND attempt_key = $2",
+            &[realm, &key.to_string()],
+        )?;
+        Ok(())
+    }
+}
+
+impl RateLimitBucketStore for PostgresTx {
+    fn get_bucket(&mut self, realm: &RealmName, key: &str) -> StorageResult<Option<TokenBucket>> {
+        Ok(self
+            .conn
+            .query_opt(
+                "SELECT tokens, updated_at FROM rate_limit_bucket \
+                WHERE realm_name = $1 AND bucket_key = $2 FOR UPDATE",
+                &[realm, &key],
+            )?
+            .map(|row| TokenBucket {
+                tokens: row.get(0),
+                updated_at: row.get(1),
+            }))
+    }
+
+    fn put_bucket(
+        &mut self,
+        realm: &RealmName,
+        key: &str,
+        bucket: &TokenBucket,
+    ) -> StorageResult<()> {
+        self.conn.execute(
+            "INSERT INTO rate_limit_bucket (realm_name, bucket_key, tokens, updated_at) \
+            VALUES ($1, $2, $3, $4) \
+            ON CONFLICT (realm_name, bucket_key) DO UPDATE SET \
+            tokens = excluded.tokens, \
+            updated_at = excluded.updated_at",
+            &[realm, &key, &bucket.tokens, &bucket.updated_at],
+        )?;
+        Ok(())
+    }
+
+    fn delete_idle_buckets(&mut self, updated_before: i64) -> StorageResult<()> {
+        self.conn.execute(
+            "DELETE FROM rate_limit_bucket WHERE updated_at < $1",
+            &[&updated_before],
+        )?;
+        Ok(())
+    }
+}
+
+impl MfaStore for PostgresTx {
+    fn get_totp(&mut self, user_id: &str) -> StorageResult<Option<TotpEnrollment>> {
+        Ok(self
+            .conn
+            .query_opt(
+                "SELECT secret, activated_at, last_used_step FROM mfa_totp WHERE user_id = $1",
+                &[&user_id],
+            )?
+            .map(|row| TotpEnrollment {
+                sealed_secret: row.get(0),
+                activated_at: row.get(1),
+                last_used_step: row.get(2),
+            }))
+    }
+
+    fn put_totp(&mut self, user_id: &str, enrollment: &TotpEnrollment) -> StorageResult<()> {
+        self.conn.execute(
+            "INSERT INTO mfa_totp (user_id, secret, activated_at, last_used_step) \
+            VALUES ($1, $2, $3, $4) \
+            ON CONFLICT (user_id) DO UPDATE SET \
+            secret = excluded.secret, \
+            activated_at = excluded.activated_at, \
+            last_used_step = excluded.last_used_step",
+            &[
+                &user_id,
+                &enrollment.sealed_secret,
+                &enrollment.activated_at,
+                &enrollment.last_used_step,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn delete_totp(&mut self, user_id: &str) -> StorageResult<()> {
+        self.conn
+            .execute("DELETE FROM mfa_totp WHERE user_id = $1", &[&user_id])?;
+        Ok(())
+    }
+
+    fn use_totp_step(&mut self, user_id: &str, step: i64) -> StorageResult<bool> {
+        let used = self.conn.execute(
+            "UPDATE mfa_totp SET last_used_step = $2 \
+            WHERE user_id = $1 AND activated_at IS NOT NULL \
+            AND (last_used_step IS NULL OR last_used_step < $2)",
+            &[&user_id, &step],
+        )?;
+        Ok(used > 0)
+    }
+
+    fn replace_recovery_codes(
+        &mut self,
+        user_id: &str,
+        codes: &[RecoveryCode],
+    ) -> StorageResult<()> {
+        self.conn.execute(
+            "DELETE FROM mfa_recovery_code WHERE user_id = $1",
+            &[&user_id],
+        )?;
+        for code in codes {
+            self.conn.execute(
+                "INSERT INTO mfa_recovery_code (user_id, code_hash, created_at, used_at, used_ip) \
+                VALUES ($1, $2, $3, $4, $5)",
+                &[
+                    &user_id,
+                    &code.code_hash,
+                    &code.created_at,
+                    &code.used_at,
+                    &code.used_ip,
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn list_recovery_codes(&mut self, user_id: &str) -> StorageResult<Vec<RecoveryCode>> {
+        Ok(self
+            .conn
+            .query(
+                "SELECT code_hash, created_at, used_at, used_ip FROM mfa_recovery_code \
+                WHERE user_id = $1 ORDER BY id",
+                &[&user_id],
+            )?
+            .iter()
+            .map(|row| RecoveryCode {
+                code_hash: row.get(0),
+                created_at: row.get(1),
+                used_at: row.get(2),
+                used_ip: row.get(3),
+            })
+            .collect())
+    }
+
+    fn use_recovery_code(
+        &mut self,
+        user_id: &str,
+        code_hash: &str,
+        used_at: i64,
+        used_ip: Option<&str>,
+    ) -> StorageResult<bool> {
+        let used = self.conn.execute(
+            "UPDATE mfa_recovery_code SET used_at = $3, used_ip = $4 \
+            WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL",
+            &[&user_id, &code_hash, &used_at, &used_ip],
+        )?;
+        Ok(used > 0)
+    }
+}
+
+impl PasskeyStore for PostgresTx {
+    fn put_challenge(
+        &mut self,
+        realm: &RealmName,
+        challenge: &PendingChallenge,
+        now: i64,
+    ) -> StorageResult<()> {
+        self.conn.execute(
+            "DELETE FROM webauthn_challenge WHERE realm_name = $1 AND expires_at < $2",
+            &[realm, &now],
+        )?;
+        self.conn.execute(
+            "INSERT INTO webauthn_challenge (realm_name, challenge, ceremony, user_id, expires_at) \
+            VALUES ($1, $2, $3, $4, $5)",
+            &[
+                realm,
+                &challenge.challenge,
+                &challenge.ceremony.to_string(),
+                &challenge.user_id,
+                &challenge.expires_at,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn take_challenge(
+        &mut self,
+        realm: &RealmName,
+        challenge: &str,
+    ) -> StorageResult<Option<PendingChallenge>> {
+        self.conn
+            .query_opt(
+                "DELETE FROM webauthn_challenge WHERE realm_name = $1 AND challenge = $2 \
+                RETURNING challenge, ceremony, user_id, expires_at",
+                &[realm, &challenge],
+            )?
+            .map(|row| pending_challenge(row.get(0), row.get(1), row.get(2), row.get(3)))
+            .transpose()
+    }
+
+    fn add_passkey(&mut self, passkey: &Passkey) -> StorageResult<()> {
+        self.conn.execute(
+            "INSERT INTO webauthn_credential \
+            (credential_id, user_id, public_key, sign_count, name, created_at, last_used_at) \
+            VALUES ($1, $2, $3, $4, $5, $6, $7)",
+            &[
+                &passkey.credential_id,
+                &passkey.user_id,
+                &public_key_text(&passkey.public_key),
+                &i64::from(passkey.sign_count),
+                &passkey.name,
+                &passkey.created_at,
+                &passkey.last_used_at,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn find_passkey(
+        &mut self,
+        realm: &RealmName,
+        credential_id: &str,
+    ) -> StorageResult<Option<Passkey>> {
+        self.conn
+            .query_opt(
+                &format!(
+                    "{} JOIN realm_user u ON u.user_id = c.user_id \
+                    WHERE c.credential_id = $1 AND u.realm_name = $2 AND u.deleted_at IS NULL",
+                    SELECT_PASSKEY
+                ),
+                &[&credential_id, realm],
+            )?
+            .map(|row| map_passkey(&row))
+            .transpose()
+    }
+
+    fn list_passkeys(&mut self, user_id: &str) -> StorageResult<Vec<Passkey>> {
+        self.conn
+            .query(
+                &format!(
+                    "{} WHERE c.user_id = $1 ORDER BY c.created_at, c.credential_id",
+                    SELECT_PASSKEY
+                ),
+                &[&user_id],
+            )?
+            .iter()
+            .map(map_passkey)
+            .collect()
+    }
+
+    fn record_passkey_use(
+        &mut self,
+        credential_id: &str,
+        sign_count: u32,
+        used_at: i64,
+    ) -> StorageResult<()> {
+        self.conn.execute(
+            "UPDATE webauthn_credential SET sign_count = $2, last_used_at = $3 \
+            WHERE credential_id = $1",
+            &[&credential_id, &i64::from(sign_count), &used_at],
+        )?;
+        Ok(())
+    }
+
+    fn delete_passkey(&mut self, user_id: &str, credential_id: &str) -> StorageResult<()> {
+        match self.conn.execute(
+            "DELETE FROM webauthn_credential WHERE user_id = $1 AND credential_id = $2",
+            &[&user_id, &credential_id],
+        )? {
+            0 => Err(StorageError::NotFound),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl PasswordlessStore for PostgresTx {
+    fn get_passwordless(&mut self, user_id: &str) -> StorageResult<Option<PasswordlessCredential>> {
+        self.conn
+            .query_opt(
+                "SELECT method, secret_hash, expires_at, attempts FROM passwordless_credential \
+                WHERE user_id = $1",
+                &[&user_id],
+            )?
+            .map(|row| {
+                passwordless_credential(
+                    row.get(0),
+                    row.get(1),
+                    row.get(2),
+                    row.get::<_, i32>(3).into(),
+                )
+            })
+            .transpose()
+    }
+
+    fn put_passwordless(
+        &mut self,
+        user_id: &str,
+        credential: &PasswordlessCredential,
+    ) -> StorageResult<()> {
+        self.conn.execute(
+            "INSERT INTO passwordless_credential (user_id, method, secret_hash, expires_at, attempts) \
+            VALUES ($1, $2, $3, $4, $5) \
+            ON CONFLICT (user_id) DO UPDATE SET \
+            method = excluded.method, \
+            secret_hash = excluded.secret_hash, \
+            expires_at = excluded.expires_at, \
+            attempts = excluded.attempts",
+            &[
+                &user_id,
+                &credential.method.to_string(),
+                &credential.secret_hash,
+                &credential.expires_at,
+                &(credential.attempts as i32),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn redeem_passwordless(
+        &mut self,
+        user_id: &str,
+        secret_hash: &str,
+        now: i64,
+    ) -> StorageResult<bool> {
+        let redeemed = self.conn.execute(
+            "DELETE FROM passwordless_credential \
+            WHERE user_id = $1 AND secret_hash = $2 AND expires_at >= $3",
+            &[&user_id, &secret_hash, &now],
+        )?;
+        Ok(redeemed > 0)
+    }
+
+    fn record_passwordless_failure(
+        &mut self,
+        user_id: &str,
+        max_attempts: u32,
+    ) -> StorageResult<()> {
+        self.conn.execute(
+            "UPDATE passwordless_credential SET attempts = attempts + 1 WHERE user_id = $1",
+            &[&user_id],
+        )?;
+        self.conn.execute(
+            "DELETE FROM passwordless_credential WHERE user_id = $1 AND attempts >= $2",
+            &[&user_id, &(max_attempts as i32)],
+        )?;
+        Ok(())
+    }
+}
+
+impl SessionStore for PostgresTx {
+    fn create_session(&mut self, session: &Session) -> StorageResult<()> {
+        self.conn.execute(
+            "DELETE FROM user_session WHERE user_id = $1 AND expires_at < $2",
+            &[&session.user_id, &session.created_at],
+        )?;
+        self.conn.execute(
+            "INSERT INTO user_session \
+            (session_id, realm_name, user_id, refresh_token_hash, user_agent, ip, created_at, \
+            last_used_at, expires_at) \
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+            &[
+                &session.session_id,
+                &session.realm,
+                &session.user_id,
+                &session.refresh_token_hash,
+                &session.user_agent,
+                &session.ip,
+                &session.created_at,
+                &session.last_used_at,
+                &session.expires_at,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn find_session(&mut self, session_id: &str) -> StorageResult<Option<Session>> {
+        Ok(self
+            .conn
+            .query_opt(
+                &format!("{} WHERE session_id = $1", SELECT_SESSION),
+                &[&session_id],
+            )?
+            .map(|row| map_session(&row)))
+    }
+
+    fn list_sessions(&mut self, user_id: &str, now: i64) -> StorageResult<Vec<Session>> {
+        Ok(self
+            .conn
+            .query(
+                &format!(
+                    "{} WHERE user_id = $1 AND expires_at >= $2 \
+                    ORDER BY last_used_at DESC, session_id",
+                    SELECT_SESSION
+                ),
+                &[&user_id, &now],
+            )?
+            .iter()
+            .map(map_session)
+            .collect())
+    }
+
+    fn rotate_session(
+        &mut self,
+        session_id: &str,
+        current_hash: &str,
+        next_hash: &str,
+        used_at: i64,
+        expires_at: i64,
+    ) -> StorageResult<bool> {
+        let rotated = self.conn.execute(
+            "UPDATE user_session \
+            SET refresh_token_hash = $3, last_used_at = $4, expires_at = $5 \
+            WHERE session_id = $1 AND refresh_token_hash = $2",
+            &[
+                &session_id,
+                &current_hash,
+                &next_hash,
+                &used_at,
+                &expires_at,
+            ],
+        )?;
+        Ok(rotated > 0)
+    }
+
+    fn delete_session(&mut self, user_id: &str, session_id: &str) -> StorageResult<()> {
+        match self.conn.execute(
+            "DELETE FROM user_session WHERE user_id = $1 AND session_id = $2",
+            &[&user_id, &session_id],
+        )? {
+            0 => Err(StorageError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    fn delete_sessions(&mut self, user_id: &str, keep: Option<&str>) -> StorageResult<()> {
+        self.conn.execute(
+            "DELETE FROM user_session WHERE user_id = $1 \
+            AND ($2::VARCHAR IS NULL OR session_id <> $2)",
+            &[&user_id, &keep],
+        )?;
+        Ok(())
+    }
+}
+
+fn map_session(row: &Row) -> Session {
+    Session {
+        session_id: row.get(0),
+        realm: row.get(1),
+        user_id: row.get(2),
+        refresh_token_hash: row.get(3),
+        user_agent: row.get(4),
+        ip: row.get(5),
+        created_at: row.get(6),
+        last_used_at: row.get(7),
+        expires_at: row.get(8),
+    }
+}
+
+impl AuditStore for PostgresTx {
+    fn append_audit_event(&mut self, event: &AuditEvent) -> StorageResult<()> {
+        // The locked chain row serialises the appends to a realm until commit.
+        self.conn.execute(
+            "INSERT INTO audit_chain \
+            (realm_name, head_sequence, head_hash, anchor_sequence, anchor_hash) \
+            VALUES ($1, 0, $2, 0, $2) ON CONFLICT (realm_name) DO NOTHING",
+            &[&event.realm, &GENESIS_HASH],
+        )?;
+        let head = self.conn.query_one(
+            "SELECT head_sequence, head_hash FROM audit_chain WHERE realm_name = $1 FOR UPDATE",
+            &[&event.realm],
+        )?;
+        let link = ChainLink::next(head.get(0), head.get(1), event);
+        self.conn.execute(
+            "INSERT INTO audit_event \
+            (realm_name, event_type, user_id, ip, detail, occurred_at, sequence, prev_hash, entry_hash, \
+            pii_hash, pii_salt) \
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
+            &[
+                &event.realm,
+                &event.event_type.to_string(),
+                &event.user_id,
+                &event.ip,
+                &event.detail,
+                &event.occurred_at,
+                &link.sequence,
+                &link.prev_hash,
+                &link.entry_hash,
+                &link.pii_hash,
+                &link.pii_salt,
+            ],
+        )?;
+        self.conn.execute(
+            "UPDATE audit_chain SET head_sequence = $2, head_hash = $3 WHERE realm_name = $1",
+            &[&event.realm, &link.sequence, &link.entry_hash],
+        )?;
+        Ok(())
+    }
+
+    fn query_audit_events(
+        &mut self,
+        realm: &RealmName,
+        query: &AuditQuery,
+        limit: u32,
+    ) -> StorageResult<Vec<AuditRecord>> {
+        let (filter, mut params) = audit_filter(realm, query, |n| format!("${}", n));
+        params.push(SqlValue::Int(limit as i64));
+        self.conn
+            .query(
+                &format!(
+                    "{} {} ORDER BY id DESC LIMIT ${}",
+                    SELECT_AUDIT_EVENT,
+                    filter,
+                    params.len()
+                ),
+                &as_params(&params),
+            )?
+            .iter()
+            .map(map_audit_record)
+            .collect()
+    }
+
+    fn audit_chain(&mut self, realm: &RealmName) -> StorageResult<Option<AuditChain>> {
+        Ok(self
+            .conn
+            .query_opt(
+                &format!("{} WHERE realm_name = $1", SELECT_AUDIT_CHAIN),
+                &[realm],
+            )?
+            .map(|row| map_audit_chain(&row)))
+    }
+
+    fn audit_chains(&mut self) -> StorageResult<Vec<AuditChain>> {
+        Ok(self
+            .conn
+            .query(&format!("{} ORDER BY realm_name", SELECT_AUDIT_CHAIN), &[])?
+            .iter()
+            .map(map_audit_chain)
+            .collect())
+    }
+
+    fn audit_chain_entries(
+        &mut self,
+        realm: &RealmName,
+        after_sequence: i64,
+        limit: u32,
+    ) -> StorageResult<Vec<AuditRecord>> {
+        self.conn
+            .query(
+                &format!(
+                    "{} WHERE realm_name = $1 AND sequence > $2 ORDER BY sequence LIMIT $3",
+                    SELECT_AUDIT_EVENT
+                ),
+                &[realm, &after_sequence, &(limit as i64)],
+            )?
+            .iter()
+            .map(map_audit_record)
+            .collect()
+    }
+
+    fn first_audit_sequence_since(
+        &mut self,
+        realm: &RealmName,
+        since: i64,
+    ) -> StorageResult<Option<i64>> {
+        let row = self.conn.query_one(
+            "SELECT MIN(sequence) FROM audit_event WHERE realm_name = $1 AND occurred_at >= $2",
+            &[realm, &since],
+        )?;
+        Ok(row.get(0))
+    }
+
+    fn truncate_audit_chain(
+        &mut self,
+        realm: &RealmName,
+        anchor: &ChainLink,
+    ) -> StorageResult<u64> {
+        let removed = self.conn.execute(
+            "DELETE FROM audit_event WHERE realm_name = $1 AND sequence <= $2",
+            &[realm, &anchor.sequence],
+        )?;
+        self.conn.execute(
+            "UPDATE audit_chain SET anchor_sequence = $2, anchor_hash = $3 WHERE realm_name = $1",
+            &[realm, &anchor.sequence, &anchor.entry_hash],
+        )?;
+        Ok(removed)
+    }
+
+    fn prune_audit_events(&mut self, before: i64) -> StorageResult<u64> {
+        let pruned = self.conn.execute(
+            "DELETE FROM audit_event WHERE sequence IS NULL AND occurred_at < $1",
+            &[&before],
+        )?;
+        Ok(pruned)
+    }
+
+    fn redact_audit_events(
+        &mut self,
+        realm: &RealmName,
+        user_id: &str,
+        pseudonym: &str,
+        redacted_at: i64,
+    ) -> StorageResult<u64> {
+        let redacted = self.conn.execute(
+            "UPDATE audit_event \
+            SET user_id = $3, ip = NULL, detail = NULL, pii_salt = NULL, redacted_at = $4 \
+            WHERE realm_name = $1 AND user_id = $2",
+            &[realm, &user_id, &pseudonym, &redacted_at],
+        )?;
+        Ok(redacted)
+    }
+}
+
+fn map_audit_record(row: &Row) -> StorageResult<AuditRecord> {
+    audit_record((
+        row.get(0),
+        row.get(1),
+        row.get(2),
+        row.get(3),
+        row.get(4),
+        row.get(5),
+        row.get(6),
+        row.get(7),
+        row.get(8),
+        row.get(9),
+        row.get(10),
+        row.get(11),
+        row.get(12),
+    ))
+}
+
+fn map_audit_chain(row: &Row) -> AuditChain {
+    audit_chain((row.get(0), row.get(1), row.get(2), row.get(3), row.get(4)))
+}
+
+impl WebhookStore for PostgresTx {
+    fn create_subscription(&mut self, subscription: &WebhookSubscription) -> StorageResult<()> {
+        self.conn.execute(
+            "INSERT INTO webhook_subscription \
+            (subscription_id, realm_name, url, sealed_secret, event_types, created_at) \
+            VALUES ($1, $2, $3, $4, $5, $6)",
+            &[
+                &subscription.subscription_id,
+                &subscription.realm,
+                &subscription.url,
+                &subscription.sealed_secret,
+                &event_types_text(&subscription.event_types),
+                &subscription.created_at,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn list_subscriptions(&mut self, realm: &RealmName) -> StorageResult<Vec<WebhookSubscription>> {
+        Ok(self
+            .conn
+            .query(
+                &format!(
+                    "{} WHERE realm_name = $1 ORDER BY created_at, subscription_id",
+                    SELECT_WEBHOOK_SUBSCRIPTION
+                ),
+                &[realm],
+            )?
+            .iter()
+            .map(map_webhook_subscription)
+            .collect())
+    }
+
+    fn find_subscription(
+        &mut self,
+        subscription_id: &str,
+    ) -> StorageResult<Option<WebhookSubscription>> {
+        Ok(self
+            .conn
+            .query_opt(
+                &format!("{} WHERE subscription_id = $1", SELECT_WEBHOOK_SUBSCRIPTION),
+                &[&subscription_id],
+            )?
+            .map(|row| map_webhook_subscription(&row)))
+    }
+
+    fn delete_subscription(
+        &mut self,
+        realm: &RealmName,
+        subscription_id: &str,
+    ) -> StorageResult<()> {
+        match self.conn.execute(
+            "DELETE FROM webhook_subscription WHERE realm_name = $1 AND subscription_id = $2",
+            &[realm, &subscription_id],
+        )? {
+            0 => Err(StorageError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    fn enqueue_delivery(&mut self, delivery: &WebhookDelivery) -> StorageResult<()> {
+        self.conn.execute(
+            "INSERT INTO webhook_delivery \
+            (delivery_id, realm_name, subscription_id, event_id, event_type, payload, status, \
+            attempts, next_attempt_at, last_error, created_at, delivered_at) \
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12) \
+            ON CONFLICT (subscription_id, event_id) DO NOTHING",
+            &[
+                &delivery.delivery_id,
+                &delivery.realm,
+                &delivery.subscription_id,
+                &delivery.event_id,
+                &delivery.event_type.to_string(),
+                &delivery.payload,
+                &delivery.status.to_string(),
+                &(delivery.attempts as i32),
+                &delivery.next_attempt_at,
+                &delivery.last_error,
+                &delivery.created_at,
+                &delivery.delivered_at,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn claim_due_deliveries(
+        &mut self,
+        now: i64,
+        lease_until: i64,
+        limit: u32,
+    ) -> StorageResult<Vec<WebhookDelivery>> {
+        // Rows claimed by a concurrent dispatcher are skipped rather than waited for.
+        let deliveries = self
+            .conn
+            .query(
+                &format!(
+                    "{} WHERE status = $1 AND next_attempt_at <= $2 \
+                    ORDER BY next_attempt_at, created_at LIMIT $3 FOR UPDATE SKIP LOCKED",
+                    SELECT_WEBHOOK_DELIVERY
+                ),
+                &[&DeliveryStatus::Pending.to_string(), &now, &(limit as i64)],
+            )?
+            .iter()
+            .map(map_webhook_delivery)
+            .collect::<StorageResult<Vec<_>>>()?;
+        let ids = deliveries
+            .iter()
+            .map(|delivery| delivery.delivery_id.clone())
+            .collect::<Vec<_>>();
+        self.conn.execute(
+            "UPDATE webhook_delivery SET next_attempt_at = $2 WHERE delivery_id = ANY($1)",
+            &[&ids, &lease_until],
+        )?;
+        Ok(deliveries)
+    }
+
+    fn update_delivery(&mut self, delivery: &WebhookDelivery) -> StorageResult<()> {
+        match self.conn.execute(
+            "UPDATE webhook_delivery \
+            SET status = $2, attempts = $3, next_attempt_at = $4, last_error = $5, \
+            delivered_at = $6 \
+            WHERE delivery_id = $1",
+            &[
+                &delivery.delivery_id,
+                &delivery.status.to_string(),
+                &(delivery.attempts as i32),
+                &delivery.next_attempt_at,
+                &delivery.last_error,
+                &delivery.delivered_at,
+            ],
+        )? {
+            0 => Err(StorageError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    fn find_delivery(
+        &mut self,
+        realm: &RealmName,
+        delivery_id: &str,
+    ) -> StorageResult<Option<WebhookDelivery>> {
+        self.conn
+            .query_opt(
+                &format!(
+                    "{} WHERE realm_name = $1 AND delivery_id = $2",
+                    SELECT_WEBHOOK_DELIVERY
+                ),
+                &[realm, &delivery_id],
+            )?
+            .map(|row| map_webhook_delivery(&row))
+            .transpose()
+    }
+
+    fn list_deliveries(
+        &mut self,
+        realm: &RealmName,
+        status: DeliveryStatus,
+        limit: u32,
+    ) -> StorageResult<Vec<WebhookDelivery>> {
+        self.conn
+            .query(
+                &format!(
+                    "{} WHERE realm_name = $1 AND status = $2 \
+                    ORDER BY created_at DESC, delivery_id LIMIT $3",
+                    SELECT_WEBHOOK_DELIVERY
+                ),
+                &[realm, &status.to_string(), &(limit as i64)],
+            )?
+            .iter()
+            .map(map_webhook_delivery)
+            .collect()
+    }
+
+    fn find_deliveries_containing(
+        &mut self,
+        realm: &RealmName,
+        text: &str,
+    ) -> StorageResult<Vec<WebhookDelivery>> {
+        self.conn
+            .query(
+                &format!(
+                    "{} WHERE realm_name = $1 AND strpos(payload, $2) > 0 ORDER BY created_at",
+                    SELECT_WEBHOOK_DELIVERY
+                ),
+                &[realm, &text],
+            )?
+            .iter()
+            .map(map_webhook_delivery)
+            .collect()
+    }
+
+    fn replace_delivery_payload(&mut self, delivery_id: &str, payload: &str) -> StorageResult<()> {
+        match self.conn.execute(
+            "UPDATE webhook_delivery SET payload = $2 WHERE delivery_id = $1",
+            &[&delivery_id, &payload],
+        )? {
+            0 => Err(StorageError::NotFound),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn map_webhook_subscription(row: &Row) -> WebhookSubscription {
+    webhook_subscription((
+        row.get(0),
+        row.get(1),
+        row.get(2),
+        row.get(3),
+        row.get(4),
+        row.get(5),
+    ))
+}
+
+fn map_webhook_delivery(row: &Row) -> StorageResult<WebhookDelivery> {
+    webhook_delivery((
+        row.get(0),
+        row.get(1),
+        row.get(2),
+        row.get(3),
+        row.get(4),
+        row.get(5),
+        row.get(6),
+        row.get::<_, i32>(7) as i64,
+        row.get(8),
+        row.get(9),
+        row.get(10),
+        row.get(11),
+    ))
+}
+
+impl OutboxStore for PostgresTx {
+    fn append_outbox_event(&mut self, event: &OutboxEvent) -> StorageResult<()> {
+        self.conn.execute(
+            "INSERT INTO outbox_event \
+            (event_id, realm_name, aggregate_type, aggregate_id, event_type, payload, \
+            occurred_at, attempts, next_attempt_at) \
+            VALUES ($1, $2, $3, $4, $5, $6, $7, 0, $7)",
+            &[
+                &event.event_id,
+                &event.realm,
+                &event.aggregate_type,
+                &event.aggregate_id,
+                &event.event_type,
+                &event.payload.to_string(),
+                &event.occurred_at,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn claim_outbox_events(
+        &mut self,
+        now: i64,
+        lease_until: i64,
+        limit: u32,
+    ) -> StorageResult<Vec<OutboxRecord>> {
+        // An event claimed by a concurrent dispatcher is skipped, and holds back the rest of its
+        // aggregate as it is still unpublished.
+        let records = self
+            .conn
+            .query(
+                &format!(
+                    "{} WHERE {} AND next_attempt_at <= $1 \
+                    ORDER BY id LIMIT $2 FOR UPDATE SKIP LOCKED",
+                    SELECT_OUTBOX_EVENT, OUTBOX_HEAD_FILTER
+                ),
+                &[&now, &(limit as i64)],
+            )?
+            .iter()
+            .map(map_outbox_record)
+            .collect::<StorageResult<Vec<_>>>()?;
+        let ids = records.iter().map(|record| record.id).collect::<Vec<_>>();
+        self.conn.execute(
+            "UPDATE outbox_event SET next_attempt_at = $2 WHERE id = ANY($1)",
+            &[&ids, &lease_until],
+        )?;
+        Ok(records)
+    }
+
+    fn update_outbox_event(&mut self, record: &OutboxRecord) -> StorageResult<()> {
+        match self.conn.execute(
+            "UPDATE outbox_event \
+            SET attempts = $2, next_attempt_at = $3, last_error = $4, published_at = $5 \
+            WHERE id = $1",
+            &[
+                &record.id,
+                &(record.attempts as i32),
+                &record.next_attempt_at,
+                &record.last_error,
+                &record.published_at,
+            ],
+        )? {
+            0 => Err(StorageError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    fn prune_outbox(&mut self, before: i64) -> StorageResult<u64> {
+        let pruned = self.conn.execute(
+            "DELETE FROM outbox_event WHERE published_at < $1",
+            &[&before],
+        )?;
+        Ok(pruned)
+    }
+
+    fn redact_outbox_events(
+        &mut self,
+        realm: &RealmName,
+        aggregate_type: &str,
+        aggregate_id: &str,
+        payload: &serde_json::Value,
+    ) -> StorageResult<u64> {
+        let redacted = self.conn.execute(
+            "UPDATE outbox_event SET payload = $4 \
+            WHERE realm_name = $1 AND aggregate_type = $2 AND aggregate_id = $3",
+            &[realm, &aggregate_type, &aggregate_id, &payload.to_string()],
+        )?;
+        Ok(redacted)
+    }
+}
+
+fn map_outbox_record(row: &Row) -> StorageResult<OutboxRecord> {
+    outbox_record((
+        row.get(0),
+        row.get(1),
+        row.get(2),
+        row.get(3),
+        row.get(4),
+        row.get(5),
+        row.get(6),
+        row.get(7),
+        row.get::<_, i32>(8) as i64,
+        row.get(9),
+        row.get(10),
+        row.get(11),
+    ))
+}
+
+fn map_passkey(row: &Row) -> StorageResult<Passkey> {
+    passkey(
+        row.get(0),
+        row.get(1),
+        row.get(2),
+        row.get(3),
+        row.get(4),
+        row.get(5),
+        row.get(6),
+    )
+}
@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::domain::rate_limit::{TokenBucket, BUCKET_IDLE_MILLIS};
+use crate::domain::realm::RealmName;
+use crate::repository::{Storage, StorageResult};
+
+/// Holds the token buckets of the rate limiting middleware. Buckets idle long enough to be full
+/// again are dropped, so keys that stop coming do not pile up.
+pub trait RateLimiter: Send + Sync {
+    /// Takes a token at `now` (unix milliseconds) from the bucket `key` of `realm`, refilled at
+    /// `per_minute`. Requests naming no known realm pass `None` and share buckets of their own.
+    /// Returns the seconds until a token is available when the bucket is empty.
+    fn acquire(
+        &self,
+        realm: Option<&RealmName>,
+        key: &str,
+        per_minute: u32,
+        now: i64,
+    ) -> StorageResult<Option<u64>>;
+}
+
+/// Buckets in process memory, each instance limits on its own.
+#[derive(Default)]
+pub struct InMemoryRateLimiter {
+    buckets: Mutex<HashMap<(Option<RealmName>, String), TokenBucket>>,
+    swept_at: AtomicI64,
+}
+
+impl RateLimiter for InMemoryRateLimiter {
+    fn acquire(
+        &self,
+        realm: Option<&RealmName>,
+        key: &str,
+        per_minute: u32,
+        now: i64,
+    ) -> StorageResult<Option<u64>> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if sweep_due(&self.swept_at, now) {
+            buckets.retain(|_, bucket| bucket.updated_at >= now - BUCKET_IDLE_MILLIS);
+        }
+        let bucket = buckets
+            .entry((realm.cloned(), key.to_string()))
+            .or_insert_with(|| TokenBucket::full(per_minute, now));
+        Ok(bucket.take(per_minute, now).err())
+    }
+}
+
+/// Buckets in the storage backend, shared by every instance using it. The buckets of requests
+/// without a known realm have no realm to be stored with and are kept in memory.
+pub struct StorageRateLimiter {
+    storage: Arc<dyn Storage>,
+    unknown_realms: InMemoryRateLimiter,
+    swept_at: AtomicI64,
+}
+
+impl StorageRateLimiter {
+    pub fn new(storage: Arc<dyn Storage>) -> StorageRateLimiter {
+        StorageRateLimiter {
+            storage,
+            unknown_realms: InMemoryRateLimiter::default(),
+            swept_at: AtomicI64::default(),
+        }
+    }
+}
+
+impl RateLimiter for StorageRateLimiter {
+    fn acquire(
+        &self,
+        realm: Option<&RealmName>,
+        key: &str,
+        per_minute: u32,
+        now: i64,
+    ) -> StorageResult<Option<u64>> {
+        let realm = match realm {
+            Some(realm) => realm,
+            None => return self.unknown_realms.acquire(None, key, per_minute, now),
+        };
+        let sweep = sweep_due(&self.swept_at, now);
+        self.storage.in_transaction(|tx| {
+            if sweep {
+                tx.delete_idle_buckets(now - BUCKET_IDLE_MILLIS)?;
+            }
+            let mut bucket = tx
+                .get_bucket(realm, key)?
+                .unwrap_or_else(|| TokenBucket::full(per_minute, now));
+            let taken = bucket.take(per_minute, now);
+            tx.put_bucket(realm, key, &bucket)?;
+            Ok(taken.err())
+        })
+    }
+}
+
+/// Whether idle buckets are due to be dropped at `now`, at most once a [`BUCKET_IDLE_MILLIS`]
+/// per limiter.
+fn sweep_due(swept_at: &AtomicI64, now: i64) -> bool {
+    let last = swept_at.load(Ordering::Relaxed);
+    now - last >= BUCKET_IDLE_MILLIS
+        && swept_at
+            .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::repository::memory::InMemoryStorage;
+    use crate::repository::rate_limit::{InMemoryRateLimiter, RateLimiter, StorageRateLimiter};
+    use crate::repository::Storage;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_limiters_share_nothing_across_keys() {
+        let storage = Arc::new(InMemoryStorage::with_default_realms());
+        let limiters: [Box<dyn RateLimiter>; 2] = [
+            Box::new(InMemoryRateLimiter::default()),
+            Box::new(StorageRateLimiter::new(storage)),
+        ];
+        let realm = "rj.wire".to_string();
+        let now = 1_700_000_000_000;
+
+        for limiter in limiters.iter() {
+            for _ in 0..2 {
+                assert_eq!(
+                    limiter
+                        .acquire(Some(&realm), "login:10.0.0.1", 2, now)
+                        .unwrap(),
+                    None
+                );
+            }
+            assert_eq!(
+                limiter
+                    .acquire(Some(&realm), "login:10.0.0.1", 2, now)
+                    .unwrap(),
+                Some(30)
+            );
+            assert_eq!(
+                limiter
+                    .acquire(Some(&realm), "login:10.0.0.2", 2, now)
+                    .unwrap(),
+                None
+            );
+            assert_eq!(
+                limiter
+                    .acquire(Some(&"rj.haven".to_string()), "login:10.0.0.1", 2, now)
+                    .unwrap(),
+                None
+            );
+
+            // Requests without a known realm are limited too.
+            for _ in 0..2 {
+                assert_eq!(
+                    limiter.acquire(None, "login:10.0.0.1", 2, now).unwrap(),
+                    None
+                );
+            }
+            assert_eq!(
+                limiter.acquire(None, "login:10.0.0.1", 2, now).unwrap(),
+                Some(30)
+            );
+        }
+    }
+
+    #[test]
+    fn test_idle_buckets_are_dropped() {
+        let realm = "rj.wire".to_string();
+        let now = 1_700_000_000_000;
+
+        let limiter = InMemoryRateLimiter::default();
+        limiter
+            .acquire(Some(&realm), "login:10.0.0.1", 2, now)
+            .unwrap();
+        limiter.acquire(None, "login:10.0.0.1", 2, now).unwrap();
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 2);
+        limiter
+            .acquire(Some(&realm), "login:10.0.0.2", 2, now + 30_000)
+            .unwrap();
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 3);
+        limiter
+            .acquire(Some(&realm), "login:10.0.0.3", 2, now + 60_001)
+            .unwrap();
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 2);
+
+        let storage: Arc<dyn Storage> = Arc::new(InMemoryStorage::with_default_realms());
+        let limiter = StorageRateLimiter::new(storage.clone());
+        limiter
+            .acquire(Some(&realm), "login:10.0.0.1", 2, now)
+            .unwrap();
+        limiter
+            .acquire(Some(&realm), "login:10.0.0.2", 2, now + 60_001)
+            .unwrap();
+        let buckets = storage
+            .in_transaction(|tx| {
+                Ok((
+                    tx.get_bucket(&realm, "login:10.0.0.1")?,
+                    tx.get_bucket(&realm, "login:10.0.0.2")?,
+                ))
+            })
+            .unwrap();
+        assert!(buckets.0.is_none());
+        assert!(buckets.1.is_some());
+    }
+}
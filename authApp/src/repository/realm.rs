@@ -1,113 +1,104 @@
-use mysql::AccessMode;
-use rand::Rng;
 use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
-use crate::db::{ExecutionContext, DB};
-use crate::domain::realm::{InternalRealmSettings, Realm, RealmName, RealmSettings};
+use crate::domain::lockout::LockoutPolicy;
+use crate::domain::mfa::MfaPolicy;
+use crate::domain::passwordless::PasswordlessPolicy;
+use crate::domain::rate_limit::RateLimits;
+use crate::domain::realm::{InternalRealmSettings, PasswordPolicy, RealmName, RealmSettings};
+use crate::domain::webauthn::WebAuthnConfig;
+use crate::repository::{Storage, StorageResult};
 
 pub struct RealmSettingProvider {
-    settings: Arc<HashMap<RealmName, RwLock<InternalRealmSettings>>>,
-    db: Arc<DB>,
+    settings: RwLock<HashMap<RealmName, InternalRealmSettings>>,
+    storage: Arc<dyn Storage>,
 }
 
 impl RealmSettingProvider {
-    pub fn init(db: Arc<DB>) -> RealmSettingProvider {
-        let realms = vec!["rj.fg", "rj.wire", "rj.haven", "rj.fa"];
-
-        let mut realm_settings = HashMap::new();
-        for r in realms {
-            let _ = &realm_settings.insert(
-                r.to_string(),
-                RwLock::new(InternalRealmSettings {
-                    is_confirmation_required: false,
-                    is_guest_allowed: false,
-                    realm_salt_itr: 10000,
-                    authentication_token_duration: std::time::Duration::new(120, 0),
-                    refresh_token_duration: std::time::Duration::new(60, 0),
-                    password_reset_token_duration: std::time::Duration::new(30, 0),
-                }),
-            );
+    pub fn init(storage: Arc<dyn Storage>) -> RealmSettingProvider {
+        let provider = RealmSettingProvider {
+            settings: RwLock::new(HashMap::new()),
+            storage,
+        };
+        if let Err(e) = provider.reload() {
+            println!("failed to load realm settings: {}", e);
         }
+        provider
+    }
 
-        RealmSettingProvider {
-            settings: Arc::new(realm_settings),
-            db,
-        }
+    fn with_settings<R>(&self, realm: &str, read: impl FnOnce(&InternalRealmSettings) -> R) -> R {
+        read(
+            self.settings
+                .read()
+                .unwrap()
+                .get(realm)
+                .expect("Failed to get realm settings"),
+        )
+    }
+
+    pub fn has_realm(&self, realm: &str) -> bool {
+        self.settings.read().unwrap().contains_key(realm)
     }
 
     pub fn is_confirmation_required(&self, realm: &str) -> bool {
-        self.settings
-            .get(realm)
-            .expect("Failed to get realm settings")
-            .read()
-            .unwrap()
-            .is_confirmation_required()
+        self.with_settings(realm, |s| s.is_confirmation_required())
     }
 
     pub fn is_guest_allowed(&self, realm: &str) -> bool {
-        self.settings
-            .get(&realm.to_string())
-            .expect("Failed to get realm settings")
-            .read()
-            .unwrap()
-            .is_guest_allowed()
+        self.with_settings(realm, |s| s.is_guest_allowed())
     }
 
     pub fn get_authentication_token_duration(&self, realm: &str) -> Duration {
-        self.settings
-            .get(&realm.to_string())
-            .expect("Failed to get realm settings")
-            .read()
-            .unwrap()
-            .get_authentication_token_duration()
+        self.with_settings(realm, |s| s.get_authentication_token_duration())
     }
 
     pub fn get_refresh_token_duration(&self, realm: &str) -> Duration {
-        self.settings
-            .get(&realm.to_string())
-            .expect("Failed to get realm settings")
-            .read()
-            .unwrap()
-            .get_refresh_token_duration()
+        self.with_settings(realm, |s| s.get_refresh_token_duration())
     }
 
     pub fn get_password_reset_token_duration(&self, realm: &str) -> Duration {
-        self.settings
-            .get(&realm.to_string())
-            .expect("Failed to get realm settings")
-            .read()
-            .unwrap()
-            .get_password_reset_token_duration()
+        self.with_settings(realm, |s| s.get_password_reset_token_duration())
     }
 
     pub fn get_realm_salt_itr(&self, realm: &str) -> u32 {
-        self.settings
-            .get(&realm.to_string())
-            .expect("Failed to get realm settings")
-            .read()
-            .unwrap()
-            .realm_salt_itr()
-    }
-
-    pub fn reload(&self) -> &Self {
-        let settings = &self.settings.clone();
-        let mut rng = rand::thread_rng();
-
-        for (realm, value) in settings.as_ref().iter() {
-            let mut lock = value.write().unwrap();
-            *lock = InternalRealmSettings {
-                is_confirmation_required: false,
-                is_guest_allowed: false,
-                realm_salt_itr: rng.gen_range(4000..20000),
-                authentication_token_duration: Duration::new(rng.gen_range(0..180), 0),
-                refresh_token_duration: Duration::new(rng.gen_range(0..90), 0),
-                password_reset_token_duration: Duration::new(rng.gen_range(0..50), 0),
-            };
+        self.with_settings(realm, |s| s.realm_salt_itr())
+    }
+
+    pub fn get_password_policy(&self, realm: &str) -> PasswordPolicy {
+        self.with_settings(realm, |s| s.password_policy().clone())
+    }
+
+    pub fn get_lockout_policy(&self, realm: &str) -> LockoutPolicy {
+        self.with_settings(realm, |s| s.lockout_policy().clone())
+    }
+
+    pub fn get_rate_limits(&self, realm: &str) -> RateLimits {
+        self.with_settings(realm, |s| s.rate_limits().clone())
+    }
+
+    pub fn get_mfa_policy(&self, realm: &str) -> MfaPolicy {
+        self.with_settings(realm, |s| s.mfa_policy().clone())
+    }
+
+    pub fn get_webauthn_config(&self, realm: &str) -> WebAuthnConfig {
+        self.with_settings(realm, |s| s.webauthn_config().clone())
+    }
+
+    pub fn get_passwordless_policy(&self, realm: &str) -> PasswordlessPolicy {
+        self.with_settings(realm, |s| s.passwordless_policy().clone())
+    }
+
+    /// Re-reads the settings of every realm from storage.
+    pub fn reload(&self) -> StorageResult<&Self> {
+        let realms = self.storage.in_transaction(|tx| tx.list_realm_settings())?;
+
+        let mut lock = self.settings.write().unwrap();
+        *lock = realms.into_iter().collect();
+        for realm in lock.keys() {
             println!("updated realm settings for {}", realm);
         }
 
-        self
+        Ok(self)
     }
 }
@@ -0,0 +1,759 @@
+//! SQL shared by the relational backends. Placeholders differ between drivers, so builders take
+//! a function rendering the n-th (1-based) bind parameter.
+
+use crate::domain::audit::{AuditChain, AuditEvent, AuditQuery, AuditRecord, ChainLink};
+use crate::domain::customer::dto::{CursorKey, PageCursor, SortOrder, UserQuery, UserSort};
+use crate::domain::customer::Role;
+use crate::domain::customer::UserStatus;
+use crate::domain::lockout::LockoutPolicy;
+use crate::domain::mfa::MfaPolicy;
+use crate::domain::outbox::{OutboxEvent, OutboxRecord};
+use crate::domain::passwordless::{PasswordlessCredential, PasswordlessPolicy};
+use crate::domain::rate_limit::RateLimits;
+use crate::domain::realm::{CharacterClass, PasswordPolicy, RealmName};
+use crate::domain::webauthn::{Ceremony, Passkey, PendingChallenge, WebAuthnConfig};
+use crate::domain::webhook::{WebhookDelivery, WebhookEventType, WebhookSubscription};
+use crate::repository::{StorageError, StorageResult};
+use data_encoding::BASE64URL_NOPAD;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// A bind parameter, converted to the driver's own value type by each backend.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SqlValue {
+    Text(String),
+    Int(i64),
+}
+
+/// Columns of a user search row, in the order the backends read them.
+pub const SELECT_USER_SUMMARY: &str = "SELECT \
+    user_id, \
+    username, \
+    name, \
+    email, \
+    email_verified, \
+    role, \
+    created_at, \
+    deleted_at \
+    FROM realm_user";
+
+/// Columns of an address row, keyed by the owning user.
+pub const SELECT_USER_ADDRESS: &str = "SELECT \
+    user_id, \
+    address_id, \
+    address_type, \
+    is_default, \
+    street, \
+    city, \
+    post_code, \
+    country \
+    FROM address";
+
+/// Order of a user's addresses: by type, default first.
+pub const ORDER_USER_ADDRESS: &str = "ORDER BY address_type, is_default DESC, address_id";
+
+/// The policy stored in the `password_*` columns of the realm table. Character classes are comma
+/// separated and the deny list holds one password per line; unknown classes are ignored.
+pub fn password_policy(
+    min_length: i64,
+    max_length: i64,
+    required_classes: &str,
+    max_repeated: Option<i64>,
+    reject_user_details: bool,
+    deny_list: &str,
+    history_size: i64,
+) -> PasswordPolicy {
+    PasswordPolicy {
+        min_length: min_length as usize,
+        max_length: max_length as usize,
+        required_classes: required_classes
+            .split(',')
+            .filter_map(|class| class.trim().parse::<CharacterClass>().ok())
+            .collect(),
+        max_repeated: max_repeated.map(|max| max as usize),
+        reject_user_details,
+        deny_list: deny_list
+            .lines()
+            .map(str::trim)
+            .filter(|password| !password.is_empty())
+            .map(String::from)
+            .collect(),
+        history_size: history_size as usize,
+    }
+}
+
+/// The policy stored in the `lockout_*` columns of the realm table, durations in seconds.
+pub fn lockout_policy(
+    max_user_failures: i64,
+    max_ip_failures: i64,
+    failure_window: i64,
+    lockout_duration: i64,
+    max_lockout_duration: i64,
+) -> LockoutPolicy {
+    LockoutPolicy {
+        max_user_failures: max_user_failures as u32,
+        max_ip_failures: max_ip_failures as u32,
+        failure_window: Duration::from_secs(failure_window as u64),
+        lockout_duration: Duration::from_secs(lockout_duration as u64),
+        max_lockout_duration: Duration::from_secs(max_lockout_duration as u64),
+    }
+}
+
+/// The limits stored in the `rate_limit_*` columns of the realm table.
+pub fn rate_limits(login: i64, registration: i64, password_reset: i64, admin: i64) -> RateLimits {
+    RateLimits {
+        login: login as u32,
+        registration: registration as u32,
+        password_reset: password_reset as u32,
+        admin: admin as u32,
+    }
+}
+
+/// The policy stored in the `mfa_*` columns of the realm table. Roles are comma separated, unknown
+/// roles are ignored.
+pub fn mfa_policy(required_roles: &str) -> MfaPolicy {
+    MfaPolicy {
+        required_roles: required_roles
+            .split(',')
+            .filter_map(|role| role.trim().parse::<Role>().ok())
+            .collect(),
+    }
+}
+
+/// The relying party stored in the `webauthn_*` columns of the realm table. Origins are comma
+/// separated.
+pub fn webauthn_config(rp_id: Option<String>, origins: &str) -> WebAuthnConfig {
+    WebAuthnConfig {
+        rp_id: rp_id.filter(|rp_id| !rp_id.trim().is_empty()),
+        origins: origins
+            .split(',')
+            .map(str::trim)
+            .filter(|origin| !origin.is_empty())
+            .map(str::to_string)
+            .collect(),
+    }
+}
+
+/// The policy stored in the `passwordless_*` columns of the realm table. An empty or unknown
+/// method disables passwordless login.
+pub fn passwordless_policy(
+    method: &str,
+    ttl_seconds: i64,
+    max_attempts: i64,
+) -> PasswordlessPolicy {
+    PasswordlessPolicy {
+        method: method.trim().parse().ok(),
+        credential_duration: Duration::from_secs(ttl_seconds.max(0) as u64),
+        max_attempts: max_attempts.max(0) as u32,
+    }
+}
+
+/// A pending passwordless credential from its `method, secret_hash, expires_at, attempts`
+/// columns.
+pub fn passwordless_credential(
+    method: &str,
+    secret_hash: String,
+    expires_at: i64,
+    attempts: i64,
+) -> StorageResult<PasswordlessCredential> {
+    Ok(PasswordlessCredential {
+        method: method.parse().map_err(|e| {
+            StorageError::Backend(format!("unknown passwordless method {}: {}", method, e))
+        })?,
+        secret_hash,
+        expires_at,
+        attempts: attempts as u32,
+    })
+}
+
+/// Columns of a passkey row, `c` being the `webauthn_credential` table.
+pub const SELECT_PASSKEY: &str = "SELECT \
+    c.credential_id, \
+    c.user_id, \
+    c.public_key, \
+    c.sign_count, \
+    c.name, \
+    c.created_at, \
+    c.last_used_at \
+    FROM webauthn_credential c";
+
+/// Passkeys keep their COSE public key as base64url text.
+pub fn public_key_text(public_key: &[u8]) -> String {
+    BASE64URL_NOPAD.encode(public_key)
+}
+
+/// A passkey from the columns of [`SELECT_PASSKEY`].
+pub fn passkey(
+    credential_id: String,
+    user_id: String,
+    public_key: &str,
+    sign_count: i64,
+    name: Option<String>,
+    created_at: i64,
+    last_used_at: Option<i64>,
+) -> StorageResult<Passkey> {
+    Ok(Passkey {
+        credential_id,
+        user_id,
+        public_key: BASE64URL_NOPAD
+            .decode(public_key.as_bytes())
+            .map_err(|e| StorageError::Backend(format!("malformed passkey: {}", e)))?,
+        sign_count: sign_count as u32,
+        name,
+        created_at,
+        last_used_at,
+    })
+}
+
+/// Columns of a `user_session` row, in the order of the [`Session`] fields.
+///
+/// [`Session`]: crate::domain::session::Session
+pub const SELECT_SESSION: &str = "SELECT \
+    session_id, \
+    realm_name, \
+    user_id, \
+    refresh_token_hash, \
+    user_agent, \
+    ip, \
+    created_at, \
+    last_used_at, \
+    expires_at \
+    FROM user_session";
+
+/// Columns of a `webauthn_challenge` row.
+pub const SELECT_WEBAUTHN_CHALLENGE: &str = "SELECT \
+    challenge, \
+    ceremony, \
+    user_id, \
+    expires_at \
+    FROM webauthn_challenge";
+
+/// A pending challenge from the columns of [`SELECT_WEBAUTHN_CHALLENGE`].
+pub fn pending_challenge(
+    challenge: String,
+    ceremony: &str,
+    user_id: Option<String>,
+    expires_at: i64,
+) -> StorageResult<PendingChallenge> {
+    Ok(PendingChallenge {
+        challenge,
+        ceremony: Ceremony::from_str(ceremony)
+            .map_err(|e| StorageError::Backend(format!("unknown ceremony {}: {}", ceremony, e)))?,
+        user_id,
+        expires_at,
+    })
+}
+
+/// Columns of an `audit_event` row.
+pub const SELECT_AUDIT_EVENT: &str = "SELECT \
+    id, \
+    realm_name, \
+    event_type, \
+    user_id, \
+    ip, \
+    detail, \
+    occurred_at, \
+    sequence, \
+    prev_hash, \
+    entry_hash, \
+    redacted_at, \
+    pii_hash, \
+    pii_salt \
+    FROM audit_event";
+
+/// The columns of [`SELECT_AUDIT_EVENT`].
+pub type AuditColumns = (
+    i64,
+    String,
+    String,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    i64,
+    Option<i64>,
+    Option<String>,
+    Option<String>,
+    Option<i64>,
+    Option<String>,
+    Option<String>,
+);
+
+/// An audit record from the columns of [`SELECT_AUDIT_EVENT`].
+pub fn audit_record(
+    (
+        id,
+        realm,
+        event_type,
+        user_id,
+        ip,
+        detail,
+        occurred_at,
+        sequence,
+        prev_hash,
+        entry_hash,
+        redacted_at,
+        pii_hash,
+        pii_salt,
+    ): AuditColumns,
+) -> StorageResult<AuditRecord> {
+    let link = match (sequence, prev_hash, entry_hash) {
+        (Some(sequence), Some(prev_hash), Some(entry_hash)) => Some(ChainLink {
+            sequence,
+            prev_hash,
+            entry_hash,
+            pii_hash,
+            pii_salt,
+        }),
+        _ => None,
+    };
+    Ok(AuditRecord {
+        id,
+        event: AuditEvent {
+            realm,
+            event_type: event_type.parse().map_err(|e| {
+                StorageError::Backend(format!("unknown audit event type {}: {}", event_type, e))
+            })?,
+            user_id,
+            ip,
+            detail,
+            occurred_at,
+        },
+        link,
+        redacted_at,
+    })
+}
+
+/// Columns of an `audit_chain` row.
+pub const SELECT_AUDIT_CHAIN: &str = "SELECT \
+    realm_name, \
+    head_sequence, \
+    head_hash, \
+    anchor_sequence, \
+    anchor_hash \
+    FROM audit_chain";
+
+/// The columns of [`SELECT_AUDIT_CHAIN`].
+pub type AuditChainColumns = (RealmName, i64, String, i64, String);
+
+pub fn audit_chain(
+    (realm, head_sequence, head_hash, anchor_sequence, anchor_hash): AuditChainColumns,
+) -> AuditChain {
+    AuditChain {
+        realm,
+        head_sequence,
+        head_hash,
+        anchor_sequence,
+        anchor_hash,
+    }
+}
+
+/// Columns of a `webhook_subscription` row.
+pub const SELECT_WEBHOOK_SUBSCRIPTION: &str = "SELECT \
+    subscription_id, \
+    realm_name, \
+    url, \
+    sealed_secret, \
+    event_types, \
+    created_at \
+    FROM webhook_subscription";
+
+/// The columns of [`SELECT_WEBHOOK_SUBSCRIPTION`].
+pub type WebhookSubscriptionColumns = (String, RealmName, String, String, String, i64);
+
+/// Event types are stored comma separated.
+pub fn event_types_text(event_types: &[WebhookEventType]) -> String {
+    event_types
+        .iter()
+        .map(|event_type| event_type.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+pub fn webhook_subscription(
+    (subscription_id, realm, url, sealed_secret, event_types, created_at): WebhookSubscriptionColumns,
+) -> WebhookSubscription {
+    WebhookSubscription {
+        subscription_id,
+        realm,
+        url,
+        sealed_secret,
+        event_types: event_types
+            .split(',')
+            .filter_map(|event_type| event_type.trim().parse().ok())
+            .collect(),
+        created_at,
+    }
+}
+
+/// Columns of a `webhook_delivery` row.
+pub const SELECT_WEBHOOK_DELIVERY: &str = "SELECT \
+    delivery_id, \
+    realm_name, \
+    subscription_id, \
+    event_id, \
+    event_type, \
+    payload, \
+    status, \
+    attempts, \
+    next_attempt_at, \
+    last_error, \
+    created_at, \
+    delivered_at \
+    FROM webhook_delivery";
+
+/// The columns of [`SELECT_WEBHOOK_DELIVERY`].
+pub type WebhookDeliveryColumns = (
+    String,
+    RealmName,
+    String,
+    String,
+    String,
+    String,
+    String,
+    i64,
+    i64,
+    Option<String>,
+    i64,
+    Option<i64>,
+);
+
+pub fn webhook_delivery(
+    (
+        delivery_id,
+        realm,
+        subscription_id,
+        event_id,
+        event_type,
+        payload,
+        status,
+        attempts,
+        next_attempt_at,
+        last_error,
+        created_at,
+        delivered_at,
+    ): WebhookDeliveryColumns,
+) -> StorageResult<WebhookDelivery> {
+    Ok(WebhookDelivery {
+        delivery_id,
+        realm,
+        subscription_id,
+        event_id,
+        event_type: event_type.parse().map_err(|e| {
+            StorageError::Backend(format!("unknown webhook event type {}: {}", event_type, e))
+        })?,
+        payload,
+        status: status.parse().map_err(|e| {
+            StorageError::Backend(format!("unknown delivery status {}: {}", status, e))
+        })?,
+        attempts: attempts as u32,
+        next_attempt_at,
+        last_error,
+        created_at,
+        delivered_at,
+    })
+}
+
+/// Columns of an `outbox_event` row.
+pub const SELECT_OUTBOX_EVENT: &str = "SELECT \
+    id, \
+    event_id, \
+    realm_name, \
+    aggregate_type, \
+    aggregate_id, \
+    event_type, \
+    payload, \
+    occurred_at, \
+    attempts, \
+    next_attempt_at, \
+    last_error, \
+    published_at \
+    FROM outbox_event";
+
+/// Unpublished events with no unpublished predecessor in their aggregate, for
+/// [`SELECT_OUTBOX_EVENT`].
+pub const OUTBOX_HEAD_FILTER: &str = "published_at IS NULL AND NOT EXISTS (\
+    SELECT 1 FROM outbox_event earlier \
+    WHERE earlier.aggregate_type = outbox_event.aggregate_type \
+    AND earlier.aggregate_id = outbox_event.aggregate_id \
+    AND earlier.published_at IS NULL \
+    AND earlier.id < outbox_event.id)";
+
+/// The columns of [`SELECT_OUTBOX_EVENT`].
+pub type OutboxColumns = (
+    i64,
+    String,
+    RealmName,
+    String,
+    String,
+    String,
+    String,
+    i64,
+    i64,
+    i64,
+    Option<String>,
+    Option<i64>,
+);
+
+pub fn outbox_record(
+    (
+        id,
+        event_id,
+        realm,
+        aggregate_type,
+        aggregate_id,
+        event_type,
+        payload,
+        occurred_at,
+        attempts,
+        next_attempt_at,
+        last_error,
+        published_at,
+    ): OutboxColumns,
+) -> StorageResult<OutboxRecord> {
+    let payload = serde_json::from_str(&payload)
+        .map_err(|e| StorageError::Backend(format!("invalid outbox payload {}: {}", id, e)))?;
+    Ok(OutboxRecord {
+        id,
+        event: OutboxEvent {
+            event_id,
+            realm,
+            aggregate_type,
+            aggregate_id,
+            event_type,
+            payload,
+            occurred_at,
+        },
+        attempts: attempts as u32,
+        next_attempt_at,
+        last_error,
+        published_at,
+    })
+}
+
+/// Columns of a login attempt row.
+pub const SELECT_LOGIN_ATTEMPT: &str = "SELECT \
+    failures, \
+    lockouts, \
+    last_failure_at, \
+    locked_until \
+    FROM login_attempt";
+
+struct Binder<F> {
+    placeholder: F,
+    params: Vec<SqlValue>,
+}
+
+impl<F: Fn(usize) -> String> Binder<F> {
+    fn bind(&mut self, value: SqlValue) -> String {
+        self.params.push(value);
+        (self.placeholder)(self.params.len())
+    }
+}
+
+/// Escapes `LIKE` wildcards, to be used with `ESCAPE '!'`.
+fn escape_like(value: &str) -> String {
+    value
+        .replace('!', "!!")
+        .replace('%', "!%")
+        .replace('_', "!_")
+}
+
+fn sort_column(sort: UserSort) -> &'static str {
+    match sort {
+        UserSort::Username => "username",
+        UserSort::CreatedAt => "created_at",
+    }
+}
+
+fn direction(order: SortOrder) -> &'static str {
+    match order {
+        SortOrder::Asc => "ASC",
+        SortOrder::Desc => "DESC",
+    }
+}
+
+/// Builds the `WHERE` clause for the filters of `query`, and the keyset condition when `after`
+/// is given.
+pub fn user_search_filter(
+    realm: &RealmName,
+    query: &UserQuery,
+    after: Option<&PageCursor>,
+    placeholder: impl Fn(usize) -> String,
+) -> (String, Vec<SqlValue>) {
+    let mut binder = Binder {
+        placeholder,
+        params: Vec::new(),
+    };
+    let mut conditions = vec![format!(
+        "realm_name = {}",
+        binder.bind(SqlValue::Text(realm.clone()))
+    )];
+
+    conditions.push(
+        match query.status {
+            UserStatus::Active => "deleted_at IS NULL",
+            UserStatus::Disabled => "deleted_at IS NOT NULL",
+        }
+        .to_string(),
+    );
+    if let Some(role) = &query.role {
+        conditions.push(format!(
+            "role = {}",
+            binder.bind(SqlValue::Text(role.to_string()))
+        ));
+    }
+    if let Some(email) = &query.email {
+        conditions.push(format!(
+            "email = {}",
+            binder.bind(SqlValue::Text(email.clone()))
+        ));
+    }
+    if let Some(prefix) = &query.username_prefix {
+        conditions.push(format!(
+            "username LIKE {} ESCAPE '!'",
+            binder.bind(SqlValue::Text(format!("{}%", escape_like(prefix))))
+        ));
+    }
+    if let Some(created_after) = query.created_after {
+        conditions.push(format!(
+            "created_at >= {}",
+            binder.bind(SqlValue::Int(created_after))
+        ));
+    }
+    if let Some(created_before) = query.created_before {
+        conditions.push(format!(
+            "created_at < {}",
+            binder.bind(SqlValue::Int(created_before))
+        ));
+    }
+    if let Some(cursor) = after {
+        let column = sort_column(query.sort);
+        let op = match query.order {
+            SortOrder::Asc => ">",
+            SortOrder::Desc => "<",
+        };
+        let key = match &cursor.key {
+            CursorKey::Text(key) => SqlValue::Text(key.clone()),
+            CursorKey::Number(key) => SqlValue::Int(*key),
+        };
+        let past_key = binder.bind(key.clone());
+        let same_key = binder.bind(key);
+        let past_id = binder.bind(SqlValue::Text(cursor.user_id.clone()));
+        conditions.push(format!(
+            "({column} {op} {past_key} OR ({column} = {same_key} AND user_id {op} {past_id}))",
+            column = column,
+            op = op,
+            past_key = past_key,
+            same_key = same_key,
+            past_id = past_id,
+        ));
+    }
+
+    (format!("WHERE {}", conditions.join(" AND ")), binder.params)
+}
+
+/// `ORDER BY` clause matching the keyset condition of [`user_search_filter`].
+pub fn user_search_order(query: &UserQuery) -> String {
+    let direction = direction(query.order);
+    format!(
+        "ORDER BY {} {}, user_id {}",
+        sort_column(query.sort),
+        direction,
+        direction
+    )
+}
+
+/// Builds the `WHERE` clause of an audit query in `realm`, to be ordered by descending id.
+pub fn audit_filter(
+    realm: &RealmName,
+    query: &AuditQuery,
+    placeholder: impl Fn(usize) -> String,
+) -> (String, Vec<SqlValue>) {
+    let mut binder = Binder {
+        placeholder,
+        params: Vec::new(),
+    };
+    let mut conditions = vec![format!(
+        "realm_name = {}",
+        binder.bind(SqlValue::Text(realm.clone()))
+    )];
+
+    if let Some(user_id) = &query.user_id {
+        conditions.push(format!(
+            "user_id = {}",
+            binder.bind(SqlValue::Text(user_id.clone()))
+        ));
+    }
+    if let Some(event_type) = query.event_type {
+        conditions.push(format!(
+            "event_type = {}",
+            binder.bind(SqlValue::Text(event_type.to_string()))
+        ));
+    }
+    if let Some(from) = query.from {
+        conditions.push(format!(
+            "occurred_at >= {}",
+            binder.bind(SqlValue::Int(from))
+        ));
+    }
+    if let Some(to) = query.to {
+        conditions.push(format!("occurred_at < {}", binder.bind(SqlValue::Int(to))));
+    }
+    if let Some(before) = query.before {
+        conditions.push(format!("id < {}", binder.bind(SqlValue::Int(before))));
+    }
+
+    (format!("WHERE {}", conditions.join(" AND ")), binder.params)
+}
+
+/// `IN` list of `count` placeholders, numbered from `first`.
+pub fn in_list(first: usize, count: usize, placeholder: impl Fn(usize) -> String) -> String {
+    let placeholders: Vec<String> = (first..first + count).map(placeholder).collect();
+    format!("({})", placeholders.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::customer::dto::{CursorKey, PageCursor, SortOrder, UserQuery, UserSort};
+    use crate::repository::sql::{user_search_filter, user_search_order, SqlValue};
+
+    #[test]
+    fn test_user_search_filter() {
+        let query = UserQuery {
+            username_prefix: Some("ru_%".to_string()),
+            created_after: Some(10),
+            sort: UserSort::CreatedAt,
+            order: SortOrder::Desc,
+            ..UserQuery::default()
+        };
+        let cursor = PageCursor {
+            key: CursorKey::Number(20),
+            user_id: "u1".to_string(),
+        };
+
+        let (filter, params) =
+            user_search_filter(&"rj.wire".to_string(), &query, Some(&cursor), |n| {
+                format!("${}", n)
+            });
+        assert_eq!(
+            filter,
+            "WHERE realm_name = $1 AND deleted_at IS NULL AND username LIKE $2 ESCAPE '!' \
+            AND created_at >= $3 AND (created_at < $4 OR (created_at = $5 AND user_id < $6))"
+        );
+        assert_eq!(
+            params,
+            vec![
+                SqlValue::Text("rj.wire".to_string()),
+                SqlValue::Text("ru!_!%%".to_string()),
+                SqlValue::Int(10),
+                SqlValue::Int(20),
+                SqlValue::Int(20),
+                SqlValue::Text("u1".to_string()),
+            ]
+        );
+        assert_eq!(
+            user_search_order(&query),
+            "ORDER BY created_at DESC, user_id DESC"
+        );
+    }
+}
@@ -0,0 +1,1525 @@
+//! Behavioural test suite shared by every storage backend.
+//!
+//! Backends that need a running server are ignored unless asked for with `--ignored`, and then
+//! need their url: `AUTH_TEST_MYSQL_URL` for MySQL and `AUTH_TEST_POSTGRES_URL` for PostgreSQL.
+//! The migrations seed the `rj.wire` and `rj.haven` realms the suite uses.
+
+use crate::config::PoolConfig;
+use crate::db::DB;
+use crate::domain::audit::{AuditEvent, AuditEventType, AuditQuery, GENESIS_HASH};
+use crate::domain::customer::dto::{
+    AddressData, CreateUser, PageCursor, SortOrder, UserMetadata, UserQuery, UserSort,
+};
+use crate::domain::customer::{Address, AddressType, Role, UserStatus};
+use crate::domain::lockout::{AttemptKey, LockoutPolicy, LoginAttempts};
+use crate::domain::mfa::{MfaPolicy, RecoveryCode, TotpEnrollment};
+use crate::domain::outbox::{OutboxEvent, OutboxRecord, USER_AGGREGATE};
+use crate::domain::passwordless::{PasswordlessCredential, PasswordlessMethod, PasswordlessPolicy};
+use crate::domain::rate_limit::{RateLimits, TokenBucket};
+use crate::domain::realm::PasswordPolicy;
+use crate::domain::session::{ClientInfo, Session};
+use crate::domain::webauthn::{Ceremony, Passkey, PendingChallenge, WebAuthnConfig};
+use crate::domain::webhook::{
+    DeliveryStatus, RetryPolicy, WebhookDelivery, WebhookEventType, WebhookPayload,
+    WebhookSubscription,
+};
+use crate::repository::memory::InMemoryStorage;
+use crate::repository::mysql_storage::MySqlStorage;
+use crate::repository::postgres_storage::PostgresStorage;
+use crate::repository::sqlite_storage::SqliteStorage;
+use crate::repository::{Storage, StorageError, TokenKind};
+use crate::service::audit::AuditService;
+use serde_json::json;
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+const REALM: &str = "rj.wire";
+const OTHER_REALM: &str = "rj.haven";
+
+fn new_user() -> CreateUser {
+    CreateUser {
+        username: format!("user-{}", Uuid::new_v4()),
+        password: "hashed".to_string(),
+        name: "Ru Ru".to_string(),
+        age: 30,
+        email: format!("{}@nitro.com", Uuid::new_v4()),
+        address: Address {
+            street: "The Street".to_string(),
+            country: "UK".to_string(),
+            city: "London".to_string(),
+            post_code: "W1 2DE".to_string(),
+        },
+    }
+}
+
+fn shipping(address: &Address) -> AddressData {
+    AddressData {
+        address: address.clone(),
+        address_type: AddressType::Shipping,
+        is_default: true,
+    }
+}
+
+fn by_username(data: &CreateUser) -> UserQuery {
+    UserQuery {
+        username_prefix: Some(data.username.clone()),
+        ..UserQuery::default()
+    }
+}
+
+fn run_suite(storage: &dyn Storage) {
+    user_round_trip(storage);
+    user_lookup_by_name_is_realm_scoped(storage);
+    user_changes_are_realm_scoped(storage);
+    addresses_are_kept_per_user(storage);
+    disabled_users_are_hidden_until_erased(storage);
+    users_are_paginated(storage);
+    rollback_discards_writes(storage);
+    realm_settings_are_listed(storage);
+    tokens_can_be_stored_and_revoked(storage);
+    password_history_is_pruned(storage);
+    login_attempts_are_kept_per_realm_and_key(storage);
+    rate_limit_buckets_are_kept_per_realm(storage);
+    totp_enrollments_are_kept_per_user(storage);
+    recovery_codes_are_used_once(storage);
+    passkeys_are_kept_per_user(storage);
+    challenges_are_taken_once(storage);
+    passwordless_credentials_are_redeemed_once(storage);
+    sessions_are_rotated_and_revoked(storage);
+    audit_events_are_chained_and_pruned(storage);
+    webhook_deliveries_are_claimed_and_retried(storage);
+    outbox_events_are_claimed_in_aggregate_order(storage);
+    erased_users_are_redacted(storage);
+}
+
+fn user_round_trip(storage: &dyn Storage) {
+    let data = new_user();
+    let realm = REALM.to_string();
+
+    let user_id = storage
+        .in_transaction(|tx| {
+            let user_id = tx.users().create(&realm, data.clone())?;
+            tx.addresses()
+                .create(&realm, (shipping(&data.address), user_id.clone()))?;
+            Ok(user_id)
+        })
+        .unwrap();
+
+    let user = storage
+        .in_transaction(|tx| tx.users().get(&realm, &user_id))
+        .unwrap()
+        .expect("created user should be found");
+    assert_eq!(user.username, data.username);
+    assert_eq!(user.hashed_pass, data.password);
+    assert_eq!(user.role, Role::CUSTOMER);
+
+    let users = storage
+        .in_transaction(|tx| tx.users().search(&realm, &by_username(&data), None, 10))
+        .unwrap();
+    let listed = users
+        .iter()
+        .find(|u| u.user_id == user_id)
+        .expect("created user should be listed");
+    assert_eq!(listed.username, data.username);
+    let address = listed.addresses.first().expect("address should be listed");
+    assert_eq!(address.address.city, "London");
+    assert_eq!(address.address.post_code, "W1 2DE");
+    assert_eq!(address.address_type, AddressType::Shipping);
+    assert!(address.is_default);
+}
+
+fn user_lookup_by_name_is_realm_scoped(storage: &dyn Storage) {
+    let data = new_user();
+    let realm = REALM.to_string();
+    let other_realm = OTHER_REALM.to_string();
+
+    let user_id = storage
+        .in_transaction(|tx| tx.users().create(&realm, data.clone()))
+        .unwrap();
+
+    let found = storage
+        .in_transaction(|tx| tx.users().get_by_name(&realm, &data.username))
+        .unwrap();
+    assert_eq!(found.map(|u| u.user_id), Some(user_id));
+
+    let other = storage
+        .in_transaction(|tx| tx.users().get_by_name(&other_realm, &data.username))
+        .unwrap();
+    assert!(other.is_none());
+}
+
+fn user_changes_are_realm_scoped(storage: &dyn Storage) {
+    let data = new_user();
+    let realm = REALM.to_string();
+    let other_realm = OTHER_REALM.to_string();
+
+    let user_id = storage
+        .in_transaction(|tx| {
+            let user_id = tx.users().create(&realm, data.clone())?;
+            tx.addresses()
+                .create(&realm, (shipping(&data.address), user_id.clone()))?;
+            Ok(user_id)
+        })
+        .unwrap();
+
+    let update = storage.in_transaction(|tx| {
+        tx.users().update(
+            &other_realm,
+            &user_id,
+            UserMetadata {
+                name: Some("Ru Paul".to_string()),
+                ..UserMetadata::default()
+            },
+        )
+    });
+    assert!(matches!(update, Err(StorageError::NotFound)));
+    let delete = storage.in_transaction(|tx| tx.users().delete(&other_realm, &user_id));
+    assert!(matches!(delete, Err(StorageError::NotFound)));
+    let foreign_address = storage.in_transaction(|tx| {
+        tx.addresses()
+            .create(&other_realm, (shipping(&data.address), user_id.clone()))
+    });
+    assert!(matches!(foreign_address, Err(StorageError::NotFound)));
+
+    storage
+        .in_transaction(|tx| {
+            tx.users().update(
+                &realm,
+                &user_id,
+                UserMetadata {
+                    name: Some("Ru Paul".to_string()),
+                    email_verified: Some(true),
+                    ..UserMetadata::default()
+                },
+            )
+        })
+        .unwrap();
+    let profile = storage
+        .in_transaction(|tx| tx.users().get_profile(&realm, &user_id))
+        .unwrap()
+        .expect("profile should be found");
+    assert_eq!(profile.name.as_deref(), Some("Ru Paul"));
+    assert_eq!(profile.addresses.len(), 1);
+    assert_eq!(profile.email.as_deref(), Some(data.email.as_str()));
+    assert!(profile.email_verified);
+    assert_eq!(profile.role, Role::CUSTOMER);
+
+    let taken = new_user();
+    storage
+        .in_transaction(|tx| tx.users().create(&realm, taken.clone()))
+        .unwrap();
+    let duplicate_email = storage.in_transaction(|tx| {
+        tx.users().update(
+            &realm,
+            &user_id,
+            UserMetadata {
+                email: Some(taken.email.clone()),
+                ..UserMetadata::default()
+            },
+        )
+    });
+    assert!(matches!(duplicate_email, Err(StorageError::Conflict(_))));
+
+    storage
+        .in_transaction(|tx| tx.users().delete(&realm, &user_id))
+        .unwrap();
+    let deleted = storage
+        .in_transaction(|tx| tx.users().get(&realm, &user_id))
+        .unwrap();
+    assert!(deleted.is_none());
+}
+
+fn addresses_are_kept_per_user(storage: &dyn Storage) {
+    let data = new_user();
+    let realm = REALM.to_string();
+    let other_realm = OTHER_REALM.to_string();
+    let moved = Address {
+        city: "Leeds".to_string(),
+        ..data.address.clone()
+    };
+
+    let (user_id, home, work, billing) = storage
+        .in_transaction(|tx| {
+            let user_id = tx.users().create(&realm, data.clone())?;
+            let home = tx
+                .addresses()
+                .create(&realm, (shipping(&data.address), user_id.clone()))?;
+            let work = tx
+                .addresses()
+                .create(&realm, (shipping(&moved), user_id.clone()))?;
+            let billing = AddressData {
+                address_type: AddressType::Billing,
+                ..shipping(&data.address)
+            };
+            let billing = tx.addresses().create(&realm, (billing, user_id.clone()))?;
+            Ok((user_id, home, work, billing))
+        })
+        .unwrap();
+
+    // A new default replaces the previous one of the same type only.
+    let addresses = storage
+        .in_transaction(|tx| tx.addresses().list_for_user(&realm, &user_id))
+        .unwrap();
+    let ids: Vec<&str> = addresses.iter().map(|a| a.address_id.as_str()).collect();
+    assert_eq!(ids, vec![billing.as_str(), work.as_str(), home.as_str()]);
+    let defaults: Vec<bool> = addresses.iter().map(|a| a.is_default).collect();
+    assert_eq!(defaults, vec![true, true, false]);
+
+    storage
+        .in_transaction(|tx| tx.addresses().update(&realm, &home, shipping(&moved)))
+        .unwrap();
+    let (updated, previous) = storage
+        .in_transaction(|tx| {
+            let updated = tx.addresses().get(&realm, &home)?;
+            let previous = tx.addresses().get(&realm, &work)?;
+            Ok((updated, previous))
+        })
+        .unwrap();
+    let updated = updated.expect("address should be found");
+    assert_eq!(updated.address.city, "Leeds");
+    assert!(updated.is_default);
+    assert!(!previous.expect("address should be found").is_default);
+
+    let foreign = storage.in_transaction(|tx| tx.addresses().get(&other_realm, &home));
+    assert!(foreign.unwrap().is_none());
+    let foreign = storage.in_transaction(|tx| tx.addresses().list_for_user(&other_realm, &user_id));
+    assert!(foreign.unwrap().is_empty());
+    let foreign =
+        storage.in_transaction(|tx| tx.addresses().update(&other_realm, &home, shipping(&moved)));
+    assert!(matches!(foreign, Err(StorageError::NotFound)));
+    let foreign = storage.in_transaction(|tx| tx.addresses().delete(&other_realm, &home));
+    assert!(matches!(foreign, Err(StorageError::NotFound)));
+
+    storage
+        .in_transaction(|tx| tx.addresses().delete(&realm, &work))
+        .unwrap();
+    let remaining = storage
+        .in_transaction(|tx| tx.addresses().list_for_user(&realm, &user_id))
+        .unwrap();
+    assert_eq!(remaining.len(), 2);
+}
+
+fn disabled_users_are_hidden_until_erased(storage: &dyn Storage) {
+    let data = new_user();
+    let realm = REALM.to_string();
+
+    let user_id = storage
+        .in_transaction(|tx| {
+            let user_id = tx.users().create(&realm, data.clone())?;
+            tx.addresses()
+                .create(&realm, (shipping(&data.address), user_id.clone()))?;
+            tx.store_token(&user_id, TokenKind::Authentication, "auth")?;
+            Ok(user_id)
+        })
+        .unwrap();
+
+    let cross_realm =
+        storage.in_transaction(|tx| tx.users().disable(&OTHER_REALM.to_string(), &user_id, 1));
+    assert!(matches!(cross_realm, Err(StorageError::NotFound)));
+
+    storage
+        .in_transaction(|tx| tx.users().disable(&realm, &user_id, 1_700_000_000))
+        .unwrap();
+    let again = storage.in_transaction(|tx| tx.users().disable(&realm, &user_id, 1_700_000_000));
+    assert!(matches!(again, Err(StorageError::NotFound)));
+
+    let (user, by_name, profile, listed, token) = storage
+        .in_transaction(|tx| {
+            let user = tx.users().get(&realm, &user_id)?;
+            let by_name = tx.users().get_by_name(&realm, &data.username)?;
+            let profile = tx.users().get_profile(&realm, &user_id)?;
+            let listed = tx.users().search(&realm, &by_username(&data), None, 10)?;
+            let token = tx.find_token(&user_id, TokenKind::Authentication)?;
+            Ok((user, by_name, profile, listed, token))
+        })
+        .unwrap();
+    assert!(user.is_none());
+    assert!(by_name.is_none());
+    assert!(profile.is_none());
+    assert!(listed.is_empty());
+
+    let disabled = storage
+        .in_transaction(|tx| {
+            let query = UserQuery {
+                status: UserStatus::Disabled,
+                ..by_username(&data)
+            };
+            tx.users().search(&realm, &query, None, 10)
+        })
+        .unwrap();
+    assert_eq!(disabled.len(), 1);
+    assert_eq!(disabled[0].status, UserStatus::Disabled);
+    assert!(token.is_none());
+
+    let reused = storage.in_transaction(|tx| tx.users().create(&realm, data.clone()));
+    assert!(matches!(reused, Err(StorageError::Conflict(_))));
+
+    // The email is released for someone else, until that user holds it.
+    let same_email = || CreateUser {
+        username: format!("user-{}", Uuid::new_v4()),
+        ..data.clone()
+    };
+    let other_id = storage
+        .in_transaction(|tx| tx.users().create(&realm, same_email()))
+        .unwrap();
+    let taken = storage.in_transaction(|tx| tx.users().create(&realm, same_email()));
+    assert!(matches!(taken, Err(StorageError::Conflict(_))));
+    storage
+        .in_transaction(|tx| tx.users().delete(&realm, &other_id))
+        .unwrap();
+
+    storage
+        .in_transaction(|tx| tx.users().delete(&realm, &user_id))
+        .unwrap();
+    let reused = storage.in_transaction(|tx| tx.users().create(&realm, data.clone()));
+    assert!(reused.is_ok());
+}
+
+fn users_are_paginated(storage: &dyn Storage) {
+    let realm = REALM.to_string();
+    let prefix = format!("page_{}", Uuid::new_v4().simple());
+
+    // Only every other user gets an address, listing must not drop the others.
+    let created: Vec<String> = (0..5)
+        .map(|i| {
+            let data = CreateUser {
+                username: format!("{}-{}", prefix, i),
+                ..new_user()
+            };
+            storage
+                .in_transaction(|tx| {
+                    let user_id = tx.users().create(&realm, data.clone())?;
+                    if i % 2 == 0 {
+                        tx.addresses()
+                            .create(&realm, (shipping(&data.address), user_id.clone()))?;
+                    }
+                    Ok(user_id)
+                })
+                .unwrap()
+        })
+        .collect();
+
+    for order in [SortOrder::Asc, SortOrder::Desc] {
+        let query = UserQuery {
+            username_prefix: Some(prefix.clone()),
+            order,
+            ..UserQuery::default()
+        };
+        let mut seen = Vec::new();
+        let mut after: Option<PageCursor> = None;
+        loop {
+            let page = storage
+                .in_transaction(|tx| tx.users().search(&realm, &query, after.as_ref(), 2))
+                .unwrap();
+            seen.extend(page.iter().map(|u| u.username.clone()));
+            match page.last() {
+                Some(last) if page.len() == 2 => after = Some(PageCursor::after(last, query.sort)),
+                _ => break,
+            }
+        }
+
+        let mut expected: Vec<String> = (0..5).map(|i| format!("{}-{}", prefix, i)).collect();
+        if order == SortOrder::Desc {
+            expected.reverse();
+        }
+        assert_eq!(seen, expected);
+
+        let total = storage
+            .in_transaction(|tx| tx.users().count(&realm, &query))
+            .unwrap();
+        assert_eq!(total, 5);
+    }
+
+    let query = UserQuery {
+        username_prefix: Some(prefix.clone()),
+        sort: UserSort::CreatedAt,
+        created_before: Some(0),
+        ..UserQuery::default()
+    };
+    let none = storage
+        .in_transaction(|tx| tx.users().search(&realm, &query, None, 10))
+        .unwrap();
+    assert!(none.is_empty());
+
+    let query = UserQuery {
+        username_prefix: Some(prefix.clone()),
+        role: Some(Role::ADMIN),
+        ..UserQuery::default()
+    };
+    let admins = storage
+        .in_transaction(|tx| tx.users().count(&realm, &query))
+        .unwrap();
+    assert_eq!(admins, 0);
+
+    let users = storage
+        .in_transaction(|tx| {
+            let query = UserQuery {
+                username_prefix: Some(prefix.clone()),
+                ..UserQuery::default()
+            };
+            tx.users().search(&realm, &query, None, 10)
+        })
+        .unwrap();
+    assert_eq!(users.len(), created.len());
+    assert_eq!(users.iter().filter(|u| !u.addresses.is_empty()).count(), 3);
+    assert!(users
+        .iter()
+        .all(|u| u.status == UserStatus::Active && u.created_at > 0));
+}
+
+fn rollback_discards_writes(storage: &dyn Storage) {
+    let data = new_user();
+    let realm = REALM.to_string();
+
+    let result: Result<(), StorageError> = storage.in_transaction(|tx| {
+        tx.users().create(&realm, data.clone())?;
+        Err(StorageError::NotFound)
+    });
+    assert!(matches!(result, Err(StorageError::NotFound)));
+
+    let user = storage
+        .in_transaction(|tx| tx.users().get_by_name(&realm, &data.username))
+        .unwrap();
+    assert!(user.is_none());
+}
+
+fn realm_settings_are_listed(storage: &dyn Storage) {
+    let realms = storage
+        .in_transaction(|tx| tx.list_realm_settings())
+        .unwrap();
+    assert!(realms.iter().any(|(name, _)| name == REALM));
+    assert!(realms.iter().any(|(name, _)| name == OTHER_REALM));
+
+    let settings = storage
+        .in_transaction(|tx| tx.get_realm_settings(&REALM.to_string()))
+        .unwrap();
+    assert_eq!(
+        settings.as_ref().map(|s| &s.password_policy),
+        Some(&PasswordPolicy::default())
+    );
+    assert_eq!(
+        settings.as_ref().map(|s| &s.lockout_policy),
+        Some(&LockoutPolicy::default())
+    );
+    assert_eq!(
+        settings.as_ref().map(|s| &s.mfa_policy),
+        Some(&MfaPolicy::default())
+    );
+    assert_eq!(
+        settings.as_ref().map(|s| &s.webauthn),
+        Some(&WebAuthnConfig::default())
+    );
+    assert_eq!(
+        settings.as_ref().map(|s| &s.passwordless_policy),
+        Some(&PasswordlessPolicy::default())
+    );
+    assert_eq!(settings.map(|s| s.rate_limits), Some(RateLimits::default()));
+
+    let missing = storage
+        .in_transaction(|tx| tx.get_realm_settings(&"rj.unknown".to_string()))
+        .unwrap();
+    assert!(missing.is_none());
+}
+
+fn tokens_can_be_stored_and_revoked(storage: &dyn Storage) {
+    let data = new_user();
+    let realm = REALM.to_string();
+
+    let user_id = storage
+        .in_transaction(|tx| tx.users().create(&realm, data.clone()))
+        .unwrap();
+
+    storage
+        .in_transaction(|tx| tx.store_token(&user_id, TokenKind::Authentication, "auth"))
+        .unwrap();
+    let token = storage
+        .in_transaction(|tx| tx.find_token(&user_id, TokenKind::Authentication))
+        .unwrap();
+    assert_eq!(token.as_deref(), Some("auth"));
+    let reset = storage
+        .in_transaction(|tx| tx.find_token(&user_id, TokenKind::PasswordReset))
+        .unwrap();
+    assert!(reset.is_none());
+
+    storage
+        .in_transaction(|tx| tx.revoke_token(&user_id, TokenKind::Authentication))
+        .unwrap();
+    let revoked = storage
+        .in_transaction(|tx| tx.find_token(&user_id, TokenKind::Authentication))
+        .unwrap();
+    assert!(revoked.is_none());
+
+    // A token is consumed only when it matches, and only once.
+    storage
+        .in_transaction(|tx| tx.store_token(&user_id, TokenKind::PasswordReset, "reset"))
+        .unwrap();
+    let consume = |token: &str| {
+        storage
+            .in_transaction(|tx| tx.consume_token(&user_id, TokenKind::PasswordReset, token))
+            .unwrap()
+    };
+    assert!(!consume("other"));
+    assert!(consume("reset"));
+    assert!(!consume("reset"));
+    let consumed = storage
+        .in_transaction(|tx| tx.find_token(&user_id, TokenKind::PasswordReset))
+        .unwrap();
+    assert!(consumed.is_none());
+
+    let staged = storage
+        .in_transaction(|tx| {
+            tx.stage_email(&user_id, Some("new@nitro.com"))?;
+            tx.staged_email(&user_id)
+        })
+        .unwrap();
+    assert_eq!(staged.as_deref(), Some("new@nitro.com"));
+    let (profile, dropped) = storage
+        .in_transaction(|tx| {
+            let profile = tx.users().get_profile(&realm, &user_id)?;
+            tx.stage_email(&user_id, None)?;
+            Ok((profile, tx.staged_email(&user_id)?))
+        })
+        .unwrap();
+    assert_eq!(profile.unwrap().email, Some(data.email));
+    assert!(dropped.is_none());
+}
+
+fn password_history_is_pruned(storage: &dyn Storage) {
+    let realm = REALM.to_string();
+    let user_id = storage
+        .in_transaction(|tx| tx.users().create(&realm, new_user()))
+        .unwrap();
+    let other_id = storage
+        .in_transaction(|tx| tx.users().create(&realm, new_user()))
+        .unwrap();
+
+    storage
+        .in_transaction(|tx| {
+            for hash in ["first", "second", "third", "fourth"] {
+                tx.push_password_history(&user_id, hash, 3)?;
+            }
+            tx.push_password_history(&other_id, "other", 3)
+        })
+        .unwrap();
+    let history = storage
+        .in_transaction(|tx| tx.password_history(&user_id, 10))
+        .unwrap();
+    assert_eq!(history, vec!["fourth", "third", "second"]);
+    let newest = storage
+        .in_transaction(|tx| tx.password_history(&user_id, 1))
+        .unwrap();
+    assert_eq!(newest, vec!["fourth"]);
+
+    storage
+        .in_transaction(|tx| tx.users().delete(&realm, &user_id))
+        .unwrap();
+    let erased = storage
+        .in_transaction(|tx| tx.password_history(&user_id, 10))
+        .unwrap();
+    assert!(erased.is_empty());
+    let other = storage
+        .in_transaction(|tx| tx.password_history(&other_id, 10))
+        .unwrap();
+    assert_eq!(other, vec!["other"]);
+}
+
+#[test]
+fn test_memory_storage() {
+    run_suite(&InMemoryStorage::with_default_realms());
+}
+
+#[test]
+fn test_sqlite_storage() {
+    run_suite(&SqliteStorage::open(":memory:").unwrap());
+}
+
+#[test]
+#[ignore = "needs a MySQL server at AUTH_TEST_MYSQL_URL"]
+fn test_mysql_storage() {
+    let url = env::var("AUTH_TEST_MYSQL_URL").expect("AUTH_TEST_MYSQL_URL is not set");
+    run_suite(&MySqlStorage::open(Arc::new(DB::init(&url))).unwrap());
+}
+
+#[test]
+#[ignore = "needs a PostgreSQL server at AUTH_TEST_POSTGRES_URL"]
+fn test_postgres_storage() {
+    let url = env::var("AUTH_TEST_POSTGRES_URL").expect("AUTH_TEST_POSTGRES_URL is not set");
+    run_suite(&PostgresStorage::connect(&url, &PoolConfig::default()).unwrap());
+}
+
+fn login_attempts_are_kept_per_realm_and_key(storage: &dyn Storage) {
+    let realm = REALM.to_string();
+    let other_realm = OTHER_REALM.to_string();
+    let user = AttemptKey::User(Uuid::new_v4().to_string());
+    let ip = AttemptKey::Ip(Uuid::new_v4().to_string());
+    let locked = LoginAttempts {
+        failures: 0,
+        lockouts: 2,
+        last_failure_at: 1_700_000_000,
+        locked_until: Some(1_700_000_600),
+    };
+
+    storage
+        .in_transaction(|tx| {
+            tx.put_attempts(&realm, &user, &LoginAttempts::default())?;
+            tx.put_attempts(&realm, &user, &locked)?;
+            tx.put_attempts(&realm, &ip, &LoginAttempts::default())
+        })
+        .unwrap();
+    let stored = storage
+        .in_transaction(|tx| tx.get_attempts(&realm, &user))
+        .unwrap();
+    assert_eq!(stored, Some(locked));
+    let elsewhere = storage
+        .in_transaction(|tx| tx.get_attempts(&other_realm, &user))
+        .unwrap();
+    assert!(elsewhere.is_none());
+
+    storage
+        .in_transaction(|tx| tx.clear_attempts(&realm, &user))
+        .unwrap();
+    let (cleared, kept) = storage
+        .in_transaction(|tx| {
+            Ok((
+                tx.get_attempts(&realm, &user)?,
+                tx.get_attempts(&realm, &ip)?,
+            ))
+        })
+        .unwrap();
+    assert!(cleared.is_none());
+    assert_eq!(kept, Some(LoginAttempts::default()));
+}
+
+fn rate_limit_buckets_are_kept_per_realm(storage: &dyn Storage) {
+    let realm = REALM.to_string();
+    let key = format!("login:-:{}", Uuid::new_v4());
+    let full = TokenBucket::full(10, 1_700_000_000_000);
+    let drained = TokenBucket {
+        tokens: 0.5,
+        updated_at: 1_700_000_001_500,
+    };
+
+    storage
+        .in_transaction(|tx| {
+            tx.put_bucket(&realm, &key, &full)?;
+            tx.put_bucket(&realm, &key, &drained)
+        })
+        .unwrap();
+    let stored = storage
+        .in_transaction(|tx| tx.get_bucket(&realm, &key))
+        .unwrap();
+    assert_eq!(stored, Some(drained.clone()));
+    let elsewhere = storage
+        .in_transaction(|tx| tx.get_bucket(&OTHER_REALM.to_string(), &key))
+        .unwrap();
+    assert!(elsewhere.is_none());
+
+    let kept = storage
+        .in_transaction(|tx| {
+            tx.delete_idle_buckets(drained.updated_at)?;
+            tx.get_bucket(&realm, &key)
+        })
+        .unwrap();
+    assert_eq!(kept, Some(drained.clone()));
+    let idle = storage
+        .in_transaction(|tx| {
+            tx.delete_idle_buckets(drained.updated_at + 1)?;
+            tx.get_bucket(&realm, &key)
+        })
+        .unwrap();
+    assert!(idle.is_none());
+}
+
+fn totp_enrollments_are_kept_per_user(storage: &dyn Storage) {
+    let realm = REALM.to_string();
+    let user_id = storage
+        .in_transaction(|tx| tx.users().create(&realm, new_user()))
+        .unwrap();
+    let pending = TotpEnrollment {
+        sealed_secret: "sealed".to_string(),
+        activated_at: None,
+        last_used_step: None,
+    };
+    let active = TotpEnrollment {
+        activated_at: Some(1_700_000_000),
+        last_used_step: Some(56_666_666),
+        ..pending.clone()
+    };
+
+    storage
+        .in_transaction(|tx| {
+            tx.put_totp(&user_id, &pending)?;
+            tx.put_totp(&user_id, &active)
+        })
+        .unwrap();
+    let stored = storage.in_transaction(|tx| tx.get_totp(&user_id)).unwrap();
+    assert_eq!(stored, Some(active));
+
+    // Steps only move forward, each is used once.
+    let use_step = |step: i64| {
+        storage
+            .in_transaction(|tx| tx.use_totp_step(&user_id, step))
+            .unwrap()
+    };
+    assert!(!use_step(56_666_666));
+    assert!(use_step(56_666_667));
+    assert!(!use_step(56_666_667));
+    assert!(!use_step(56_666_600));
+    let stored = storage.in_transaction(|tx| tx.get_totp(&user_id)).unwrap();
+    assert_eq!(
+        stored.and_then(|totp| totp.last_used_step),
+        Some(56_666_667)
+    );
+    storage
+        .in_transaction(|tx| tx.put_totp(&user_id, &pending))
+        .unwrap();
+    assert!(!use_step(56_666_700));
+
+    storage
+        .in_transaction(|tx| tx.delete_totp(&user_id))
+        .unwrap();
+    let stored = storage.in_transaction(|tx| tx.get_totp(&user_id)).unwrap();
+    assert!(stored.is_none());
+}
+
+fn recovery_codes_are_used_once(storage: &dyn Storage) {
+    let realm = REALM.to_string();
+    let user_id = storage
+        .in_transaction(|tx| tx.users().create(&realm, new_user()))
+        .unwrap();
+    let codes = vec![
+        RecoveryCode::new("aaaaa-aaaaa", 100),
+        RecoveryCode::new("bbbbb-bbbbb", 100),
+    ];
+    let hash = codes[0].code_hash.clone();
+    storage
+        .in_transaction(|tx| tx.replace_recovery_codes(&user_id, &codes))
+        .unwrap();
+
+    let used = storage
+        .in_transaction(|tx| tx.use_recovery_code(&user_id, &hash, 200, Some("10.0.0.1")))
+        .unwrap();
+    assert!(used);
+    let used = storage
+        .in_transaction(|tx| tx.use_recovery_code(&user_id, &hash, 300, None))
+        .unwrap();
+    assert!(!used);
+    let stored = storage
+        .in_transaction(|tx| tx.list_recovery_codes(&user_id))
+        .unwrap();
+    assert_eq!(stored.len(), 2);
+    assert_eq!(stored[0].used_at, Some(200));
+    assert_eq!(stored[0].used_ip.as_deref(), Some("10.0.0.1"));
+    assert_eq!(stored[1], codes[1]);
+
+    storage
+        .in_transaction(|tx| tx.replace_recovery_codes(&user_id, &codes[1..]))
+        .unwrap();
+    let stored = storage
+        .in_transaction(|tx| tx.list_recovery_codes(&user_id))
+        .unwrap();
+    assert_eq!(stored, codes[1..].to_vec());
+}
+
+fn passkeys_are_kept_per_user(storage: &dyn Storage) {
+    let realm = REALM.to_string();
+    let user_id = storage
+        .in_transaction(|tx| tx.users().create(&realm, new_user()))
+        .unwrap();
+    let passkey = Passkey {
+        credential_id: format!("cred-{}", Uuid::new_v4()),
+        user_id: user_id.clone(),
+        public_key: vec![0xa5, 0x01, 0x02],
+        sign_count: 0,
+        name: Some("Laptop".to_string()),
+        created_at: 100,
+        last_used_at: None,
+    };
+    storage
+        .in_transaction(|tx| tx.add_passkey(&passkey))
+        .unwrap();
+    let duplicate = storage.in_transaction(|tx| tx.add_passkey(&passkey));
+    assert!(matches!(duplicate, Err(StorageError::Conflict(_))));
+
+    storage
+        .in_transaction(|tx| tx.record_passkey_use(&passkey.credential_id, 7, 200))
+        .unwrap();
+    let (found, other_realm, listed) = storage
+        .in_transaction(|tx| {
+            let found = tx.find_passkey(&realm, &passkey.credential_id)?;
+            let other_realm = tx.find_passkey(&OTHER_REALM.to_string(), &passkey.credential_id)?;
+            let listed = tx.list_passkeys(&user_id)?;
+            Ok((found, other_realm, listed))
+        })
+        .unwrap();
+    let used = Passkey {
+        sign_count: 7,
+        last_used_at: Some(200),
+        ..passkey.clone()
+    };
+    assert_eq!(found, Some(used.clone()));
+    assert!(other_realm.is_none());
+    assert_eq!(listed, vec![used]);
+
+    let missing = storage.in_transaction(|tx| tx.delete_passkey(&user_id, "unknown"));
+    assert!(matches!(missing, Err(StorageError::NotFound)));
+    storage
+        .in_transaction(|tx| tx.delete_passkey(&user_id, &passkey.credential_id))
+        .unwrap();
+    let listed = storage
+        .in_transaction(|tx| tx.list_passkeys(&user_id))
+        .unwrap();
+    assert!(listed.is_empty());
+}
+
+fn challenges_are_taken_once(storage: &dyn Storage) {
+    let realm = REALM.to_string();
+    let expired = PendingChallenge::new(Ceremony::Authentication, None, 0);
+    let pending = PendingChallenge::new(Ceremony::Registration, Some("user".to_string()), 1_000);
+    storage
+        .in_transaction(|tx| {
+            tx.put_challenge(&realm, &expired, 0)?;
+            tx.put_challenge(&realm, &pending, 1_000)
+        })
+        .unwrap();
+
+    let (taken, again, dropped) = storage
+        .in_transaction(|tx| {
+            let taken = tx.take_challenge(&realm, &pending.challenge)?;
+            let again = tx.take_challenge(&realm, &pending.challenge)?;
+            let dropped = tx.take_challenge(&realm, &expired.challenge)?;
+            Ok((taken, again, dropped))
+        })
+        .unwrap();
+    assert_eq!(taken, Some(pending));
+    assert!(again.is_none());
+    assert!(dropped.is_none());
+}
+
+fn passwordless_credentials_are_redeemed_once(storage: &dyn Storage) {
+    let realm = REALM.to_string();
+    let user_id = storage
+        .in_transaction(|tx| tx.users().create(&realm, new_user()))
+        .unwrap();
+    let credential = PasswordlessCredential {
+        method: PasswordlessMethod::EmailCode,
+        secret_hash: "hash".to_string(),
+        expires_at: 1_000,
+        attempts: 0,
+    };
+    storage
+        .in_transaction(|tx| tx.put_passwordless(&user_id, &credential))
+        .unwrap();
+
+    let (wrong, expired) = storage
+        .in_transaction(|tx| {
+            let wrong = tx.redeem_passwordless(&user_id, "other", 500)?;
+            tx.record_passwordless_failure(&user_id, 3)?;
+            let expired = tx.redeem_passwordless(&user_id, "hash", 1_001)?;
+            Ok((wrong, expired))
+        })
+        .unwrap();
+    assert!(!wrong);
+    assert!(!expired);
+    let stored = storage
+        .in_transaction(|tx| tx.get_passwordless(&user_id))
+        .unwrap();
+    assert_eq!(stored.map(|c| c.attempts), Some(1));
+
+    let redeemed = storage
+        .in_transaction(|tx| {
+            let first = tx.redeem_passwordless(&user_id, "hash", 1_000)?;
+            let second = tx.redeem_passwordless(&user_id, "hash", 1_000)?;
+            Ok((first, second))
+        })
+        .unwrap();
+    assert_eq!(redeemed, (true, false));
+
+    // Wrong guesses use the credential up.
+    let stored = storage
+        .in_transaction(|tx| {
+            tx.put_passwordless(&user_id, &credential)?;
+            tx.record_passwordless_failure(&user_id, 2)?;
+            tx.record_passwordless_failure(&user_id, 2)?;
+            tx.get_passwordless(&user_id)
+        })
+        .unwrap();
+    assert!(stored.is_none());
+}
+
+fn sessions_are_rotated_and_revoked(storage: &dyn Storage) {
+    let realm = REALM.to_string();
+    let user_id = storage
+        .in_transaction(|tx| tx.users().create(&realm, new_user()))
+        .unwrap();
+    let client = ClientInfo::new(Some("phone"), Some("10.0.0.1".to_string()));
+    let valid_for = std::time::Duration::from_secs(100);
+    let (stale, _) = Session::open(&realm, &user_id, client.clone(), 0, valid_for);
+    let (first, _) = Session::open(&realm, &user_id, client.clone(), 1_000, valid_for);
+    let (second, _) = Session::open(&realm, &user_id, ClientInfo::default(), 1_050, valid_for);
+
+    // Opening a session drops the expired ones.
+    storage
+        .in_transaction(|tx| {
+            tx.create_session(&stale)?;
+            tx.create_session(&first)?;
+            tx.create_session(&second)
+        })
+        .unwrap();
+    let (found, stale_found) = storage
+        .in_transaction(|tx| {
+            Ok((
+                tx.find_session(&first.session_id)?,
+                tx.find_session(&stale.session_id)?,
+            ))
+        })
+        .unwrap();
+    assert_eq!(found, Some(first.clone()));
+    assert!(stale_found.is_none());
+
+    let (rotated, replayed) = storage
+        .in_transaction(|tx| {
+            let rotated = tx.rotate_session(
+                &first.session_id,
+                &first.refresh_token_hash,
+                "next",
+                1_060,
+                1_160,
+            )?;
+            let replayed = tx.rotate_session(
+                &first.session_id,
+                &first.refresh_token_hash,
+                "again",
+                1_070,
+                1_170,
+            )?;
+            Ok((rotated, replayed))
+        })
+        .unwrap();
+    assert!(rotated);
+    assert!(!replayed);
+
+    let listed = storage
+        .in_transaction(|tx| tx.list_sessions(&user_id, 1_100))
+        .unwrap();
+    let ids: Vec<&str> = listed.iter().map(|s| s.session_id.as_str()).collect();
+    assert_eq!(
+        ids,
+        vec![first.session_id.as_str(), second.session_id.as_str()]
+    );
+    assert_eq!(listed[0].refresh_token_hash, "next");
+    assert_eq!(listed[0].user_agent.as_deref(), Some("phone"));
+    assert_eq!(listed[1].ip, None);
+    let listed = storage
+        .in_transaction(|tx| tx.list_sessions(&user_id, 1_155))
+        .unwrap();
+    assert_eq!(listed.len(), 1);
+
+    let other = storage
+        .in_transaction(|tx| tx.delete_session("someone-else", &first.session_id))
+        .unwrap_err();
+    assert!(matches!(other, StorageError::NotFound));
+    storage
+        .in_transaction(|tx| tx.delete_session(&user_id, &first.session_id))
+        .unwrap();
+    let gone = storage
+        .in_transaction(|tx| tx.find_session(&first.session_id))
+        .unwrap();
+    assert!(gone.is_none());
+
+    let (third, _) = Session::open(&realm, &user_id, ClientInfo::default(), 1_060, valid_for);
+    let kept = storage
+        .in_transaction(|tx| {
+            tx.create_session(&third)?;
+            tx.delete_sessions(&user_id, Some(&third.session_id))?;
+            tx.list_sessions(&user_id, 1_100)
+        })
+        .unwrap();
+    assert_eq!(kept, vec![third]);
+    let listed = storage
+        .in_transaction(|tx| {
+            tx.delete_sessions(&user_id, None)?;
+            tx.list_sessions(&user_id, 1_100)
+        })
+        .unwrap();
+    assert!(listed.is_empty());
+}
+
+fn audit_events_are_chained_and_pruned(storage: &dyn Storage) {
+    // Audit events outlive their realm and user, so a fresh realm name keeps runs apart.
+    let realm = format!("audit-{}", Uuid::new_v4());
+    let other_realm = format!("audit-{}", Uuid::new_v4());
+    let events = vec![
+        AuditEvent::new(&realm, AuditEventType::UserRegistered, Some("alice"), 1_000),
+        AuditEvent::new(&realm, AuditEventType::LoginFailed, Some("alice"), 1_010)
+            .with_ip(Some("10.0.0.1"))
+            .with_detail("Incorrect password"),
+        AuditEvent::new(&realm, AuditEventType::LoginSucceeded, Some("alice"), 1_020),
+        AuditEvent::new(&realm, AuditEventType::LoginFailed, None, 1_030),
+        AuditEvent::new(
+            &other_realm,
+            AuditEventType::LoginFailed,
+            Some("alice"),
+            1_040,
+        ),
+    ];
+    storage
+        .in_transaction(|tx| {
+            for event in &events {
+                tx.append_audit_event(event)?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+    let all = storage
+        .in_transaction(|tx| tx.query_audit_events(&realm, &AuditQuery::default(), 10))
+        .unwrap();
+    let occurred: Vec<i64> = all.iter().map(|r| r.event.occurred_at).collect();
+    assert_eq!(occurred, vec![1_030, 1_020, 1_010, 1_000]);
+    assert_eq!(all[2].event, events[1]);
+    assert!(all.windows(2).all(|pair| pair[0].id > pair[1].id));
+
+    let failed = AuditQuery {
+        user_id: Some("alice".to_string()),
+        event_type: Some(AuditEventType::LoginFailed),
+        ..AuditQuery::default()
+    };
+    let found = storage
+        .in_transaction(|tx| tx.query_audit_events(&realm, &failed, 10))
+        .unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].event.detail.as_deref(), Some("Incorrect password"));
+
+    let window = AuditQuery {
+        from: Some(1_010),
+        to: Some(1_030),
+        before: Some(all[1].id),
+        ..AuditQuery::default()
+    };
+    let found = storage
+        .in_transaction(|tx| tx.query_audit_events(&realm, &window, 10))
+        .unwrap();
+    let occurred: Vec<i64> = found.iter().map(|r| r.event.occurred_at).collect();
+    assert_eq!(occurred, vec![1_010]);
+    let page = storage
+        .in_transaction(|tx| tx.query_audit_events(&realm, &AuditQuery::default(), 2))
+        .unwrap();
+    assert_eq!(page, all[..2].to_vec());
+
+    // Each realm has its own chain.
+    let sequences: Vec<i64> = all
+        .iter()
+        .map(|r| r.link.as_ref().unwrap().sequence)
+        .collect();
+    assert_eq!(sequences, vec![4, 3, 2, 1]);
+    assert_eq!(all[3].link.as_ref().unwrap().prev_hash, GENESIS_HASH);
+    let chain = storage
+        .in_transaction(|tx| tx.audit_chain(&realm))
+        .unwrap()
+        .unwrap();
+    assert_eq!(chain.head_sequence, 4);
+    assert_eq!(chain.head_hash, all[0].link.as_ref().unwrap().entry_hash);
+    assert_eq!(chain.anchor_sequence, 0);
+    let other_chain = storage
+        .in_transaction(|tx| tx.audit_chain(&other_realm))
+        .unwrap()
+        .unwrap();
+    assert_eq!(other_chain.head_sequence, 1);
+
+    let entries = storage
+        .in_transaction(|tx| tx.audit_chain_entries(&realm, 1, 2))
+        .unwrap();
+    assert_eq!(entries, vec![all[2].clone(), all[1].clone()]);
+    let (since, none_since) = storage
+        .in_transaction(|tx| {
+            Ok((
+                tx.first_audit_sequence_since(&realm, 1_015)?,
+                tx.first_audit_sequence_since(&realm, 5_000)?,
+            ))
+        })
+        .unwrap();
+    assert_eq!(since, Some(3));
+    assert_eq!(none_since, None);
+
+    // Truncating a chain anchors it at the last removed entry, other chains are untouched.
+    let anchor = all[2].link.clone().unwrap();
+    let removed = storage
+        .in_transaction(|tx| tx.truncate_audit_chain(&realm, &anchor))
+        .unwrap();
+    assert_eq!(removed, 2);
+    let kept = storage
+        .in_transaction(|tx| tx.query_audit_events(&realm, &AuditQuery::default(), 10))
+        .unwrap();
+    assert_eq!(kept, all[..2].to_vec());
+    let chain = storage
+        .in_transaction(|tx| tx.audit_chain(&realm))
+        .unwrap()
+        .unwrap();
+    assert_eq!(chain.anchor_sequence, 2);
+    assert_eq!(chain.anchor_hash, anchor.entry_hash);
+    let other = storage
+        .in_transaction(|tx| tx.query_audit_events(&other_realm, &AuditQuery::default(), 10))
+        .unwrap();
+    assert_eq!(other.len(), 1);
+    let verification = AuditService::verify(&realm, storage).unwrap();
+    assert_eq!(verification.first_break, None);
+    assert_eq!(verification.verified_entries, 2);
+
+    // Chained events are only pruned through their chain.
+    storage
+        .in_transaction(|tx| tx.prune_audit_events(i64::MAX))
+        .unwrap();
+    let kept = storage
+        .in_transaction(|tx| tx.query_audit_events(&realm, &AuditQuery::default(), 10))
+        .unwrap();
+    assert_eq!(kept.len(), 2);
+    AuditService::prune(Duration::from_secs(0), storage).unwrap();
+    let kept = storage
+        .in_transaction(|tx| tx.query_audit_events(&realm, &AuditQuery::default(), 10))
+        .unwrap();
+    assert!(kept.is_empty());
+
+    // The next entry links to the pruned head.
+    let event = AuditEvent::new(&realm, AuditEventType::UserUpdated, Some("alice"), 2_000);
+    storage
+        .in_transaction(|tx| tx.append_audit_event(&event))
+        .unwrap();
+    let verification = AuditService::verify(&realm, storage).unwrap();
+    assert_eq!(verification.anchor_sequence, 4);
+    assert_eq!(verification.head_sequence, 5);
+    assert_eq!(verification.first_break, None);
+    assert_eq!(verification.verified_entries, 1);
+}
+
+fn webhook_deliveries_are_claimed_and_retried(storage: &dyn Storage) {
+    let realm = REALM.to_string();
+    let subscription = WebhookSubscription {
+        subscription_id: Uuid::new_v4().to_string(),
+        realm: realm.clone(),
+        url: "http://127.0.0.1:9/hook".to_string(),
+        sealed_secret: "sealed".to_string(),
+        event_types: vec![WebhookEventType::UserCreated, WebhookEventType::UserDeleted],
+        created_at: 1_000,
+    };
+    let payload = |n: i32| WebhookPayload {
+        id: Uuid::new_v4().to_string(),
+        event_type: WebhookEventType::UserCreated,
+        realm: realm.clone(),
+        user_id: "ruru".to_string(),
+        occurred_at: 1_000,
+        sequence: n.into(),
+        data: json!({ "n": n }),
+    };
+    let mut first = WebhookDelivery::new(&subscription, &payload(1), 1_000);
+    let mut second = WebhookDelivery::new(&subscription, &payload(2), 1_010);
+    storage
+        .in_transaction(|tx| {
+            tx.create_subscription(&subscription)?;
+            tx.enqueue_delivery(&second)?;
+            tx.enqueue_delivery(&first)
+        })
+        .unwrap();
+    let (found, listed) = storage
+        .in_transaction(|tx| {
+            Ok((
+                tx.find_subscription(&subscription.subscription_id)?,
+                tx.list_subscriptions(&realm)?,
+            ))
+        })
+        .unwrap();
+    assert_eq!(found, Some(subscription.clone()));
+    assert!(listed.contains(&subscription));
+
+    // Other tests' leftovers may be due as well, only this subscription's deliveries count.
+    let claim = |now: i64, lease_until: i64| {
+        storage
+            .in_transaction(|tx| tx.claim_due_deliveries(now, lease_until, 1_000))
+            .unwrap()
+            .into_iter()
+            .filter(|delivery| delivery.subscription_id == subscription.subscription_id)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(claim(1_010, 1_100), vec![first.clone(), second.clone()]);
+    assert!(claim(1_050, 1_100).is_empty());
+
+    let policy = RetryPolicy {
+        max_attempts: 2,
+        ..RetryPolicy::default()
+    };
+    first.failed("503 Service Unavailable", 1_020, &policy);
+    second.delivered(1_020);
+    storage
+        .in_transaction(|tx| {
+            tx.update_delivery(&first)?;
+            tx.update_delivery(&second)
+        })
+        .unwrap();
+    let stored = storage
+        .in_transaction(|tx| tx.find_delivery(&realm, &first.delivery_id))
+        .unwrap();
+    assert_eq!(stored, Some(first.clone()));
+    assert_eq!(claim(first.next_attempt_at, 2_000), vec![first.clone()]);
+
+    first.failed("503 Service Unavailable", 1_100, &policy);
+    storage
+        .in_transaction(|tx| tx.update_delivery(&first))
+        .unwrap();
+    let (dead, elsewhere) = storage
+        .in_transaction(|tx| {
+            Ok((
+                tx.list_deliveries(&realm, DeliveryStatus::DeadLettered, 1_000)?,
+                tx.find_delivery(&OTHER_REALM.to_string(), &first.delivery_id)?,
+            ))
+        })
+        .unwrap();
+    assert!(dead.contains(&first));
+    assert!(elsewhere.is_none());
+    assert!(claim(10_000, 10_100).is_empty());
+
+    // The same event queued again for the subscription is ignored.
+    let again = WebhookDelivery {
+        delivery_id: Uuid::new_v4().to_string(),
+        status: DeliveryStatus::Pending,
+        attempts: 0,
+        ..first.clone()
+    };
+    storage
+        .in_transaction(|tx| tx.enqueue_delivery(&again))
+        .unwrap();
+    assert!(claim(10_000, 10_100).is_empty());
+
+    let other = storage
+        .in_transaction(|tx| {
+            tx.delete_subscription(&OTHER_REALM.to_string(), &subscription.subscription_id)
+        })
+        .unwrap_err();
+    assert!(matches!(other, StorageError::NotFound));
+    let gone = storage
+        .in_transaction(|tx| {
+            tx.delete_subscription(&realm, &subscription.subscription_id)?;
+            tx.find_delivery(&realm, &first.delivery_id)
+        })
+        .unwrap();
+    assert!(gone.is_none());
+}
+
+fn outbox_events_are_claimed_in_aggregate_order(storage: &dyn Storage) {
+    let realm = REALM.to_string();
+    let (alice, bob) = (Uuid::new_v4().to_string(), Uuid::new_v4().to_string());
+    let event = |user_id: &str, n: i32| {
+        OutboxEvent::user(
+            &realm,
+            WebhookEventType::UserUpdated,
+            user_id,
+            json!({ "n": n }),
+            1_000,
+        )
+    };
+    let events = vec![event(&alice, 1), event(&bob, 1), event(&alice, 2)];
+    storage
+        .in_transaction(|tx| {
+            for event in &events {
+                tx.append_outbox_event(event)?;
+            }
+            Ok(())
+        })
+        .unwrap();
+    assert!(storage
+        .in_transaction(|tx| tx.append_outbox_event(&events[0]))
+        .is_err());
+    let rolled_back = storage.in_transaction(|tx| {
+        tx.append_outbox_event(&event(&bob, 2))?;
+        Err::<(), _>(StorageError::Conflict("rolled back".to_string()))
+    });
+    assert!(rolled_back.is_err());
+
+    // Other tests' leftovers may be due as well, only these aggregates count.
+    let claim = |now: i64, lease_until: i64| {
+        storage
+            .in_transaction(|tx| tx.claim_outbox_events(now, lease_until, 1_000))
+            .unwrap()
+            .into_iter()
+            .filter(|record| record.event.aggregate_id == alice || record.event.aggregate_id == bob)
+            .collect::<Vec<_>>()
+    };
+    let update = |record: &OutboxRecord| {
+        storage
+            .in_transaction(|tx| tx.update_outbox_event(record))
+            .unwrap()
+    };
+    let mut claimed = claim(1_000, 1_100);
+    let claimed_events = claimed
+        .iter()
+        .map(|record| record.event.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(claimed_events, vec![events[0].clone(), events[1].clone()]);
+    assert!(claimed[0].id < claimed[1].id);
+    assert!(claim(1_050, 1_100).is_empty());
+
+    // Alice's first event failed, her second one waits for it.
+    claimed[0].failed("unavailable", 1_010, &RetryPolicy::default());
+    claimed[1].published(1_010);
+    update(&claimed[0]);
+    update(&claimed[1]);
+    assert!(claim(1_039, 1_100).is_empty());
+    let mut retried = claim(1_040, 1_200);
+    assert_eq!(retried, vec![claimed[0].clone()]);
+    retried[0].published(1_050);
+    update(&retried[0]);
+    let mut next = claim(1_050, 1_300);
+    assert_eq!(next.len(), 1);
+    assert_eq!(next[0].event, events[2]);
+    next[0].published(1_060);
+    update(&next[0]);
+    assert!(claim(10_000, 10_100).is_empty());
+
+    let pruned = storage.in_transaction(|tx| tx.prune_outbox(1_055)).unwrap();
+    assert!(pruned >= 2);
+    let gone = storage
+        .in_transaction(|tx| tx.update_outbox_event(&claimed[1]))
+        .unwrap_err();
+    assert!(matches!(gone, StorageError::NotFound));
+    storage
+        .in_transaction(|tx| tx.update_outbox_event(&next[0]))
+        .unwrap();
+}
+
+fn erased_users_are_redacted(storage: &dyn Storage) {
+    let realm = format!("erasure-{}", Uuid::new_v4());
+    let (alice, bob) = (Uuid::new_v4().to_string(), Uuid::new_v4().to_string());
+    let events = vec![
+        AuditEvent::new(&realm, AuditEventType::UserRegistered, Some(&alice), 1_000),
+        AuditEvent::new(&realm, AuditEventType::LoginFailed, Some(&alice), 1_010)
+            .with_ip(Some("10.0.0.1"))
+            .with_detail("Incorrect password"),
+        AuditEvent::new(&realm, AuditEventType::UserRegistered, Some(&bob), 1_020),
+    ];
+    storage
+        .in_transaction(|tx| {
+            for event in &events {
+                tx.append_audit_event(event)?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+    // The erased user's events keep their links under a pseudonym, other users' are untouched.
+    let redacted = storage
+        .in_transaction(|tx| tx.redact_audit_events(&realm, &alice, "erased", 2_000))
+        .unwrap();
+    assert_eq!(redacted, 2);
+    let all = storage
+        .in_transaction(|tx| tx.query_audit_events(&realm, &AuditQuery::default(), 10))
+        .unwrap();
+    assert_eq!(all[0].event, events[2]);
+    assert_eq!(all[0].redacted_at, None);
+    for record in &all[1..] {
+        assert_eq!(record.event.user_id.as_deref(), Some("erased"));
+        assert_eq!((&record.event.ip, &record.event.detail), (&None, &None));
+        assert_eq!(record.redacted_at, Some(2_000));
+        let link = record.link.as_ref().unwrap();
+        assert!(link.pii_hash.is_some());
+        assert_eq!(link.pii_salt, None);
+    }
+    assert!(all[0].link.as_ref().unwrap().pii_salt.is_some());
+    let verification = AuditService::verify(&realm, storage).unwrap();
+    assert_eq!(verification.first_break, None);
+    assert_eq!(verification.verified_entries, 3);
+    assert_eq!(verification.redacted_entries, 2);
+
+    let subscription = WebhookSubscription {
+        subscription_id: Uuid::new_v4().to_string(),
+        realm: REALM.to_string(),
+        url: "http://127.0.0.1:9/hook".to_string(),
+        sealed_secret: "sealed".to_string(),
+        event_types: vec![WebhookEventType::UserCreated],
+        created_at: 1_000,
+    };
+    let payload = |user_id: &str| WebhookPayload {
+        id: Uuid::new_v4().to_string(),
+        event_type: WebhookEventType::UserCreated,
+        realm: REALM.to_string(),
+        user_id: user_id.to_string(),
+        occurred_at: 1_000,
+        sequence: 1,
+        data: json!({ "email": format!("{}@nitro.com", user_id) }),
+    };
+    let delivery = WebhookDelivery::new(&subscription, &payload(&alice), 1_000);
+    let other = WebhookDelivery::new(&subscription, &payload(&bob), 1_000);
+    storage
+        .in_transaction(|tx| {
+            tx.create_subscription(&subscription)?;
+            tx.enqueue_delivery(&delivery)?;
+            tx.enqueue_delivery(&other)
+        })
+        .unwrap();
+    let found = storage
+        .in_transaction(|tx| tx.find_deliveries_containing(&REALM.to_string(), &alice))
+        .unwrap();
+    assert_eq!(found, vec![delivery.clone()]);
+    storage
+        .in_transaction(|tx| tx.replace_delivery_payload(&delivery.delivery_id, "{}"))
+        .unwrap();
+    let (replaced, kept) = storage
+        .in_transaction(|tx| {
+            Ok((
+                tx.find_delivery(&REALM.to_string(), &delivery.delivery_id)?,
+                tx.find_delivery(&REALM.to_string(), &other.delivery_id)?,
+            ))
+        })
+        .unwrap();
+    assert_eq!(replaced.unwrap().payload, "{}");
+    assert_eq!(kept.unwrap(), other);
+    let missing = storage
+        .in_transaction(|tx| tx.replace_delivery_payload(&Uuid::new_v4().to_string(), "{}"))
+        .unwrap_err();
+    assert!(matches!(missing, StorageError::NotFound));
+    storage
+        .in_transaction(|tx| {
+            tx.delete_subscription(&REALM.to_string(), &subscription.subscription_id)
+        })
+        .unwrap();
+
+    let outbox = |user_id: &str| {
+        OutboxEvent::user(
+            &REALM.to_string(),
+            WebhookEventType::UserUpdated,
+            user_id,
+            json!({ "email": format!("{}@nitro.com", user_id) }),
+            1_000,
+        )
+    };
+    storage
+        .in_transaction(|tx| {
+            tx.append_outbox_event(&outbox(&alice))?;
+            tx.append_outbox_event(&outbox(&alice))?;
+            tx.append_outbox_event(&outbox(&bob))
+        })
+        .unwrap();
+    let redacted = storage
+        .in_transaction(|tx| {
+            tx.redact_outbox_events(&REALM.to_string(), USER_AGGREGATE, &alice, &json!({}))
+        })
+        .unwrap();
+    assert_eq!(redacted, 2);
+    let mut claimed = storage
+        .in_transaction(|tx| tx.claim_outbox_events(1_000, 1_100, 1_000))
+        .unwrap()
+        .into_iter()
+        .filter(|record| record.event.aggregate_id == alice || record.event.aggregate_id == bob)
+        .collect::<Vec<_>>();
+    let payloads = claimed
+        .iter()
+        .map(|record| record.event.payload.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(payloads, vec![json!({}), outbox(&bob).payload]);
+    for record in &mut claimed {
+        record.published(1_010);
+        storage
+            .in_transaction(|tx| tx.update_outbox_event(record))
+            .unwrap();
+    }
+}
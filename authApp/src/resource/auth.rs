@@ -0,0 +1,105 @@
+//! Authentication of requests by the access token of a session, see [`Authenticated`],
+//! [`Admin`] and [`Enrolling`].
+
+use crate::app::Error;
+use crate::domain::infra::web::{JsonErrorResponse, LoginError};
+use crate::domain::session::{Principal, TokenScope};
+use crate::resource::customer::request_realm;
+use crate::service::customer_service::AuthenticatorService;
+use crate::AppState;
+use actix_web::dev::Payload;
+use actix_web::http::{header, StatusCode};
+use actix_web::web::Data;
+use actix_web::{web, FromRequest, HttpRequest};
+use std::future::Future;
+use std::pin::Pin;
+
+type AuthFuture<T> = Pin<Box<dyn Future<Output = Result<T, JsonErrorResponse<Option<String>>>>>>;
+
+/// The caller named by the `Authorization: Bearer` access token of the request, which must
+/// belong to an open session in the request's realm. Other requests are refused with a 401, and
+/// enrollment tokens, see [`Enrolling`], with a 403.
+pub struct Authenticated(pub Principal);
+
+impl FromRequest for Authenticated {
+    type Error = JsonErrorResponse<Option<String>>;
+    type Future = AuthFuture<Self>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move {
+            let principal = authenticate(&req).await?;
+            require_full_scope(&principal)?;
+            Ok(Authenticated(principal))
+        })
+    }
+}
+
+/// Like [`Authenticated`], also accepting the enrollment token a login hands out to users whose
+/// role requires a second factor they have not enrolled. Only for the endpoints enrolling one.
+pub struct Enrolling(pub Principal);
+
+impl FromRequest for Enrolling {
+    type Error = JsonErrorResponse<Option<String>>;
+    type Future = AuthFuture<Self>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move { authenticate(&req).await.map(Enrolling) })
+    }
+}
+
+/// An [`Authenticated`] caller with the admin role, other callers are refused with a 403.
+pub struct Admin(pub Principal);
+
+impl FromRequest for Admin {
+    type Error = JsonErrorResponse<Option<String>>;
+    type Future = AuthFuture<Self>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move {
+            let principal = authenticate(&req).await?;
+            require_full_scope(&principal)?;
+            principal.require_admin()?;
+            Ok(Admin(principal))
+        })
+    }
+}
+
+async fn authenticate(req: &HttpRequest) -> Result<Principal, JsonErrorResponse<Option<String>>> {
+    let data = req
+        .app_data::<Data<AppState>>()
+        .cloned()
+        .ok_or(LoginError::MissingAppState)?;
+    let realm = request_realm(req, &data)?;
+    let token = bearer_token(req).ok_or(Error::Unauthenticated)?;
+
+    let result = web::block(move || {
+        let storage = data.execution_context.storage.as_ref();
+        AuthenticatorService::authenticate(&realm, &token, &data.access_token_key, storage)
+    })
+    .await
+    .map_err(|e| JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    Ok(result?)
+}
+
+fn require_full_scope(principal: &Principal) -> Result<(), Error> {
+    match principal.scope {
+        TokenScope::Full => Ok(()),
+        TokenScope::MfaEnrollment => Err(Error::Forbidden(
+            "enroll a second factor and log in again first".to_string(),
+        )),
+    }
+}
+
+fn bearer_token(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(|token| token.trim().to_string())
+        .filter(|token| !token.is_empty())
+}
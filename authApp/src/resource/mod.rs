@@ -1,39 +1,159 @@
+pub mod auth;
+pub mod rate_limit;
+
 pub mod customer {
-    use crate::domain::customer::{dto::CreateUser, LoginRequest, LoginRequestArguments, User};
-    use crate::domain::infra::web::auth::verify_login;
+    use crate::domain::customer::dto::{
+        AddressData, ChangePassword, CreateUser, DeleteUser, EmailConfirmation, MfaLogin,
+        PasswordReset, PasswordResetRequest, Reauthentication, TotpCode, UpdateUser,
+    };
+    use crate::domain::customer::{Address, FormattedAddress, LoginRequest, Role, User};
     use crate::domain::infra::web::{JsonErrorResponse, LoginError, RealmFinder};
-    use crate::service::customer_service::{AuthenticatorService, CustomerService};
+    use crate::domain::passwordless::{PasswordlessLogin, PasswordlessRequest};
+    use crate::service::customer_service::{
+        AuthenticatorService, CustomerService, LoginOutcome, TokenLifetimes,
+    };
     use crate::AppState;
 
     use crate::domain::realm::RealmName;
+    use crate::domain::session::{ClientInfo, RefreshRequest};
+    use crate::domain::validation::ValidateRequest;
+    use crate::domain::webauthn::{
+        AuthenticationCredential, PasskeyLoginStart, RegistrationCredential,
+    };
     use crate::repository::realm::RealmSettingProvider;
-    use actix_web::http::StatusCode;
+    use crate::resource::auth::{Authenticated, Enrolling};
+    use actix_web::http::{header, StatusCode};
     use actix_web::web::Data;
     use actix_web::{web, web::Path, HttpRequest, HttpResponse, Responder};
     use serde::Deserialize;
 
     #[derive(Deserialize)]
     pub struct UserId {
-        user_id: String, // must match the path param name
+        pub user_id: String, // must match the path param name
     }
 
-    struct LoginUserData<'a> {
-        user: User,
-        realm: RealmName,
-        realm_settings_provider: &'a RealmSettingProvider,
+    #[derive(Deserialize)]
+    pub struct AddressId {
+        pub user_id: String,
+        pub address_id: String,
+    }
+
+    #[derive(Deserialize)]
+    pub struct PasskeyId {
+        pub user_id: String,
+        pub credential_id: String,
+    }
+
+    #[derive(Deserialize)]
+    pub struct SessionId {
+        pub user_id: String,
+        pub session_id: String,
     }
 
     type LoginErrorResponse = JsonErrorResponse<Option<String>>;
 
-    pub async fn login(
-        path_param: Path<UserId>,
-        json: web::Json<LoginRequest>,
-        req: HttpRequest,
-    ) -> Result<HttpResponse, LoginErrorResponse> {
+    /// The realm named by the request's `Realm` header, which must be a configured realm. Every
+    /// lookup is scoped to it, so users of other realms are reported as not found.
+    pub(crate) fn request_realm(
+        req: &HttpRequest,
+        data: &AppState,
+    ) -> Result<RealmName, LoginError> {
         let realm = req
             .headers()
             .get_realm()
             .ok_or(LoginError::MissingRealmHeader)?;
+        if data.realm_settings_provider.has_realm(&realm) {
+            Ok(realm)
+        } else {
+            Err(LoginError::UnknownRealm)
+        }
+    }
+
+    /// Runs the declarative and realm specific rules of a request body, see
+    /// [`ValidateRequest`].
+    pub(crate) fn validated<T: ValidateRequest>(
+        body: web::Json<T>,
+        realm: &str,
+        data: &AppState,
+    ) -> Result<T, JsonErrorResponse<Option<String>>> {
+        let policy = data.realm_settings_provider.get_password_policy(realm);
+        body.validate_request(&policy)?;
+        Ok(body.into_inner())
+    }
+
+    /// How long the tokens of sessions of `realm` live.
+    fn token_lifetimes(realm: &str, data: &AppState) -> TokenLifetimes {
+        let settings = &data.realm_settings_provider;
+        TokenLifetimes {
+            access: settings.get_authentication_token_duration(realm),
+            refresh: settings.get_refresh_token_duration(realm),
+        }
+    }
+
+    /// The user agent and IP of the request, kept with the session a login opens.
+    fn client_info(req: &HttpRequest) -> ClientInfo {
+        let user_agent = req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|agent| agent.to_str().ok());
+        ClientInfo::new(
+            user_agent,
+            req.peer_addr().map(|addr| addr.ip().to_string()),
+        )
+    }
+
+    /// Opens a session for a user who just logged in and answers with its tokens, see
+    /// [`AuthenticatorService::start_session`].
+    async fn session_response(
+        user: User,
+        req: &HttpRequest,
+    ) -> Result<HttpResponse, LoginErrorResponse> {
+        let data = req
+            .app_data::<Data<AppState>>()
+            .cloned()
+            .ok_or(LoginError::MissingAppState)?;
+        let realm = request_realm(req, &data)?;
+        let client = client_info(req);
+        let lifetimes = token_lifetimes(&realm, &data);
+
+        let tokens = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            let key = data.access_token_key.as_ref();
+            AuthenticatorService::start_session(&realm, &user, client, lifetimes, key, storage)
+        })
+        .await
+        .map_err(|e| LoginError::DatabaseError(e.to_string()))??;
+
+        Ok(HttpResponse::Ok().json(tokens))
+    }
+
+    /// Answers a login that must enroll a second factor first with an enrollment token.
+    async fn enrollment_response(
+        user: User,
+        req: &HttpRequest,
+    ) -> Result<HttpResponse, LoginErrorResponse> {
+        let data = req
+            .app_data::<Data<AppState>>()
+            .cloned()
+            .ok_or(LoginError::MissingAppState)?;
+        let realm = request_realm(req, &data)?;
+        let client = client_info(req);
+
+        let enrollment = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            let key = data.access_token_key.as_ref();
+            AuthenticatorService::start_enrollment_session(&realm, &user, client, key, storage)
+        })
+        .await
+        .map_err(|e| LoginError::DatabaseError(e.to_string()))??;
+
+        Ok(HttpResponse::Ok().json(enrollment))
+    }
+
+    pub async fn login(
+        json: web::Json<LoginRequest>,
+        req: HttpRequest,
+    ) -> Result<HttpResponse, LoginErrorResponse> {
         let login_request = json.0;
 
         let data = match req.app_data::<Data<AppState>>() {
@@ -46,121 +166,305 @@ pub mod customer {
                 ))
             }
         };
+        let realm = request_realm(&req, &data)?;
+        let client_ip = req.peer_addr().map(|addr| addr.ip().to_string());
+        let lockout = data.realm_settings_provider.get_lockout_policy(&realm);
+        let mfa = data.realm_settings_provider.get_mfa_policy(&realm);
+
+        let outcome = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            AuthenticatorService::login(
+                &realm,
+                &login_request,
+                client_ip.as_deref(),
+                &lockout,
+                &mfa,
+                storage,
+            )
+        })
+        .await
+        .map_err(|e| LoginError::DatabaseError(e.to_string()))??;
 
-        let login_user_data = fetch_user_data(&data, &realm, &login_request).await?;
+        match outcome {
+            LoginOutcome::Authenticated(user) => session_response(user, &req).await,
+            LoginOutcome::MfaRequired(challenge) => Ok(HttpResponse::Ok().json(challenge)),
+            LoginOutcome::EnrollmentRequired(user) => enrollment_response(user, &req).await,
+        }
+    }
+
+    /// Second login step for users with a second factor, exchanges the challenge of
+    /// [`login`] and a code for an access token.
+    pub async fn login_mfa(
+        req_body: web::Json<MfaLogin>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, LoginErrorResponse> {
+        let realm = request_realm(&req, &data)?;
+        let mfa_login = validated(req_body, &realm, &data)?;
+        let client_ip = req.peer_addr().map(|addr| addr.ip().to_string());
+        let lockout = data.realm_settings_provider.get_lockout_policy(&realm);
+
+        let user = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            AuthenticatorService::complete_mfa(
+                &realm,
+                mfa_login,
+                client_ip.as_deref(),
+                &lockout,
+                &data.secret_cipher,
+                storage,
+            )
+        })
+        .await
+        .map_err(|e| LoginError::DatabaseError(e.to_string()))??;
 
-        authenticate_user(login_user_data, login_request).await
+        session_response(user, &req).await
     }
 
-    async fn authenticate_user<'a>(
-        login_user_data: LoginUserData<'a>,
-        login_request: LoginRequest,
+    /// Sends a magic link or a login code to the user's email address, see
+    /// [`login_passwordless`]. Always accepted for known realms, so callers cannot probe which
+    /// usernames exist.
+    pub async fn request_passwordless(
+        req_body: web::Json<PasswordlessRequest>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
     ) -> Result<HttpResponse, LoginErrorResponse> {
-        let login_arg = LoginRequestArguments {
-            login_request,
-            user: login_user_data.user,
-        };
-        let itr = login_user_data
-            .realm_settings_provider
-            .get_realm_salt_itr(login_user_data.realm.as_str());
-        let is_ok = verify_login(&login_arg, login_user_data.realm, itr);
+        let realm = request_realm(&req, &data)?;
+        let request = validated(req_body, &realm, &data)?;
+        let policy = data.realm_settings_provider.get_passwordless_policy(&realm);
 
-        if is_ok {
-            let token = AuthenticatorService::initialise_token(&login_arg.user);
-            Ok(HttpResponse::Ok().json(token))
-        } else {
-            Err(LoginErrorResponse::new(
-                None,
-                "Authentication failed".to_string(),
-                StatusCode::UNAUTHORIZED,
-            ))
-        }
+        web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            AuthenticatorService::request_passwordless(
+                &realm,
+                request,
+                &policy,
+                data.delivery_channel.as_ref(),
+                storage,
+            )
+        })
+        .await
+        .map_err(|e| LoginError::DatabaseError(e.to_string()))??;
+
+        Ok(HttpResponse::Accepted().finish())
     }
 
-    async fn fetch_user_data<'a>(
-        data: &'a Data<AppState>,
-        realm: &RealmName,
-        payload: &LoginRequest,
-    ) -> Result<LoginUserData<'a>, LoginError> {
-        let db = data.execution_context.db.clone();
-        let username = payload.username.clone();
-        let rlm = realm.clone();
-        let result = web::block(move || CustomerService::fetch_user_by_name(&username, &rlm, &db))
-            .await
-            .map_err(|e| LoginError::DatabaseError(e.to_string()))?;
+    /// Exchanges an emailed code or magic link token for an access token, or for the
+    /// challenge of a second factor like [`login`].
+    pub async fn login_passwordless(
+        req_body: web::Json<PasswordlessLogin>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, LoginErrorResponse> {
+        let realm = request_realm(&req, &data)?;
+        let login = validated(req_body, &realm, &data)?;
+        let client_ip = req.peer_addr().map(|addr| addr.ip().to_string());
+        let provider = &data.realm_settings_provider;
+        let policy = provider.get_passwordless_policy(&realm);
+        let lockout = provider.get_lockout_policy(&realm);
+        let mfa = provider.get_mfa_policy(&realm);
+
+        let outcome = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            AuthenticatorService::passwordless_login(
+                &realm,
+                login,
+                client_ip.as_deref(),
+                &policy,
+                &lockout,
+                &mfa,
+                storage,
+            )
+        })
+        .await
+        .map_err(|e| LoginError::DatabaseError(e.to_string()))??;
 
-        match result {
-            None => Err(LoginError::UserNotFound),
-            Some(u) => Ok(LoginUserData {
-                user: u,
-                realm: realm.clone(),
-                realm_settings_provider: data.realm_settings_provider.as_ref(),
-            }),
+        match outcome {
+            LoginOutcome::Authenticated(user) => session_response(user, &req).await,
+            LoginOutcome::MfaRequired(challenge) => Ok(HttpResponse::Ok().json(challenge)),
+            LoginOutcome::EnrollmentRequired(user) => enrollment_response(user, &req).await,
         }
     }
 
+    /// Options for a passwordless login with a passkey, see [`login_passkey`].
+    pub async fn login_passkey_options(
+        req_body: web::Json<PasskeyLoginStart>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, LoginErrorResponse> {
+        let realm = request_realm(&req, &data)?;
+        let start = validated(req_body, &realm, &data)?;
+        let config = data.realm_settings_provider.get_webauthn_config(&realm);
+
+        let options = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            AuthenticatorService::passkey_login_options(&realm, start, &config, storage)
+        })
+        .await
+        .map_err(|e| LoginError::DatabaseError(e.to_string()))??;
+
+        Ok(HttpResponse::Ok().json(options))
+    }
+
+    /// Exchanges a passkey assertion for an access token, like [`login`] does a password.
+    pub async fn login_passkey(
+        req_body: web::Json<AuthenticationCredential>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, LoginErrorResponse> {
+        let realm = request_realm(&req, &data)?;
+        let credential = validated(req_body, &realm, &data)?;
+        let client_ip = req.peer_addr().map(|addr| addr.ip().to_string());
+        let lockout = data.realm_settings_provider.get_lockout_policy(&realm);
+        let config = data.realm_settings_provider.get_webauthn_config(&realm);
+
+        let user = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            AuthenticatorService::passkey_login(
+                &realm,
+                credential,
+                client_ip.as_deref(),
+                &lockout,
+                &config,
+                storage,
+            )
+        })
+        .await
+        .map_err(|e| LoginError::DatabaseError(e.to_string()))??;
+
+        session_response(user, &req).await
+    }
+
+    /// Exchanges a refresh token for new tokens of its session, see
+    /// [`AuthenticatorService::refresh_session`].
+    pub async fn refresh_token(
+        req_body: web::Json<RefreshRequest>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, LoginErrorResponse> {
+        let realm = request_realm(&req, &data)?;
+        let request = validated(req_body, &realm, &data)?;
+        let lifetimes = token_lifetimes(&realm, &data);
+
+        let tokens = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            let key = data.access_token_key.as_ref();
+            AuthenticatorService::refresh_session(&realm, request, lifetimes, key, storage)
+        })
+        .await
+        .map_err(|e| LoginError::DatabaseError(e.to_string()))??;
+
+        Ok(HttpResponse::Ok().json(tokens))
+    }
+
     pub async fn get(
+        Authenticated(principal): Authenticated,
+        path_param: Path<UserId>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        principal.require_user(&path_param.user_id)?;
+        apply_get(path_param, req, data).await
+    }
+
+    /// Shared by the customer and admin profile endpoints.
+    pub(crate) async fn apply_get(
         path_param: Path<UserId>,
+        req: HttpRequest,
         data: web::Data<AppState>,
     ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
-        let db_res = web::block(move || {
-            let db = &data.execution_context.db;
-            CustomerService::fetch_user(&path_param.user_id, &db)
+        let realm = request_realm(&req, &data)?;
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            CustomerService::fetch_user(&path_param.user_id, &realm, storage)
         })
-        .await;
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
 
-        match db_res {
-            Ok(user_res) => user_res
-                .map(|user| Ok(HttpResponse::Ok().json(user.unwrap())))
-                .unwrap_or_else(|_| {
-                    Err(JsonErrorResponse::<Option<String>>::new(
-                        None,
-                        "User not found".to_string(),
-                        StatusCode::NOT_FOUND,
-                    ))
-                }),
-            Err(e) => Err(JsonErrorResponse::<Option<String>>::new(
-                None,
-                e.to_string(),
-                StatusCode::BAD_REQUEST,
-            )),
-        }
+        Ok(HttpResponse::Ok().json(result?))
+    }
+
+    pub async fn update(
+        Authenticated(principal): Authenticated,
+        path_param: Path<UserId>,
+        req_body: web::Json<UpdateUser>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        principal.require_user(&path_param.user_id)?;
+        apply_update(path_param, req_body, req, data, Role::CUSTOMER).await
     }
 
-    pub async fn get_all(
+    /// Shared by the customer and admin update endpoints, which differ only in `acting_role`.
+    pub(crate) async fn apply_update(
+        path_param: Path<UserId>,
+        req_body: web::Json<UpdateUser>,
         req: HttpRequest,
         data: web::Data<AppState>,
+        acting_role: Role,
     ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
-        let db_res = web::block(move || {
-            // HeaderValue::
-            let provider = &data.realm_settings_provider;
-            let db = &data.execution_context.db;
-            let dur = provider.get_password_reset_token_duration("rj.wire");
-            println!("duration: {:?}", dur);
-            CustomerService::fetch_users(&db)
+        let realm = request_realm(&req, &data)?;
+        let changes = validated(req_body, &realm, &data)?;
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            CustomerService::update(
+                &path_param.user_id,
+                &realm,
+                changes,
+                acting_role,
+                data.delivery_channel.as_ref(),
+                storage,
+            )
         })
-        .await;
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
 
-        match db_res {
-            Ok(user_res) => user_res
-                .map(|users| Ok(HttpResponse::Ok().json(users)))
-                .unwrap_or_else(|_| {
-                    Err(JsonErrorResponse::<Option<String>>::new(
-                        None,
-                        "User not found".to_string(),
-                        StatusCode::NOT_FOUND,
-                    ))
-                }),
-            Err(e) => Err(JsonErrorResponse::<Option<String>>::new(
-                None,
-                e.to_string(),
-                StatusCode::BAD_REQUEST,
-            )),
-        }
+        Ok(HttpResponse::Ok().json(result?))
     }
 
-    pub async fn update() -> impl Responder {
-        HttpResponse::Ok().body("Hello world!")
+    pub async fn delete(
+        Authenticated(principal): Authenticated,
+        path_param: Path<UserId>,
+        query: web::Query<DeleteUser>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        principal.require_user(&path_param.user_id)?;
+        apply_delete(path_param, query, req, data, Role::CUSTOMER).await
+    }
+
+    /// Shared by the customer and admin delete endpoints, which differ only in `acting_role`.
+    pub(crate) async fn apply_delete(
+        path_param: Path<UserId>,
+        query: web::Query<DeleteUser>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+        acting_role: Role,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        let realm = request_realm(&req, &data)?;
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            CustomerService::delete(
+                &path_param.user_id,
+                &realm,
+                query.mode,
+                acting_role,
+                storage,
+            )
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        result?;
+        Ok(HttpResponse::NoContent().finish())
     }
 
     pub async fn create(
@@ -168,72 +472,3601 @@ pub mod customer {
         req: HttpRequest,
         data: web::Data<AppState>,
     ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
-        let realm_header = req.headers().get_realm();
-        if let None = realm_header {
-            Err(JsonErrorResponse::<Option<String>>::new(
+        let realm = request_realm(&req, &data)?;
+        let user_data = validated(req_body, &realm, &data)?;
+
+        let future =
+            web::block(move || CustomerService::create(user_data, realm, data.get_ref())).await;
+
+        match future {
+            Ok(result) => result
+                .map(|user_id| HttpResponse::Ok().body(user_id))
+                .map_err(JsonErrorResponse::from),
+            //TODO:: Message should be logged
+            Err(_) => Err(JsonErrorResponse::<Option<String>>::new(
                 None,
                 "Failed to process information".to_string(),
                 StatusCode::INTERNAL_SERVER_ERROR,
-            ))
-        } else {
-            let future = web::block(move || {
-                let user_data = req_body.into_inner();
-                CustomerService::create(user_data, realm_header.unwrap(), data.get_ref())
-            })
-            .await;
-
-            match future {
-                Ok(result) => result
-                    .map(|user_id| HttpResponse::Ok().body(user_id))
-                    .map_err(|e| {
-                        JsonErrorResponse::new(None, e.to_string(), StatusCode::BAD_REQUEST)
-                    }),
-                //TODO:: Message should be logged
-                Err(err) => Err(JsonErrorResponse::<Option<String>>::new(
-                    None,
-                    "Failed to process information".to_string(),
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                )),
-            }
+            )),
         }
     }
 
-    pub async fn manual_hello() -> impl Responder {
-        HttpResponse::Ok().body("Hey there!")
+    pub async fn list_addresses(
+        Authenticated(principal): Authenticated,
+        path_param: Path<UserId>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        principal.require_user(&path_param.user_id)?;
+        let realm = request_realm(&req, &data)?;
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            CustomerService::list_addresses(&path_param.user_id, &realm, storage)
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        Ok(HttpResponse::Ok().json(result?))
     }
-    
-}
 
-pub mod admin {}
+    pub async fn add_address(
+        Authenticated(principal): Authenticated,
+        path_param: Path<UserId>,
+        req_body: web::Json<AddressData>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        principal.require_user(&path_param.user_id)?;
+        let realm = request_realm(&req, &data)?;
+        let address = validated(req_body, &realm, &data)?;
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            CustomerService::add_address(&path_param.user_id, &realm, address, storage)
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
 
-//
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::AppState;
-    use actix_web::{http, test, web::Data, App};
-
-    // Imports depending on the structure of your project
-
-    // #[actix_web::test]
-    // pub async fn test_login() {
-    //     let mut app = test::init_service(
-    //         App::new()
-    //             .data(Data::new(AppState {
-    //                 realm_settings_provider:
-    //             }))
-    //             .route("/{id}", web::post().to(login))
-    //     ).await;
-
-    //     let req = test::TestRequest::post()
-    //         .uri("/test_user_id")
-    //         .header(http::header::CONTENT_TYPE, "application/json")
-    //         .header("Realm", "test_realm")
-    //         .set_json(&LoginRequest {
-    //             // Fill with example data
-    //         })
-    //         .to_request();
-
-    //     let resp: Result<HttpResponse, JsonErrorResponse<Option<String>>> = test::call_service(&mut app, req).await;
-    // }
+        Ok(HttpResponse::Created().json(result?))
+    }
+
+    pub async fn get_address(
+        Authenticated(principal): Authenticated,
+        path_param: Path<AddressId>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        principal.require_user(&path_param.user_id)?;
+        let realm = request_realm(&req, &data)?;
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            CustomerService::get_address(
+                &path_param.user_id,
+                &path_param.address_id,
+                &realm,
+                storage,
+            )
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        Ok(HttpResponse::Ok().json(result?))
+    }
+
+    /// The address rendered as label lines, see [`Address::lines`].
+    pub async fn format_address(
+        Authenticated(principal): Authenticated,
+        path_param: Path<AddressId>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        principal.require_user(&path_param.user_id)?;
+        let realm = request_realm(&req, &data)?;
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            CustomerService::get_address(
+                &path_param.user_id,
+                &path_param.address_id,
+                &realm,
+                storage,
+            )
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        Ok(HttpResponse::Ok().json(FormattedAddress::from(&result?.address)))
+    }
+
+    pub async fn update_address(
+        Authenticated(principal): Authenticated,
+        path_param: Path<AddressId>,
+        req_body: web::Json<AddressData>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        principal.require_user(&path_param.user_id)?;
+        let realm = request_realm(&req, &data)?;
+        let address = validated(req_body, &realm, &data)?;
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            CustomerService::update_address(
+                &path_param.user_id,
+                &path_param.address_id,
+                &realm,
+                address,
+                storage,
+            )
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        Ok(HttpResponse::Ok().json(result?))
+    }
+
+    pub async fn delete_address(
+        Authenticated(principal): Authenticated,
+        path_param: Path<AddressId>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        principal.require_user(&path_param.user_id)?;
+        let realm = request_realm(&req, &data)?;
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            CustomerService::delete_address(
+                &path_param.user_id,
+                &path_param.address_id,
+                &realm,
+                storage,
+            )
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        result?;
+        Ok(HttpResponse::NoContent().finish())
+    }
+
+    pub async fn change_password(
+        Authenticated(principal): Authenticated,
+        path_param: Path<UserId>,
+        req_body: web::Json<ChangePassword>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        principal.require_user(&path_param.user_id)?;
+        let realm = request_realm(&req, &data)?;
+        let change = validated(req_body, &realm, &data)?;
+        let policy = data.realm_settings_provider.get_password_policy(&realm);
+        let lockout = data.realm_settings_provider.get_lockout_policy(&realm);
+        let client_ip = req.peer_addr().map(|addr| addr.ip().to_string());
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            CustomerService::change_password(
+                &principal,
+                change,
+                &policy,
+                client_ip.as_deref(),
+                &lockout,
+                storage,
+            )
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        result?;
+        Ok(HttpResponse::NoContent().finish())
+    }
+
+    /// Starts a TOTP enrollment, the secret is only ever returned here.
+    pub async fn enroll_totp(
+        Enrolling(principal): Enrolling,
+        path_param: Path<UserId>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        principal.require_user(&path_param.user_id)?;
+        let realm = request_realm(&req, &data)?;
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            AuthenticatorService::enroll_totp(
+                &path_param.user_id,
+                &realm,
+                &data.secret_cipher,
+                storage,
+            )
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        Ok(HttpResponse::Created().json(result?))
+    }
+
+    pub async fn activate_totp(
+        Enrolling(principal): Enrolling,
+        path_param: Path<UserId>,
+        req_body: web::Json<TotpCode>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        principal.require_user(&path_param.user_id)?;
+        let realm = request_realm(&req, &data)?;
+        let code = validated(req_body, &realm, &data)?;
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            AuthenticatorService::activate_totp(
+                &path_param.user_id,
+                &realm,
+                code,
+                &data.secret_cipher,
+                storage,
+            )
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        result?;
+        Ok(HttpResponse::NoContent().finish())
+    }
+
+    /// Needs the current password or an authenticator code in the body.
+    pub async fn remove_totp(
+        Authenticated(principal): Authenticated,
+        path_param: Path<UserId>,
+        req_body: web::Json<Reauthentication>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        principal.require_user(&path_param.user_id)?;
+        let realm = request_realm(&req, &data)?;
+        let proof = validated(req_body, &realm, &data)?;
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            AuthenticatorService::remove_totp(
+                &path_param.user_id,
+                &realm,
+                &proof,
+                &data.secret_cipher,
+                storage,
+            )
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        result?;
+        Ok(HttpResponse::NoContent().finish())
+    }
+
+    /// Replaces the recovery codes, the new ones are only ever returned here.
+    /// Needs the current password or an authenticator code in the body, like the TOTP removal.
+    pub async fn regenerate_recovery_codes(
+        Authenticated(principal): Authenticated,
+        path_param: Path<UserId>,
+        req_body: web::Json<Reauthentication>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        principal.require_user(&path_param.user_id)?;
+        let realm = request_realm(&req, &data)?;
+        let proof = validated(req_body, &realm, &data)?;
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            AuthenticatorService::regenerate_recovery_codes(
+                &path_param.user_id,
+                &realm,
+                &proof,
+                &data.secret_cipher,
+                storage,
+            )
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        Ok(HttpResponse::Created().json(result?))
+    }
+
+    pub async fn recovery_code_status(
+        Authenticated(principal): Authenticated,
+        path_param: Path<UserId>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        principal.require_user(&path_param.user_id)?;
+        let realm = request_realm(&req, &data)?;
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            AuthenticatorService::recovery_code_status(&path_param.user_id, &realm, storage)
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        Ok(HttpResponse::Ok().json(result?))
+    }
+
+    /// Options for registering a passkey, see [`register_passkey`].
+    pub async fn passkey_registration_options(
+        Enrolling(principal): Enrolling,
+        path_param: Path<UserId>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        principal.require_user(&path_param.user_id)?;
+        let realm = request_realm(&req, &data)?;
+        let config = data.realm_settings_provider.get_webauthn_config(&realm);
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            AuthenticatorService::passkey_registration_options(
+                &path_param.user_id,
+                &realm,
+                &config,
+                storage,
+            )
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        Ok(HttpResponse::Ok().json(result?))
+    }
+
+    pub async fn register_passkey(
+        Enrolling(principal): Enrolling,
+        path_param: Path<UserId>,
+        req_body: web::Json<RegistrationCredential>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        principal.require_user(&path_param.user_id)?;
+        let realm = request_realm(&req, &data)?;
+        let credential = validated(req_body, &realm, &data)?;
+        let config = data.realm_settings_provider.get_webauthn_config(&realm);
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            AuthenticatorService::register_passkey(
+                &path_param.user_id,
+                &realm,
+                credential,
+                &config,
+                storage,
+            )
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        Ok(HttpResponse::Created().json(result?))
+    }
+
+    pub async fn list_passkeys(
+        Authenticated(principal): Authenticated,
+        path_param: Path<UserId>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        principal.require_user(&path_param.user_id)?;
+        let realm = request_realm(&req, &data)?;
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            AuthenticatorService::list_passkeys(&path_param.user_id, &realm, storage)
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        Ok(HttpResponse::Ok().json(result?))
+    }
+
+    pub async fn remove_passkey(
+        Authenticated(principal): Authenticated,
+        path_param: Path<PasskeyId>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        principal.require_user(&path_param.user_id)?;
+        let realm = request_realm(&req, &data)?;
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            AuthenticatorService::remove_passkey(
+                &path_param.user_id,
+                &path_param.credential_id,
+                &realm,
+                storage,
+            )
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        result?;
+        Ok(HttpResponse::NoContent().finish())
+    }
+
+    pub async fn list_sessions(
+        Authenticated(principal): Authenticated,
+        path_param: Path<UserId>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        principal.require_user(&path_param.user_id)?;
+        apply_list_sessions(path_param, req, data).await
+    }
+
+    /// Shared by the customer and admin session lists.
+    pub(crate) async fn apply_list_sessions(
+        path_param: Path<UserId>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        let realm = request_realm(&req, &data)?;
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            AuthenticatorService::list_sessions(&path_param.user_id, &realm, storage)
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        Ok(HttpResponse::Ok().json(result?))
+    }
+
+    pub async fn revoke_session(
+        Authenticated(principal): Authenticated,
+        path_param: Path<SessionId>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        principal.require_user(&path_param.user_id)?;
+        apply_revoke_session(path_param, req, data, Role::CUSTOMER).await
+    }
+
+    /// Shared by the customer and admin revocations, which are audited as `acting_role`.
+    pub(crate) async fn apply_revoke_session(
+        path_param: Path<SessionId>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+        acting_role: Role,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        let realm = request_realm(&req, &data)?;
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            AuthenticatorService::revoke_session(
+                &path_param.user_id,
+                &path_param.session_id,
+                &realm,
+                acting_role,
+                storage,
+            )
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        result?;
+        Ok(HttpResponse::NoContent().finish())
+    }
+
+    /// Always accepted, so callers cannot probe which usernames exist.
+    pub async fn request_password_reset(
+        req_body: web::Json<PasswordResetRequest>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        let realm = request_realm(&req, &data)?;
+        let request = validated(req_body, &realm, &data)?;
+        let valid_for = data
+            .realm_settings_provider
+            .get_password_reset_token_duration(&realm);
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            CustomerService::request_password_reset(
+                &realm,
+                request,
+                valid_for,
+                data.delivery_channel.as_ref(),
+                storage,
+            )
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        result?;
+        Ok(HttpResponse::Accepted().finish())
+    }
+
+    pub async fn reset_password(
+        req_body: web::Json<PasswordReset>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        let realm = request_realm(&req, &data)?;
+        let reset = validated(req_body, &realm, &data)?;
+        let policy = data.realm_settings_provider.get_password_policy(&realm);
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            CustomerService::reset_password(&realm, reset, &policy, storage)
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        result?;
+        Ok(HttpResponse::NoContent().finish())
+    }
+
+    pub async fn confirm_email(
+        req_body: web::Json<EmailConfirmation>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        let realm = request_realm(&req, &data)?;
+        let confirmation = validated(req_body, &realm, &data)?;
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            CustomerService::confirm_email(&realm, confirmation, storage)
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        result?;
+        Ok(HttpResponse::NoContent().finish())
+    }
+
+    #[derive(Deserialize)]
+    pub struct RealmPath {
+        pub realm: RealmName,
+    }
+
+    /// The password rules of a realm, for UIs to render next to password fields.
+    pub async fn password_policy(
+        path_param: Path<RealmPath>,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        let provider = &data.realm_settings_provider;
+        if !provider.has_realm(&path_param.realm) {
+            return Err(LoginError::UnknownRealm.into());
+        }
+        Ok(HttpResponse::Ok().json(provider.get_password_policy(&path_param.realm)))
+    }
+
+    pub async fn manual_hello() -> impl Responder {
+        HttpResponse::Ok().body("Hey there!")
+    }
+}
+
+pub mod admin {
+    use crate::domain::audit::{AuditQuery, SignedCheckpoint};
+    use crate::domain::customer::dto::{DeleteUser, SetPassword, UpdateUser, UserQuery};
+    use crate::domain::customer::Role;
+    use crate::domain::infra::web::JsonErrorResponse;
+    use crate::domain::webhook::CreateSubscription;
+    use crate::resource::auth::Admin;
+    use crate::resource::customer;
+    use crate::resource::customer::{
+        apply_delete, apply_get, apply_list_sessions, apply_revoke_session, apply_update,
+        request_realm, validated, SessionId, UserId,
+    };
+    use crate::service::audit::AuditService;
+    use crate::service::customer_service::{AuthenticatorService, CustomerService};
+    use crate::service::webhook::WebhookService;
+    use crate::AppState;
+    use actix_web::http::StatusCode;
+    use actix_web::{web, web::Path, HttpRequest, HttpResponse};
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    pub struct SubscriptionId {
+        pub subscription_id: String,
+    }
+
+    #[derive(Deserialize)]
+    pub struct DeliveryId {
+        pub delivery_id: String,
+    }
+
+    /// The users of the admin's realm matching the filters, a page at a time.
+    pub async fn list_customers(
+        _admin: Admin,
+        query: web::Query<UserQuery>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        let realm = request_realm(&req, &data)?;
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            CustomerService::search_users(&realm, &query, storage)
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        Ok(HttpResponse::Ok().json(result?))
+    }
+
+    /// The profile of any user in the admin's realm, like the user's own.
+    pub async fn get_customer(
+        _admin: Admin,
+        path_param: Path<UserId>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        apply_get(path_param, req, data).await
+    }
+
+    /// Same as the customer update, but may also change the user's role.
+    pub async fn update_customer(
+        Admin(admin): Admin,
+        path_param: Path<UserId>,
+        req_body: web::Json<UpdateUser>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        apply_update(path_param, req_body, req, data, admin.role).await
+    }
+
+    /// Same as the customer delete, but may also erase the user with `?mode=erase`.
+    pub async fn delete_customer(
+        Admin(admin): Admin,
+        path_param: Path<UserId>,
+        query: web::Query<DeleteUser>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        apply_delete(path_param, query, req, data, admin.role).await
+    }
+
+    /// Sets a password without knowing the current one, still subject to the realm's policy.
+    pub async fn set_password(
+        _admin: Admin,
+        path_param: Path<UserId>,
+        req_body: web::Json<SetPassword>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        let realm = request_realm(&req, &data)?;
+        let password = validated(req_body, &realm, &data)?;
+        let policy = data.realm_settings_provider.get_password_policy(&realm);
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            CustomerService::set_password(&path_param.user_id, &realm, password, &policy, storage)
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        result?;
+        Ok(HttpResponse::NoContent().finish())
+    }
+
+    /// Lifts the brute-force lockout of a user before it expires.
+    pub async fn unlock_customer(
+        _admin: Admin,
+        path_param: Path<UserId>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        let realm = request_realm(&req, &data)?;
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            AuthenticatorService::unlock(&path_param.user_id, &realm, storage)
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        result?;
+        Ok(HttpResponse::NoContent().finish())
+    }
+
+    /// The sessions of any user in the admin's realm, like the user's own listing.
+    pub async fn list_sessions(
+        _admin: Admin,
+        path_param: Path<UserId>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        apply_list_sessions(path_param, req, data).await
+    }
+
+    /// Ends a session of any user in the admin's realm, e.g. of a lost device.
+    pub async fn revoke_session(
+        Admin(admin): Admin,
+        path_param: Path<SessionId>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        apply_revoke_session(path_param, req, data, admin.role).await
+    }
+
+    /// Audit events of the admin's realm, newest first, a page at a time.
+    pub async fn audit_events(
+        _admin: Admin,
+        query: web::Query<AuditQuery>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        let realm = request_realm(&req, &data)?;
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            AuditService::search(&realm, &query, storage)
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        Ok(HttpResponse::Ok().json(result?))
+    }
+
+    /// Walks the hash chain of the admin's realm and reports the first break, if any.
+    pub async fn verify_audit_chain(
+        _admin: Admin,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        let realm = request_realm(&req, &data)?;
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            AuditService::verify(&realm, storage)
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        Ok(HttpResponse::Ok().json(result?))
+    }
+
+    /// A signed checkpoint of the current head of the realm's chain, for auditors to keep.
+    pub async fn audit_checkpoint(
+        _admin: Admin,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        let realm = request_realm(&req, &data)?;
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            AuditService::checkpoint(&realm, &data.checkpoint_signer, storage)
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        Ok(HttpResponse::Ok().json(result?))
+    }
+
+    /// Checks a previously exported checkpoint against the realm's chain.
+    pub async fn verify_audit_checkpoint(
+        _admin: Admin,
+        req_body: web::Json<SignedCheckpoint>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        let realm = request_realm(&req, &data)?;
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            AuditService::verify_checkpoint(&realm, &req_body, &data.checkpoint_signer, storage)
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        Ok(HttpResponse::Ok().json(result?))
+    }
+
+    /// Subscribes a URL to user events of the admin's realm. The response holds the signing
+    /// secret, which is not shown again.
+    pub async fn create_webhook(
+        _admin: Admin,
+        req_body: web::Json<CreateSubscription>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        let realm = request_realm(&req, &data)?;
+        let request = validated(req_body, &realm, &data)?;
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            WebhookService::subscribe(
+                &realm,
+                request,
+                &data.webhook_endpoints,
+                &data.secret_cipher,
+                storage,
+            )
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        Ok(HttpResponse::Created().json(result?))
+    }
+
+    pub async fn list_webhooks(
+        _admin: Admin,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        let realm = request_realm(&req, &data)?;
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            WebhookService::list(&realm, storage)
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        Ok(HttpResponse::Ok().json(result?))
+    }
+
+    pub async fn delete_webhook(
+        _admin: Admin,
+        path_param: Path<SubscriptionId>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        let realm = request_realm(&req, &data)?;
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            WebhookService::unsubscribe(&realm, &path_param.subscription_id, storage)
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        result?;
+        Ok(HttpResponse::NoContent().finish())
+    }
+
+    /// Deliveries of the admin's realm that ran out of retries, newest first.
+    pub async fn dead_letters(
+        _admin: Admin,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        let realm = request_realm(&req, &data)?;
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            WebhookService::dead_letters(&realm, storage)
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        Ok(HttpResponse::Ok().json(result?))
+    }
+
+    /// Queues a delivery again, it is sent on the next dispatch run.
+    pub async fn replay_delivery(
+        _admin: Admin,
+        path_param: Path<DeliveryId>,
+        req: HttpRequest,
+        data: web::Data<AppState>,
+    ) -> Result<HttpResponse, JsonErrorResponse<Option<String>>> {
+        let realm = request_realm(&req, &data)?;
+
+        let result = web::block(move || {
+            let storage = data.execution_context.storage.as_ref();
+            WebhookService::replay(&realm, &path_param.delivery_id, storage)
+        })
+        .await
+        .map_err(|e| {
+            JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        Ok(HttpResponse::Accepted().json(result?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::ExecutionContext;
+    use crate::domain::audit::{
+        AuditEventType, AuditPage, AuditQuery, ChainVerification, CheckpointSigner,
+        CheckpointStatus, CheckpointVerification, SignedCheckpoint,
+    };
+    use crate::domain::customer::dto::{hash_password, verify_password, CreateUser, UserMetadata};
+    use crate::domain::customer::{
+        Address, AddressType, FormattedAddress, Role, User, UserAddress, UserPage, UserProfile,
+    };
+    use crate::domain::mfa::{
+        totp_code, MfaChallenge, MfaEnrollment, MfaMethod, MfaPolicy, RecoveryCodeStatus,
+        RecoveryCodes, SecretCipher, TotpSetup,
+    };
+    use crate::domain::passwordless::{PasswordlessMethod, PasswordlessPolicy};
+    use crate::domain::realm::PasswordPolicy;
+    use crate::domain::session::{AccessTokenKey, ClientInfo, Session, SessionInfo, SessionTokens};
+    use crate::domain::webauthn::{
+        AuthenticationCredential, CreationOptions, PasskeyInfo, RequestOptions, SoftAuthenticator,
+        WebAuthnConfig,
+    };
+    use crate::domain::webhook::{
+        sign_payload, CreateSubscription, CreatedSubscription, DeliveryStatus, EndpointPolicy,
+        RetryPolicy, SubscriptionInfo, WebhookDelivery, WebhookEventType, WebhookPayload,
+    };
+    use crate::repository::memory::{default_realm_settings, InMemoryStorage};
+    use crate::repository::rate_limit::InMemoryRateLimiter;
+    use crate::repository::realm::RealmSettingProvider;
+    use crate::repository::{Storage, TokenKind};
+    use crate::route::routes;
+    use crate::service::audit::AuditService;
+    use crate::service::delivery::{
+        AccountMessage, AccountMessageKind, DeliveryChannel, RecordingChannel, UnconfiguredChannel,
+        WebhookChannel,
+    };
+    use crate::service::outbox::{OutboxService, RecordingPublisher};
+    use crate::service::webhook::{
+        HttpTransport, WebhookPublisher, WebhookService, WebhookTransport,
+    };
+    use crate::AppState;
+    use actix_web::{http::StatusCode, test, web::Data, App};
+    use chrono::Utc;
+    use data_encoding::BASE32_NOPAD;
+    use serde_json::json;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{mpsc, Arc};
+    use std::time::Duration;
+
+    fn app_state() -> Data<AppState> {
+        app_state_with(InMemoryStorage::with_default_realms())
+    }
+
+    fn app_state_with(storage: InMemoryStorage) -> Data<AppState> {
+        app_state_delivering(storage, Arc::new(RecordingChannel::default()))
+    }
+
+    fn app_state_delivering(
+        storage: InMemoryStorage,
+        delivery_channel: Arc<dyn DeliveryChannel>,
+    ) -> Data<AppState> {
+        let storage: Arc<dyn Storage> = Arc::new(storage);
+        Data::new(AppState {
+            realm_settings_provider: Arc::new(RealmSettingProvider::init(storage.clone())),
+            execution_context: ExecutionContext { storage },
+            rate_limiter: Arc::new(InMemoryRateLimiter::default()),
+            secret_cipher: Arc::new(SecretCipher::ephemeral()),
+            delivery_channel,
+            checkpoint_signer: Arc::new(CheckpointSigner::ephemeral()),
+            access_token_key: Arc::new(AccessTokenKey::ephemeral()),
+            // The webhook receivers of the tests listen on plain http on the loopback interface.
+            webhook_endpoints: EndpointPolicy {
+                allow_http: true,
+                allow_private_targets: true,
+            },
+        })
+    }
+
+    fn create_user(username: &str) -> CreateUser {
+        CreateUser {
+            username: username.to_string(),
+            password: "passw0rd".to_string(),
+            name: "Ru Ru".to_string(),
+            age: 30,
+            email: "ruru@nitro.com".to_string(),
+            address: Address {
+                street: "The Street".to_string(),
+                country: "UK".to_string(),
+                city: "London".to_string(),
+                post_code: "W1 2DE".to_string(),
+            },
+        }
+    }
+
+    /// An access token of a new session of the user, as a login hands out. The session is
+    /// stored directly so that the test's audit trail stays as the test made it.
+    fn access_token(state: &AppState, realm: &str, user_id: &str) -> String {
+        let storage = state.execution_context.storage.as_ref();
+        let now = Utc::now().timestamp();
+        let valid_for = Duration::from_secs(120);
+        let (session, _) = Session::open(
+            &realm.to_string(),
+            user_id,
+            ClientInfo::default(),
+            now,
+            valid_for,
+        );
+        storage
+            .in_transaction(|tx| tx.create_session(&session))
+            .unwrap();
+        state.access_token_key.issue(&session, now, valid_for)
+    }
+
+    /// An access token of a new admin of `realm`, for the admin endpoints.
+    fn admin_token(state: &AppState, realm: &str) -> String {
+        let storage = state.execution_context.storage.as_ref();
+        let realm_name = realm.to_string();
+        let admin = CreateUser {
+            password: hash_password("root", realm, "passw0rd").unwrap(),
+            email: "root@nitro.com".to_string(),
+            ..create_user("root")
+        };
+        let user_id = storage
+            .in_transaction(|tx| {
+                let user_id = tx.users().create(&realm_name, admin)?;
+                let metadata = UserMetadata {
+                    role: Some(Role::ADMIN),
+                    ..UserMetadata::default()
+                };
+                tx.users().update(&realm_name, &user_id, metadata)?;
+                Ok(user_id)
+            })
+            .unwrap();
+        access_token(state, realm, &user_id)
+    }
+
+    fn bearer(token: &str) -> (&'static str, String) {
+        ("Authorization", format!("Bearer {}", token))
+    }
+
+    #[actix_web::test]
+    async fn test_create_and_fetch_user_without_database() {
+        let state = app_state();
+        let wire_admin = admin_token(&state, "rj.wire");
+        let haven_admin = admin_token(&state, "rj.haven");
+        let app = test::init_service(App::new().app_data(state.clone()).configure(routes)).await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/customer")
+            .insert_header(("Realm", "rj.wire"))
+            .set_json(create_user("ruru"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let user_id = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        let token = access_token(&state, "rj.wire", &user_id);
+
+        let profile = |token: Option<&str>| {
+            let req = test::TestRequest::get()
+                .uri(&format!("/api/customer/{}", user_id))
+                .insert_header(("Realm", "rj.wire"));
+            match token {
+                Some(token) => req.insert_header(bearer(token)),
+                None => req,
+            }
+            .to_request()
+        };
+        let resp = test::call_service(&app, profile(None)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let user: serde_json::Value =
+            test::call_and_read_body_json(&app, profile(Some(&token))).await;
+        assert_eq!(user["username"], "ruru");
+        assert_eq!(user["email"], "ruru@nitro.com");
+        assert!(user.get("hashed_pass").is_none());
+
+        // Listing users is left to admins, profiles of others too.
+        let list = |realm: &str, token: &str| {
+            test::TestRequest::get()
+                .uri("/api/admin/customer?role=CUSTOMER")
+                .insert_header(("Realm", realm))
+                .insert_header(bearer(token))
+                .to_request()
+        };
+        let resp = test::call_service(&app, list("rj.wire", &token)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/admin/customer/{}", user_id))
+            .insert_header(("Realm", "rj.wire"))
+            .insert_header(bearer(&wire_admin))
+            .to_request();
+        let user: UserProfile = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(user.username, "ruru");
+        let page: UserPage =
+            test::call_and_read_body_json(&app, list("rj.wire", &wire_admin)).await;
+        assert_eq!(page.total, 1);
+        assert_eq!(
+            page.users[0]
+                .addresses
+                .first()
+                .map(|a| a.address.city.as_str()),
+            Some("London")
+        );
+
+        let page: UserPage =
+            test::call_and_read_body_json(&app, list("rj.haven", &haven_admin)).await;
+        assert!(page.users.is_empty());
+
+        let invalid = CreateUser {
+            password: String::new(),
+            age: 200,
+            ..create_user("ru ru")
+        };
+        let req = test::TestRequest::post()
+            .uri("/api/customer")
+            .insert_header(("Realm", "rj.wire"))
+            .set_json(invalid)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        let mut fields: Vec<&String> = body["errors"].as_object().unwrap().keys().collect();
+        fields.sort();
+        assert_eq!(fields, vec!["age", "password", "username"]);
+    }
+
+    #[actix_web::test]
+    async fn test_patch_user() {
+        let channel = Arc::new(RecordingChannel::default());
+        let state = app_state_delivering(InMemoryStorage::with_default_realms(), channel.clone());
+        let app = test::init_service(App::new().app_data(state.clone()).configure(routes)).await;
+
+        let mut ids = Vec::new();
+        for (username, email) in [("ruru", "ruru@nitro.com"), ("kiki", "kiki@nitro.com")] {
+            let user = CreateUser {
+                email: email.to_string(),
+                ..create_user(username)
+            };
+            let req = test::TestRequest::post()
+                .uri("/api/customer")
+                .insert_header(("Realm", "rj.wire"))
+                .set_json(user)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            ids.push(String::from_utf8(test::read_body(resp).await.to_vec()).unwrap());
+        }
+
+        let token = access_token(&state, "rj.wire", &ids[0]);
+        let patch = |uri: String, realm: &str, body: serde_json::Value| {
+            test::TestRequest::patch()
+                .uri(&uri)
+                .insert_header(("Realm", realm.to_string()))
+                .insert_header(bearer(&token))
+                .set_json(body)
+                .to_request()
+        };
+        let customer_uri = format!("/api/customer/{}", ids[0]);
+        let reset_tokens = || {
+            channel
+                .tokens()
+                .into_iter()
+                .filter(|message| message.kind == AccountMessageKind::PasswordReset)
+                .map(|message| message.email)
+                .collect::<Vec<_>>()
+        };
+        let request_reset = || {
+            test::TestRequest::post()
+                .uri("/api/customer/password-reset")
+                .insert_header(("Realm", "rj.wire"))
+                .set_json(json!({ "username": "ruru" }))
+                .to_request()
+        };
+
+        // Only the user may change their profile.
+        let req = test::TestRequest::patch()
+            .uri(&customer_uri)
+            .insert_header(("Realm", "rj.wire"))
+            .set_json(json!({ "email": "mallory@nitro.com" }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let req = test::TestRequest::patch()
+            .uri(&customer_uri)
+            .insert_header(("Realm", "rj.wire"))
+            .insert_header(bearer(&access_token(&state, "rj.wire", &ids[1])))
+            .set_json(json!({ "email": "mallory@nitro.com" }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        // A new email waits for confirmation, the current one stays in use meanwhile.
+        let req = patch(
+            customer_uri.clone(),
+            "rj.wire",
+            json!({ "name": "Ru Paul", "email": "paul@nitro.com" }),
+        );
+        let profile: UserProfile = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(profile.name.as_deref(), Some("Ru Paul"));
+        assert_eq!(profile.email.as_deref(), Some("ruru@nitro.com"));
+        assert!(!profile.email_verified);
+        assert_eq!(profile.addresses.len(), 1);
+
+        // Reset tokens only go to a verified email.
+        let resp = test::call_service(&app, request_reset()).await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        assert!(reset_tokens().is_empty());
+
+        // Signing up and changing the address each send a verification token to the address.
+        let sent: Vec<(String, String)> = channel
+            .tokens()
+            .into_iter()
+            .filter(|message| message.kind == AccountMessageKind::EmailVerification)
+            .map(|message| (message.email, message.token))
+            .collect();
+        let emails: Vec<&str> = sent.iter().map(|(email, _)| email.as_str()).collect();
+        assert_eq!(
+            emails,
+            vec!["ruru@nitro.com", "kiki@nitro.com", "paul@nitro.com"]
+        );
+        let confirm = |token: &str| {
+            test::TestRequest::post()
+                .uri("/api/customer/email/confirm")
+                .insert_header(("Realm", "rj.wire"))
+                .set_json(json!({ "username": "ruru", "token": token }))
+                .to_request()
+        };
+        // The token of the replaced address no longer confirms anything.
+        let resp = test::call_service(&app, confirm(&sent[0].1)).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = test::call_service(&app, confirm(&sent[2].1)).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let req = patch(
+            customer_uri.clone(),
+            "rj.wire",
+            json!({ "name": "Ru Paul" }),
+        );
+        let profile: UserProfile = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(profile.email.as_deref(), Some("paul@nitro.com"));
+        assert!(profile.email_verified);
+        let resp = test::call_service(&app, request_reset()).await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        assert_eq!(reset_tokens(), vec!["paul@nitro.com"]);
+
+        let cases = [
+            (
+                customer_uri.clone(),
+                "rj.wire",
+                json!({ "email": "kiki@nitro.com" }),
+                StatusCode::CONFLICT,
+            ),
+            (
+                customer_uri.clone(),
+                "rj.wire",
+                json!({ "email": "not-an-email" }),
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            (
+                customer_uri.clone(),
+                "rj.wire",
+                json!({ "role": "ADMIN" }),
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                customer_uri.clone(),
+                "rj.haven",
+                json!({ "name": "Ru" }),
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                "/api/customer/does-not-exist".to_string(),
+                "rj.wire",
+                json!({ "name": "Ru" }),
+                StatusCode::FORBIDDEN,
+            ),
+        ];
+        for (uri, realm, body, status) in cases {
+            let resp = test::call_service(&app, patch(uri, realm, body.clone())).await;
+            assert_eq!(resp.status(), status, "{}", body);
+        }
+
+        // Only authenticated admins may use the admin endpoint.
+        let admin_uri = format!("/api/admin/customer/{}", ids[0]);
+        let req = test::TestRequest::patch()
+            .uri(&admin_uri)
+            .insert_header(("Realm", "rj.wire"))
+            .set_json(json!({ "role": "ADMIN" }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let req = test::TestRequest::patch()
+            .uri(&admin_uri)
+            .insert_header(("Realm", "rj.wire"))
+            .insert_header(bearer(&access_token(&state, "rj.wire", &ids[1])))
+            .set_json(json!({ "role": "ADMIN" }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::patch()
+            .uri(&admin_uri)
+            .insert_header(("Realm", "rj.wire"))
+            .insert_header(bearer(&admin_token(&state, "rj.wire")))
+            .set_json(json!({ "role": "ADMIN" }))
+            .to_request();
+        let profile: UserProfile = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(profile.role, Role::ADMIN);
+    }
+
+    #[actix_web::test]
+    async fn test_delete_user() {
+        let state = app_state();
+        let app = test::init_service(App::new().app_data(state.clone()).configure(routes)).await;
+        let token = admin_token(&state, "rj.wire");
+
+        let mut ids = Vec::new();
+        for (username, email) in [("ruru", "ruru@nitro.com"), ("kiki", "kiki@nitro.com")] {
+            let user = CreateUser {
+                email: email.to_string(),
+                ..create_user(username)
+            };
+            let req = test::TestRequest::post()
+                .uri("/api/customer")
+                .insert_header(("Realm", "rj.wire"))
+                .set_json(user)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            ids.push(String::from_utf8(test::read_body(resp).await.to_vec()).unwrap());
+        }
+        let own = access_token(&state, "rj.wire", &ids[0]);
+
+        let delete = |uri: String, token: &str| {
+            test::TestRequest::delete()
+                .uri(&uri)
+                .insert_header(("Realm", "rj.wire"))
+                .insert_header(bearer(token))
+                .to_request()
+        };
+        let req = test::TestRequest::delete()
+            .uri(&format!("/api/admin/customer/{}?mode=erase", ids[1]))
+            .insert_header(("Realm", "rj.wire"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        // Users may only disable their own account, the disabled account's sessions stop working.
+        let cases = [
+            (
+                format!("/api/customer/{}", ids[1]),
+                &own,
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                format!("/api/customer/{}?mode=erase", ids[0]),
+                &own,
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                format!("/api/customer/{}", ids[0]),
+                &own,
+                StatusCode::NO_CONTENT,
+            ),
+            (
+                format!("/api/customer/{}", ids[0]),
+                &own,
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                format!("/api/admin/customer/{}?mode=erase", ids[0]),
+                &token,
+                StatusCode::NO_CONTENT,
+            ),
+            (
+                format!("/api/admin/customer/{}?mode=erase", ids[0]),
+                &token,
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                format!("/api/admin/customer/{}?mode=shred", ids[1]),
+                &token,
+                StatusCode::BAD_REQUEST,
+            ),
+        ];
+        for (uri, token, status) in cases {
+            let resp = test::call_service(&app, delete(uri.clone(), token)).await;
+            assert_eq!(resp.status(), status, "{}", uri);
+        }
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/admin/customer/{}", ids[0]))
+            .insert_header(("Realm", "rj.wire"))
+            .insert_header(bearer(&token))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_erase_scrubs_personal_data() {
+        let state = app_state();
+        let app = test::init_service(App::new().app_data(state.clone()).configure(routes)).await;
+        let storage = state.execution_context.storage.as_ref();
+        let realm = "rj.wire".to_string();
+        let token = admin_token(&state, "rj.wire");
+        let subscribe = CreateSubscription {
+            url: "http://127.0.0.1:9/hook".to_string(),
+            event_types: vec![
+                WebhookEventType::UserCreated,
+                WebhookEventType::UserUpdated,
+                WebhookEventType::UserDeleted,
+            ],
+        };
+        WebhookService::subscribe(
+            &realm,
+            subscribe,
+            &state.webhook_endpoints,
+            &state.secret_cipher,
+            storage,
+        )
+        .unwrap();
+        let publisher = WebhookPublisher {
+            storage: state.execution_context.storage.clone(),
+        };
+        let policy = RetryPolicy::default();
+
+        let req = test::TestRequest::post()
+            .uri("/api/customer")
+            .insert_header(("Realm", "rj.wire"))
+            .set_json(create_user("ruru"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let user_id = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        let user_token = access_token(&state, "rj.wire", &user_id);
+        let update = |name: &str| {
+            test::TestRequest::patch()
+                .uri(&format!("/api/customer/{}", user_id))
+                .insert_header(("Realm", "rj.wire"))
+                .insert_header(bearer(&user_token))
+                .set_json(json!({ "name": name }))
+                .to_request()
+        };
+        let resp = test::call_service(&app, update("Ru Paul")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let now = Utc::now().timestamp();
+        assert_eq!(
+            OutboxService::dispatch(storage, &publisher, &policy, now).unwrap(),
+            2
+        );
+        let resp = test::call_service(&app, update("Ru")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("/api/admin/customer/{}?mode=erase", user_id))
+            .insert_header(("Realm", "rj.wire"))
+            .insert_header(bearer(&token))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        // The audit trail keeps the events under a pseudonym, without ip or detail.
+        let events = storage
+            .in_transaction(|tx| tx.query_audit_events(&realm, &AuditQuery::default(), 100))
+            .unwrap();
+        assert!(events
+            .iter()
+            .all(|record| record.event.user_id.as_deref() != Some(&user_id)));
+        let erased = events
+            .iter()
+            .find(|record| record.event.event_type == AuditEventType::AdminUserErased)
+            .unwrap();
+        let redacted = events
+            .iter()
+            .filter(|record| record.redacted_at.is_some())
+            .collect::<Vec<_>>();
+        assert_eq!(redacted.len(), 3);
+        for record in &redacted {
+            assert_eq!(record.event.user_id, erased.event.user_id);
+            assert_eq!((&record.event.ip, &record.event.detail), (&None, &None));
+        }
+        let verification = AuditService::verify(&realm, storage).unwrap();
+        assert_eq!(verification.first_break, None);
+        assert_eq!(verification.redacted_entries, 3);
+
+        // Queued webhooks keep their envelope but lose the data.
+        let deliveries = storage
+            .in_transaction(|tx| tx.list_deliveries(&realm, DeliveryStatus::Pending, 100))
+            .unwrap();
+        assert_eq!(deliveries.len(), 2);
+        for delivery in &deliveries {
+            assert!(!delivery.payload.contains("ruru@nitro.com"));
+            let payload: WebhookPayload = serde_json::from_str(&delivery.payload).unwrap();
+            assert_eq!(payload.data, json!({}));
+        }
+
+        // Only the deletion itself still tells consumers about the user.
+        let recorder = RecordingPublisher::failing(0);
+        assert_eq!(
+            OutboxService::dispatch(storage, &recorder, &policy, now).unwrap(),
+            2
+        );
+        let published = recorder
+            .attempts()
+            .into_iter()
+            .map(|event| (event.event_type, event.payload))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            published,
+            vec![
+                ("user_updated".to_string(), json!({})),
+                ("user_deleted".to_string(), json!({ "mode": "erase" })),
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_address_crud() {
+        let state = app_state();
+        let app = test::init_service(App::new().app_data(state.clone()).configure(routes)).await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/customer")
+            .insert_header(("Realm", "rj.wire"))
+            .set_json(create_user("ruru"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let user_id = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        let addresses_uri = format!("/api/customer/{}/addresses", user_id);
+        let token = access_token(&state, "rj.wire", &user_id);
+        let other = admin_token(&state, "rj.wire");
+
+        // Only the user may see their addresses, not even an admin.
+        let list = || {
+            test::TestRequest::get()
+                .uri(&addresses_uri)
+                .insert_header(("Realm", "rj.wire"))
+        };
+        let resp = test::call_service(&app, list().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let req = list().insert_header(bearer(&other)).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::post()
+            .uri(&addresses_uri)
+            .insert_header(("Realm", "rj.wire"))
+            .insert_header(bearer(&token))
+            .set_json(json!({
+                "street": "1 Billing Road",
+                "city": "Leeds",
+                "country": "UK",
+                "post_code": "LS1 1AA",
+                "address_type": "billing"
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let billing: UserAddress = test::read_body_json(resp).await;
+        assert_eq!(billing.address_type, AddressType::Billing);
+        assert!(billing.is_default, "first billing address becomes default");
+
+        let req = test::TestRequest::post()
+            .uri(&addresses_uri)
+            .insert_header(("Realm", "rj.wire"))
+            .insert_header(bearer(&token))
+            .set_json(json!({
+                "street": "2 Shipping Lane",
+                "city": "York",
+                "country": "UK",
+                "post_code": "YO1 7HH",
+                "is_default": true
+            }))
+            .to_request();
+        let shipping: UserAddress = test::call_and_read_body_json(&app, req).await;
+        assert!(shipping.is_default);
+
+        let req = test::TestRequest::get()
+            .uri(&addresses_uri)
+            .insert_header(("Realm", "rj.wire"))
+            .insert_header(bearer(&token))
+            .to_request();
+        let addresses: Vec<UserAddress> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(addresses.len(), 3);
+        let defaults: Vec<&str> = addresses
+            .iter()
+            .filter(|a| a.is_default)
+            .map(|a| a.address.city.as_str())
+            .collect();
+        assert_eq!(defaults, vec!["Leeds", "York"]);
+        assert!(addresses.iter().all(|a| a.address.country == "GB"));
+
+        let req = test::TestRequest::post()
+            .uri(&addresses_uri)
+            .insert_header(("Realm", "rj.wire"))
+            .insert_header(bearer(&token))
+            .set_json(json!({
+                "street": "1 Nowhere Street",
+                "city": "Poseidonis",
+                "country": "Atlantis",
+                "post_code": "12345"
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["status"], 422);
+        assert_eq!(body["errors"]["country"][0]["code"], "unknown_country");
+
+        let req = test::TestRequest::put()
+            .uri(&format!("{}/{}", addresses_uri, billing.address_id))
+            .insert_header(("Realm", "rj.wire"))
+            .insert_header(bearer(&token))
+            .set_json(json!({
+                "street": "3 Billing Road",
+                "city": "Leeds",
+                "country": "UK",
+                "post_code": "LS1 1AA",
+                "address_type": "billing"
+            }))
+            .to_request();
+        let updated: UserAddress = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(updated.address.street, "3 Billing Road");
+        assert!(updated.is_default, "the only billing address stays default");
+
+        let address_uri = format!("{}/{}", addresses_uri, shipping.address_id);
+        let cases = [
+            (
+                test::TestRequest::delete(),
+                "rj.haven",
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                test::TestRequest::delete(),
+                "rj.wire",
+                StatusCode::NO_CONTENT,
+            ),
+            (
+                test::TestRequest::delete(),
+                "rj.wire",
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                test::TestRequest::put().set_json(json!({
+                    "street": " ",
+                    "city": "York",
+                    "country": "UK",
+                    "post_code": "YO1 7HH"
+                })),
+                "rj.wire",
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+        ];
+        for (req, realm, status) in cases {
+            let req = req
+                .uri(&address_uri)
+                .insert_header(("Realm", realm))
+                .insert_header(bearer(&token))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), status, "{}", realm);
+        }
+
+        // The remaining shipping address is promoted to default.
+        let req = test::TestRequest::get()
+            .uri(&addresses_uri)
+            .insert_header(("Realm", "rj.wire"))
+            .insert_header(bearer(&token))
+            .to_request();
+        let addresses: Vec<UserAddress> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(addresses.len(), 2);
+        assert!(addresses.iter().all(|a| a.is_default));
+
+        let add = |post_code: &str| {
+            test::TestRequest::post()
+                .uri(&addresses_uri)
+                .insert_header(("Realm", "rj.wire"))
+                .insert_header(bearer(&token))
+                .set_json(json!({
+                    "street": "9336 Civic Center Drive",
+                    "city": "Beverly Hills",
+                    "country": "USA",
+                    "post_code": post_code
+                }))
+                .to_request()
+        };
+        let resp = test::call_service(&app, add("9021")).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let us: UserAddress = test::call_and_read_body_json(&app, add("902101234")).await;
+        assert_eq!(us.address.post_code, "90210-1234");
+
+        let req = test::TestRequest::get()
+            .uri(&format!("{}/{}/formatted", addresses_uri, us.address_id))
+            .insert_header(("Realm", "rj.wire"))
+            .insert_header(bearer(&token))
+            .to_request();
+        let formatted: FormattedAddress = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            formatted.lines,
+            vec![
+                "9336 Civic Center Drive",
+                "Beverly Hills 90210-1234",
+                "UNITED STATES"
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_cross_realm_access_is_not_found() {
+        let state = app_state();
+        let app = test::init_service(App::new().app_data(state.clone()).configure(routes)).await;
+        let haven_admin = admin_token(&state, "rj.haven");
+
+        let req = test::TestRequest::post()
+            .uri("/api/customer")
+            .insert_header(("Realm", "rj.wire"))
+            .set_json(create_user("ruru"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let user_id = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        let token = access_token(&state, "rj.wire", &user_id);
+        let customer_uri = format!("/api/customer/{}", user_id);
+        let admin_uri = format!("/api/admin/customer/{}", user_id);
+
+        let requests = [
+            test::TestRequest::get().uri(&admin_uri),
+            test::TestRequest::patch()
+                .uri(&admin_uri)
+                .set_json(json!({ "role": "ADMIN" })),
+            test::TestRequest::delete().uri(&format!("{}?mode=erase", admin_uri)),
+        ];
+        for req in requests {
+            let req = req
+                .insert_header(("Realm", "rj.haven"))
+                .insert_header(bearer(&haven_admin))
+                .to_request();
+            let (method, uri) = (req.method().clone(), req.uri().clone());
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{} {}", method, uri);
+        }
+
+        // Tokens only hold in the realm they were issued in.
+        let req = test::TestRequest::get()
+            .uri(&customer_uri)
+            .insert_header(("Realm", "rj.haven"))
+            .insert_header(bearer(&token))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::get()
+            .uri("/api/admin/customer")
+            .insert_header(("Realm", "rj.nowhere"))
+            .insert_header(bearer(&haven_admin))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let req = test::TestRequest::get()
+            .uri(&customer_uri)
+            .insert_header(("Realm", "rj.wire"))
+            .insert_header(bearer(&token))
+            .to_request();
+        let user: UserProfile = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(user.username, "ruru");
+        assert_eq!(user.role, Role::CUSTOMER);
+    }
+
+    #[actix_web::test]
+    async fn test_unknown_user_is_not_found() {
+        let state = app_state();
+        let admin = admin_token(&state, "rj.wire");
+        let app = test::init_service(App::new().app_data(state).configure(routes)).await;
+
+        let req = test::TestRequest::get()
+            .uri("/api/admin/customer/does-not-exist")
+            .insert_header(("Realm", "rj.wire"))
+            .insert_header(bearer(&admin))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_password_changes() {
+        let channel = Arc::new(RecordingChannel::default());
+        let state = app_state_delivering(InMemoryStorage::with_default_realms(), channel.clone());
+        let storage = state.execution_context.storage.clone();
+        let admin = admin_token(&state, "rj.wire");
+        let app = test::init_service(App::new().app_data(state.clone()).configure(routes)).await;
+
+        let req = test::TestRequest::get()
+            .uri("/api/realm/rj.wire/password-policy")
+            .to_request();
+        let policy: PasswordPolicy = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(policy, PasswordPolicy::default());
+        let req = test::TestRequest::get()
+            .uri("/api/realm/rj.nowhere/password-policy")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let req = test::TestRequest::post()
+            .uri("/api/customer")
+            .insert_header(("Realm", "rj.wire"))
+            .set_json(create_user("ruru"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let user_id = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        let password_matches = |password: &str| {
+            let realm = "rj.wire".to_string();
+            let user = storage
+                .in_transaction(|tx| tx.users().get(&realm, &user_id))
+                .unwrap()
+                .unwrap();
+            verify_password(password, &user.hashed_pass)
+        };
+
+        let put = |uri: &str, token: &str, body: serde_json::Value| {
+            test::TestRequest::put()
+                .uri(uri)
+                .insert_header(("Realm", "rj.wire"))
+                .insert_header(bearer(token))
+                .set_json(body)
+                .to_request()
+        };
+        let own = access_token(&state, "rj.wire", &user_id);
+        let elsewhere = access_token(&state, "rj.wire", &user_id);
+        let change_uri = format!("/api/customer/{}/password", user_id);
+        let admin_uri = format!("/api/admin/customer/{}/password", user_id);
+        let cases = [
+            (
+                &change_uri,
+                &admin,
+                json!({ "current_password": "passw0rd", "new_password": "n3w-passw0rd" }),
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                &change_uri,
+                &own,
+                json!({ "current_password": "wrong", "new_password": "n3w-passw0rd" }),
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                &change_uri,
+                &own,
+                json!({ "current_password": "passw0rd", "new_password": "ruru1234" }),
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            (
+                &change_uri,
+                &own,
+                json!({ "current_password": "passw0rd", "new_password": "n3w-passw0rd" }),
+                StatusCode::NO_CONTENT,
+            ),
+            (
+                &change_uri,
+                &own,
+                json!({ "current_password": "n3w-passw0rd", "new_password": "passw0rd" }),
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            (
+                &admin_uri,
+                &admin,
+                json!({ "password": "short" }),
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+        ];
+        for (uri, token, body, status) in cases {
+            let resp = test::call_service(&app, put(uri, token, body.clone())).await;
+            assert_eq!(resp.status(), status, "{}", body);
+        }
+        assert!(password_matches("n3w-passw0rd"));
+
+        // The change signs out the user's other sessions but not the one that made it.
+        let sessions = |token: &str| {
+            test::TestRequest::get()
+                .uri(&format!("/api/customer/{}/sessions", user_id))
+                .insert_header(("Realm", "rj.wire"))
+                .insert_header(bearer(token))
+                .to_request()
+        };
+        let resp = test::call_service(&app, sessions(&own)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = test::call_service(&app, sessions(&elsewhere)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        // Guessing the current password locks the user like failed logins do.
+        let guess = json!({ "current_password": "guess", "new_password": "g00d-passw0rd" });
+        for _ in 0..5 {
+            let resp = test::call_service(&app, put(&change_uri, &own, guess.clone())).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        }
+        let body = json!({ "current_password": "n3w-passw0rd", "new_password": "g00d-passw0rd" });
+        let resp = test::call_service(&app, put(&change_uri, &own, body)).await;
+        assert_eq!(resp.status(), StatusCode::LOCKED);
+        let realm = "rj.wire".to_string();
+        let failed = AuditQuery {
+            user_id: Some(user_id.clone()),
+            event_type: Some(AuditEventType::LoginFailed),
+            ..AuditQuery::default()
+        };
+        let failures = storage
+            .in_transaction(|tx| tx.query_audit_events(&realm, &failed, 100))
+            .unwrap();
+        assert_eq!(failures.len(), 6);
+
+        let resp = test::call_service(
+            &app,
+            put(&admin_uri, &admin, json!({ "password": "adm1n-set" })),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert!(password_matches("adm1n-set"));
+
+        let verification = channel
+            .tokens()
+            .into_iter()
+            .find(|message| message.kind == AccountMessageKind::EmailVerification)
+            .unwrap();
+        let req = test::TestRequest::post()
+            .uri("/api/customer/email/confirm")
+            .insert_header(("Realm", "rj.wire"))
+            .set_json(json!({ "username": "ruru", "token": verification.token }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        for username in ["ruru", "nobody"] {
+            let req = test::TestRequest::post()
+                .uri("/api/customer/password-reset")
+                .insert_header(("Realm", "rj.wire"))
+                .set_json(json!({ "username": username }))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        }
+        // Only the known user is sent a token, to the address on file.
+        let sent: Vec<AccountMessage> = channel
+            .tokens()
+            .into_iter()
+            .filter(|message| message.kind == AccountMessageKind::PasswordReset)
+            .collect();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(
+            (sent[0].user_id.as_str(), sent[0].email.as_str()),
+            (user_id.as_str(), "ruru@nitro.com")
+        );
+        let token = sent[0].token.clone();
+        // Only the expiry and a hash of the token are stored.
+        let stored = storage
+            .in_transaction(|tx| tx.find_token(&user_id, TokenKind::PasswordReset))
+            .unwrap()
+            .unwrap();
+        assert_ne!(stored, token);
+        assert_eq!(
+            stored.split_once('.').unwrap().0,
+            token.split_once('.').unwrap().0
+        );
+
+        let confirm = |token: &str, new_password: &str| {
+            test::TestRequest::post()
+                .uri("/api/customer/password-reset/confirm")
+                .insert_header(("Realm", "rj.wire"))
+                .set_json(json!({
+                    "username": "ruru",
+                    "token": token,
+                    "new_password": new_password
+                }))
+                .to_request()
+        };
+        // A reset signs the user out everywhere.
+        let session = access_token(&state, "rj.wire", &user_id);
+        let resp = test::call_service(&app, sessions(&session)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let cases = [
+            ("not-the-token", "r3set-passw0rd", StatusCode::BAD_REQUEST),
+            (token.as_str(), "short", StatusCode::UNPROCESSABLE_ENTITY),
+            (token.as_str(), "r3set-passw0rd", StatusCode::NO_CONTENT),
+            (token.as_str(), "again-r3set", StatusCode::BAD_REQUEST),
+        ];
+        for (token, new_password, status) in cases {
+            let resp = test::call_service(&app, confirm(token, new_password)).await;
+            assert_eq!(resp.status(), status, "{}", new_password);
+        }
+        assert!(password_matches("r3set-passw0rd"));
+        let resp = test::call_service(&app, sessions(&session)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn test_login_lockout() {
+        let state = app_state();
+        let admin = admin_token(&state, "rj.wire");
+        let app = test::init_service(App::new().app_data(state).configure(routes)).await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/customer")
+            .insert_header(("Realm", "rj.wire"))
+            .set_json(create_user("ruru"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let user_id = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+
+        let login = |username: &str, password: &str, ip: &str| {
+            test::TestRequest::post()
+                .uri("/api/realm/rj.wire/login")
+                .insert_header(("Realm", "rj.wire"))
+                .peer_addr(format!("{}:4000", ip).parse().unwrap())
+                .set_json(json!({ "username": username, "password": password }))
+                .to_request()
+        };
+        let resp = test::call_service(&app, login("ruru", "passw0rd", "10.0.0.1")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // The fifth failure locks the user, whatever the IP.
+        for attempt in 0..5 {
+            let ip = format!("10.0.1.{}", attempt);
+            let resp = test::call_service(&app, login("ruru", "wrong", &ip)).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        }
+        let resp = test::call_service(&app, login("ruru", "passw0rd", "10.0.0.1")).await;
+        assert_eq!(resp.status(), StatusCode::LOCKED);
+        let retry_after: u64 = resp
+            .headers()
+            .get("Retry-After")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(retry_after > 0 && retry_after <= 300);
+
+        let unlock = |user_id: &str| {
+            test::TestRequest::post()
+                .uri(&format!("/api/admin/customer/{}/unlock", user_id))
+                .insert_header(("Realm", "rj.wire"))
+                .insert_header(bearer(&admin))
+                .to_request()
+        };
+        let resp = test::call_service(&app, unlock("nobody")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = test::call_service(&app, unlock(&user_id)).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let resp = test::call_service(&app, login("ruru", "passw0rd", "10.0.0.1")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Unknown usernames count against the IP only, and lock it for every user.
+        for attempt in 0..20 {
+            let username = format!("nobody-{}", attempt);
+            let resp = test::call_service(&app, login(&username, "wrong", "10.0.2.1")).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        }
+        let resp = test::call_service(&app, login("ruru", "passw0rd", "10.0.2.1")).await;
+        assert_eq!(resp.status(), StatusCode::LOCKED);
+        let resp = test::call_service(&app, login("ruru", "passw0rd", "10.0.0.1")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_totp_login() {
+        let storage = InMemoryStorage::with_default_realms();
+        let mut strict = default_realm_settings();
+        strict.mfa_policy = MfaPolicy {
+            required_roles: vec![Role::CUSTOMER],
+        };
+        storage.add_realm("rj.haven".to_string(), strict);
+        let state = app_state_with(storage);
+        let app = test::init_service(App::new().app_data(state.clone()).configure(routes)).await;
+
+        let login = |realm: &str, password: &str| {
+            test::TestRequest::post()
+                .uri(&format!("/api/realm/{}/login", realm))
+                .insert_header(("Realm", realm))
+                .set_json(json!({ "username": "ruru", "password": password }))
+                .to_request()
+        };
+        let login_mfa = |mfa_token: &str, code: &str| {
+            test::TestRequest::post()
+                .uri("/api/realm/rj.wire/login/mfa")
+                .insert_header(("Realm", "rj.wire"))
+                .set_json(json!({ "username": "ruru", "mfa_token": mfa_token, "code": code }))
+                .to_request()
+        };
+        let mut user_ids = Vec::new();
+        for realm in ["rj.wire", "rj.haven"] {
+            let req = test::TestRequest::post()
+                .uri("/api/customer")
+                .insert_header(("Realm", realm))
+                .set_json(create_user("ruru"))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            user_ids.push(String::from_utf8(test::read_body(resp).await.to_vec()).unwrap());
+        }
+        let totp_uri = format!("/api/customer/{}/mfa/totp", user_ids[0]);
+        let token = access_token(&state, "rj.wire", &user_ids[0]);
+        let verify = |user_id: &str, code: &str| {
+            test::TestRequest::post()
+                .uri(&format!("/api/customer/{}/mfa/totp/verify", user_id))
+                .insert_header(("Realm", "rj.wire"))
+                .insert_header(bearer(&token))
+                .set_json(json!({ "code": code }))
+                .to_request()
+        };
+
+        // Only the user's own session may enroll, not even an admin's.
+        let enroll = |token: Option<&str>| {
+            let req = test::TestRequest::post()
+                .uri(&totp_uri)
+                .insert_header(("Realm", "rj.wire"));
+            match token {
+                Some(token) => req.insert_header(bearer(token)).to_request(),
+                None => req.to_request(),
+            }
+        };
+        let resp = test::call_service(&app, enroll(None)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let admin = admin_token(&state, "rj.wire");
+        let resp = test::call_service(&app, enroll(Some(&admin))).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        // Enrollment only counts once a first code activates it.
+        let resp = test::call_service(&app, enroll(Some(&token))).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let setup: TotpSetup = test::read_body_json(resp).await;
+        assert!(setup
+            .otpauth_uri
+            .starts_with("otpauth://totp/rj.wire:ruru?secret="));
+        let secret = BASE32_NOPAD.decode(setup.secret.as_bytes()).unwrap();
+        let code = |skew: i64| {
+            let step = Utc::now().timestamp() / 30 + skew;
+            format!("{:06}", totp_code(&secret, step))
+        };
+
+        let resp = test::call_service(&app, login("rj.wire", "passw0rd")).await;
+        let tokens: SessionTokens = test::read_body_json(resp).await;
+        assert!(!tokens.refresh_token.is_empty());
+
+        let resp = test::call_service(&app, verify(&user_ids[0], "000000x")).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        // A step behind, like a slow clock, which leaves the current step for the login.
+        let activation_code = code(-1);
+        let resp = test::call_service(&app, verify(&user_ids[0], &activation_code)).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        // The password now only earns a challenge, and each code is accepted once.
+        let resp = test::call_service(&app, login("rj.wire", "passw0rd")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let challenge: MfaChallenge = test::read_body_json(resp).await;
+        let resp = test::call_service(&app, login_mfa(&challenge.mfa_token, "123")).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = test::call_service(&app, login_mfa("1.forged", &code(0))).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp =
+            test::call_service(&app, login_mfa(&challenge.mfa_token, &activation_code)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = test::call_service(&app, login_mfa(&challenge.mfa_token, &code(0))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let tokens: SessionTokens = test::read_body_json(resp).await;
+        assert!(!tokens.refresh_token.is_empty());
+        let resp = test::call_service(&app, login_mfa(&challenge.mfa_token, &code(0))).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // Removal needs the user to prove itself again, used codes do not count.
+        let remove = |token: &str, proof: serde_json::Value| {
+            test::TestRequest::delete()
+                .uri(&totp_uri)
+                .insert_header(("Realm", "rj.wire"))
+                .insert_header(bearer(token))
+                .set_json(proof)
+                .to_request()
+        };
+        let cases = [
+            (
+                &admin,
+                json!({ "password": "passw0rd" }),
+                StatusCode::FORBIDDEN,
+            ),
+            (&token, json!({}), StatusCode::UNPROCESSABLE_ENTITY),
+            (
+                &token,
+                json!({ "password": "wrong" }),
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                &token,
+                json!({ "code": activation_code }),
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                &token,
+                json!({ "password": "passw0rd" }),
+                StatusCode::NO_CONTENT,
+            ),
+        ];
+        for (token, proof, status) in cases {
+            let resp = test::call_service(&app, remove(token, proof.clone())).await;
+            assert_eq!(resp.status(), status, "{}", proof);
+        }
+        let resp = test::call_service(&app, login("rj.wire", "passw0rd")).await;
+        let tokens: SessionTokens = test::read_body_json(resp).await;
+        assert!(!tokens.refresh_token.is_empty());
+
+        // Realms can require a second factor for a role, see test_mfa_enrollment_login.
+        let resp = test::call_service(&app, login("rj.haven", "passw0rd")).await;
+        let enrollment: MfaEnrollment = test::read_body_json(resp).await;
+        assert!(!enrollment.enrollment_token.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_mfa_enrollment_login() {
+        let storage = InMemoryStorage::with_default_realms();
+        let mut strict = default_realm_settings();
+        strict.mfa_policy = MfaPolicy {
+            required_roles: vec![Role::CUSTOMER],
+        };
+        storage.add_realm("rj.haven".to_string(), strict);
+        let state = app_state_with(storage);
+        let app = test::init_service(App::new().app_data(state.clone()).configure(routes)).await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/customer")
+            .insert_header(("Realm", "rj.haven"))
+            .set_json(create_user("ruru"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let user_id = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        let login = || {
+            test::TestRequest::post()
+                .uri("/api/realm/rj.haven/login")
+                .insert_header(("Realm", "rj.haven"))
+                .set_json(json!({ "username": "ruru", "password": "passw0rd" }))
+                .to_request()
+        };
+        let call = |method: test::TestRequest, path: &str, token: &str| {
+            method
+                .uri(&format!("/api/customer/{}{}", user_id, path))
+                .insert_header(("Realm", "rj.haven"))
+                .insert_header(bearer(token))
+        };
+
+        // Without a second factor the password only earns an enrollment token.
+        let resp = test::call_service(&app, login()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let enrollment: MfaEnrollment = test::read_body_json(resp).await;
+        let token = enrollment.enrollment_token;
+        assert!(enrollment.expires_at <= Utc::now().timestamp() + 5 * 60);
+
+        // It reaches nothing but the enrollment endpoints.
+        let resp = test::call_service(
+            &app,
+            call(test::TestRequest::get(), "/sessions", &token).to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = test::call_service(
+            &app,
+            call(test::TestRequest::get(), "", &token).to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let resp = test::call_service(
+            &app,
+            call(test::TestRequest::post(), "/mfa/totp", &token).to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let setup: TotpSetup = test::read_body_json(resp).await;
+        let secret = BASE32_NOPAD.decode(setup.secret.as_bytes()).unwrap();
+        let code = |skew: i64| {
+            let step = Utc::now().timestamp() / 30 + skew;
+            format!("{:06}", totp_code(&secret, step))
+        };
+        let req = call(test::TestRequest::post(), "/mfa/totp/verify", &token)
+            .set_json(json!({ "code": code(-1) }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        // Once enrolled the login is challenged and completes with full tokens.
+        let resp = test::call_service(&app, login()).await;
+        let challenge: MfaChallenge = test::read_body_json(resp).await;
+        let req = test::TestRequest::post()
+            .uri("/api/realm/rj.haven/login/mfa")
+            .insert_header(("Realm", "rj.haven"))
+            .set_json(
+                json!({ "username": "ruru", "mfa_token": challenge.mfa_token, "code": code(0) }),
+            )
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let tokens: SessionTokens = test::read_body_json(resp).await;
+        let resp = test::call_service(
+            &app,
+            call(test::TestRequest::get(), "/sessions", &tokens.access_token).to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_recovery_codes() {
+        let state = app_state();
+        let app = test::init_service(App::new().app_data(state.clone()).configure(routes)).await;
+        let req = test::TestRequest::post()
+            .uri("/api/customer")
+            .insert_header(("Realm", "rj.wire"))
+            .set_json(create_user("ruru"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let user_id = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        let recovery_uri = format!("/api/customer/{}/mfa/recovery-codes", user_id);
+        let token = access_token(&state, "rj.wire", &user_id);
+
+        let challenge = |ip: &str| {
+            test::TestRequest::post()
+                .uri("/api/realm/rj.wire/login")
+                .insert_header(("Realm", "rj.wire"))
+                .peer_addr(format!("{}:4000", ip).parse().unwrap())
+                .set_json(json!({ "username": "ruru", "password": "passw0rd" }))
+                .to_request()
+        };
+        let login_mfa = |mfa_token: &str, code: &str, ip: &str| {
+            test::TestRequest::post()
+                .uri("/api/realm/rj.wire/login/mfa")
+                .insert_header(("Realm", "rj.wire"))
+                .peer_addr(format!("{}:4000", ip).parse().unwrap())
+                .set_json(json!({
+                    "username": "ruru",
+                    "mfa_token": mfa_token,
+                    "code": code,
+                    "method": "recovery_code",
+                }))
+                .to_request()
+        };
+
+        let regenerate = |token: &str, proof: serde_json::Value| {
+            test::TestRequest::post()
+                .uri(&recovery_uri)
+                .insert_header(("Realm", "rj.wire"))
+                .insert_header(bearer(token))
+                .set_json(proof)
+                .to_request()
+        };
+        let password = json!({ "password": "passw0rd" });
+        let resp = test::call_service(&app, regenerate(&token, password.clone())).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/customer/{}/mfa/totp", user_id))
+            .insert_header(("Realm", "rj.wire"))
+            .insert_header(bearer(&token))
+            .to_request();
+        let setup: TotpSetup = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(setup.recovery_codes.len(), 10);
+        let secret = BASE32_NOPAD.decode(setup.secret.as_bytes()).unwrap();
+        let code = format!("{:06}", totp_code(&secret, Utc::now().timestamp() / 30));
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/customer/{}/mfa/totp/verify", user_id))
+            .insert_header(("Realm", "rj.wire"))
+            .insert_header(bearer(&token))
+            .set_json(json!({ "code": code }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        // A recovery code replaces the authenticator app, once.
+        let resp = test::call_service(&app, challenge("10.0.0.1")).await;
+        let first: MfaChallenge = test::read_body_json(resp).await;
+        assert_eq!(
+            first.methods,
+            vec![MfaMethod::Totp, MfaMethod::RecoveryCode]
+        );
+        let used = setup.recovery_codes[0].to_uppercase();
+        let resp = test::call_service(&app, login_mfa(&first.mfa_token, &used, "10.0.0.2")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = test::call_service(&app, challenge("10.0.0.1")).await;
+        let second: MfaChallenge = test::read_body_json(resp).await;
+        let resp = test::call_service(&app, login_mfa(&second.mfa_token, &used, "10.0.0.2")).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::get()
+            .uri(&recovery_uri)
+            .insert_header(("Realm", "rj.wire"))
+            .insert_header(bearer(&token))
+            .to_request();
+        let status: RecoveryCodeStatus = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(status.remaining, 9);
+        assert_eq!(status.uses.len(), 1);
+        assert_eq!(status.uses[0].ip.as_deref(), Some("10.0.0.2"));
+
+        // Regenerating needs the user's own session and a fresh proof, then drops the old codes.
+        let req = test::TestRequest::post()
+            .uri(&recovery_uri)
+            .insert_header(("Realm", "rj.wire"))
+            .set_json(&password)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let admin = admin_token(&state, "rj.wire");
+        let resp = test::call_service(&app, regenerate(&admin, password.clone())).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = test::call_service(&app, regenerate(&token, json!({ "code": code }))).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let fresh = format!("{:06}", totp_code(&secret, Utc::now().timestamp() / 30 + 1));
+        let resp = test::call_service(&app, regenerate(&token, json!({ "code": fresh }))).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let regenerated: RecoveryCodes = test::read_body_json(resp).await;
+        let old = &setup.recovery_codes[1];
+        let resp = test::call_service(&app, login_mfa(&second.mfa_token, old, "10.0.0.2")).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let new = &regenerated.recovery_codes[0];
+        let resp = test::call_service(&app, login_mfa(&second.mfa_token, new, "10.0.0.2")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Every change to the second factor and every recovery code use is audited.
+        let realm = "rj.wire".to_string();
+        let query = AuditQuery {
+            user_id: Some(user_id.clone()),
+            ..AuditQuery::default()
+        };
+        let events = state
+            .execution_context
+            .storage
+            .in_transaction(|tx| tx.query_audit_events(&realm, &query, 100))
+            .unwrap();
+        let mfa_events: Vec<(AuditEventType, Option<&str>)> = events
+            .iter()
+            .map(|record| (record.event.event_type, record.event.detail.as_deref()))
+            .filter(|(event_type, _)| {
+                *event_type != AuditEventType::LoginSucceeded
+                    && *event_type != AuditEventType::LoginFailed
+                    && *event_type != AuditEventType::UserRegistered
+            })
+            .collect();
+        assert_eq!(
+            mfa_events,
+            vec![
+                (AuditEventType::RecoveryCodeUsed, Some("9 remaining")),
+                (AuditEventType::RecoveryCodesRegenerated, None),
+                (AuditEventType::RecoveryCodeUsed, Some("9 remaining")),
+                (AuditEventType::TotpEnrolled, None),
+            ]
+        );
+        let used = events
+            .iter()
+            .find(|record| record.event.event_type == AuditEventType::RecoveryCodeUsed)
+            .unwrap();
+        assert_eq!(used.event.ip.as_deref(), Some("10.0.0.2"));
+    }
+
+    #[actix_web::test]
+    async fn test_passkey_login() {
+        let storage = InMemoryStorage::with_default_realms();
+        let mut passkeys = default_realm_settings();
+        passkeys.webauthn = WebAuthnConfig {
+            rp_id: Some("localhost".to_string()),
+            origins: vec!["http://localhost:8080".to_string()],
+        };
+        storage.add_realm("rj.haven".to_string(), passkeys);
+        let state = app_state_with(storage);
+        let app = test::init_service(App::new().app_data(state.clone()).configure(routes)).await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/customer")
+            .insert_header(("Realm", "rj.haven"))
+            .set_json(create_user("ruru"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let user_id = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        let passkeys_uri = format!("/api/customer/{}/passkeys", user_id);
+        let token = access_token(&state, "rj.haven", &user_id);
+        let login_options = |realm: &str, username: Option<&str>| {
+            test::TestRequest::post()
+                .uri(&format!("/api/realm/{}/login/passkey/options", realm))
+                .insert_header(("Realm", realm))
+                .set_json(json!({ "username": username }))
+                .to_request()
+        };
+        let login = |credential: &AuthenticationCredential| {
+            test::TestRequest::post()
+                .uri("/api/realm/rj.haven/login/passkey")
+                .insert_header(("Realm", "rj.haven"))
+                .set_json(credential)
+                .to_request()
+        };
+
+        // Only the user's own session may register a passkey.
+        let registration_options = |token: Option<&str>| {
+            let req = test::TestRequest::post()
+                .uri(&format!("{}/options", passkeys_uri))
+                .insert_header(("Realm", "rj.haven"));
+            match token {
+                Some(token) => req.insert_header(bearer(token)).to_request(),
+                None => req.to_request(),
+            }
+        };
+        let resp = test::call_service(&app, registration_options(None)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let admin = admin_token(&state, "rj.haven");
+        let resp = test::call_service(&app, registration_options(Some(&admin))).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = test::call_service(&app, registration_options(Some(&token))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let options: CreationOptions = test::read_body_json(resp).await;
+        assert_eq!(options.rp.id, "localhost");
+        assert_eq!(options.user.name, "ruru");
+
+        let mut authenticator = SoftAuthenticator::new("localhost", "http://localhost:8080");
+        let credential = authenticator.register(&options.challenge);
+        let register = |token: &str| {
+            test::TestRequest::post()
+                .uri(&passkeys_uri)
+                .insert_header(("Realm", "rj.haven"))
+                .insert_header(bearer(token))
+                .set_json(&credential)
+                .to_request()
+        };
+        let resp = test::call_service(&app, register(&admin)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = test::call_service(&app, register(&token)).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp = test::call_service(&app, register(&token)).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::get()
+            .uri(&passkeys_uri)
+            .insert_header(("Realm", "rj.haven"))
+            .insert_header(bearer(&token))
+            .to_request();
+        let listed: Vec<PasskeyInfo> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].credential_id, credential.id);
+        assert_eq!(listed[0].name.as_deref(), Some("Soft key"));
+
+        // Each challenge is answered once.
+        let resp = test::call_service(&app, login_options("rj.haven", Some("ruru"))).await;
+        let options: RequestOptions = test::read_body_json(resp).await;
+        assert_eq!(options.allow_credentials.len(), 1);
+        let assertion = authenticator.authenticate(&options.challenge);
+        let resp = test::call_service(&app, login(&assertion)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let tokens: SessionTokens = test::read_body_json(resp).await;
+        assert!(!tokens.refresh_token.is_empty());
+        let resp = test::call_service(&app, login(&assertion)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        // A counter that went back hints at a cloned authenticator.
+        let resp = test::call_service(&app, login_options("rj.haven", None)).await;
+        let options: RequestOptions = test::read_body_json(resp).await;
+        assert!(options.allow_credentials.is_empty());
+        authenticator.sign_count = 0;
+        let cloned = authenticator.authenticate(&options.challenge);
+        let resp = test::call_service(&app, login(&cloned)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let remove = test::TestRequest::delete()
+            .uri(&format!("{}/{}", passkeys_uri, credential.id))
+            .insert_header(("Realm", "rj.haven"))
+            .insert_header(bearer(&token));
+        let resp = test::call_service(&app, remove.to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let resp = test::call_service(&app, login_options("rj.haven", None)).await;
+        let options: RequestOptions = test::read_body_json(resp).await;
+        let assertion = authenticator.authenticate(&options.challenge);
+        let resp = test::call_service(&app, login(&assertion)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = test::call_service(&app, login_options("rj.wire", None)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn test_passwordless_login() {
+        let storage = InMemoryStorage::with_default_realms();
+        let mut passwordless = default_realm_settings();
+        passwordless.passwordless_policy = PasswordlessPolicy {
+            method: Some(PasswordlessMethod::EmailCode),
+            max_attempts: 2,
+            ..PasswordlessPolicy::default()
+        };
+        storage.add_realm("rj.haven".to_string(), passwordless);
+        let channel = Arc::new(RecordingChannel::default());
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state_delivering(storage, channel.clone()))
+                .configure(routes),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/customer")
+            .insert_header(("Realm", "rj.haven"))
+            .set_json(create_user("ruru"))
+            .to_request();
+        test::call_service(&app, req).await;
+        let request = |realm: &str, username: &str| {
+            test::TestRequest::post()
+                .uri(&format!("/api/realm/{}/login/passwordless/request", realm))
+                .insert_header(("Realm", realm))
+                .set_json(json!({ "username": username }))
+                .to_request()
+        };
+        let login = |code: &str| {
+            test::TestRequest::post()
+                .uri("/api/realm/rj.haven/login/passwordless")
+                .insert_header(("Realm", "rj.haven"))
+                .set_json(json!({ "username": "ruru", "code": code }))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, request("rj.haven", "nobody")).await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        assert!(channel.sent().is_empty());
+        let resp = test::call_service(&app, request("rj.haven", "ruru")).await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        let message = channel.sent().pop().unwrap();
+        assert_eq!(message.email, "ruru@nitro.com");
+        assert_eq!(message.method, PasswordlessMethod::EmailCode);
+
+        // The code works once.
+        let resp = test::call_service(&app, login("x")).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = test::call_service(&app, login(&message.secret)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let tokens: SessionTokens = test::read_body_json(resp).await;
+        assert!(!tokens.refresh_token.is_empty());
+        let resp = test::call_service(&app, login(&message.secret)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        // Wrong guesses use a code up.
+        test::call_service(&app, request("rj.haven", "ruru")).await;
+        let message = channel.sent().pop().unwrap();
+        for _ in 0..2 {
+            let resp = test::call_service(&app, login("000000x")).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        }
+        let resp = test::call_service(&app, login(&message.secret)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = test::call_service(&app, request("rj.wire", "ruru")).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn test_sessions() {
+        let state = app_state();
+        let app = test::init_service(App::new().app_data(state.clone()).configure(routes)).await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/customer")
+            .insert_header(("Realm", "rj.wire"))
+            .set_json(create_user("ruru"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let user_id = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+
+        let login = |agent: &str| {
+            test::TestRequest::post()
+                .uri("/api/realm/rj.wire/login")
+                .insert_header(("Realm", "rj.wire"))
+                .insert_header(("User-Agent", agent))
+                .peer_addr("10.0.0.1:4000".parse().unwrap())
+                .set_json(json!({ "username": "ruru", "password": "passw0rd" }))
+                .to_request()
+        };
+        let refresh = |refresh_token: &str| {
+            test::TestRequest::post()
+                .uri("/api/realm/rj.wire/token/refresh")
+                .insert_header(("Realm", "rj.wire"))
+                .set_json(json!({ "refresh_token": refresh_token }))
+                .to_request()
+        };
+        // The user's own endpoints take the user's token, the admin ones an admin's.
+        let own = access_token(&state, "rj.wire", &user_id);
+        let own_session = state.access_token_key.verify(&own).unwrap().sid;
+        let admin = admin_token(&state, "rj.wire");
+        let token = |scope: &str| match scope {
+            "customer" => own.clone(),
+            _ => admin.clone(),
+        };
+        let sessions = |scope: &str| {
+            test::TestRequest::get()
+                .uri(&format!("/api/{}/{}/sessions", scope, user_id))
+                .insert_header(("Realm", "rj.wire"))
+                .insert_header(bearer(&token(scope)))
+                .to_request()
+        };
+        let revoke = |scope: &str, session_id: &str| {
+            test::TestRequest::delete()
+                .uri(&format!(
+                    "/api/{}/{}/sessions/{}",
+                    scope, user_id, session_id
+                ))
+                .insert_header(("Realm", "rj.wire"))
+                .insert_header(bearer(&token(scope)))
+                .to_request()
+        };
+        let cases = [
+            ("/api/customer", None, StatusCode::UNAUTHORIZED),
+            ("/api/customer", Some(&admin), StatusCode::FORBIDDEN),
+            ("/api/admin/customer", None, StatusCode::UNAUTHORIZED),
+            ("/api/admin/customer", Some(&own), StatusCode::FORBIDDEN),
+        ];
+        for (scope, token, status) in cases {
+            let uri = format!("{}/{}/sessions/{}", scope, user_id, own_session);
+            let req = test::TestRequest::delete()
+                .uri(&uri)
+                .insert_header(("Realm", "rj.wire"));
+            let req = match token {
+                Some(token) => req.insert_header(bearer(token)),
+                None => req,
+            };
+            let resp = test::call_service(&app, req.to_request()).await;
+            assert_eq!(resp.status(), status, "{}", uri);
+        }
+
+        let phone: SessionTokens = test::call_and_read_body_json(&app, login("phone")).await;
+        let laptop: SessionTokens = test::call_and_read_body_json(&app, login("laptop")).await;
+        let listed: Vec<SessionInfo> =
+            test::call_and_read_body_json(&app, sessions("customer")).await;
+        assert_eq!(listed.len(), 3);
+        let phone_session = listed
+            .iter()
+            .find(|session| session.session_id == phone.session_id)
+            .unwrap();
+        assert_eq!(phone_session.user_agent.as_deref(), Some("phone"));
+        assert_eq!(phone_session.ip.as_deref(), Some("10.0.0.1"));
+
+        // Access tokens are signed and name the session they were issued for.
+        let claims = state.access_token_key.verify(&phone.access_token).unwrap();
+        assert_eq!(claims.sub, user_id);
+        assert_eq!(claims.sid, phone.session_id);
+        assert_ne!(phone.access_token, laptop.access_token);
+
+        // Refreshing rotates the token, replaying the old one revokes the session.
+        let resp = test::call_service(&app, refresh(&phone.refresh_token)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let refreshed: SessionTokens = test::read_body_json(resp).await;
+        assert_eq!(refreshed.session_id, phone.session_id);
+        assert_ne!(refreshed.refresh_token, phone.refresh_token);
+        let claims = state
+            .access_token_key
+            .verify(&refreshed.access_token)
+            .unwrap();
+        assert_eq!(claims.sid, phone.session_id);
+        let resp = test::call_service(&app, refresh(&phone.refresh_token)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = test::call_service(&app, refresh(&refreshed.refresh_token)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = test::call_service(&app, refresh("garbage")).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        // Users and admins can revoke sessions.
+        let resp = test::call_service(&app, revoke("customer", &laptop.session_id)).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let resp = test::call_service(&app, refresh(&laptop.refresh_token)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = test::call_service(&app, revoke("customer", &laptop.session_id)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let tablet: SessionTokens = test::call_and_read_body_json(&app, login("tablet")).await;
+        let listed: Vec<SessionInfo> =
+            test::call_and_read_body_json(&app, sessions("admin/customer")).await;
+        assert_eq!(listed.len(), 2);
+        assert!(listed
+            .iter()
+            .any(|session| session.session_id == tablet.session_id));
+        let resp = test::call_service(&app, revoke("admin/customer", &tablet.session_id)).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let listed: Vec<SessionInfo> =
+            test::call_and_read_body_json(&app, sessions("customer")).await;
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].session_id, own_session);
+
+        // Sessions of other realms' users are out of reach.
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/admin/customer/{}/sessions", user_id))
+            .insert_header(("Realm", "rj.haven"))
+            .insert_header(bearer(&admin_token(&state, "rj.haven")))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_audit_log() {
+        let state = app_state();
+        let admin = admin_token(&state, "rj.wire");
+        let haven_admin = admin_token(&state, "rj.haven");
+        let app = test::init_service(App::new().app_data(state.clone()).configure(routes)).await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/customer")
+            .insert_header(("Realm", "rj.wire"))
+            .set_json(create_user("ruru"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let user_id = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+
+        let login = |password: &str| {
+            test::TestRequest::post()
+                .uri("/api/realm/rj.wire/login")
+                .insert_header(("Realm", "rj.wire"))
+                .peer_addr("10.0.0.1:4000".parse().unwrap())
+                .set_json(json!({ "username": "ruru", "password": password }))
+                .to_request()
+        };
+        let resp = test::call_service(&app, login("wrong")).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = test::call_service(&app, login("passw0rd")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let tokens: SessionTokens = test::read_body_json(resp).await;
+        let req = test::TestRequest::put()
+            .uri(&format!("/api/customer/{}/password", user_id))
+            .insert_header(("Realm", "rj.wire"))
+            .insert_header(bearer(&tokens.access_token))
+            .set_json(json!({ "current_password": "passw0rd", "new_password": "n3w-passw0rd" }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let req = test::TestRequest::patch()
+            .uri(&format!("/api/admin/customer/{}", user_id))
+            .insert_header(("Realm", "rj.wire"))
+            .insert_header(bearer(&admin))
+            .set_json(json!({ "name": "Ruru" }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let audit = |realm: &str, query: &str| {
+            let token = match realm {
+                "rj.haven" => &haven_admin,
+                _ => &admin,
+            };
+            test::TestRequest::get()
+                .uri(&format!("/api/admin/audit?{}", query))
+                .insert_header(("Realm", realm))
+                .insert_header(bearer(token))
+                .to_request()
+        };
+        // The log names users and their IPs, so only admins may read it.
+        let req = test::TestRequest::get()
+            .uri("/api/admin/audit")
+            .insert_header(("Realm", "rj.wire"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let req = test::TestRequest::get()
+            .uri("/api/admin/audit")
+            .insert_header(("Realm", "rj.wire"))
+            .insert_header(bearer(&access_token(&state, "rj.wire", &user_id)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let page: AuditPage = test::call_and_read_body_json(&app, audit("rj.wire", "")).await;
+        let types: Vec<AuditEventType> = page.events.iter().map(|r| r.event.event_type).collect();
+        assert_eq!(
+            types,
+            vec![
+                AuditEventType::AdminUserUpdated,
+                AuditEventType::PasswordChanged,
+                AuditEventType::LoginSucceeded,
+                AuditEventType::LoginFailed,
+                AuditEventType::UserRegistered,
+            ]
+        );
+        assert_eq!(page.events[0].event.detail.as_deref(), Some("name"));
+        assert!(page.next_before.is_none());
+
+        let query = format!("user_id={}&event_type=login_failed", user_id);
+        let page: AuditPage = test::call_and_read_body_json(&app, audit("rj.wire", &query)).await;
+        assert_eq!(page.events.len(), 1);
+        let failed = &page.events[0].event;
+        assert_eq!(failed.ip.as_deref(), Some("10.0.0.1"));
+        assert_eq!(failed.detail.as_deref(), Some("Incorrect password"));
+
+        // Pages are chained through `next_before`.
+        let page: AuditPage =
+            test::call_and_read_body_json(&app, audit("rj.wire", "limit=2")).await;
+        assert_eq!(page.events.len(), 2);
+        let query = format!("before={}", page.next_before.unwrap());
+        let rest: AuditPage = test::call_and_read_body_json(&app, audit("rj.wire", &query)).await;
+        assert_eq!(rest.events.len(), 3);
+        assert_eq!(
+            rest.events[0].event.event_type,
+            AuditEventType::LoginSucceeded
+        );
+
+        let query = format!("from={}", Utc::now().timestamp() + 60);
+        let page: AuditPage = test::call_and_read_body_json(&app, audit("rj.wire", &query)).await;
+        assert!(page.events.is_empty());
+        let page: AuditPage = test::call_and_read_body_json(&app, audit("rj.haven", "")).await;
+        assert!(page.events.is_empty());
+
+        for query in ["limit=0", "limit=501", "event_type=nonsense", "colour=red"].iter() {
+            let resp = test::call_service(&app, audit("rj.wire", query)).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", query);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_audit_chain() {
+        let state = app_state();
+        let admin = admin_token(&state, "rj.wire");
+        let haven_admin = admin_token(&state, "rj.haven");
+        let app = test::init_service(App::new().app_data(state).configure(routes)).await;
+
+        let get = |uri: &str, realm: &str| {
+            test::TestRequest::get()
+                .uri(uri)
+                .insert_header(("Realm", realm))
+                .insert_header(bearer(&admin))
+                .to_request()
+        };
+        let verify_checkpoint = |checkpoint: &SignedCheckpoint, realm: &str| {
+            let token = match realm {
+                "rj.haven" => &haven_admin,
+                _ => &admin,
+            };
+            test::TestRequest::post()
+                .uri("/api/admin/audit/checkpoint/verify")
+                .insert_header(("Realm", realm))
+                .insert_header(bearer(token))
+                .set_json(checkpoint)
+                .to_request()
+        };
+
+        // Only admins may walk the chain or have checkpoints signed.
+        for uri in ["/api/admin/audit/verify", "/api/admin/audit/checkpoint"] {
+            let req = test::TestRequest::get()
+                .uri(uri)
+                .insert_header(("Realm", "rj.wire"))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED, "{}", uri);
+        }
+
+        // A realm without events has an empty chain.
+        let verification: ChainVerification =
+            test::call_and_read_body_json(&app, get("/api/admin/audit/verify", "rj.wire")).await;
+        assert_eq!(verification.head_sequence, 0);
+        assert_eq!(verification.first_break, None);
+        let empty: SignedCheckpoint =
+            test::call_and_read_body_json(&app, get("/api/admin/audit/checkpoint", "rj.wire"))
+                .await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/customer")
+            .insert_header(("Realm", "rj.wire"))
+            .set_json(create_user("ruru"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        for _ in 0..2 {
+            let req = test::TestRequest::post()
+                .uri("/api/realm/rj.wire/login")
+                .insert_header(("Realm", "rj.wire"))
+                .set_json(json!({ "username": "ruru", "password": "wrong" }))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        }
+        let verification: ChainVerification =
+            test::call_and_read_body_json(&app, get("/api/admin/audit/verify", "rj.wire")).await;
+        assert_eq!(verification.head_sequence, 3);
+        assert_eq!(verification.verified_entries, 3);
+        assert_eq!(verification.first_break, None);
+        let page: AuditPage =
+            test::call_and_read_body_json(&app, get("/api/admin/audit", "rj.wire")).await;
+        let head = page.events[0].link.as_ref().unwrap();
+        assert_eq!(head.entry_hash, verification.head_hash);
+
+        let checkpoint: SignedCheckpoint =
+            test::call_and_read_body_json(&app, get("/api/admin/audit/checkpoint", "rj.wire"))
+                .await;
+        assert_eq!(checkpoint.checkpoint.sequence, 3);
+        assert_eq!(checkpoint.checkpoint.entry_hash, verification.head_hash);
+        for signed in [&empty, &checkpoint].iter() {
+            let result: CheckpointVerification =
+                test::call_and_read_body_json(&app, verify_checkpoint(signed, "rj.wire")).await;
+            assert_eq!(result.status, CheckpointStatus::Consistent);
+        }
+
+        // Checkpoints are bound to their content, signer and realm.
+        let mut forged = checkpoint.clone();
+        forged.checkpoint.sequence = 2;
+        let result: CheckpointVerification =
+            test::call_and_read_body_json(&app, verify_checkpoint(&forged, "rj.wire")).await;
+        assert_eq!(result.status, CheckpointStatus::InvalidSignature);
+        let foreign = CheckpointSigner::ephemeral().sign(checkpoint.checkpoint.clone());
+        let result: CheckpointVerification =
+            test::call_and_read_body_json(&app, verify_checkpoint(&foreign, "rj.wire")).await;
+        assert_eq!(result.status, CheckpointStatus::InvalidSignature);
+        let resp = test::call_service(&app, verify_checkpoint(&checkpoint, "rj.haven")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    /// A request received by [`webhook_receiver`], header names in lowercase.
+    struct ReceivedRequest {
+        headers: Vec<(String, String)>,
+        body: String,
+    }
+
+    impl ReceivedRequest {
+        fn header(&self, name: &str) -> &str {
+            self.headers
+                .iter()
+                .find(|(header, _)| header == &name.to_lowercase())
+                .map_or("", |(_, value)| value.as_str())
+        }
+    }
+
+    /// A local stand-in for a webhook endpoint. Answers one request with each of `statuses` in
+    /// turn and passes the requests on.
+    fn webhook_receiver(statuses: Vec<u16>) -> (String, mpsc::Receiver<ReceivedRequest>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hooks/users", listener.local_addr().unwrap());
+        let (sender, received) = mpsc::channel();
+        std::thread::spawn(move || {
+            for status in statuses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut headers = Vec::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    match line.trim_end().split_once(": ") {
+                        Some((name, value)) => {
+                            headers.push((name.to_lowercase(), value.to_string()))
+                        }
+                        None if line.trim_end().is_empty() => break,
+                        None => {}
+                    }
+                }
+                let length = headers
+                    .iter()
+                    .find(|(name, _)| name == "content-length")
+                    .map_or(0, |(_, value)| value.parse().unwrap());
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                let reason = match status {
+                    200 => "OK",
+                    _ => "Service Unavailable",
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {} {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status, reason
+                )
+                .unwrap();
+                let body = String::from_utf8(body).unwrap();
+                sender.send(ReceivedRequest { headers, body }).unwrap();
+            }
+        });
+        (url, received)
+    }
+
+    #[actix_web::test]
+    async fn test_webhook_transport() {
+        let (url, received) = webhook_receiver(vec![200]);
+        let trusted = EndpointPolicy {
+            allow_http: true,
+            allow_private_targets: true,
+        };
+        let public_only = EndpointPolicy {
+            allow_http: true,
+            ..EndpointPolicy::default()
+        };
+        let transport = |endpoints: EndpointPolicy, proxy: Option<String>| {
+            HttpTransport::new(Duration::from_secs(5), endpoints, proxy)
+        };
+
+        // Only https URLs on public addresses are called and subscribed by default.
+        let refused = HttpTransport::default().post(&url, &[], "{}").unwrap_err();
+        assert!(refused.ends_with("must be an https url"), "{}", refused);
+        let refused = transport(public_only, None)
+            .post(&url, &[], "{}")
+            .unwrap_err();
+        assert_eq!(refused, "127.0.0.1 has no public address");
+        let subscribe = CreateSubscription {
+            url: url.clone(),
+            event_types: vec![WebhookEventType::UserCreated],
+        };
+        let subscribed = WebhookService::subscribe(
+            &"rj.wire".to_string(),
+            subscribe,
+            &EndpointPolicy::default(),
+            &SecretCipher::ephemeral(),
+            &InMemoryStorage::with_default_realms(),
+        );
+        assert!(subscribed.is_err());
+
+        // An https endpoint must speak TLS, a plain http answer is not accepted.
+        let plain = TcpListener::bind("127.0.0.1:0").unwrap();
+        let https_url = format!("https://{}/hook", plain.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = plain.accept().unwrap();
+            let _ = write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        });
+        assert!(transport(trusted, None)
+            .post(&https_url, &[], "{}")
+            .is_err());
+
+        // A proxy is asked to tunnel to the checked address, which must still be allowed.
+        let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy_address = proxy.local_addr().unwrap().to_string();
+        let (connect_sender, connects) = mpsc::channel();
+        std::thread::spawn(move || {
+            let (mut client, _) = proxy.accept().unwrap();
+            let mut reader = BufReader::new(client.try_clone().unwrap());
+            let mut connect = String::new();
+            reader.read_line(&mut connect).unwrap();
+            let mut line = String::new();
+            while line != "\r\n" {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+            }
+            let target = connect.split(' ').nth(1).unwrap().to_string();
+            connect_sender.send(connect.trim_end().to_string()).unwrap();
+            write!(client, "HTTP/1.1 200 Connection established\r\n\r\n").unwrap();
+            let mut upstream = std::net::TcpStream::connect(target).unwrap();
+            let mut to_upstream = upstream.try_clone().unwrap();
+            std::thread::spawn(move || std::io::copy(&mut reader, &mut to_upstream));
+            let _ = std::io::copy(&mut upstream, &mut client);
+        });
+        let refused = transport(public_only, Some(proxy_address.clone()))
+            .post(&url, &[], "{}")
+            .unwrap_err();
+        assert_eq!(refused, "127.0.0.1 has no public address");
+        transport(trusted, Some(proxy_address))
+            .post(&url, &[("X-Webhook-Id", "1".to_string())], "{}")
+            .unwrap();
+        let target = url
+            .trim_start_matches("http://")
+            .trim_end_matches("/hooks/users");
+        assert_eq!(
+            connects.recv_timeout(Duration::from_secs(5)).unwrap(),
+            format!("CONNECT {} HTTP/1.1", target)
+        );
+        let request = received.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(request.header("X-Webhook-Id"), "1");
+        assert_eq!(request.body, "{}");
+    }
+
+    #[actix_web::test]
+    async fn test_delivery_channels() {
+        let reset_request = || {
+            test::TestRequest::post()
+                .uri("/api/customer/password-reset")
+                .insert_header(("Realm", "rj.wire"))
+                .set_json(json!({ "username": "ruru" }))
+                .to_request()
+        };
+        // Password resets are only sent to verified addresses.
+        let register = |state: &Data<AppState>| {
+            let realm = "rj.wire".to_string();
+            state
+                .execution_context
+                .storage
+                .in_transaction(|tx| {
+                    let user_id = tx.users().create(&realm, create_user("ruru"))?;
+                    let metadata = UserMetadata {
+                        email_verified: Some(true),
+                        ..UserMetadata::default()
+                    };
+                    tx.users().update(&realm, &user_id, metadata)?;
+                    Ok(user_id)
+                })
+                .unwrap()
+        };
+
+        // Without a channel, messages are refused rather than dropped.
+        let state = app_state_delivering(
+            InMemoryStorage::with_default_realms(),
+            Arc::new(UnconfiguredChannel),
+        );
+        register(&state);
+        let app = test::init_service(App::new().app_data(state).configure(routes)).await;
+        let resp = test::call_service(&app, reset_request()).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // A webhook channel posts the token, signed with its secret.
+        let (url, received) = webhook_receiver(vec![200, 503]);
+        let secret = b"a delivery secret of 32 bytes or more".to_vec();
+        let channel = WebhookChannel {
+            url,
+            secret: secret.clone(),
+            transport: Arc::new(HttpTransport::new(
+                Duration::from_secs(5),
+                EndpointPolicy {
+                    allow_http: true,
+                    allow_private_targets: true,
+                },
+                None,
+            )),
+        };
+        let state = app_state_delivering(InMemoryStorage::with_default_realms(), Arc::new(channel));
+        let user_id = register(&state);
+        let app = test::init_service(App::new().app_data(state).configure(routes)).await;
+        let resp = test::call_service(&app, reset_request()).await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        let request = received.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(request.header("X-Webhook-Event"), "password_reset");
+        let signature = request.header("X-Webhook-Signature");
+        let timestamp: i64 = signature
+            .strip_prefix("t=")
+            .and_then(|rest| rest.split(',').next())
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(signature, sign_payload(&secret, timestamp, &request.body));
+        let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(
+            (&body["kind"], &body["user_id"], &body["email"]),
+            (
+                &json!("password_reset"),
+                &json!(user_id),
+                &json!("ruru@nitro.com")
+            )
+        );
+        assert!(body["token"]
+            .as_str()
+            .is_some_and(|token| !token.is_empty()));
+
+        // An endpoint that does not take the message fails the request.
+        let resp = test::call_service(&app, reset_request()).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[actix_web::test]
+    async fn test_webhooks() {
+        let channel = Arc::new(RecordingChannel::default());
+        let state = app_state_delivering(InMemoryStorage::with_default_realms(), channel.clone());
+        let app = test::init_service(App::new().app_data(state.clone()).configure(routes)).await;
+        let (url, received) = webhook_receiver(vec![500, 200, 200, 503, 503, 200]);
+        let next_request = || received.recv_timeout(Duration::from_secs(5)).unwrap();
+        let policy = RetryPolicy {
+            max_attempts: 2,
+            base_delay: Duration::from_secs(60),
+            max_delay: Duration::from_secs(60),
+        };
+        let dispatch = |now: i64| {
+            WebhookService::dispatch_due(
+                state.execution_context.storage.as_ref(),
+                &state.secret_cipher,
+                &HttpTransport::new(Duration::from_secs(5), state.webhook_endpoints, None),
+                &policy,
+                now,
+            )
+            .unwrap()
+        };
+        let publisher = WebhookPublisher {
+            storage: state.execution_context.storage.clone(),
+        };
+        let publish = |now: i64| {
+            OutboxService::dispatch(
+                state.execution_context.storage.as_ref(),
+                &publisher,
+                &policy,
+                now,
+            )
+            .unwrap()
+        };
+        let admin_tokens = [
+            ("rj.wire", admin_token(&state, "rj.wire")),
+            ("rj.haven", admin_token(&state, "rj.haven")),
+        ];
+        let admin = |req: test::TestRequest, uri: &str, realm: &str| {
+            let (_, token) = admin_tokens.iter().find(|(r, _)| *r == realm).unwrap();
+            req.uri(&format!("/api/admin/webhooks{}", uri))
+                .insert_header(("Realm", realm))
+                .insert_header(bearer(token))
+                .to_request()
+        };
+
+        // Subscribers receive every user's data, so only admins may manage them.
+        let req = test::TestRequest::get()
+            .uri("/api/admin/webhooks")
+            .insert_header(("Realm", "rj.wire"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        for url in ["ftp://crm/hook", "http://crm\r\nX-Evil: 1/hook"] {
+            let subscribe = json!({ "url": url, "event_types": ["user_created"] });
+            let req = admin(test::TestRequest::post().set_json(subscribe), "", "rj.wire");
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY, "{}", url);
+        }
+        let subscribe = json!({
+            "url": url,
+            "event_types": ["user_created", "user_confirmed", "user_deleted"],
+        });
+        let req = admin(
+            test::TestRequest::post().set_json(&subscribe),
+            "",
+            "rj.wire",
+        );
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let created: CreatedSubscription = test::read_body_json(resp).await;
+        let listed: Vec<SubscriptionInfo> =
+            test::call_and_read_body_json(&app, admin(test::TestRequest::get(), "", "rj.wire"))
+                .await;
+        assert_eq!(listed, vec![created.subscription.clone()]);
+
+        let req = test::TestRequest::post()
+            .uri("/api/customer")
+            .insert_header(("Realm", "rj.wire"))
+            .set_json(create_user("ruru"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let user_id = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+
+        // Nothing is sent before the event leaves the outbox.
+        let now = Utc::now().timestamp();
+        assert_eq!(dispatch(now), 0);
+        assert_eq!(publish(now), 1);
+        assert_eq!(publish(now), 0);
+
+        // The endpoint fails first, the retry waits for the backoff and sends the same event.
+        assert_eq!(dispatch(now), 1);
+        let failed = next_request();
+        assert_eq!(dispatch(now), 0);
+        assert_eq!(dispatch(now + 60), 1);
+        let accepted = next_request();
+        assert_eq!(accepted.body, failed.body);
+        let payload: WebhookPayload = serde_json::from_str(&accepted.body).unwrap();
+        assert_eq!(payload.event_type, WebhookEventType::UserCreated);
+        assert_eq!(payload.user_id, user_id);
+        assert_eq!(payload.data["username"], "ruru");
+        assert_eq!(accepted.header("X-Webhook-Id"), payload.id);
+        assert_eq!(accepted.header("X-Webhook-Event"), "user_created");
+        let signature = accepted.header("X-Webhook-Signature");
+        let timestamp: i64 = signature
+            .strip_prefix("t=")
+            .and_then(|rest| rest.split(',').next())
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(
+            signature,
+            sign_payload(created.secret.as_bytes(), timestamp, &accepted.body)
+        );
+        assert_eq!(dispatch(now + 600), 0);
+
+        let token = channel.tokens()[0].token.clone();
+        let confirm = |token: &str| {
+            test::TestRequest::post()
+                .uri("/api/customer/email/confirm")
+                .insert_header(("Realm", "rj.wire"))
+                .set_json(json!({ "username": "ruru", "token": token }))
+                .to_request()
+        };
+        let resp = test::call_service(&app, confirm("wrong")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = test::call_service(&app, confirm(&token)).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let resp = test::call_service(&app, confirm(&token)).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let req = test::TestRequest::delete()
+            .uri(&format!("/api/customer/{}", user_id))
+            .insert_header(("Realm", "rj.wire"))
+            .insert_header(bearer(&access_token(&state, "rj.wire", &user_id)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        // Out of retries, the delivery is dead-lettered until replayed.
+        let now = Utc::now().timestamp();
+        assert_eq!(publish(now), 2);
+        assert_eq!(dispatch(now), 2);
+        let confirmed: WebhookPayload = serde_json::from_str(&next_request().body).unwrap();
+        assert_eq!(confirmed.event_type, WebhookEventType::UserConfirmed);
+        let deleted: WebhookPayload = serde_json::from_str(&next_request().body).unwrap();
+        assert_eq!(deleted.event_type, WebhookEventType::UserDeleted);
+        assert_eq!(deleted.data["mode"], "disable");
+        // The sequence lets receivers order the events of a user whatever order they arrive in.
+        assert!(payload.sequence < confirmed.sequence);
+        assert!(confirmed.sequence < deleted.sequence);
+        assert_eq!(dispatch(now + 60), 1);
+        next_request();
+        let dead: Vec<WebhookDelivery> = test::call_and_read_body_json(
+            &app,
+            admin(test::TestRequest::get(), "/dead-letters", "rj.wire"),
+        )
+        .await;
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].event_id, deleted.id);
+        assert_eq!(dead[0].attempts, 2);
+        assert_eq!(
+            dead[0].last_error.as_deref(),
+            Some("503 Service Unavailable")
+        );
+        let others: Vec<WebhookDelivery> = test::call_and_read_body_json(
+            &app,
+            admin(test::TestRequest::get(), "/dead-letters", "rj.haven"),
+        )
+        .await;
+        assert!(others.is_empty());
+
+        let dead_id = dead[0].delivery_id.clone();
+        let find_dead = || {
+            state
+                .execution_context
+                .storage
+                .in_transaction(|tx| tx.find_delivery(&"rj.wire".to_string(), &dead_id))
+                .unwrap()
+        };
+        let replay_uri = format!("/deliveries/{}/replay", dead_id);
+        let cases = [
+            ("rj.haven", StatusCode::NOT_FOUND),
+            ("rj.wire", StatusCode::ACCEPTED),
+            ("rj.wire", StatusCode::CONFLICT),
+        ];
+        for (realm, status) in cases.iter() {
+            let req = admin(test::TestRequest::post(), &replay_uri, realm);
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), *status, "replay in {}", realm);
+        }
+        assert_eq!(dispatch(Utc::now().timestamp()), 1);
+        let replayed = next_request();
+        assert_eq!(replayed.header("X-Webhook-Id"), deleted.id);
+        let dead: Vec<WebhookDelivery> = test::call_and_read_body_json(
+            &app,
+            admin(test::TestRequest::get(), "/dead-letters", "rj.wire"),
+        )
+        .await;
+        assert!(dead.is_empty());
+        let status = find_dead().map(|delivery| delivery.status);
+        assert_eq!(status, Some(DeliveryStatus::Delivered));
+
+        let subscription_uri = format!("/{}", created.subscription.subscription_id);
+        let cases = [
+            ("rj.haven", StatusCode::NOT_FOUND),
+            ("rj.wire", StatusCode::NO_CONTENT),
+            ("rj.wire", StatusCode::NOT_FOUND),
+        ];
+        for (realm, status) in cases.iter() {
+            let req = admin(test::TestRequest::delete(), &subscription_uri, realm);
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), *status, "unsubscribe in {}", realm);
+        }
+        assert!(find_dead().is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_outbox() {
+        let state = app_state();
+        let app = test::init_service(App::new().app_data(state.clone()).configure(routes)).await;
+        let publisher = RecordingPublisher::failing(1);
+        let policy = RetryPolicy::default();
+        let publish = |now: i64| {
+            OutboxService::dispatch(
+                state.execution_context.storage.as_ref(),
+                &publisher,
+                &policy,
+                now,
+            )
+            .unwrap()
+        };
+        let create = || {
+            test::TestRequest::post()
+                .uri("/api/customer")
+                .insert_header(("Realm", "rj.wire"))
+                .set_json(create_user("ruru"))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, create()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let user_id = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        let resp = test::call_service(&app, create()).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        let req = test::TestRequest::patch()
+            .uri(&format!("/api/customer/{}", user_id))
+            .insert_header(("Realm", "rj.wire"))
+            .insert_header(bearer(&access_token(&state, "rj.wire", &user_id)))
+            .set_json(json!({ "name": "Ru Paul" }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // The first attempt fails and holds back the update until the retry goes through.
+        let now = Utc::now().timestamp();
+        assert_eq!(publish(now), 1);
+        assert_eq!(publish(now + 29), 0);
+        assert_eq!(publish(now + 30), 2);
+        assert_eq!(publish(now + 600), 0);
+
+        let attempts = publisher.attempts();
+        let types = attempts
+            .iter()
+            .map(|event| event.event_type.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(types, vec!["user_created", "user_created", "user_updated"]);
+        assert_eq!(attempts[0], attempts[1]);
+        assert_ne!(attempts[1].event_id, attempts[2].event_id);
+        assert!(attempts.iter().all(|event| event.aggregate_id == user_id));
+        assert_eq!(attempts[2].payload["changed"], json!(["name"]));
+    }
+
+    #[actix_web::test]
+    async fn test_rate_limit() {
+        let app = test::init_service(App::new().app_data(app_state()).configure(routes)).await;
+        let reset = |realm: &str, ip: &str, client: &str| {
+            test::TestRequest::post()
+                .uri("/api/customer/password-reset")
+                .insert_header(("Realm", realm))
+                .insert_header(("Client-Id", client))
+                .peer_addr(format!("{}:4000", ip).parse().unwrap())
+                .set_json(json!({ "username": "nobody" }))
+                .to_request()
+        };
+
+        for _ in 0..10 {
+            let resp = test::call_service(&app, reset("rj.wire", "10.0.0.1", "web")).await;
+            assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        }
+        let resp = test::call_service(&app, reset("rj.wire", "10.0.0.1", "web")).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers().get("Retry-After").unwrap(), "6");
+
+        // Buckets are kept per realm and IP, a client header of the caller's choosing changes
+        // nothing.
+        let resp = test::call_service(&app, reset("rj.wire", "10.0.0.1", "mobile")).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        let resp = test::call_service(&app, reset("rj.wire", "10.0.0.2", "web")).await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        let resp = test::call_service(&app, reset("rj.haven", "10.0.0.1", "web")).await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+
+        // Unknown realms are throttled as well, whatever realm they name.
+        for realm in (0..10).map(|i| format!("rj.unknown{}", i)) {
+            let resp = test::call_service(&app, reset(&realm, "10.0.0.1", "web")).await;
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        }
+        let resp = test::call_service(&app, reset("rj.unknown", "10.0.0.1", "web")).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
 }
@@ -0,0 +1,127 @@
+//! Rate limiting middleware, wrapped around the routes of each [`RouteClass`].
+
+use crate::app::Error;
+use crate::domain::infra::web::{JsonErrorResponse, RealmFinder};
+use crate::domain::rate_limit::{RateLimits, RouteClass};
+use crate::AppState;
+use actix_web::body::BoxBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::StatusCode;
+use actix_web::web::Data;
+use actix_web::{web, HttpRequest};
+use chrono::Utc;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+
+/// Limits requests to the wrapped routes with a token bucket per realm and IP, sized by the
+/// realm's limit for `class`. Throttled requests get a 429 with `Retry-After`.
+///
+/// Requests without a known realm share buckets per IP, sized by the default limits, before the
+/// handler rejects them.
+#[derive(Clone, Copy)]
+pub struct RateLimit {
+    class: RouteClass,
+}
+
+impl RateLimit {
+    pub fn new(class: RouteClass) -> RateLimit {
+        RateLimit { class }
+    }
+}
+
+impl<S> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = actix_web::Error>
+        + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = actix_web::Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            class: self.class,
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    class: RouteClass,
+}
+
+impl<S> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = actix_web::Error>
+        + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let class = self.class;
+        Box::pin(async move {
+            if let Err(e) = throttle(class, req.request()).await {
+                return Ok(req.error_response(e));
+            }
+            service.call(req).await
+        })
+    }
+}
+
+async fn throttle(
+    class: RouteClass,
+    req: &HttpRequest,
+) -> Result<(), JsonErrorResponse<Option<String>>> {
+    let data = match req.app_data::<Data<AppState>>() {
+        Some(data) => data.clone(),
+        None => return Ok(()),
+    };
+    let realm = req
+        .headers()
+        .get_realm()
+        .filter(|realm| data.realm_settings_provider.has_realm(realm));
+    let limits = match &realm {
+        Some(realm) => data.realm_settings_provider.get_rate_limits(realm),
+        None => RateLimits::default(),
+    };
+    let per_minute = limits.per_minute(class);
+    if per_minute == 0 {
+        return Ok(());
+    }
+
+    let ip = req
+        .peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_else(|| "-".to_string());
+    let key = format!("{}:{}", class, ip);
+    let now = Utc::now().timestamp_millis();
+
+    let throttled = web::block(move || {
+        data.rate_limiter
+            .acquire(realm.as_ref(), &key, per_minute, now)
+            .map_err(Error::from)
+    })
+    .await
+    .map_err(|e| {
+        JsonErrorResponse::new(None, e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+    })??;
+
+    match throttled {
+        Some(retry_after) => Err(JsonErrorResponse::new(
+            None,
+            format!("Too many requests, retry in {} seconds", retry_after),
+            StatusCode::TOO_MANY_REQUESTS,
+        )
+        .with_retry_after(retry_after)),
+        None => Ok(()),
+    }
+}
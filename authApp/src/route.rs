@@ -1,6 +1,9 @@
-use crate::resource::customer;
+use crate::domain::rate_limit::RouteClass;
+use crate::resource::rate_limit::RateLimit;
+use crate::resource::{admin, customer};
+use actix_web::dev::HttpServiceFactory;
 use actix_web::web::scope;
-use actix_web::{web, HttpResponse, Resource, Scope};
+use actix_web::{web, HttpResponse, Scope};
 
 pub fn routes(config: &mut web::ServiceConfig) {
     config.service(
@@ -13,40 +16,207 @@ pub fn routes(config: &mut web::ServiceConfig) {
 fn user_api_config(cfg: &mut web::ServiceConfig) {
     cfg.service(customer_resource())
         .service(admin_resource())
+        .service(admin_customer_resource())
+        .service(admin_audit_resource())
+        .service(admin_webhook_resource())
         .service(realm_resource());
 }
 
 fn customer_resource() -> Scope {
-    return web::scope("/customer")
+    web::scope("/customer")
+        .service(
+            web::resource("/password-reset")
+                .wrap(RateLimit::new(RouteClass::PasswordReset))
+                .route(web::post().to(customer::request_password_reset)),
+        )
+        .service(
+            web::resource("/password-reset/confirm")
+                .wrap(RateLimit::new(RouteClass::PasswordReset))
+                .route(web::post().to(customer::reset_password)),
+        )
+        .service(
+            web::resource("/email/confirm")
+                .wrap(RateLimit::new(RouteClass::PasswordReset))
+                .route(web::post().to(customer::confirm_email)),
+        )
+        .service(
+            web::resource("/{user_id}/password")
+                .wrap(RateLimit::new(RouteClass::Login))
+                .route(web::put().to(customer::change_password)),
+        )
+        .service(
+            web::resource("/{user_id}/mfa/totp")
+                .route(web::post().to(customer::enroll_totp))
+                .route(web::delete().to(customer::remove_totp)),
+        )
+        .service(
+            web::resource("/{user_id}/mfa/totp/verify")
+                .route(web::post().to(customer::activate_totp)),
+        )
+        .service(
+            web::resource("/{user_id}/mfa/recovery-codes")
+                .route(web::get().to(customer::recovery_code_status))
+                .route(web::post().to(customer::regenerate_recovery_codes)),
+        )
+        .service(
+            web::resource("/{user_id}/passkeys/options")
+                .route(web::post().to(customer::passkey_registration_options)),
+        )
+        .service(
+            web::resource("/{user_id}/passkeys")
+                .route(web::get().to(customer::list_passkeys))
+                .route(web::post().to(customer::register_passkey)),
+        )
+        .service(
+            web::resource("/{user_id}/passkeys/{credential_id}")
+                .route(web::delete().to(customer::remove_passkey)),
+        )
+        .service(web::resource("/{user_id}/sessions").route(web::get().to(customer::list_sessions)))
+        .service(
+            web::resource("/{user_id}/sessions/{session_id}")
+                .route(web::delete().to(customer::revoke_session)),
+        )
+        .service(
+            web::resource("/{user_id}/addresses")
+                .route(web::get().to(customer::list_addresses))
+                .route(web::post().to(customer::add_address)),
+        )
+        .service(
+            web::resource("/{user_id}/addresses/{address_id}/formatted")
+                .route(web::get().to(customer::format_address)),
+        )
+        .service(
+            web::resource("/{user_id}/addresses/{address_id}")
+                .route(web::get().to(customer::get_address))
+                .route(web::put().to(customer::update_address))
+                .route(web::delete().to(customer::delete_address)),
+        )
         .service(
             web::resource("/{user_id}")
                 .route(web::get().to(customer::get))
-                .route(web::put().to(customer::update)),
+                .route(web::put().to(customer::update))
+                .route(web::patch().to(customer::update))
+                .route(web::delete().to(customer::delete)),
         )
         .service(
-            web::resource("")
-                .route(web::post().to(customer::create))
-                .route(web::get().to(customer::get_all)),
-        );
+            web::resource("").route(
+                web::post()
+                    .to(customer::create)
+                    .wrap(RateLimit::new(RouteClass::Registration)),
+            ),
+        )
 }
 
 fn realm_resource() -> Scope {
-    return web::scope("/realm")
-        .service(web::resource("/{realm}/login").route(web::post().to(customer::login)))
+    web::scope("/realm")
+        .service(
+            web::resource("/{realm}/login")
+                .wrap(RateLimit::new(RouteClass::Login))
+                .route(web::post().to(customer::login)),
+        )
+        .service(
+            web::resource("/{realm}/login/mfa")
+                .wrap(RateLimit::new(RouteClass::Login))
+                .route(web::post().to(customer::login_mfa)),
+        )
+        .service(
+            web::resource("/{realm}/login/passwordless/request")
+                .wrap(RateLimit::new(RouteClass::PasswordReset))
+                .route(web::post().to(customer::request_passwordless)),
+        )
+        .service(
+            web::resource("/{realm}/login/passwordless")
+                .wrap(RateLimit::new(RouteClass::Login))
+                .route(web::post().to(customer::login_passwordless)),
+        )
+        .service(
+            web::resource("/{realm}/login/passkey/options")
+                .wrap(RateLimit::new(RouteClass::Login))
+                .route(web::post().to(customer::login_passkey_options)),
+        )
+        .service(
+            web::resource("/{realm}/login/passkey")
+                .wrap(RateLimit::new(RouteClass::Login))
+                .route(web::post().to(customer::login_passkey)),
+        )
+        .service(
+            web::resource("/{realm}/token/refresh")
+                .wrap(RateLimit::new(RouteClass::Login))
+                .route(web::post().to(customer::refresh_token)),
+        )
+        .service(
+            web::resource("/{realm}/password-policy")
+                .route(web::get().to(customer::password_policy)),
+        )
         .service(
             web::resource("/{realm}")
-                .route(web::put().to(customer::login))
+                .route(
+                    web::put()
+                        .to(customer::login)
+                        .wrap(RateLimit::new(RouteClass::Login)),
+                )
                 .route(web::get().to(customer::get)), // .route(web::put().to(customer::update)),
         )
+        .service(
+            web::resource("").route(
+                web::post()
+                    .to(customer::create)
+                    .wrap(RateLimit::new(RouteClass::Registration)),
+            ),
+        )
+}
+
+fn admin_customer_resource() -> impl HttpServiceFactory {
+    web::scope("/admin/customer")
+        .wrap(RateLimit::new(RouteClass::Admin))
+        .service(web::resource("/{user_id}/password").route(web::put().to(admin::set_password)))
+        .service(web::resource("/{user_id}/unlock").route(web::post().to(admin::unlock_customer)))
+        .service(web::resource("/{user_id}/sessions").route(web::get().to(admin::list_sessions)))
+        .service(
+            web::resource("/{user_id}/sessions/{session_id}")
+                .route(web::delete().to(admin::revoke_session)),
+        )
+        .service(
+            web::resource("/{user_id}")
+                .route(web::get().to(admin::get_customer))
+                .route(web::put().to(admin::update_customer))
+                .route(web::patch().to(admin::update_customer))
+                .route(web::delete().to(admin::delete_customer)),
+        )
+        .service(web::resource("").route(web::get().to(admin::list_customers)))
+}
+
+fn admin_audit_resource() -> impl HttpServiceFactory {
+    web::scope("/admin/audit")
+        .wrap(RateLimit::new(RouteClass::Admin))
+        .service(web::resource("").route(web::get().to(admin::audit_events)))
+        .service(web::resource("/verify").route(web::get().to(admin::verify_audit_chain)))
+        .service(web::resource("/checkpoint").route(web::get().to(admin::audit_checkpoint)))
+        .service(
+            web::resource("/checkpoint/verify")
+                .route(web::post().to(admin::verify_audit_checkpoint)),
+        )
+}
+
+fn admin_webhook_resource() -> impl HttpServiceFactory {
+    web::scope("/admin/webhooks")
+        .wrap(RateLimit::new(RouteClass::Admin))
+        .service(web::resource("/dead-letters").route(web::get().to(admin::dead_letters)))
+        .service(
+            web::resource("/deliveries/{delivery_id}/replay")
+                .route(web::post().to(admin::replay_delivery)),
+        )
+        .service(web::resource("/{subscription_id}").route(web::delete().to(admin::delete_webhook)))
         .service(
             web::resource("")
-                .route(web::post().to(customer::create))
-                .route(web::get().to(customer::get_all)),
-        );
+                .route(web::get().to(admin::list_webhooks))
+                .route(web::post().to(admin::create_webhook)),
+        )
 }
 
-fn admin_resource() -> Resource {
-    return web::resource("/admin")
+fn admin_resource() -> impl HttpServiceFactory {
+    web::resource("/admin")
+        .wrap(RateLimit::new(RouteClass::Admin))
         .route(web::get().to(|| async { HttpResponse::Ok().body("test") }))
-        .route(web::head().to(|| async { HttpResponse::MethodNotAllowed() }));
+        .route(web::head().to(|| async { HttpResponse::MethodNotAllowed().finish() }))
 }
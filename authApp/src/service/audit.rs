@@ -0,0 +1,173 @@
+use crate::app::Error;
+use crate::domain::audit::{
+    AuditChain, AuditPage, AuditQuery, ChainBreak, ChainVerification, ChainWalk, Checkpoint,
+    CheckpointSigner, CheckpointStatus, CheckpointVerification, SignedCheckpoint, GENESIS_HASH,
+};
+use crate::domain::realm::RealmName;
+use crate::repository::{Storage, StorageResult, StorageTx};
+use chrono::Utc;
+use std::time::Duration;
+
+/// Chain entries read at a time while verifying.
+const VERIFY_BATCH_SIZE: u32 = 500;
+
+pub struct AuditService;
+
+impl AuditService {
+    /// A page of the realm's events matching `query`, newest first.
+    pub fn search(
+        realm: &RealmName,
+        query: &AuditQuery,
+        storage: &dyn Storage,
+    ) -> Result<AuditPage, Error> {
+        let limit = query.page_size()?;
+        let events = storage.in_transaction(|tx| tx.query_audit_events(realm, query, limit))?;
+        let next_before = events
+            .last()
+            .filter(|_| events.len() == limit as usize)
+            .map(|record| record.id);
+        Ok(AuditPage {
+            events,
+            next_before,
+        })
+    }
+
+    /// Removes the events of every realm older than `retention`, returns how many. Chains are
+    /// cut before their oldest kept entry and anchored at the last removed one, so they still
+    /// verify.
+    pub fn prune(retention: Duration, storage: &dyn Storage) -> Result<u64, Error> {
+        let before = Utc::now().timestamp() - retention.as_secs() as i64;
+        Ok(storage.in_transaction(|tx| {
+            let mut pruned = tx.prune_audit_events(before)?;
+            for chain in tx.audit_chains()? {
+                let last_expired = match tx.first_audit_sequence_since(&chain.realm, before)? {
+                    Some(sequence) => sequence - 1,
+                    None => chain.head_sequence,
+                };
+                if last_expired <= chain.anchor_sequence {
+                    continue;
+                }
+                // A missing entry is a break that verification should still find, so the
+                // chain is left as it is.
+                let anchor = tx
+                    .audit_chain_entries(&chain.realm, last_expired - 1, 1)?
+                    .into_iter()
+                    .find_map(|record| record.link.filter(|link| link.sequence == last_expired));
+                if let Some(anchor) = anchor {
+                    pruned += tx.truncate_audit_chain(&chain.realm, &anchor)?;
+                }
+            }
+            Ok(pruned)
+        })?)
+    }
+
+    /// Walks the realm's chain from its anchor to its head and reports the first break.
+    pub fn verify(realm: &RealmName, storage: &dyn Storage) -> Result<ChainVerification, Error> {
+        let (chain, walk, first_break) = storage.in_transaction(|tx| {
+            let chain = tx
+                .audit_chain(realm)?
+                .unwrap_or_else(|| AuditChain::empty(realm));
+            let mut walk = ChainWalk::new(&chain);
+            let first_break = AuditService::walk_chain(tx, &chain, &mut walk)?.err();
+            Ok((chain, walk, first_break))
+        })?;
+        Ok(ChainVerification {
+            realm: chain.realm,
+            anchor_sequence: chain.anchor_sequence,
+            head_sequence: chain.head_sequence,
+            head_hash: chain.head_hash,
+            verified_entries: walk.verified(),
+            redacted_entries: walk.redacted(),
+            first_break,
+        })
+    }
+
+    /// A signed checkpoint of the head of the realm's chain.
+    pub fn checkpoint(
+        realm: &RealmName,
+        signer: &CheckpointSigner,
+        storage: &dyn Storage,
+    ) -> Result<SignedCheckpoint, Error> {
+        let chain = storage
+            .in_transaction(|tx| tx.audit_chain(realm))?
+            .unwrap_or_else(|| AuditChain::empty(realm));
+        Ok(signer.sign(Checkpoint {
+            realm: chain.realm,
+            sequence: chain.head_sequence,
+            entry_hash: chain.head_hash,
+            issued_at: Utc::now().timestamp(),
+        }))
+    }
+
+    /// Compares a checkpoint signed by `signer` with the realm's chain as it is now. Together
+    /// with an intact chain, a consistent checkpoint shows that no entry up to it was changed.
+    pub fn verify_checkpoint(
+        realm: &RealmName,
+        signed: &SignedCheckpoint,
+        signer: &CheckpointSigner,
+        storage: &dyn Storage,
+    ) -> Result<CheckpointVerification, Error> {
+        let checkpoint = &signed.checkpoint;
+        if &checkpoint.realm != realm {
+            return Err(Error::Validation(
+                "checkpoint belongs to another realm".to_string(),
+            ));
+        }
+        if !signer.verify(signed) {
+            return Ok(CheckpointVerification {
+                status: CheckpointStatus::InvalidSignature,
+            });
+        }
+
+        let status = storage.in_transaction(|tx| {
+            let chain = tx
+                .audit_chain(realm)?
+                .unwrap_or_else(|| AuditChain::empty(realm));
+            let recorded_hash = if checkpoint.sequence == 0 {
+                Some(GENESIS_HASH.to_string())
+            } else if checkpoint.sequence == chain.anchor_sequence {
+                Some(chain.anchor_hash)
+            } else if checkpoint.sequence < chain.anchor_sequence {
+                return Ok(CheckpointStatus::Pruned);
+            } else {
+                tx.audit_chain_entries(realm, checkpoint.sequence - 1, 1)?
+                    .into_iter()
+                    .filter_map(|record| record.link)
+                    .find(|link| link.sequence == checkpoint.sequence)
+                    .map(|link| link.entry_hash)
+            };
+            Ok(match recorded_hash {
+                Some(hash) if hash == checkpoint.entry_hash => CheckpointStatus::Consistent,
+                Some(_) => CheckpointStatus::Diverged,
+                None => CheckpointStatus::Missing,
+            })
+        })?;
+        Ok(CheckpointVerification { status })
+    }
+
+    /// Feeds the chain's entries up to its head to `walk`, a batch at a time.
+    fn walk_chain(
+        tx: &mut dyn StorageTx,
+        chain: &AuditChain,
+        walk: &mut ChainWalk,
+    ) -> StorageResult<Result<(), ChainBreak>> {
+        let mut after = chain.anchor_sequence;
+        loop {
+            let batch = tx.audit_chain_entries(&chain.realm, after, VERIFY_BATCH_SIZE)?;
+            for record in &batch {
+                let sequence = record.link.as_ref().map_or(after, |link| link.sequence);
+                // Entries appended since the head was read are left to the next verification.
+                if sequence > chain.head_sequence {
+                    return Ok(walk.finish(chain));
+                }
+                if let Err(found) = walk.step(record) {
+                    return Ok(Err(found));
+                }
+                after = sequence;
+            }
+            if batch.len() < VERIFY_BATCH_SIZE as usize {
+                return Ok(walk.finish(chain));
+            }
+        }
+    }
+}
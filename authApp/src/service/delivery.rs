@@ -0,0 +1,160 @@
+use crate::domain::passwordless::PasswordlessMessage;
+use crate::domain::realm::RealmName;
+use crate::domain::webhook::{sign_payload, EVENT_TYPE_HEADER, SIGNATURE_HEADER};
+use crate::service::webhook::WebhookTransport;
+use chrono::Utc;
+use serde_json::json;
+use std::sync::Arc;
+use strum_macros::Display;
+
+/// Why an [`AccountMessage`] is sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum AccountMessageKind {
+    /// Carries the token of `POST /api/customer/password-reset/confirm`.
+    PasswordReset,
+    /// Carries the token of `POST /api/customer/email/confirm`, to the new address.
+    EmailVerification,
+}
+
+/// A single-use token sent to an email address of the user, proving access to it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccountMessage {
+    pub realm: RealmName,
+    pub user_id: String,
+    pub username: String,
+    pub email: String,
+    pub kind: AccountMessageKind,
+    pub token: String,
+}
+
+/// Carries passwordless login messages and account tokens to users, e.g. by email. Magic links
+/// and confirmation links are rendered here from the token of the message, so the link target
+/// stays a deployment concern.
+pub trait DeliveryChannel: Send + Sync {
+    fn deliver(&self, message: &PasswordlessMessage) -> Result<(), String>;
+
+    fn deliver_token(&self, message: &AccountMessage) -> Result<(), String>;
+}
+
+/// Stands in when no channel is configured. Every message is refused, so that the endpoints
+/// sending one answer that they cannot rather than pretend to, and only the refusal is printed,
+/// without the secret: logs are read by more people than the users the secrets belong to.
+pub struct UnconfiguredChannel;
+
+impl UnconfiguredChannel {
+    const REFUSAL: &'static str = "no delivery channel is configured";
+}
+
+impl DeliveryChannel for UnconfiguredChannel {
+    fn deliver(&self, message: &PasswordlessMessage) -> Result<(), String> {
+        println!(
+            "{} for {} in {} not sent, {}",
+            message.method,
+            message.user_id,
+            message.realm,
+            UnconfiguredChannel::REFUSAL
+        );
+        Err(UnconfiguredChannel::REFUSAL.to_string())
+    }
+
+    fn deliver_token(&self, message: &AccountMessage) -> Result<(), String> {
+        println!(
+            "{} token for {} in {} not sent, {}",
+            message.kind,
+            message.user_id,
+            message.realm,
+            UnconfiguredChannel::REFUSAL
+        );
+        Err(UnconfiguredChannel::REFUSAL.to_string())
+    }
+}
+
+/// Posts every message as JSON to a mail relay or notification service, which renders and sends
+/// it. Requests are signed like webhooks, with the [`SIGNATURE_HEADER`] over the body and the
+/// kind of message in the [`EVENT_TYPE_HEADER`], and go through the same [`WebhookTransport`]
+/// and its endpoint policy.
+pub struct WebhookChannel {
+    pub url: String,
+    pub secret: Vec<u8>,
+    pub transport: Arc<dyn WebhookTransport>,
+}
+
+impl WebhookChannel {
+    fn post(&self, kind: &str, body: serde_json::Value) -> Result<(), String> {
+        let body = body.to_string();
+        let headers = [
+            (
+                SIGNATURE_HEADER,
+                sign_payload(&self.secret, Utc::now().timestamp(), &body),
+            ),
+            (EVENT_TYPE_HEADER, kind.to_string()),
+        ];
+        self.transport.post(&self.url, &headers, &body)
+    }
+}
+
+impl DeliveryChannel for WebhookChannel {
+    fn deliver(&self, message: &PasswordlessMessage) -> Result<(), String> {
+        let kind = message.method.to_string();
+        self.post(
+            &kind,
+            json!({
+                "kind": kind,
+                "realm": message.realm.to_string(),
+                "user_id": message.user_id,
+                "username": message.username,
+                "email": message.email,
+                "secret": message.secret,
+                "expires_at": message.expires_at,
+            }),
+        )
+    }
+
+    fn deliver_token(&self, message: &AccountMessage) -> Result<(), String> {
+        let kind = message.kind.to_string();
+        self.post(
+            &kind,
+            json!({
+                "kind": kind,
+                "realm": message.realm.to_string(),
+                "user_id": message.user_id,
+                "username": message.username,
+                "email": message.email,
+                "token": message.token,
+            }),
+        )
+    }
+}
+
+/// Keeps delivered messages in memory, for tests to read the secrets back.
+#[cfg(test)]
+#[derive(Default)]
+pub struct RecordingChannel {
+    sent: std::sync::Mutex<Vec<PasswordlessMessage>>,
+    tokens: std::sync::Mutex<Vec<AccountMessage>>,
+}
+
+#[cfg(test)]
+impl RecordingChannel {
+    pub fn sent(&self) -> Vec<PasswordlessMessage> {
+        self.sent.lock().unwrap().clone()
+    }
+
+    pub fn tokens(&self) -> Vec<AccountMessage> {
+        self.tokens.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl DeliveryChannel for RecordingChannel {
+    fn deliver(&self, message: &PasswordlessMessage) -> Result<(), String> {
+        self.sent.lock().unwrap().push(message.clone());
+        Ok(())
+    }
+
+    fn deliver_token(&self, message: &AccountMessage) -> Result<(), String> {
+        self.tokens.lock().unwrap().push(message.clone());
+        Ok(())
+    }
+}
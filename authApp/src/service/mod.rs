@@ -1,99 +1,1896 @@
+pub mod audit;
+pub mod delivery;
+pub mod outbox;
 pub mod token;
+pub mod webhook;
 
 pub mod customer_service {
-    use crate::db::DB;
-    use crate::domain::customer::{dto::CreateUser, Role, User, UserWithAddress};
-    use crate::domain::realm::RealmName;
-    use crate::repository::realm::RealmSettingProvider;
-    use crate::repository::{AddressStorage, Repository, UserStorage};
-    use crate::{AppState, Principal};
-    use actix_web::http::header::{HeaderMap, HeaderValue};
-    use mysql::{AccessMode, Error, Result, Transaction};
-    use std::str::FromStr;
-    use std::sync::Arc;
-    use std::{clone::Clone, option::Option};
+    use crate::app::Error;
+    use crate::domain::audit::{AuditEvent, AuditEventType};
+    use crate::domain::customer::dto::{
+        hash_password, is_same_password, verify_password, AddressData, ChangePassword, CreateUser,
+        DeletionMode, EmailConfirmation, MfaLogin, PageCursor, PasswordReset, PasswordResetRequest,
+        Reauthentication, SetPassword, TotpCode, UpdateUser, UserMetadata, UserQuery,
+    };
+    use crate::domain::customer::{
+        AddressType, LoginRequest, Role, User, UserAddress, UserPage, UserProfile,
+    };
+    use crate::domain::lockout::{AttemptKey, LockoutPolicy, LoginAttempts};
+    use crate::domain::mfa::{
+        generate_recovery_codes, generate_totp_secret, hash_recovery_code, otpauth_uri,
+        verify_totp, MfaChallenge, MfaEnrollment, MfaMethod, MfaPolicy, RecoveryCode,
+        RecoveryCodeStatus, RecoveryCodes, SecretCipher, TotpEnrollment, TotpSetup,
+    };
+    use crate::domain::outbox::{OutboxEvent, USER_AGGREGATE};
+    use crate::domain::passwordless::{
+        hash_passwordless_secret, PasswordlessCredential, PasswordlessLogin, PasswordlessMessage,
+        PasswordlessMethod, PasswordlessPolicy, PasswordlessRequest,
+    };
+    use crate::domain::realm::{PasswordPolicy, RealmName};
+    use crate::domain::session::{
+        hash_refresh_secret, refresh_secret, refresh_token, split_refresh_token, AccessTokenKey,
+        ClientInfo, Principal, RefreshRequest, Session, SessionInfo, SessionTokens, TokenScope,
+    };
+    use crate::domain::validation::check_password;
+    use crate::domain::webauthn::{
+        user_handle, verify_assertion, verify_registration, AuthenticationCredential, Ceremony,
+        ClientData, CreationOptions, Passkey, PasskeyInfo, PasskeyLoginStart, PendingChallenge,
+        RegistrationCredential, RelyingParty, RequestOptions, UserEntity, WebAuthnConfig,
+    };
+    use crate::domain::webhook::WebhookEventType;
+    use crate::repository::{Storage, StorageError, StorageResult, StorageTx, TokenKind};
+    use crate::service::delivery::{AccountMessage, AccountMessageKind, DeliveryChannel};
+    use crate::service::webhook::WebhookService;
+    use crate::AppState;
+    use chrono::Utc;
+    use data_encoding::{BASE32_NOPAD, HEXLOWER};
+    use ring::digest::{digest, SHA256};
+    use serde_json::json;
+    use std::time::Duration;
+    use subtle::ConstantTimeEq;
+    use uuid::Uuid;
+
+    /// How long the token of an [`MfaChallenge`] can be exchanged for an access token.
+    const MFA_CHALLENGE_DURATION: Duration = Duration::from_secs(5 * 60);
+    /// How long a new email address can be confirmed with its verification token.
+    const EMAIL_VERIFICATION_DURATION: Duration = Duration::from_secs(3 * 24 * 60 * 60);
+
+    /// Failed attempt state of the keys a login counts against, with the threshold of each.
+    type CountedAttempts = Vec<(AttemptKey, LoginAttempts, u32)>;
+
+    /// Result of a login that passed the password check.
+    pub enum LoginOutcome {
+        Authenticated(User),
+        /// The user has a second factor, see [`AuthenticatorService::complete_mfa`].
+        MfaRequired(MfaChallenge),
+        /// The user's role requires a second factor they have not enrolled, see
+        /// [`AuthenticatorService::start_enrollment_session`].
+        EnrollmentRequired(User),
+    }
+
+    /// How long the tokens of a session live, from the realm settings.
+    #[derive(Clone, Copy, Debug)]
+    pub struct TokenLifetimes {
+        pub access: Duration,
+        pub refresh: Duration,
+    }
 
     pub struct AuthenticatorService {}
 
     impl AuthenticatorService {
-        pub fn initialise_token(data: &User) -> String {
-            "a".parse().unwrap()
+        /// Checks the credentials of a login, counting failures against the user and the client
+        /// IP. Locked users and IPs are refused before the password is checked, and unknown
+        /// usernames fail like wrong passwords so that they cannot be told apart.
+        ///
+        /// Users with an active second factor get a challenge instead of being authenticated,
+        /// users whose role requires one but who have none may only enroll one.
+        pub fn login(
+            realm: &RealmName,
+            request: &LoginRequest,
+            client_ip: Option<&str>,
+            lockout: &LockoutPolicy,
+            mfa: &MfaPolicy,
+            storage: &dyn Storage,
+        ) -> Result<LoginOutcome, Error> {
+            let now = Utc::now().timestamp();
+            let (user, counted) = storage.in_transaction(|tx| {
+                let user = tx.users().get_by_name(realm, &request.username)?;
+                let counted = AuthenticatorService::counted_attempts(
+                    tx,
+                    realm,
+                    user.as_ref(),
+                    client_ip,
+                    lockout,
+                )?;
+                Ok((user, counted))
+            })?;
+            AuthenticatorService::ensure_unlocked(&counted, now)?;
+
+            let user =
+                match user.filter(|user| verify_password(&request.password, &user.hashed_pass)) {
+                    Some(user) => user,
+                    None => {
+                        let error = Error::IncorrectPassword;
+                        AuthenticatorService::record_failures(
+                            realm, counted, now, lockout, &error, storage,
+                        )?;
+                        return Err(error);
+                    }
+                };
+
+            AuthenticatorService::second_factor(realm, user, mfa, storage)
+        }
+
+        /// Sends a passwordless login credential to the email address of a user, see
+        /// [`AuthenticatorService::passwordless_login`]. A new request replaces the pending
+        /// credential. Unknown usernames and users without an email address are silently
+        /// ignored so that they cannot be told apart.
+        pub fn request_passwordless(
+            realm: &RealmName,
+            request: PasswordlessRequest,
+            policy: &PasswordlessPolicy,
+            channel: &dyn DeliveryChannel,
+            storage: &dyn Storage,
+        ) -> Result<(), Error> {
+            let method = passwordless_method(policy)?;
+            let now = Utc::now().timestamp();
+            let message = storage.in_transaction(|tx| {
+                let user = match tx.users().get_by_name(realm, &request.username)? {
+                    Some(user) => user,
+                    None => return Ok(None),
+                };
+                let profile = tx.users().get_profile(realm, &user.user_id)?;
+                let email = match profile.and_then(|profile| profile.email) {
+                    Some(email) => email,
+                    None => return Ok(None),
+                };
+                let (credential, secret) = PasswordlessCredential::issue(
+                    &user.user_id,
+                    method,
+                    now,
+                    policy.credential_duration,
+                );
+                tx.put_passwordless(&user.user_id, &credential)?;
+                Ok(Some(PasswordlessMessage {
+                    realm: realm.clone(),
+                    user_id: user.user_id,
+                    username: user.username,
+                    email,
+                    method,
+                    secret,
+                    expires_at: credential.expires_at,
+                }))
+            })?;
+            match message {
+                Some(message) => channel.deliver(&message).map_err(Error::Delivery),
+                None => Ok(()),
+            }
         }
+
+        /// Redeems a credential sent by [`AuthenticatorService::request_passwordless`] in place
+        /// of a password. Wrong codes count against the credential, which stops working after
+        /// the realm's maximum of attempts, and as failed logins like wrong passwords.
+        pub fn passwordless_login(
+            realm: &RealmName,
+            login: PasswordlessLogin,
+            client_ip: Option<&str>,
+            policy: &PasswordlessPolicy,
+            lockout: &LockoutPolicy,
+            mfa: &MfaPolicy,
+            storage: &dyn Storage,
+        ) -> Result<LoginOutcome, Error> {
+            passwordless_method(policy)?;
+            let now = Utc::now().timestamp();
+            let (user, counted) = storage.in_transaction(|tx| {
+                let user = tx.users().get_by_name(realm, &login.username)?;
+                let counted = AuthenticatorService::counted_attempts(
+                    tx,
+                    realm,
+                    user.as_ref(),
+                    client_ip,
+                    lockout,
+                )?;
+                Ok((user, counted))
+            })?;
+            AuthenticatorService::ensure_unlocked(&counted, now)?;
+
+            let redeemed = match &user {
+                Some(user) => storage.in_transaction(|tx| {
+                    let secret_hash = hash_passwordless_secret(&user.user_id, &login.code);
+                    let redeemed = tx.redeem_passwordless(&user.user_id, &secret_hash, now)?;
+                    if !redeemed {
+                        tx.record_passwordless_failure(&user.user_id, policy.max_attempts)?;
+                    }
+                    Ok(redeemed)
+                })?,
+                None => false,
+            };
+            match user.filter(|_| redeemed) {
+                Some(user) => AuthenticatorService::second_factor(realm, user, mfa, storage),
+                None => {
+                    let error = Error::IncorrectCode;
+                    AuthenticatorService::record_failures(
+                        realm, counted, now, lockout, &error, storage,
+                    )?;
+                    Err(error)
+                }
+            }
+        }
+
+        /// Forgets the failed attempts of a user who passed the first factor, and challenges
+        /// users with an active second factor for it.
+        fn second_factor(
+            realm: &RealmName,
+            user: User,
+            mfa: &MfaPolicy,
+            storage: &dyn Storage,
+        ) -> Result<LoginOutcome, Error> {
+            let challenge = storage.in_transaction(|tx| {
+                tx.clear_attempts(realm, &AttemptKey::User(user.user_id.clone()))?;
+                let enrolled = tx
+                    .get_totp(&user.user_id)?
+                    .is_some_and(|totp| totp.activated_at.is_some());
+                if !enrolled {
+                    return Ok(None);
+                }
+                let token = expiring_token(MFA_CHALLENGE_DURATION);
+                tx.store_token(
+                    &user.user_id,
+                    TokenKind::MfaChallenge,
+                    &hash_expiring_token(&token),
+                )?;
+                let mut methods = vec![MfaMethod::Totp];
+                let recovery = RecoveryCodeStatus::of(&tx.list_recovery_codes(&user.user_id)?);
+                if recovery.remaining > 0 {
+                    methods.push(MfaMethod::RecoveryCode);
+                }
+                Ok(Some(MfaChallenge {
+                    mfa_token: token,
+                    methods,
+                }))
+            })?;
+            match challenge {
+                Some(challenge) => Ok(LoginOutcome::MfaRequired(challenge)),
+                None if mfa.is_required(&user.role) => Ok(LoginOutcome::EnrollmentRequired(user)),
+                None => Ok(LoginOutcome::Authenticated(user)),
+            }
+        }
+
+        /// Second step of a login that got an [`MfaChallenge`], with a TOTP or a recovery code.
+        /// Wrong codes count as failed attempts like wrong passwords, and each code is accepted
+        /// once. Recovery codes keep when and from where they were used.
+        pub fn complete_mfa(
+            realm: &RealmName,
+            login: MfaLogin,
+            client_ip: Option<&str>,
+            lockout: &LockoutPolicy,
+            cipher: &SecretCipher,
+            storage: &dyn Storage,
+        ) -> Result<User, Error> {
+            let now = Utc::now().timestamp();
+            let (issued, counted) = storage.in_transaction(|tx| {
+                let user = tx.users().get_by_name(realm, &login.username)?;
+                let counted = AuthenticatorService::counted_attempts(
+                    tx,
+                    realm,
+                    user.as_ref(),
+                    client_ip,
+                    lockout,
+                )?;
+                let issued = match user {
+                    Some(user) => {
+                        let token = tx.find_token(&user.user_id, TokenKind::MfaChallenge)?;
+                        let totp = tx.get_totp(&user.user_id)?;
+                        token.zip(totp).map(|(token, totp)| (user, token, totp))
+                    }
+                    None => None,
+                };
+                Ok((issued, counted))
+            })?;
+            AuthenticatorService::ensure_unlocked(&counted, now)?;
+
+            let invalid = || Error::Validation("invalid or expired MFA token".to_string());
+            let (user, totp) = match issued {
+                Some((user, stored, totp)) if is_issued_token(&stored, &login.mfa_token, now) => {
+                    (user, totp)
+                }
+                _ => return Err(invalid()),
+            };
+            let step = match login.method {
+                MfaMethod::Totp => {
+                    let secret = cipher
+                        .open(&user.user_id, &totp.sealed_secret)
+                        .ok_or(Error::SecretDecryption)?;
+                    verify_totp(&secret, &login.code, now, totp.last_used_step)
+                }
+                MfaMethod::RecoveryCode => None,
+            };
+
+            // The challenge is consumed with the code, so that both are accepted once also when
+            // requests race, and put back when the code is wrong.
+            let token = hash_expiring_token(&login.mfa_token);
+            let accepted = storage.in_transaction(|tx| {
+                if !tx.consume_token(&user.user_id, TokenKind::MfaChallenge, &token)? {
+                    return Ok(None);
+                }
+                let accepted = match login.method {
+                    MfaMethod::Totp => match step {
+                        Some(step) => tx.use_totp_step(&user.user_id, step)?,
+                        None => false,
+                    },
+                    MfaMethod::RecoveryCode => {
+                        let code_hash = hash_recovery_code(&login.code);
+                        if tx.use_recovery_code(&user.user_id, &code_hash, now, client_ip)? {
+                            let left =
+                                RecoveryCodeStatus::of(&tx.list_recovery_codes(&user.user_id)?);
+                            let used = AuditEvent::new(
+                                realm,
+                                AuditEventType::RecoveryCodeUsed,
+                                Some(&user.user_id),
+                                now,
+                            )
+                            .with_ip(client_ip)
+                            .with_detail(format!("{} remaining", left.remaining));
+                            tx.append_audit_event(&used)?;
+                            true
+                        } else {
+                            false
+                        }
+                    }
+                };
+                match accepted {
+                    true => tx.clear_attempts(realm, &AttemptKey::User(user.user_id.clone()))?,
+                    false => tx.store_token(&user.user_id, TokenKind::MfaChallenge, &token)?,
+                }
+                Ok(Some(accepted))
+            })?;
+            match accepted {
+                Some(true) => {}
+                Some(false) => {
+                    let error = Error::IncorrectCode;
+                    AuthenticatorService::record_failures(
+                        realm, counted, now, lockout, &error, storage,
+                    )?;
+                    return Err(error);
+                }
+                None => return Err(invalid()),
+            }
+            Ok(user)
+        }
+
+        /// Lifts the lockout of a user and forgets its failed attempts.
+        pub fn unlock(
+            user_id: &str,
+            realm: &RealmName,
+            storage: &dyn Storage,
+        ) -> Result<(), Error> {
+            let now = Utc::now().timestamp();
+            let unlocked =
+                AuditEvent::new(realm, AuditEventType::AdminUserUnlocked, Some(user_id), now);
+            storage.in_transaction(|tx| {
+                CustomerService::ensure_user(tx, realm, user_id)?;
+                tx.clear_attempts(realm, &AttemptKey::User(user_id.to_string()))?;
+                tx.append_audit_event(&unlocked)
+            })?;
+            Ok(())
+        }
+
+        /// Starts a TOTP enrollment with a new secret and recovery codes, replacing a pending one.
+        /// Logins do not ask for codes until the enrollment is activated with
+        /// [`AuthenticatorService::activate_totp`].
+        pub fn enroll_totp(
+            user_id: &str,
+            realm: &RealmName,
+            cipher: &SecretCipher,
+            storage: &dyn Storage,
+        ) -> Result<TotpSetup, Error> {
+            let secret = generate_totp_secret();
+            let recovery_codes = generate_recovery_codes();
+            let stored = AuthenticatorService::stored_recovery_codes(&recovery_codes);
+            let user = storage.in_transaction(|tx| {
+                let user = CustomerService::ensure_user(tx, realm, user_id)?;
+                if tx
+                    .get_totp(user_id)?
+                    .is_some_and(|totp| totp.activated_at.is_some())
+                {
+                    return Err(StorageError::Conflict("TOTP is already active".to_string()));
+                }
+                let pending = TotpEnrollment {
+                    sealed_secret: cipher.seal(user_id, &secret),
+                    activated_at: None,
+                    last_used_step: None,
+                };
+                tx.put_totp(user_id, &pending)?;
+                tx.replace_recovery_codes(user_id, &stored)?;
+                Ok(user)
+            })?;
+
+            Ok(TotpSetup {
+                secret: BASE32_NOPAD.encode(&secret),
+                otpauth_uri: otpauth_uri(realm, &user.username, &secret),
+                recovery_codes,
+            })
+        }
+
+        /// Activates a pending TOTP enrollment with a first code from the authenticator app.
+        pub fn activate_totp(
+            user_id: &str,
+            realm: &RealmName,
+            code: TotpCode,
+            cipher: &SecretCipher,
+            storage: &dyn Storage,
+        ) -> Result<(), Error> {
+            let now = Utc::now().timestamp();
+            let totp = storage.in_transaction(|tx| {
+                CustomerService::ensure_user(tx, realm, user_id)?;
+                tx.get_totp(user_id)?.ok_or(StorageError::NotFound)
+            })?;
+            if totp.activated_at.is_some() {
+                return Err(StorageError::Conflict("TOTP is already active".to_string()).into());
+            }
+
+            let secret = cipher
+                .open(user_id, &totp.sealed_secret)
+                .ok_or(Error::SecretDecryption)?;
+            let step = verify_totp(&secret, &code.code, now, None).ok_or_else(|| {
+                Error::field(
+                    "code",
+                    "incorrect_code",
+                    "does not match the authenticator app".to_string(),
+                )
+            })?;
+            let active = TotpEnrollment {
+                activated_at: Some(now),
+                last_used_step: Some(step),
+                ..totp
+            };
+            let enrolled = AuditEvent::new(realm, AuditEventType::TotpEnrolled, Some(user_id), now);
+            storage.in_transaction(|tx| {
+                tx.put_totp(user_id, &active)?;
+                tx.append_audit_event(&enrolled)
+            })?;
+            Ok(())
+        }
+
+        /// Removes the TOTP enrollment of a user, active or pending, with its recovery codes,
+        /// once `proof` re-authenticates the user.
+        pub fn remove_totp(
+            user_id: &str,
+            realm: &RealmName,
+            proof: &Reauthentication,
+            cipher: &SecretCipher,
+            storage: &dyn Storage,
+        ) -> Result<(), Error> {
+            let user =
+                storage.in_transaction(|tx| CustomerService::ensure_user(tx, realm, user_id))?;
+            AuthenticatorService::reauthenticate(&user, proof, cipher, storage)?;
+            let now = Utc::now().timestamp();
+            let removed = AuditEvent::new(realm, AuditEventType::TotpRemoved, Some(user_id), now);
+            storage.in_transaction(|tx| {
+                tx.delete_totp(user_id)?;
+                tx.replace_recovery_codes(user_id, &[])?;
+                tx.append_audit_event(&removed)
+            })?;
+            Ok(())
+        }
+
+        /// Checks the current password of `user`, or else a code of its active authenticator
+        /// app, which cannot be used again afterwards.
+        fn reauthenticate(
+            user: &User,
+            proof: &Reauthentication,
+            cipher: &SecretCipher,
+            storage: &dyn Storage,
+        ) -> Result<(), Error> {
+            let given = |value: &Option<String>| value.clone().filter(|value| !value.is_empty());
+            if let Some(password) = given(&proof.password) {
+                return match verify_password(&password, &user.hashed_pass) {
+                    true => Ok(()),
+                    false => Err(Error::IncorrectPassword),
+                };
+            }
+            let code = given(&proof.code).ok_or_else(|| {
+                Error::field(
+                    "password",
+                    "required",
+                    "the current password or an authenticator code is required".to_string(),
+                )
+            })?;
+
+            let now = Utc::now().timestamp();
+            let totp = storage
+                .in_transaction(|tx| tx.get_totp(&user.user_id))?
+                .filter(|totp| totp.activated_at.is_some())
+                .ok_or(Error::IncorrectCode)?;
+            let secret = cipher
+                .open(&user.user_id, &totp.sealed_secret)
+                .ok_or(Error::SecretDecryption)?;
+            let step = verify_totp(&secret, &code, now, totp.last_used_step)
+                .ok_or(Error::IncorrectCode)?;
+            match storage.in_transaction(|tx| tx.use_totp_step(&user.user_id, step))? {
+                true => Ok(()),
+                false => Err(Error::IncorrectCode),
+            }
+        }
+
+        /// Replaces the recovery codes of a user with a second factor by a new set once `proof`
+        /// re-authenticates the user, the old codes stop working.
+        pub fn regenerate_recovery_codes(
+            user_id: &str,
+            realm: &RealmName,
+            proof: &Reauthentication,
+            cipher: &SecretCipher,
+            storage: &dyn Storage,
+        ) -> Result<RecoveryCodes, Error> {
+            let user = storage.in_transaction(|tx| {
+                let user = CustomerService::ensure_user(tx, realm, user_id)?;
+                tx.get_totp(user_id)?.ok_or(StorageError::NotFound)?;
+                Ok(user)
+            })?;
+            AuthenticatorService::reauthenticate(&user, proof, cipher, storage)?;
+
+            let recovery_codes = generate_recovery_codes();
+            let stored = AuthenticatorService::stored_recovery_codes(&recovery_codes);
+            let now = Utc::now().timestamp();
+            let regenerated = AuditEvent::new(
+                realm,
+                AuditEventType::RecoveryCodesRegenerated,
+                Some(user_id),
+                now,
+            );
+            storage.in_transaction(|tx| {
+                tx.replace_recovery_codes(user_id, &stored)?;
+                tx.append_audit_event(&regenerated)
+            })?;
+            Ok(RecoveryCodes { recovery_codes })
+        }
+
+        /// How many recovery codes are left and when and from where the others were used.
+        pub fn recovery_code_status(
+            user_id: &str,
+            realm: &RealmName,
+            storage: &dyn Storage,
+        ) -> Result<RecoveryCodeStatus, Error> {
+            let codes = storage.in_transaction(|tx| {
+                CustomerService::ensure_user(tx, realm, user_id)?;
+                tx.list_recovery_codes(user_id)
+            })?;
+            Ok(RecoveryCodeStatus::of(&codes))
+        }
+
+        /// Starts the registration of a passkey, the options are passed to
+        /// `navigator.credentials.create`. The user's passkeys are excluded so that an
+        /// authenticator is not registered twice.
+        pub fn passkey_registration_options(
+            user_id: &str,
+            realm: &RealmName,
+            config: &WebAuthnConfig,
+            storage: &dyn Storage,
+        ) -> Result<CreationOptions, Error> {
+            let rp_id = relying_party(config)?;
+            let now = Utc::now().timestamp();
+            let challenge =
+                PendingChallenge::new(Ceremony::Registration, Some(user_id.to_string()), now);
+            let (user, registered) = storage.in_transaction(|tx| {
+                let user = CustomerService::ensure_user(tx, realm, user_id)?;
+                let registered = tx.list_passkeys(user_id)?;
+                tx.put_challenge(realm, &challenge, now)?;
+                Ok((user, registered))
+            })?;
+
+            let rp = RelyingParty {
+                id: rp_id.to_string(),
+                name: realm.to_string(),
+            };
+            let entity = UserEntity {
+                id: user_handle(user_id),
+                name: user.username.clone(),
+                display_name: user.username,
+            };
+            Ok(CreationOptions::new(&challenge, rp, entity, &registered))
+        }
+
+        /// Completes a registration started by
+        /// [`AuthenticatorService::passkey_registration_options`] for the same user.
+        pub fn register_passkey(
+            user_id: &str,
+            realm: &RealmName,
+            credential: RegistrationCredential,
+            config: &WebAuthnConfig,
+            storage: &dyn Storage,
+        ) -> Result<PasskeyInfo, Error> {
+            let rp_id = relying_party(config)?;
+            let now = Utc::now().timestamp();
+            let client_data = ClientData::decode(&credential.response.client_data_json)
+                .map_err(Error::Validation)?;
+            let pending = storage.in_transaction(|tx| {
+                CustomerService::ensure_user(tx, realm, user_id)?;
+                tx.take_challenge(realm, &client_data.challenge)
+            })?;
+            match pending {
+                Some(pending)
+                    if pending.ceremony == Ceremony::Registration
+                        && pending.user_id.as_deref() == Some(user_id)
+                        && pending.expires_at >= now => {}
+                _ => {
+                    return Err(Error::Validation(
+                        "unknown or expired passkey challenge".to_string(),
+                    ))
+                }
+            }
+
+            let verified = verify_registration(config, rp_id, &client_data, &credential)
+                .map_err(Error::Validation)?;
+            let passkey = Passkey {
+                credential_id: verified.credential_id,
+                user_id: user_id.to_string(),
+                public_key: verified.public_key,
+                sign_count: verified.sign_count,
+                name: credential.name,
+                created_at: now,
+                last_used_at: None,
+            };
+            let registered =
+                AuditEvent::new(realm, AuditEventType::PasskeyRegistered, Some(user_id), now)
+                    .with_detail(format!("passkey {}", passkey.credential_id));
+            storage.in_transaction(|tx| {
+                tx.add_passkey(&passkey)?;
+                tx.append_audit_event(&registered)
+            })?;
+            Ok(PasskeyInfo::from(&passkey))
+        }
+
+        pub fn list_passkeys(
+            user_id: &str,
+            realm: &RealmName,
+            storage: &dyn Storage,
+        ) -> Result<Vec<PasskeyInfo>, Error> {
+            let passkeys = storage.in_transaction(|tx| {
+                CustomerService::ensure_user(tx, realm, user_id)?;
+                tx.list_passkeys(user_id)
+            })?;
+            Ok(passkeys.iter().map(PasskeyInfo::from).collect())
+        }
+
+        pub fn remove_passkey(
+            user_id: &str,
+            credential_id: &str,
+            realm: &RealmName,
+            storage: &dyn Storage,
+        ) -> Result<(), Error> {
+            let now = Utc::now().timestamp();
+            let removed =
+                AuditEvent::new(realm, AuditEventType::PasskeyRemoved, Some(user_id), now)
+                    .with_detail(format!("passkey {}", credential_id));
+            storage.in_transaction(|tx| {
+                CustomerService::ensure_user(tx, realm, user_id)?;
+                tx.delete_passkey(user_id, credential_id)?;
+                tx.append_audit_event(&removed)
+            })?;
+            Ok(())
+        }
+
+        /// Starts a passwordless login, the options are passed to `navigator.credentials.get`.
+        /// With a username only that user's passkeys may answer, otherwise any discoverable
+        /// passkey of the realm.
+        pub fn passkey_login_options(
+            realm: &RealmName,
+            start: PasskeyLoginStart,
+            config: &WebAuthnConfig,
+            storage: &dyn Storage,
+        ) -> Result<RequestOptions, Error> {
+            let rp_id = relying_party(config)?;
+            let now = Utc::now().timestamp();
+            let (challenge, allowed) = storage.in_transaction(|tx| {
+                let user = match &start.username {
+                    Some(username) => tx.users().get_by_name(realm, username)?,
+                    None => None,
+                };
+                let allowed = match &user {
+                    Some(user) => tx.list_passkeys(&user.user_id)?,
+                    None => Vec::new(),
+                };
+                let user_id = user.map(|user| user.user_id);
+                let challenge = PendingChallenge::new(Ceremony::Authentication, user_id, now);
+                tx.put_challenge(realm, &challenge, now)?;
+                Ok((challenge, allowed))
+            })?;
+            Ok(RequestOptions::new(&challenge, rp_id, &allowed))
+        }
+
+        /// Completes a passwordless login started by
+        /// [`AuthenticatorService::passkey_login_options`]. Rejected assertions count as failed
+        /// attempts like wrong passwords, and a passkey stands in for both factors.
+        pub fn passkey_login(
+            realm: &RealmName,
+            credential: AuthenticationCredential,
+            client_ip: Option<&str>,
+            lockout: &LockoutPolicy,
+            config: &WebAuthnConfig,
+            storage: &dyn Storage,
+        ) -> Result<User, Error> {
+            let rp_id = relying_party(config)?;
+            let now = Utc::now().timestamp();
+            let client_data = ClientData::decode(&credential.response.client_data_json)
+                .map_err(Error::PasskeyRejected)?;
+            let (pending, found, counted) = storage.in_transaction(|tx| {
+                let pending = tx.take_challenge(realm, &client_data.challenge)?;
+                let found = match tx.find_passkey(realm, &credential.id)? {
+                    Some(passkey) => tx
+                        .users()
+                        .get(realm, &passkey.user_id)?
+                        .map(|user| (user, passkey)),
+                    None => None,
+                };
+                let counted = AuthenticatorService::counted_attempts(
+                    tx,
+                    realm,
+                    found.as_ref().map(|(user, _)| user),
+                    client_ip,
+                    lockout,
+                )?;
+                Ok((pending, found, counted))
+            })?;
+            AuthenticatorService::ensure_unlocked(&counted, now)?;
+
+            let verified = match (pending, found) {
+                (Some(pending), Some((user, passkey))) => {
+                    let handle = credential.response.user_handle.as_deref();
+                    if pending.ceremony != Ceremony::Authentication || pending.expires_at < now {
+                        Err("unknown or expired challenge".to_string())
+                    } else if pending.user_id.is_some_and(|id| id != user.user_id)
+                        || handle.is_some_and(|handle| handle != user_handle(&user.user_id))
+                    {
+                        Err("passkey belongs to another user".to_string())
+                    } else {
+                        verify_assertion(config, rp_id, &client_data, &credential, &passkey)
+                            .map(|sign_count| (user, sign_count))
+                    }
+                }
+                (None, _) => Err("unknown or expired challenge".to_string()),
+                (_, None) => Err("unknown passkey".to_string()),
+            };
+            let (user, sign_count) = match verified {
+                Ok(verified) => verified,
+                Err(reason) => {
+                    let error = Error::PasskeyRejected(reason);
+                    AuthenticatorService::record_failures(
+                        realm, counted, now, lockout, &error, storage,
+                    )?;
+                    return Err(error);
+                }
+            };
+
+            storage.in_transaction(|tx| {
+                tx.record_passkey_use(&credential.id, sign_count, now)?;
+                tx.clear_attempts(realm, &AttemptKey::User(user.user_id.clone()))
+            })?;
+            Ok(user)
+        }
+
+        /// Opens a session for a user who just logged in, see [`Session`]. The refresh token is
+        /// bound to the session and lives for the realm's refresh token duration, the access
+        /// token names the session and lives for `lifetimes.access`.
+        pub fn start_session(
+            realm: &RealmName,
+            user: &User,
+            client: ClientInfo,
+            lifetimes: TokenLifetimes,
+            key: &AccessTokenKey,
+            storage: &dyn Storage,
+        ) -> Result<SessionTokens, Error> {
+            let now = Utc::now().timestamp();
+            let (session, refresh_token) =
+                Session::open(realm, &user.user_id, client, now, lifetimes.refresh);
+            let succeeded = AuditEvent::new(
+                realm,
+                AuditEventType::LoginSucceeded,
+                Some(&user.user_id),
+                now,
+            )
+            .with_ip(session.ip.as_deref())
+            .with_detail(format!("session {}", session.session_id));
+            storage.in_transaction(|tx| {
+                tx.create_session(&session)?;
+                tx.append_audit_event(&succeeded)
+            })?;
+            Ok(SessionTokens {
+                access_token: key.issue(&session, now, lifetimes.access),
+                refresh_token,
+                session_id: session.session_id,
+                refresh_expires_at: session.expires_at,
+            })
+        }
+
+        /// Opens a short session for a user who must enroll a second factor before logging in,
+        /// with an access token that only reaches the enrollment endpoints. Its refresh token is
+        /// never handed out, so the session ends with the token.
+        pub fn start_enrollment_session(
+            realm: &RealmName,
+            user: &User,
+            client: ClientInfo,
+            key: &AccessTokenKey,
+            storage: &dyn Storage,
+        ) -> Result<MfaEnrollment, Error> {
+            let now = Utc::now().timestamp();
+            let (session, _) =
+                Session::open(realm, &user.user_id, client, now, MFA_CHALLENGE_DURATION);
+            let succeeded = AuditEvent::new(
+                realm,
+                AuditEventType::LoginSucceeded,
+                Some(&user.user_id),
+                now,
+            )
+            .with_ip(session.ip.as_deref())
+            .with_detail(format!("enrollment session {}", session.session_id));
+            storage.in_transaction(|tx| {
+                tx.create_session(&session)?;
+                tx.append_audit_event(&succeeded)
+            })?;
+            Ok(MfaEnrollment {
+                enrollment_token: key.issue_scoped(
+                    &session,
+                    TokenScope::MfaEnrollment,
+                    now,
+                    MFA_CHALLENGE_DURATION,
+                ),
+                expires_at: session.expires_at,
+            })
+        }
+
+        /// Exchanges a refresh token for new tokens of the same session, rotating the refresh
+        /// token. A token that was already rotated revokes its session, as a second party holding
+        /// it means it leaked; so do expired tokens and tokens of disabled users.
+        pub fn refresh_session(
+            realm: &RealmName,
+            request: RefreshRequest,
+            lifetimes: TokenLifetimes,
+            key: &AccessTokenKey,
+            storage: &dyn Storage,
+        ) -> Result<SessionTokens, Error> {
+            let (session_id, secret) =
+                split_refresh_token(&request.refresh_token).ok_or(Error::InvalidRefreshToken)?;
+            let now = Utc::now().timestamp();
+            let expires_at = now + lifetimes.refresh.as_secs() as i64;
+            let next_secret = refresh_secret();
+
+            let session = storage.in_transaction(|tx| {
+                let session = match tx.find_session(session_id)? {
+                    Some(session) if &session.realm == realm => session,
+                    _ => return Ok(None),
+                };
+                let rotated = tx.users().get(realm, &session.user_id)?.is_some()
+                    && session.expires_at >= now
+                    && tx.rotate_session(
+                        session_id,
+                        &hash_refresh_secret(secret),
+                        &hash_refresh_secret(&next_secret),
+                        now,
+                        expires_at,
+                    )?;
+                if !rotated {
+                    tx.delete_session(&session.user_id, session_id)?;
+                    return Ok(None);
+                }
+                Ok(Some(session))
+            })?;
+            let session = session.ok_or(Error::InvalidRefreshToken)?;
+
+            Ok(SessionTokens {
+                access_token: key.issue(&session, now, lifetimes.access),
+                refresh_token: refresh_token(session_id, &next_secret),
+                session_id: session_id.to_string(),
+                refresh_expires_at: expires_at,
+            })
+        }
+
+        /// The caller named by an access token of `realm`. The token's session must still be
+        /// open and its user enabled, so revoking a session or disabling a user takes effect
+        /// before the token expires.
+        pub fn authenticate(
+            realm: &RealmName,
+            access_token: &str,
+            key: &AccessTokenKey,
+            storage: &dyn Storage,
+        ) -> Result<Principal, Error> {
+            let claims = key
+                .verify(access_token)
+                .filter(|claims| &claims.realm == realm)
+                .ok_or(Error::Unauthenticated)?;
+            let now = Utc::now().timestamp();
+            let user = storage.in_transaction(|tx| {
+                match tx.find_session(&claims.sid)? {
+                    Some(session)
+                        if &session.realm == realm
+                            && session.user_id == claims.sub
+                            && session.expires_at >= now => {}
+                    _ => return Ok(None),
+                }
+                tx.users().get(realm, &claims.sub)
+            })?;
+            let user = user.ok_or(Error::Unauthenticated)?;
+            Ok(Principal {
+                user_id: user.user_id,
+                realm: realm.clone(),
+                session_id: claims.sid,
+                role: user.role,
+                scope: claims.scope,
+            })
+        }
+
+        /// The unexpired sessions of a user, most recently used first.
+        pub fn list_sessions(
+            user_id: &str,
+            realm: &RealmName,
+            storage: &dyn Storage,
+        ) -> Result<Vec<SessionInfo>, Error> {
+            let now = Utc::now().timestamp();
+            let sessions = storage.in_transaction(|tx| {
+                CustomerService::ensure_user(tx, realm, user_id)?;
+                tx.list_sessions(user_id, now)
+            })?;
+            Ok(sessions.iter().map(SessionInfo::from).collect())
+        }
+
+        /// Ends a session of the user, its refresh token stops working at once.
+        pub fn revoke_session(
+            user_id: &str,
+            session_id: &str,
+            realm: &RealmName,
+            acting_role: Role,
+            storage: &dyn Storage,
+        ) -> Result<(), Error> {
+            let event_type = acted_by(
+                acting_role,
+                AuditEventType::SessionRevoked,
+                AuditEventType::AdminSessionRevoked,
+            );
+            let revoked = AuditEvent::new(realm, event_type, Some(user_id), Utc::now().timestamp())
+                .with_detail(format!("session {}", session_id));
+            storage.in_transaction(|tx| {
+                CustomerService::ensure_user(tx, realm, user_id)?;
+                tx.delete_session(user_id, session_id)?;
+                tx.append_audit_event(&revoked)
+            })?;
+            Ok(())
+        }
+
+        fn stored_recovery_codes(codes: &[String]) -> Vec<RecoveryCode> {
+            let now = Utc::now().timestamp();
+            codes
+                .iter()
+                .map(|code| RecoveryCode::new(code, now))
+                .collect()
+        }
+
+        /// The failed attempts of `user`, when known, and of the client IP.
+        fn counted_attempts(
+            tx: &mut dyn StorageTx,
+            realm: &RealmName,
+            user: Option<&User>,
+            client_ip: Option<&str>,
+            policy: &LockoutPolicy,
+        ) -> StorageResult<CountedAttempts> {
+            let mut counted = Vec::new();
+            if let Some(user) = user {
+                let key = AttemptKey::User(user.user_id.clone());
+                let attempts = tx.get_attempts(realm, &key)?.unwrap_or_default();
+                counted.push((key, attempts, policy.max_user_failures));
+            }
+            if let Some(ip) = client_ip {
+                let key = AttemptKey::Ip(ip.to_string());
+                let attempts = tx.get_attempts(realm, &key)?.unwrap_or_default();
+                counted.push((key, attempts, policy.max_ip_failures));
+            }
+            Ok(counted)
+        }
+
+        fn ensure_unlocked(counted: &CountedAttempts, now: i64) -> Result<(), Error> {
+            let locked_for = counted
+                .iter()
+                .filter_map(|(_, attempts, _)| attempts.locked_for(now))
+                .max();
+            match locked_for {
+                Some(retry_after) => Err(Error::Locked { retry_after }),
+                None => Ok(()),
+            }
+        }
+
+        /// Counts a failed login against its keys and audits it with `reason`, together with
+        /// the lockouts it caused.
+        fn record_failures(
+            realm: &RealmName,
+            mut counted: CountedAttempts,
+            now: i64,
+            policy: &LockoutPolicy,
+            reason: &Error,
+            storage: &dyn Storage,
+        ) -> StorageResult<()> {
+            let user_id = counted.iter().find_map(|(key, _, _)| match key {
+                AttemptKey::User(user_id) => Some(user_id.clone()),
+                AttemptKey::Ip(_) => None,
+            });
+            let client_ip = counted.iter().find_map(|(key, _, _)| match key {
+                AttemptKey::Ip(ip) => Some(ip.clone()),
+                AttemptKey::User(_) => None,
+            });
+            counted.retain(|(_, _, max_failures)| *max_failures > 0);
+            storage.in_transaction(|tx| {
+                let failed =
+                    AuditEvent::new(realm, AuditEventType::LoginFailed, user_id.as_deref(), now)
+                        .with_ip(client_ip.as_deref())
+                        .with_detail(reason.to_string());
+                tx.append_audit_event(&failed)?;
+                for (key, attempts, max_failures) in counted.iter_mut() {
+                    let locked_until = attempts.locked_until;
+                    attempts.record_failure(now, *max_failures, policy);
+                    tx.put_attempts(realm, key, attempts)?;
+                    if let Some(until) = attempts
+                        .locked_until
+                        .filter(|_| attempts.locked_until != locked_until)
+                    {
+                        let locked = match key {
+                            AttemptKey::User(user_id) => {
+                                tx.append_outbox_event(&OutboxEvent::user(
+                                    realm,
+                                    WebhookEventType::UserLocked,
+                                    user_id,
+                                    json!({ "locked_until": until }),
+                                    now,
+                                ))?;
+                                AuditEvent::new(
+                                    realm,
+                                    AuditEventType::UserLocked,
+                                    Some(user_id),
+                                    now,
+                                )
+                            }
+                            AttemptKey::Ip(ip) => {
+                                AuditEvent::new(realm, AuditEventType::IpLocked, None, now)
+                                    .with_ip(Some(ip))
+                            }
+                        };
+                        tx.append_audit_event(
+                            &locked.with_detail(format!("locked until {}", until)),
+                        )?;
+                    }
+                }
+                Ok(())
+            })
+        }
+    }
+
+    /// A random token that carries its expiry, as `{expiry}.{hex}`. The token columns have no
+    /// timestamp of their own.
+    fn expiring_token(valid_for: Duration) -> String {
+        let expiry = Utc::now().timestamp() + valid_for.as_secs() as i64;
+        format!(
+            "{}.{}",
+            expiry,
+            HEXLOWER.encode(&rand::random::<[u8; 32]>())
+        )
+    }
+
+    /// What is stored of a token of [`expiring_token`]: its expiry and the SHA-256 of the whole
+    /// token, so that a copy of the database holds no token that can be redeemed.
+    fn hash_expiring_token(token: &str) -> String {
+        let expiry = token.split_once('.').map_or("", |(expiry, _)| expiry);
+        format!(
+            "{}.{}",
+            expiry,
+            HEXLOWER.encode(digest(&SHA256, token.as_bytes()).as_ref())
+        )
+    }
+
+    /// Whether `token` is unexpired and the one `stored` by [`hash_expiring_token`], compared in
+    /// constant time.
+    fn is_issued_token(stored: &str, token: &str, now: i64) -> bool {
+        let hash = hash_expiring_token(token);
+        bool::from(stored.as_bytes().ct_eq(hash.as_bytes())) && is_unexpired(token, now)
+    }
+
+    fn passwordless_method(policy: &PasswordlessPolicy) -> Result<PasswordlessMethod, Error> {
+        policy.method.ok_or_else(|| {
+            Error::Forbidden("passwordless login is not enabled for this realm".to_string())
+        })
+    }
+
+    /// The relying party id of a realm, passkeys are refused in realms without one.
+    fn relying_party(config: &WebAuthnConfig) -> Result<&str, Error> {
+        config
+            .rp_id
+            .as_deref()
+            .ok_or_else(|| Error::Forbidden("passkeys are not enabled for this realm".to_string()))
+    }
+
+    /// The admin variant of an audit event when an admin acts on behalf of the user.
+    fn acted_by(acting_role: Role, own: AuditEventType, admin: AuditEventType) -> AuditEventType {
+        match acting_role {
+            Role::ADMIN => admin,
+            _ => own,
+        }
+    }
+
+    fn is_unexpired(token: &str, now: i64) -> bool {
+        token
+            .split_once('.')
+            .and_then(|(expiry, _)| expiry.parse::<i64>().ok())
+            .is_some_and(|expiry| expiry >= now)
     }
 
     pub struct CustomerService {}
 
+    /// Why [`CustomerService::store_password`] sets a password.
+    #[derive(Clone, Copy)]
+    enum PasswordChange<'a> {
+        /// By the user, who stays signed in with `keep_session`.
+        Changed { keep_session: &'a str },
+        /// By an admin on behalf of the user.
+        AdminSet,
+        /// With a reset `token`, which must still be outstanding.
+        Reset { token: &'a str },
+    }
+
     impl CustomerService {
-        // type Handler = fn(&mut Transaction) -> Result<_>;
+        /// One page of the users matching `query`, with the total number of matches.
+        pub fn search_users(
+            realm: &RealmName,
+            query: &UserQuery,
+            storage: &dyn Storage,
+        ) -> Result<UserPage, Error> {
+            let limit = query.page_size()?;
+            let after = query
+                .cursor
+                .as_deref()
+                .map(|cursor| PageCursor::decode(cursor, query.sort))
+                .transpose()?;
 
-        pub fn fetch_users(db_context: &DB) -> std::result::Result<Vec<UserWithAddress>, Error> {
-            let principal_vec: Vec<UserWithAddress> = db_context
-                .in_transaction(AccessMode::ReadWrite, CustomerService::handle_fetch_users());
+            // One extra row tells whether there is a next page.
+            let (mut users, total) = storage.in_transaction(|tx| {
+                let users = tx.users().search(realm, query, after.as_ref(), limit + 1)?;
+                let total = tx.users().count(realm, query)?;
+                Ok((users, total))
+            })?;
 
-            Ok(principal_vec)
+            let next_cursor = if users.len() > limit as usize {
+                users.truncate(limit as usize);
+                users
+                    .last()
+                    .map(|user| PageCursor::after(user, query.sort).encode())
+            } else {
+                None
+            };
+
+            Ok(UserPage {
+                users,
+                total,
+                next_cursor,
+            })
         }
 
+        /// The profile of a user of the realm, without credentials.
         pub fn fetch_user(
-            user_id: &String,
-            db_context: &DB,
-        ) -> std::result::Result<Option<User>, Error> {
-            Ok(
-                db_context.in_transaction(AccessMode::ReadWrite, |tx: &mut Transaction| {
-                    UserStorage::get_user(user_id, tx)
-                }),
-            )
+            user_id: &str,
+            realm: &RealmName,
+            storage: &dyn Storage,
+        ) -> Result<UserProfile, Error> {
+            let profile = storage.in_transaction(|tx| tx.users().get_profile(realm, user_id))?;
+            Ok(profile.ok_or(StorageError::NotFound)?)
         }
 
         pub fn fetch_user_by_name(
-            username: &String,
+            username: &str,
             realm: &RealmName,
-            db_context: &DB,
-        ) -> Option<User> {
-            db_context.in_transaction(AccessMode::ReadWrite, |tx: &mut Transaction| {
-                UserStorage::get_user_by_name(username, realm, tx)
-            })
+            storage: &dyn Storage,
+        ) -> StorageResult<Option<User>> {
+            storage.in_transaction(|tx| tx.users().get_by_name(realm, username))
         }
 
         pub fn create(
-            user_data: CreateUser,
+            mut user_data: CreateUser,
             realm: RealmName,
             app: &AppState,
-        ) -> std::result::Result<String, Error> {
-            let realm_settings_provider = &app.realm_settings_provider;
-            let db_context = &app.execution_context.db;
-
-            let result = db_context.in_transaction(
-                AccessMode::ReadWrite,
-                CustomerService::handle_create_user(
-                    user_data,
-                    &realm,
-                    realm_settings_provider.clone(),
-                ),
+        ) -> Result<String, Error> {
+            user_data
+                .address
+                .normalise()
+                .map_err(|e| e.nested("address"))?;
+            user_data.hash_password(&realm)?;
+            let storage = app.execution_context.storage.as_ref();
+            let history_size = app
+                .realm_settings_provider
+                .get_password_policy(&realm)
+                .history_size;
+
+            let username = user_data.username.clone();
+            let email = user_data.email.clone();
+            let token = expiring_token(EMAIL_VERIFICATION_DURATION);
+
+            let (user_id, _) = storage.in_transaction(CustomerService::handle_create_user(
+                user_data,
+                &realm,
+                history_size,
+                &token,
+            ))?;
+            CustomerService::send_email_verification(
+                app.delivery_channel.as_ref(),
+                AccountMessage {
+                    realm,
+                    user_id: user_id.clone(),
+                    username,
+                    email,
+                    kind: AccountMessageKind::EmailVerification,
+                    token,
+                },
             );
-            let user_id = result.0;
-            println!("user_id {}", &user_id);
 
-            return Ok(user_id);
+            Ok(user_id)
         }
 
-        fn handle_create_user(
-            mut user_data: CreateUser,
+        /// Applies a partial update on behalf of a user with `acting_role` and returns the result.
+        ///
+        /// Only admins may change roles. A changed email is marked unverified and a new
+        /// verification token is sent to it through `channel`.
+        pub fn update(
+            user_id: &String,
             realm: &RealmName,
-            arc: Arc<RealmSettingProvider>,
-        ) -> impl FnOnce(&mut Transaction) -> Result<(String, String)> + '_ {
-            return move |tx: &mut Transaction| {
-                let address = user_data.address.clone();
-                user_data.hash_password(realm);
-                let user_id =
-                    UserStorage::create_from(user_data, realm, tx).expect("Failed to create user");
-                let address_id = AddressStorage::create_from((address, user_id.to_owned()), realm, tx)
-                    .expect("Failed to create user");
-                Ok((user_id, address_id))
+            changes: UpdateUser,
+            acting_role: Role,
+            channel: &dyn DeliveryChannel,
+            storage: &dyn Storage,
+        ) -> Result<UserProfile, Error> {
+            if changes.role.is_some() && acting_role != Role::ADMIN {
+                return Err(Error::Forbidden(
+                    "only admins can change a user's role".to_string(),
+                ));
+            }
+
+            let now = Utc::now().timestamp();
+            let event_type = acted_by(
+                acting_role,
+                AuditEventType::UserUpdated,
+                AuditEventType::AdminUserUpdated,
+            );
+            let token = expiring_token(EMAIL_VERIFICATION_DURATION);
+            let (profile, staged_email) = storage.in_transaction(|tx| {
+                let current = tx
+                    .users()
+                    .get_profile(realm, user_id)?
+                    .ok_or(StorageError::NotFound)?;
+
+                // A new email only replaces the current one once it is confirmed, so that it
+                // cannot be used to take the account over.
+                let staged_email = changes
+                    .email
+                    .filter(|email| current.email.as_ref() != Some(email));
+                if let Some(email) = &staged_email {
+                    let taken = UserQuery {
+                        email: Some(email.clone()),
+                        ..UserQuery::default()
+                    };
+                    if tx.users().count(realm, &taken)? > 0 {
+                        return Err(StorageError::Conflict(email.clone()));
+                    }
+                }
+                let metadata = UserMetadata {
+                    name: changes.name,
+                    role: changes.role,
+                    ..UserMetadata::default()
+                };
+                let changed: Vec<&str> = [
+                    ("name", metadata.name.is_some()),
+                    ("role", metadata.role.is_some()),
+                ]
+                .iter()
+                .filter(|(_, changed)| *changed)
+                .map(|(field, _)| *field)
+                .collect();
+                let requested: Vec<&str> = changed
+                    .iter()
+                    .copied()
+                    .chain(staged_email.as_ref().map(|_| "pending_email"))
+                    .collect();
+                let updated = AuditEvent::new(realm, event_type, Some(user_id), now)
+                    .with_detail(requested.join(", "));
+
+                tx.users().update(realm, user_id, metadata)?;
+                tx.append_audit_event(&updated)?;
+                if let Some(email) = &staged_email {
+                    tx.stage_email(user_id, Some(email))?;
+                    tx.store_token(
+                        user_id,
+                        TokenKind::EmailVerification,
+                        &hash_expiring_token(&token),
+                    )?;
+                }
+                if !changed.is_empty() {
+                    tx.append_outbox_event(&OutboxEvent::user(
+                        realm,
+                        WebhookEventType::UserUpdated,
+                        user_id,
+                        json!({ "changed": changed }),
+                        now,
+                    ))?;
+                }
+
+                let profile = tx
+                    .users()
+                    .get_profile(realm, user_id)?
+                    .ok_or(StorageError::NotFound)?;
+                Ok((profile, staged_email))
+            })?;
+
+            if let Some(email) = staged_email {
+                CustomerService::send_email_verification(
+                    channel,
+                    AccountMessage {
+                        realm: realm.clone(),
+                        user_id: profile.user_id.clone(),
+                        username: profile.username.clone(),
+                        email,
+                        kind: AccountMessageKind::EmailVerification,
+                        token,
+                    },
+                );
+            }
+            Ok(profile)
+        }
+
+        /// Disables the user, or with [`DeletionMode::Erase`] removes the user together with its
+        /// addresses and tokens. Only admins may erase. Erasing also pseudonymises the user's
+        /// audit events and clears the data of its outbox events and queued webhooks, only the
+        /// final deletion event names the user.
+        pub fn delete(
+            user_id: &str,
+            realm: &RealmName,
+            mode: DeletionMode,
+            acting_role: Role,
+            storage: &dyn Storage,
+        ) -> Result<(), Error> {
+            if mode == DeletionMode::Erase && acting_role != Role::ADMIN {
+                return Err(Error::Forbidden("only admins can erase a user".to_string()));
+            }
+
+            let now = Utc::now().timestamp();
+            let event_type = match mode {
+                DeletionMode::Disable => acted_by(
+                    acting_role,
+                    AuditEventType::UserDisabled,
+                    AuditEventType::AdminUserDisabled,
+                ),
+                DeletionMode::Erase => AuditEventType::AdminUserErased,
+            };
+            storage.in_transaction(|tx| {
+                match mode {
+                    DeletionMode::Disable => {
+                        tx.users().disable(realm, user_id, now)?;
+                        tx.append_audit_event(&AuditEvent::new(
+                            realm,
+                            event_type,
+                            Some(user_id),
+                            now,
+                        ))?;
+                    }
+                    DeletionMode::Erase => {
+                        tx.users().delete(realm, &user_id.to_string())?;
+                        let pseudonym = Uuid::new_v4().to_string();
+                        tx.redact_audit_events(realm, user_id, &pseudonym, now)?;
+                        tx.append_audit_event(&AuditEvent::new(
+                            realm,
+                            event_type,
+                            Some(&pseudonym),
+                            now,
+                        ))?;
+                        tx.redact_outbox_events(realm, USER_AGGREGATE, user_id, &json!({}))?;
+                        WebhookService::redact_user(tx, realm, user_id)?;
+                    }
+                }
+                tx.append_outbox_event(&OutboxEvent::user(
+                    realm,
+                    WebhookEventType::UserDeleted,
+                    user_id,
+                    json!({ "mode": mode }),
+                    now,
+                ))
+            })?;
+            Ok(())
+        }
+
+        /// Marks the user's email verified with the token issued when the email was set.
+        pub fn confirm_email(
+            realm: &RealmName,
+            confirmation: EmailConfirmation,
+            storage: &dyn Storage,
+        ) -> Result<(), Error> {
+            let now = Utc::now().timestamp();
+            let confirmed = storage.in_transaction(|tx| {
+                let user = match tx.users().get_by_name(realm, &confirmation.username)? {
+                    Some(user) => user,
+                    None => return Ok(false),
+                };
+                let token = hash_expiring_token(&confirmation.token);
+                if !is_unexpired(&confirmation.token, now)
+                    || !tx.consume_token(&user.user_id, TokenKind::EmailVerification, &token)?
+                {
+                    return Ok(false);
+                }
+
+                let metadata = UserMetadata {
+                    email: tx.staged_email(&user.user_id)?,
+                    email_verified: Some(true),
+                    ..UserMetadata::default()
+                };
+                tx.users().update(realm, &user.user_id, metadata)?;
+                tx.stage_email(&user.user_id, None)?;
+                let profile = tx
+                    .users()
+                    .get_profile(realm, &user.user_id)?
+                    .ok_or(StorageError::NotFound)?;
+                let event = AuditEvent::new(
+                    realm,
+                    AuditEventType::EmailConfirmed,
+                    Some(&user.user_id),
+                    now,
+                );
+                tx.append_audit_event(&event)?;
+                tx.append_outbox_event(&OutboxEvent::user(
+                    realm,
+                    WebhookEventType::UserConfirmed,
+                    &user.user_id,
+                    json!({ "email": profile.email }),
+                    now,
+                ))?;
+                Ok(true)
+            })?;
+            match confirmed {
+                true => Ok(()),
+                false => Err(Error::Validation(
+                    "invalid or expired verification token".to_string(),
+                )),
+            }
+        }
+
+        /// Addresses of a user, grouped by type with the default first.
+        pub fn list_addresses(
+            user_id: &str,
+            realm: &RealmName,
+            storage: &dyn Storage,
+        ) -> Result<Vec<UserAddress>, Error> {
+            let addresses = storage.in_transaction(|tx| {
+                CustomerService::ensure_user(tx, realm, user_id)?;
+                tx.addresses().list_for_user(realm, user_id)
+            })?;
+            Ok(addresses)
+        }
+
+        pub fn get_address(
+            user_id: &str,
+            address_id: &str,
+            realm: &RealmName,
+            storage: &dyn Storage,
+        ) -> Result<UserAddress, Error> {
+            let address = storage.in_transaction(|tx| {
+                CustomerService::find_address(tx, realm, user_id, address_id)
+            })?;
+            Ok(address)
+        }
+
+        /// Adds an address. The first address of a type always becomes its default.
+        pub fn add_address(
+            user_id: &str,
+            realm: &RealmName,
+            mut data: AddressData,
+            storage: &dyn Storage,
+        ) -> Result<UserAddress, Error> {
+            data.address.normalise()?;
+
+            let address = storage.in_transaction(|tx| {
+                CustomerService::ensure_user(tx, realm, user_id)?;
+                let has_type = tx
+                    .addresses()
+                    .list_for_user(realm, user_id)?
+                    .iter()
+                    .any(|a| a.address_type == data.address_type);
+                data.is_default |= !has_type;
+
+                let address_id = tx.addresses().create(realm, (data, user_id.to_string()))?;
+                tx.addresses()
+                    .get(realm, &address_id)?
+                    .ok_or(StorageError::NotFound)
+            })?;
+            Ok(address)
+        }
+
+        /// Replaces an address of the user.
+        pub fn update_address(
+            user_id: &str,
+            address_id: &str,
+            realm: &RealmName,
+            mut data: AddressData,
+            storage: &dyn Storage,
+        ) -> Result<UserAddress, Error> {
+            data.address.normalise()?;
+
+            let address = storage.in_transaction(|tx| {
+                let current = CustomerService::find_address(tx, realm, user_id, address_id)?;
+                let address_type = data.address_type;
+                tx.addresses().update(realm, &current.address_id, data)?;
+                CustomerService::ensure_default(tx, realm, user_id, current.address_type)?;
+                CustomerService::ensure_default(tx, realm, user_id, address_type)?;
+
+                tx.addresses()
+                    .get(realm, &current.address_id)?
+                    .ok_or(StorageError::NotFound)
+            })?;
+            Ok(address)
+        }
+
+        /// Removes an address of the user. Removing a default promotes another address of the
+        /// same type.
+        pub fn delete_address(
+            user_id: &str,
+            address_id: &str,
+            realm: &RealmName,
+            storage: &dyn Storage,
+        ) -> Result<(), Error> {
+            storage.in_transaction(|tx| {
+                let current = CustomerService::find_address(tx, realm, user_id, address_id)?;
+                tx.addresses().delete(realm, &current.address_id)?;
+                CustomerService::ensure_default(tx, realm, user_id, current.address_type)
+            })?;
+            Ok(())
+        }
+
+        /// Changes the password of a user who knows the current one, signing out every other
+        /// session than the principal's. Wrong current passwords count towards the lockout and are
+        /// audited like failed logins, so that this cannot be used to guess the password.
+        pub fn change_password(
+            principal: &Principal,
+            change: ChangePassword,
+            policy: &PasswordPolicy,
+            client_ip: Option<&str>,
+            lockout: &LockoutPolicy,
+            storage: &dyn Storage,
+        ) -> Result<(), Error> {
+            let realm = &principal.realm;
+            let now = Utc::now().timestamp();
+            let (user, profile) =
+                CustomerService::load_credentials(realm, &principal.user_id, storage)?;
+            let counted = storage.in_transaction(|tx| {
+                AuthenticatorService::counted_attempts(tx, realm, Some(&user), client_ip, lockout)
+            })?;
+            AuthenticatorService::ensure_unlocked(&counted, now)?;
+            if !verify_password(&change.current_password, &user.hashed_pass) {
+                let error = Error::IncorrectPassword;
+                AuthenticatorService::record_failures(
+                    realm, counted, now, lockout, &error, storage,
+                )?;
+                return Err(error);
+            }
+
+            CustomerService::store_password(
+                &profile,
+                realm,
+                ("new_password", &change.new_password),
+                policy,
+                PasswordChange::Changed {
+                    keep_session: &principal.session_id,
+                },
+                storage,
+            )?;
+            storage
+                .in_transaction(|tx| tx.clear_attempts(realm, &AttemptKey::User(user.user_id)))?;
+            Ok(())
+        }
+
+        /// Sets the password of a user on behalf of an admin, without the current one.
+        pub fn set_password(
+            user_id: &str,
+            realm: &RealmName,
+            data: SetPassword,
+            policy: &PasswordPolicy,
+            storage: &dyn Storage,
+        ) -> Result<(), Error> {
+            let (_, profile) = CustomerService::load_credentials(realm, user_id, storage)?;
+            CustomerService::store_password(
+                &profile,
+                realm,
+                ("password", &data.password),
+                policy,
+                PasswordChange::AdminSet,
+                storage,
+            )?;
+            Ok(())
+        }
+
+        /// Issues a reset token that expires after `valid_for`, replacing any earlier one, and
+        /// sends it to the user's email address through `channel`. Users without a verified email
+        /// get no token. The caller is not told whether the user exists.
+        pub fn request_password_reset(
+            realm: &RealmName,
+            request: PasswordResetRequest,
+            valid_for: Duration,
+            channel: &dyn DeliveryChannel,
+            storage: &dyn Storage,
+        ) -> Result<(), Error> {
+            let token = expiring_token(valid_for);
+            let message = storage.in_transaction(|tx| {
+                let user = match tx.users().get_by_name(realm, &request.username)? {
+                    Some(user) => user,
+                    None => return Ok(None),
+                };
+                let now = Utc::now().timestamp();
+                let requested = AuditEvent::new(
+                    realm,
+                    AuditEventType::PasswordResetRequested,
+                    Some(&user.user_id),
+                    now,
+                );
+                let verified_email = tx
+                    .users()
+                    .get_profile(realm, &user.user_id)?
+                    .filter(|profile| profile.email_verified)
+                    .and_then(|profile| profile.email);
+                let email = match verified_email {
+                    Some(email) => email,
+                    None => {
+                        tx.append_audit_event(&requested.with_detail("no verified email"))?;
+                        return Ok(None);
+                    }
+                };
+                tx.store_token(
+                    &user.user_id,
+                    TokenKind::PasswordReset,
+                    &hash_expiring_token(&token),
+                )?;
+                tx.append_audit_event(&requested)?;
+                Ok(Some(AccountMessage {
+                    realm: realm.clone(),
+                    user_id: user.user_id,
+                    username: user.username,
+                    email,
+                    kind: AccountMessageKind::PasswordReset,
+                    token: token.clone(),
+                }))
+            })?;
+            match message {
+                Some(message) => channel.deliver_token(&message).map_err(Error::Delivery),
+                None => Ok(()),
+            }
+        }
+
+        /// Sets a new password with a token issued by
+        /// [`CustomerService::request_password_reset`].
+        pub fn reset_password(
+            realm: &RealmName,
+            reset: PasswordReset,
+            policy: &PasswordPolicy,
+            storage: &dyn Storage,
+        ) -> Result<(), Error> {
+            let invalid = || Error::Validation("invalid or expired reset token".to_string());
+            let issued = storage.in_transaction(|tx| {
+                let user = tx.users().get_by_name(realm, &reset.username)?;
+                match user {
+                    Some(user) => Ok(tx
+                        .find_token(&user.user_id, TokenKind::PasswordReset)?
+                        .map(|token| (user, token))),
+                    None => Ok(None),
+                }
+            })?;
+
+            // Checked up front so that only the holder of the token learns about the policy,
+            // and consumed with the new password so that the token is redeemed once.
+            let now = Utc::now().timestamp();
+            let user = match issued {
+                Some((user, stored)) if is_issued_token(&stored, &reset.token, now) => user,
+                _ => return Err(invalid()),
+            };
+
+            let (_, profile) = CustomerService::load_credentials(realm, &user.user_id, storage)?;
+            let stored = CustomerService::store_password(
+                &profile,
+                realm,
+                ("new_password", &reset.new_password),
+                policy,
+                PasswordChange::Reset {
+                    token: &reset.token,
+                },
+                storage,
+            )?;
+            match stored {
+                true => Ok(()),
+                false => Err(invalid()),
+            }
+        }
+
+        fn load_credentials(
+            realm: &RealmName,
+            user_id: &str,
+            storage: &dyn Storage,
+        ) -> Result<(User, UserProfile), Error> {
+            let credentials = storage.in_transaction(|tx| {
+                let user = CustomerService::ensure_user(tx, realm, user_id)?;
+                let profile = tx
+                    .users()
+                    .get_profile(realm, user_id)?
+                    .ok_or(StorageError::NotFound)?;
+                Ok((user, profile))
+            })?;
+            Ok(credentials)
+        }
+
+        /// Checks `password` against the realm's policy and password history, reporting failures
+        /// on `field`, and stores its hash, audited as the kind of `change`. Outstanding reset
+        /// tokens and every session of the user but the one of a [`PasswordChange::Changed`] are
+        /// revoked. A [`PasswordChange::Reset`] token is consumed in the same transaction,
+        /// `false` when it was consumed or replaced meanwhile and nothing is stored.
+        fn store_password(
+            profile: &UserProfile,
+            realm: &RealmName,
+            (field, password): (&'static str, &str),
+            policy: &PasswordPolicy,
+            change: PasswordChange,
+            storage: &dyn Storage,
+        ) -> Result<bool, Error> {
+            check_password(
+                policy,
+                field,
+                password,
+                &profile.username,
+                profile.email.as_deref(),
+            )?;
+            let hash = hash_password(&profile.username, realm, password)?;
+
+            let history_size = policy.history_size;
+            let reused = storage
+                .in_transaction(|tx| tx.password_history(&profile.user_id, history_size))?
+                .iter()
+                .any(|previous| is_same_password(password, &hash, previous));
+            if reused {
+                return Err(Error::field(
+                    field,
+                    "password_reused",
+                    format!("must differ from the last {} passwords", history_size),
+                ));
+            }
+
+            let (event_type, keep_session) = match change {
+                PasswordChange::Changed { keep_session } => {
+                    (AuditEventType::PasswordChanged, Some(keep_session))
+                }
+                PasswordChange::AdminSet => (AuditEventType::AdminPasswordSet, None),
+                PasswordChange::Reset { .. } => (AuditEventType::PasswordReset, None),
             };
+            let stored = storage.in_transaction(|tx| {
+                if let PasswordChange::Reset { token } = change {
+                    let token = hash_expiring_token(token);
+                    if !tx.consume_token(&profile.user_id, TokenKind::PasswordReset, &token)? {
+                        return Ok(false);
+                    }
+                }
+                let metadata = UserMetadata {
+                    password: Some(hash.clone()),
+                    ..UserMetadata::default()
+                };
+                tx.users().update(realm, &profile.user_id, metadata)?;
+                tx.push_password_history(&profile.user_id, &hash, history_size)?;
+                tx.revoke_token(&profile.user_id, TokenKind::PasswordReset)?;
+                tx.delete_sessions(&profile.user_id, keep_session)?;
+                let now = Utc::now().timestamp();
+                tx.append_audit_event(&AuditEvent::new(
+                    realm,
+                    event_type,
+                    Some(&profile.user_id),
+                    now,
+                ))?;
+                Ok(true)
+            })?;
+            Ok(stored)
         }
 
-        fn handle_fetch_users() -> fn(&mut Transaction) -> Result<Vec<UserWithAddress>> {
-            return |tx: &mut Transaction| UserStorage::get_users(tx);
+        fn ensure_user(
+            tx: &mut dyn StorageTx,
+            realm: &RealmName,
+            user_id: &str,
+        ) -> StorageResult<User> {
+            tx.users()
+                .get(realm, &user_id.to_string())?
+                .ok_or(StorageError::NotFound)
+        }
+
+        /// An address of the user; addresses of other users are not found.
+        fn find_address(
+            tx: &mut dyn StorageTx,
+            realm: &RealmName,
+            user_id: &str,
+            address_id: &str,
+        ) -> StorageResult<UserAddress> {
+            tx.addresses()
+                .list_for_user(realm, user_id)?
+                .into_iter()
+                .find(|a| a.address_id == address_id)
+                .ok_or(StorageError::NotFound)
+        }
+
+        /// Marks the first address of `address_type` as default when the user has none.
+        fn ensure_default(
+            tx: &mut dyn StorageTx,
+            realm: &RealmName,
+            user_id: &str,
+            address_type: AddressType,
+        ) -> StorageResult<()> {
+            let addresses: Vec<UserAddress> = tx
+                .addresses()
+                .list_for_user(realm, user_id)?
+                .into_iter()
+                .filter(|a| a.address_type == address_type)
+                .collect();
+            if addresses.iter().any(|a| a.is_default) {
+                return Ok(());
+            }
+            match addresses.into_iter().next() {
+                Some(first) => tx.addresses().update(
+                    realm,
+                    &first.address_id,
+                    AddressData {
+                        address: first.address,
+                        address_type,
+                        is_default: true,
+                    },
+                ),
+                None => Ok(()),
+            }
+        }
+
+        /// Sends the token of [`CustomerService::confirm_email`]. The change of address it belongs
+        /// to is committed already, so a failed delivery is only logged.
+        fn send_email_verification(channel: &dyn DeliveryChannel, message: AccountMessage) {
+            if let Err(e) = channel.deliver_token(&message) {
+                println!(
+                    "cannot send the email verification of {}: {}",
+                    message.user_id, e
+                );
+            }
+        }
+
+        fn handle_create_user<'a>(
+            user_data: CreateUser,
+            realm: &'a RealmName,
+            history_size: usize,
+            verification_token: &'a str,
+        ) -> impl FnOnce(&mut dyn StorageTx) -> StorageResult<(String, String)> + 'a {
+            move |tx: &mut dyn StorageTx| {
+                let address = AddressData {
+                    address: user_data.address.clone(),
+                    address_type: AddressType::Shipping,
+                    is_default: true,
+                };
+                let hash = user_data.password.clone();
+                let created = json!({
+                    "username": user_data.username,
+                    "email": user_data.email,
+                });
+                let user_id = tx.users().create(realm, user_data)?;
+                tx.push_password_history(&user_id, &hash, history_size)?;
+                let address_id = tx.addresses().create(realm, (address, user_id.clone()))?;
+                tx.store_token(
+                    &user_id,
+                    TokenKind::EmailVerification,
+                    &hash_expiring_token(verification_token),
+                )?;
+                let now = Utc::now().timestamp();
+                let registered =
+                    AuditEvent::new(realm, AuditEventType::UserRegistered, Some(&user_id), now);
+                tx.append_audit_event(&registered)?;
+                tx.append_outbox_event(&OutboxEvent::user(
+                    realm,
+                    WebhookEventType::UserCreated,
+                    &user_id,
+                    created,
+                    now,
+                ))?;
+                Ok((user_id, address_id))
+            }
         }
     }
 }
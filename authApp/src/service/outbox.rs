@@ -0,0 +1,102 @@
+use crate::app::Error;
+use crate::domain::outbox::{OutboxEvent, OutboxRecord};
+use crate::domain::webhook::RetryPolicy;
+use crate::repository::{Storage, StorageError};
+use chrono::Utc;
+use std::time::Duration;
+
+/// Events claimed at a time.
+const DISPATCH_BATCH_SIZE: u32 = 50;
+/// Batches per dispatch run. A batch holds at most one event per aggregate, the next one of each
+/// comes up in the following batch.
+const DISPATCH_ROUNDS: usize = 10;
+/// How long a claimed event is hidden from other dispatchers.
+const OUTBOX_LEASE: Duration = Duration::from_secs(5 * 60);
+/// How long published events are kept, for investigating what went out.
+pub const PUBLISHED_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Hands a committed event to its consumers. An error leaves the event in the outbox to be
+/// published again later, so consumers may see an event more than once. The record `id` orders
+/// the events of an aggregate.
+pub trait OutboxPublisher: Send + Sync {
+    fn publish(&self, record: &OutboxRecord) -> Result<(), String>;
+}
+
+/// Records every event it is handed, failing the first `failures` of them.
+#[cfg(test)]
+#[derive(Default)]
+pub struct RecordingPublisher {
+    failures: std::sync::Mutex<u32>,
+    attempts: std::sync::Mutex<Vec<OutboxEvent>>,
+}
+
+#[cfg(test)]
+impl RecordingPublisher {
+    pub fn failing(failures: u32) -> RecordingPublisher {
+        RecordingPublisher {
+            failures: std::sync::Mutex::new(failures),
+            ..RecordingPublisher::default()
+        }
+    }
+
+    pub fn attempts(&self) -> Vec<OutboxEvent> {
+        self.attempts.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl OutboxPublisher for RecordingPublisher {
+    fn publish(&self, record: &OutboxRecord) -> Result<(), String> {
+        self.attempts.lock().unwrap().push(record.event.clone());
+        let mut failures = self.failures.lock().unwrap();
+        if *failures > 0 {
+            *failures -= 1;
+            return Err("consumer unavailable".to_string());
+        }
+        Ok(())
+    }
+}
+
+pub struct OutboxService;
+
+impl OutboxService {
+    /// Publishes the events due at `now` with `publisher`, oldest first within each aggregate,
+    /// returns how many were attempted. A failed event is retried after the delays of `policy`
+    /// and holds back the later events of its aggregate meanwhile.
+    pub fn dispatch(
+        storage: &dyn Storage,
+        publisher: &dyn OutboxPublisher,
+        policy: &RetryPolicy,
+        now: i64,
+    ) -> Result<usize, Error> {
+        let lease_until = now + OUTBOX_LEASE.as_secs() as i64;
+        let mut attempted = 0;
+        for _ in 0..DISPATCH_ROUNDS {
+            let claimed = storage.in_transaction(|tx| {
+                tx.claim_outbox_events(now, lease_until, DISPATCH_BATCH_SIZE)
+            })?;
+            if claimed.is_empty() {
+                break;
+            }
+            attempted += claimed.len();
+            for mut record in claimed.iter().cloned() {
+                match publisher.publish(&record) {
+                    Ok(()) => record.published(now),
+                    Err(error) => record.failed(&error, now, policy),
+                }
+                // A record pruned meanwhile was published already.
+                match storage.in_transaction(|tx| tx.update_outbox_event(&record)) {
+                    Ok(()) | Err(StorageError::NotFound) => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        Ok(attempted)
+    }
+
+    /// Removes the events published longer than `retention` ago, returns how many.
+    pub fn prune(retention: Duration, storage: &dyn Storage) -> Result<u64, Error> {
+        let before = Utc::now().timestamp() - retention.as_secs() as i64;
+        Ok(storage.in_transaction(|tx| tx.prune_outbox(before))?)
+    }
+}
@@ -0,0 +1,429 @@
+use crate::app::Error;
+use crate::domain::mfa::SecretCipher;
+use crate::domain::outbox::{OutboxRecord, USER_AGGREGATE};
+use crate::domain::realm::RealmName;
+use crate::domain::webhook::{
+    sign_payload, webhook_secret, CreateSubscription, CreatedSubscription, DeliveryStatus,
+    EndpointPolicy, RetryPolicy, SubscriptionInfo, WebhookDelivery, WebhookEventType,
+    WebhookPayload, WebhookSubscription, WebhookUrl, EVENT_ID_HEADER, EVENT_TYPE_HEADER,
+    SIGNATURE_HEADER,
+};
+use crate::repository::{Storage, StorageError, StorageResult, StorageTx};
+use crate::service::outbox::OutboxPublisher;
+use chrono::Utc;
+use rustls::crypto::ring::default_provider;
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use std::convert::TryFrom;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Deliveries sent per dispatch run.
+const DISPATCH_BATCH_SIZE: u32 = 20;
+/// How long a claimed delivery is hidden from other dispatchers. Covers a full batch of
+/// endpoints that time out.
+const DELIVERY_LEASE: Duration = Duration::from_secs(15 * 60);
+/// Newest dead letters listed to admins.
+const DEAD_LETTER_LIMIT: u32 = 100;
+
+/// Sends a signed webhook request. An error describes why the endpoint did not accept it.
+pub trait WebhookTransport: Send + Sync {
+    fn post(&self, url: &str, headers: &[(&str, String)], body: &str) -> Result<(), String>;
+}
+
+/// HTTP/1.1 over TLS for `https` URLs, checked against the Mozilla root certificates, and over
+/// plain TCP for the `http` ones the [`EndpointPolicy`] allows. Any 2xx status counts as
+/// accepted. Every resolved address of the host is checked before connecting, by default only
+/// public ones are called.
+///
+/// Through a proxy, the request is tunnelled with `CONNECT` to the checked address rather than
+/// the host name, so that the proxy's own resolution cannot lead it elsewhere.
+pub struct HttpTransport {
+    /// Connect, read and write timeout, each on its own.
+    pub timeout: Duration,
+    pub endpoints: EndpointPolicy,
+    /// `host:port` of an HTTP proxy.
+    pub proxy: Option<String>,
+    tls: Arc<ClientConfig>,
+}
+
+impl Default for HttpTransport {
+    fn default() -> Self {
+        HttpTransport::new(Duration::from_secs(10), EndpointPolicy::default(), None)
+    }
+}
+
+impl HttpTransport {
+    pub fn new(timeout: Duration, endpoints: EndpointPolicy, proxy: Option<String>) -> Self {
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        let tls = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .expect("the ring provider supports the default protocol versions")
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        HttpTransport {
+            timeout,
+            endpoints,
+            proxy,
+            tls: Arc::new(tls),
+        }
+    }
+
+    /// Connects to the first reachable address of the URL's host that may be called, directly
+    /// or through the proxy.
+    fn connect(&self, url: &WebhookUrl) -> Result<TcpStream, String> {
+        let addresses = (url.host.as_str(), url.port)
+            .to_socket_addrs()
+            .map_err(|e| format!("cannot resolve {}: {}", url.host, e))?
+            .collect::<Vec<_>>();
+        let allowed = addresses
+            .into_iter()
+            .filter(|address| self.endpoints.allows_address(address.ip()))
+            .collect::<Vec<_>>();
+        if allowed.is_empty() {
+            return Err(format!("{} has no public address", url.host));
+        }
+
+        let mut error = String::new();
+        for address in allowed {
+            let connected = match &self.proxy {
+                Some(proxy) => self.tunnel(proxy, address),
+                None => self.open(address),
+            };
+            match connected {
+                Ok(stream) => return Ok(stream),
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+
+    fn open(&self, address: SocketAddr) -> Result<TcpStream, String> {
+        let stream = TcpStream::connect_timeout(&address, self.timeout)
+            .map_err(|e| format!("cannot connect to {}: {}", address, e))?;
+        stream
+            .set_read_timeout(Some(self.timeout))
+            .and_then(|_| stream.set_write_timeout(Some(self.timeout)))
+            .map_err(|e| e.to_string())?;
+        Ok(stream)
+    }
+
+    /// A stream to `target` through the proxy, once it answered the `CONNECT` with a 2xx status.
+    fn tunnel(&self, proxy: &str, target: SocketAddr) -> Result<TcpStream, String> {
+        let address = proxy
+            .to_socket_addrs()
+            .map_err(|e| format!("cannot resolve proxy {}: {}", proxy, e))?
+            .next()
+            .ok_or_else(|| format!("proxy {} has no address", proxy))?;
+        let mut stream = self.open(address)?;
+        write!(
+            stream,
+            "CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n",
+            target, target
+        )
+        .map_err(|e| format!("cannot send request to proxy: {}", e))?;
+
+        // Read byte by byte, whatever follows the header belongs to the tunnel.
+        let mut response = Vec::new();
+        let mut byte = [0];
+        while !response.ends_with(b"\r\n\r\n") {
+            match stream.read(&mut byte) {
+                Ok(1) => response.push(byte[0]),
+                Ok(_) => return Err("the proxy closed the connection".to_string()),
+                Err(e) => return Err(format!("no response from proxy: {}", e)),
+            }
+        }
+        let response = String::from_utf8_lossy(&response);
+        let status = response.lines().next().unwrap_or_default();
+        match status_code(status) {
+            Some(code) if (200..300).contains(&code) => Ok(stream),
+            _ => Err(format!("proxy refused {}: {}", target, status)),
+        }
+    }
+}
+
+impl WebhookTransport for HttpTransport {
+    fn post(&self, url: &str, headers: &[(&str, String)], body: &str) -> Result<(), String> {
+        let url = self
+            .endpoints
+            .check_url(url)
+            .map_err(|e| format!("invalid url {}: {}", url, e))?;
+        let stream = self.connect(&url)?;
+        if !url.https {
+            return exchange(stream, &url, headers, body);
+        }
+        let server_name = ServerName::try_from(url.host.clone())
+            .map_err(|e| format!("invalid server name {}: {}", url.host, e))?;
+        let connection = ClientConnection::new(self.tls.clone(), server_name)
+            .map_err(|e| format!("cannot start tls: {}", e))?;
+        exchange(StreamOwned::new(connection, stream), &url, headers, body)
+    }
+}
+
+/// Sends the request over `stream` and reads the status of the response.
+fn exchange<S: Read + Write>(
+    mut stream: S,
+    url: &WebhookUrl,
+    headers: &[(&str, String)],
+    body: &str,
+) -> Result<(), String> {
+    let mut request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+        Content-Length: {}\r\nConnection: close\r\n",
+        url.path,
+        url.authority(),
+        body.len()
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+    stream
+        .write_all(request.as_bytes())
+        .and_then(|_| stream.flush())
+        .map_err(|e| format!("cannot send request: {}", e))?;
+
+    let mut status_line = String::new();
+    BufReader::new(stream)
+        .read_line(&mut status_line)
+        .map_err(|e| format!("no response: {}", e))?;
+    let status = status_line.trim_end();
+    match status_code(status) {
+        Some(code) if (200..300).contains(&code) => Ok(()),
+        Some(_) => Err(status
+            .split_once(' ')
+            .map_or(status, |(_, reason)| reason)
+            .to_string()),
+        None => Err(format!("malformed response: {}", status)),
+    }
+}
+
+/// The code of an HTTP status line.
+fn status_code(status_line: &str) -> Option<u16> {
+    status_line.split(' ').nth(1)?.parse().ok()
+}
+
+/// Publishes outbox events of users as webhook deliveries.
+pub struct WebhookPublisher {
+    pub storage: Arc<dyn Storage>,
+}
+
+impl OutboxPublisher for WebhookPublisher {
+    fn publish(&self, record: &OutboxRecord) -> Result<(), String> {
+        let now = Utc::now().timestamp();
+        self.storage
+            .in_transaction(|tx| WebhookService::enqueue(tx, record, now))
+            .map_err(|e| e.to_string())
+    }
+}
+
+pub struct WebhookService;
+
+impl WebhookService {
+    /// Subscribes `request.url` to events of the realm if `endpoints` allows it. The secret is
+    /// only returned here.
+    pub fn subscribe(
+        realm: &RealmName,
+        request: CreateSubscription,
+        endpoints: &EndpointPolicy,
+        cipher: &SecretCipher,
+        storage: &dyn Storage,
+    ) -> Result<CreatedSubscription, Error> {
+        endpoints
+            .check_url(&request.url)
+            .map_err(|message| Error::field("url", "url", message))?;
+        let subscription_id = Uuid::new_v4().to_string();
+        let secret = webhook_secret();
+        let mut event_types = Vec::new();
+        for event_type in request.event_types {
+            if !event_types.contains(&event_type) {
+                event_types.push(event_type);
+            }
+        }
+        let subscription = WebhookSubscription {
+            sealed_secret: cipher.seal(&subscription_id, secret.as_bytes()),
+            subscription_id,
+            realm: realm.clone(),
+            url: request.url,
+            event_types,
+            created_at: Utc::now().timestamp(),
+        };
+        storage.in_transaction(|tx| tx.create_subscription(&subscription))?;
+        Ok(CreatedSubscription {
+            subscription: SubscriptionInfo::from(&subscription),
+            secret,
+        })
+    }
+
+    pub fn list(realm: &RealmName, storage: &dyn Storage) -> Result<Vec<SubscriptionInfo>, Error> {
+        let subscriptions = storage.in_transaction(|tx| tx.list_subscriptions(realm))?;
+        Ok(subscriptions.iter().map(SubscriptionInfo::from).collect())
+    }
+
+    /// Removes the subscription, deliveries still queued for it are dropped.
+    pub fn unsubscribe(
+        realm: &RealmName,
+        subscription_id: &str,
+        storage: &dyn Storage,
+    ) -> Result<(), Error> {
+        Ok(storage.in_transaction(|tx| tx.delete_subscription(realm, subscription_id))?)
+    }
+
+    /// Queues a user event of the outbox for every subscription of its realm that wants it,
+    /// with the event id as payload id. Queueing an event again adds nothing, and other events
+    /// have no webhooks.
+    pub fn enqueue(tx: &mut dyn StorageTx, record: &OutboxRecord, now: i64) -> StorageResult<()> {
+        let event = &record.event;
+        let event_type = match WebhookEventType::from_str(&event.event_type) {
+            Ok(event_type) if event.aggregate_type == USER_AGGREGATE => event_type,
+            _ => return Ok(()),
+        };
+        let subscriptions = tx.list_subscriptions(&event.realm)?;
+        if !subscriptions.iter().any(|s| s.wants(event_type)) {
+            return Ok(());
+        }
+        let payload = WebhookPayload {
+            id: event.event_id.clone(),
+            event_type,
+            realm: event.realm.clone(),
+            user_id: event.aggregate_id.clone(),
+            occurred_at: event.occurred_at,
+            sequence: record.id,
+            data: event.payload.clone(),
+        };
+        for subscription in subscriptions.iter().filter(|s| s.wants(event_type)) {
+            tx.enqueue_delivery(&WebhookDelivery::new(subscription, &payload, now))?;
+        }
+        Ok(())
+    }
+
+    /// Clears the data of every queued delivery about an erased user, keeping the envelope so
+    /// that pending deliveries can still be sent. Returns how many were scrubbed.
+    pub fn redact_user(
+        tx: &mut dyn StorageTx,
+        realm: &RealmName,
+        user_id: &str,
+    ) -> StorageResult<usize> {
+        let mut redacted = 0;
+        for delivery in tx.find_deliveries_containing(realm, user_id)? {
+            let mut payload: WebhookPayload = match serde_json::from_str(&delivery.payload) {
+                Ok(payload) => payload,
+                Err(_) => continue,
+            };
+            if payload.user_id != user_id {
+                continue;
+            }
+            payload.data = serde_json::json!({});
+            let payload = serde_json::to_string(&payload).expect("webhook payloads serialize");
+            tx.replace_delivery_payload(&delivery.delivery_id, &payload)?;
+            redacted += 1;
+        }
+        Ok(redacted)
+    }
+
+    /// Sends the deliveries due at `now` and records the outcome of each, returns how many were
+    /// attempted. Failures are retried as `policy` says.
+    pub fn dispatch_due(
+        storage: &dyn Storage,
+        cipher: &SecretCipher,
+        transport: &dyn WebhookTransport,
+        policy: &RetryPolicy,
+        now: i64,
+    ) -> Result<usize, Error> {
+        let lease_until = now + DELIVERY_LEASE.as_secs() as i64;
+        let claimed = storage.in_transaction(|tx| {
+            let deliveries = tx.claim_due_deliveries(now, lease_until, DISPATCH_BATCH_SIZE)?;
+            deliveries
+                .into_iter()
+                .map(|delivery| {
+                    let subscription = tx.find_subscription(&delivery.subscription_id)?;
+                    Ok((delivery, subscription))
+                })
+                .collect::<StorageResult<Vec<_>>>()
+        })?;
+
+        let attempted = claimed.len();
+        for (mut delivery, subscription) in claimed {
+            // A subscription removed meanwhile took its deliveries along.
+            let subscription = match subscription {
+                Some(subscription) => subscription,
+                None => continue,
+            };
+            match WebhookService::send(&delivery, &subscription, cipher, transport, now) {
+                Ok(()) => delivery.delivered(now),
+                Err(error) => delivery.failed(&error, now, policy),
+            }
+            // The rest of the batch is sent and recorded all the same. A delivery whose outcome
+            // is lost is sent again once its lease runs out, receivers deduplicate on the id.
+            match storage.in_transaction(|tx| tx.update_delivery(&delivery)) {
+                Ok(()) | Err(StorageError::NotFound) => {}
+                Err(e) => println!(
+                    "failed to record webhook delivery {}: {}",
+                    delivery.delivery_id, e
+                ),
+            }
+        }
+        Ok(attempted)
+    }
+
+    /// The newest deliveries of the realm that ran out of retries.
+    pub fn dead_letters(
+        realm: &RealmName,
+        storage: &dyn Storage,
+    ) -> Result<Vec<WebhookDelivery>, Error> {
+        Ok(storage.in_transaction(|tx| {
+            tx.list_deliveries(realm, DeliveryStatus::DeadLettered, DEAD_LETTER_LIMIT)
+        })?)
+    }
+
+    /// Queues a dead-lettered or delivered delivery again, with the same payload and event id.
+    pub fn replay(
+        realm: &RealmName,
+        delivery_id: &str,
+        storage: &dyn Storage,
+    ) -> Result<WebhookDelivery, Error> {
+        let now = Utc::now().timestamp();
+        Ok(storage.in_transaction(|tx| {
+            let mut delivery = tx
+                .find_delivery(realm, delivery_id)?
+                .ok_or(StorageError::NotFound)?;
+            if delivery.status == DeliveryStatus::Pending {
+                return Err(StorageError::Conflict(format!(
+                    "delivery {} is already pending",
+                    delivery_id
+                )));
+            }
+            delivery.replay(now);
+            tx.update_delivery(&delivery)?;
+            Ok(delivery)
+        })?)
+    }
+
+    fn send(
+        delivery: &WebhookDelivery,
+        subscription: &WebhookSubscription,
+        cipher: &SecretCipher,
+        transport: &dyn WebhookTransport,
+        now: i64,
+    ) -> Result<(), String> {
+        let secret = cipher
+            .open(&subscription.subscription_id, &subscription.sealed_secret)
+            .ok_or_else(|| "the subscription secret does not open".to_string())?;
+        let headers = [
+            (
+                SIGNATURE_HEADER,
+                sign_payload(&secret, now, &delivery.payload),
+            ),
+            (EVENT_ID_HEADER, delivery.event_id.clone()),
+            (EVENT_TYPE_HEADER, delivery.event_type.to_string()),
+        ];
+        transport.post(&subscription.url, &headers, &delivery.payload)
+    }
+}
@@ -1,5 +1,8 @@
 USE auth;
 
+-- The version 1 schema with example users. The service applies authApp/migrations/mysql on
+-- startup, starting with the same schema in 0001_init.sql.
+
 -- 1) realm table (holds settings and configs)
 CREATE TABLE IF NOT EXISTS realm (
     realm_name                              VARCHAR(255)  NOT NULL,